@@ -0,0 +1,83 @@
+use super::Error;
+
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn with_capacity(bytes: usize) -> BitWriter {
+        BitWriter {
+            buf: Vec::with_capacity(bytes),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.len.is_multiple_of(8) {
+                self.buf.push(0);
+            }
+
+            if (value >> i) & 1 == 1 {
+                let last = self.buf.last_mut().unwrap();
+                *last |= 0x80 >> (self.len % 8);
+            }
+
+            self.len += 1;
+        }
+    }
+
+    pub fn align(&mut self) {
+        self.len = self.buf.len() * 8;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Returns the next `bits` bits without consuming them, padding with zeros past the end.
+    pub fn peek(&self, bits: u32) -> u32 {
+        let mut value: u64 = 0;
+
+        for i in 0..bits as usize {
+            let pos = self.pos + i;
+            let bit = self.data.get(pos / 8).map(|x| (x >> (7 - pos % 8)) & 1).unwrap_or(0);
+            value = (value << 1) | u64::from(bit);
+        }
+
+        value as u32
+    }
+
+    pub fn skip(&mut self, bits: u32) -> Result<(), Error> {
+        self.pos += bits as usize;
+
+        if self.pos > self.data.len() * 8 {
+            Err(Error::Truncated)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read(&mut self, bits: u32) -> Result<u32, Error> {
+        let value = self.peek(bits);
+        self.skip(bits)?;
+        Ok(value)
+    }
+}
@@ -0,0 +1,154 @@
+use super::{bits::BitReader, predictor::{self, Coefs, MAX_COEFS}, rice, Config, Error, ID_CPE, ID_END, ID_SCE};
+
+/// Decodes ALAC frames back into interleaved, sign-extended samples.
+pub struct Decoder {
+    config: Config,
+}
+
+struct ChannelHeader {
+    den_shift: u32,
+    pb_factor: u32,
+    order: usize,
+    coefs: Coefs,
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+impl Decoder {
+    pub fn new(config: Config) -> Decoder {
+        Decoder { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn decode(&mut self, frame: &[u8]) -> Result<Vec<i32>, Error> {
+        let mut r = BitReader::new(frame);
+        let mut samples = None;
+
+        loop {
+            match r.read(3)? {
+                ID_END => break,
+                tag @ (ID_SCE | ID_CPE) if samples.is_none() => {
+                    let channels = if tag == ID_CPE { 2 } else { 1 };
+
+                    if channels != self.config.channels {
+                        return Err(Error::InvalidFrame);
+                    }
+
+                    samples = Some(self.decode_element(&mut r, usize::from(channels))?);
+                },
+                _ => return Err(Error::InvalidFrame),
+            }
+        }
+
+        samples.ok_or(Error::InvalidFrame)
+    }
+
+    fn decode_element(&self, r: &mut BitReader, channels: usize) -> Result<Vec<i32>, Error> {
+        let _instance_tag = r.read(4)?;
+
+        if r.read(12)? != 0 {
+            return Err(Error::InvalidFrame);
+        }
+
+        let partial = r.read(1)? == 1;
+        let bytes_shifted = r.read(2)?;
+        let escape = r.read(1)? == 1;
+
+        let num_samples = if partial {
+            r.read(32)? as usize
+        } else {
+            self.config.frame_length as usize
+        };
+
+        if num_samples > self.config.frame_length as usize {
+            return Err(Error::InvalidSampleCount(num_samples));
+        }
+
+        let bit_depth = u32::from(self.config.bit_depth);
+
+        if escape {
+            return (0..num_samples * channels).map(|_| Ok(sign_extend(r.read(bit_depth)?, bit_depth))).collect();
+        }
+
+        let shift = bytes_shifted * 8;
+
+        if shift >= bit_depth {
+            return Err(Error::InvalidFrame);
+        }
+
+        let chan_bits = bit_depth - shift + channels as u32 - 1;
+        let mix_bits = r.read(8)?;
+        let mix_res = r.read(8)? as u8 as i8 as i32;
+
+        if mix_bits >= 32 {
+            return Err(Error::InvalidFrame);
+        }
+
+        let headers = (0..channels).map(|_| {
+            let mode = r.read(4)?;
+            let den_shift = r.read(4)?;
+            let pb_factor = r.read(3)?;
+            let order = r.read(5)? as usize;
+
+            if mode != 0 || den_shift == 0 || order >= MAX_COEFS {
+                return Err(Error::InvalidFrame);
+            }
+
+            let mut coefs = [0; MAX_COEFS];
+
+            for coef in coefs.iter_mut().take(order) {
+                *coef = r.read(16)? as u16 as i16;
+            }
+
+            Ok(ChannelHeader { den_shift, pb_factor, order, coefs })
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        let shift_buffer = if shift > 0 {
+            (0..num_samples * channels).map(|_| r.read(shift)).collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+
+        let mut planes = Vec::with_capacity(channels);
+
+        for mut header in headers {
+            let mut residuals = vec![0; num_samples];
+            rice::decode(r, &mut residuals, rice::Params::new(header.pb_factor), chan_bits)?;
+
+            let mut plane = vec![0; num_samples];
+            predictor::decode(&residuals, &mut plane, &mut header.coefs, header.order, chan_bits, header.den_shift);
+            planes.push(plane);
+        }
+
+        if channels == 2 && mix_res != 0 {
+            let (u, v) = planes.split_at_mut(1);
+
+            for (u, v) in u[0].iter_mut().zip(v[0].iter_mut()) {
+                let l = u.wrapping_add(*v).wrapping_sub(mix_res.wrapping_mul(*v) >> mix_bits);
+                *v = l.wrapping_sub(*v);
+                *u = l;
+            }
+        }
+
+        let mut samples = Vec::with_capacity(num_samples * channels);
+
+        for i in 0..num_samples {
+            for plane in &planes {
+                samples.push(plane[i]);
+            }
+        }
+
+        if shift > 0 {
+            for (x, low) in samples.iter_mut().zip(shift_buffer) {
+                *x = (*x << shift) | low as i32;
+            }
+        }
+
+        Ok(samples)
+    }
+}
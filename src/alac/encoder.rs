@@ -0,0 +1,170 @@
+use super::{bits::BitWriter, predictor::{self, Coefs, DEN_SHIFT}, rice, Config, Error, ID_CPE, ID_END, ID_SCE};
+
+/// Predictor orders tried for every channel of every frame.
+const ORDERS: [usize; 2] = [4, 8];
+
+const MIX_BITS: u32 = 2;
+const MAX_MIX_RES: i32 = 4;
+const PB_FACTOR: u32 = 4;
+
+struct Channel {
+    order: usize,
+    coefs: Coefs,
+    adapted: Coefs,
+    residuals: Vec<i32>,
+    bits: usize,
+}
+
+/// Encodes interleaved PCM into ALAC frames, keeping predictor state between frames like Apple's encoder.
+pub struct Encoder {
+    config: Config,
+    coefs: [[Coefs; ORDERS.len()]; 2],
+}
+
+fn residual_bits(residuals: &[i32], chan_bits: u32) -> usize {
+    let mut w = BitWriter::default();
+    rice::encode(&mut w, residuals, rice::Params::new(PB_FACTOR), chan_bits);
+    w.len()
+}
+
+fn predict(input: &[i32], mut coefs: Coefs, order: usize, chan_bits: u32) -> (Vec<i32>, Coefs, usize) {
+    let mut residuals = vec![0; input.len()];
+    predictor::encode(input, &mut residuals, &mut coefs, order, chan_bits, DEN_SHIFT);
+    let bits = residual_bits(&residuals, chan_bits);
+    (residuals, coefs, bits)
+}
+
+fn mix(left: &[i32], right: &[i32], mix_res: i32) -> (Vec<i32>, Vec<i32>) {
+    if mix_res == 0 {
+        return (left.to_vec(), right.to_vec());
+    }
+
+    let m2 = (1 << MIX_BITS) - mix_res;
+
+    left.iter().zip(right).map(|(&l, &r)| {
+        ((mix_res * l + m2 * r) >> MIX_BITS, l - r)
+    }).unzip()
+}
+
+impl Encoder {
+    pub fn new(config: Config) -> Encoder {
+        let coefs = ORDERS.map(predictor::init_coefs);
+
+        Encoder {
+            config,
+            coefs: [coefs, coefs],
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn compress_channel(&self, index: usize, input: &[i32], chan_bits: u32) -> (usize, Channel) {
+        ORDERS.iter().enumerate().map(|(slot, &order)| {
+            let coefs = self.coefs[index][slot];
+            let (residuals, adapted, bits) = predict(input, coefs, order, chan_bits);
+            (slot, Channel { order, coefs, adapted, residuals, bits: bits + 16 * order })
+        }).min_by_key(|(_, x)| x.bits).unwrap()
+    }
+
+    fn best_mix_res(&self, left: &[i32], right: &[i32], chan_bits: u32) -> i32 {
+        let coefs = predictor::init_coefs(8);
+
+        (0..=MAX_MIX_RES).min_by_key(|&mix_res| {
+            let (u, v) = mix(left, right, mix_res);
+            predict(&u, coefs, 8, chan_bits).2 + predict(&v, coefs, 8, chan_bits).2
+        }).unwrap()
+    }
+
+    /// Encodes one frame of interleaved, sign-extended samples. Frames shorter than `frame_length` are
+    /// marked as partial.
+    pub fn encode(&mut self, samples: &[i32]) -> Result<Vec<u8>, Error> {
+        let channels = usize::from(self.config.channels);
+        let num_samples = samples.len() / channels;
+
+        if !samples.len().is_multiple_of(channels) || num_samples > self.config.frame_length as usize {
+            return Err(Error::InvalidSampleCount(samples.len()));
+        }
+
+        let bytes_shifted = self.config.bytes_shifted();
+        let shift = bytes_shifted * 8;
+        let mask = (1 << shift) - 1;
+        let chan_bits = u32::from(self.config.bit_depth) - shift + channels as u32 - 1;
+
+        let shift_buffer: Vec<u32> = samples.iter().map(|&x| x as u32 & mask).collect();
+        let planes: Vec<Vec<i32>> = (0..channels).map(|c| {
+            samples.iter().skip(c).step_by(channels).map(|&x| x >> shift).collect()
+        }).collect();
+
+        let (mix_res, inputs) = if channels == 2 {
+            let mix_res = self.best_mix_res(&planes[0], &planes[1], chan_bits);
+            let (u, v) = mix(&planes[0], &planes[1], mix_res);
+            (mix_res, vec![u, v])
+        } else {
+            (0, planes)
+        };
+
+        let compressed: Vec<(usize, Channel)> = inputs.iter().enumerate().map(|(i, x)| self.compress_channel(i, x, chan_bits)).collect();
+
+        let header_bits = 16 + compressed.iter().map(|(_, x)| 16 + 16 * x.order).sum::<usize>();
+        let shift_bits = samples.len() * shift as usize;
+        let compressed_bits = header_bits + shift_bits + compressed.iter().map(|(_, x)| x.bits - 16 * x.order).sum::<usize>();
+        let escaped_bits = samples.len() * usize::from(self.config.bit_depth);
+        let escape = compressed_bits >= escaped_bits;
+
+        let mut w = BitWriter::with_capacity(self.config.max_frame_bytes());
+        w.write(if channels == 2 { ID_CPE } else { ID_SCE }, 3);
+        w.write(0, 4);
+        w.write(0, 12);
+
+        let partial = num_samples != self.config.frame_length as usize;
+        w.write(u32::from(partial), 1);
+        w.write(if escape { 0 } else { bytes_shifted }, 2);
+        w.write(u32::from(escape), 1);
+
+        if partial {
+            w.write(num_samples as u32, 32);
+        }
+
+        if escape {
+            let bit_depth = u32::from(self.config.bit_depth);
+            let mask = u32::MAX >> (32 - bit_depth);
+
+            for x in samples {
+                w.write(*x as u32 & mask, bit_depth);
+            }
+        } else {
+            w.write(MIX_BITS, 8);
+            w.write(mix_res as u32, 8);
+
+            for (_, channel) in &compressed {
+                w.write(DEN_SHIFT, 8);
+                w.write((PB_FACTOR << 5) | channel.order as u32, 8);
+
+                for coef in &channel.coefs[..channel.order] {
+                    w.write(*coef as u16 as u32, 16);
+                }
+            }
+
+            if shift > 0 {
+                for x in &shift_buffer {
+                    w.write(*x, shift);
+                }
+            }
+
+            for (_, channel) in &compressed {
+                rice::encode(&mut w, &channel.residuals, rice::Params::new(PB_FACTOR), chan_bits);
+            }
+        }
+
+        w.write(ID_END, 3);
+        w.align();
+
+        for (index, (slot, channel)) in compressed.into_iter().enumerate() {
+            self.coefs[index][slot] = channel.adapted;
+        }
+
+        Ok(w.into_bytes())
+    }
+}
@@ -0,0 +1,213 @@
+//! Apple Lossless (ALAC) encoding and decoding, following the structure of Apple's reference implementation.
+
+use std::fmt;
+
+mod bits;
+mod decoder;
+mod encoder;
+mod predictor;
+mod rice;
+
+pub use decoder::Decoder;
+pub use encoder::Encoder;
+
+/// Samples per frame negotiated as `spf` for realtime audio streams.
+pub const FRAME_LENGTH_REALTIME: u32 = 352;
+
+/// Samples per frame negotiated as `spf` for buffered audio streams.
+pub const FRAME_LENGTH_BUFFERED: u32 = 4096;
+
+const ID_SCE: u32 = 0;
+const ID_CPE: u32 = 1;
+const ID_END: u32 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnsupportedBitDepth(u8),
+    UnsupportedChannels(u8),
+    InvalidSampleCount(usize),
+    Truncated,
+    InvalidFrame,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedBitDepth(x) => write!(f, "unsupported ALAC bit depth: {}", x),
+            Self::UnsupportedChannels(x) => write!(f, "unsupported ALAC channel count: {}", x),
+            Self::InvalidSampleCount(x) => write!(f, "invalid ALAC frame sample count: {}", x),
+            Self::Truncated => f.write_str("truncated ALAC frame"),
+            Self::InvalidFrame => f.write_str("invalid ALAC frame"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub frame_length: u32,
+    pub sample_rate: u32,
+    pub bit_depth: u8,
+    pub channels: u8,
+}
+
+impl Config {
+    pub fn new(frame_length: u32, sample_rate: u32, bit_depth: u8, channels: u8) -> Result<Config, Error> {
+        if bit_depth != 16 && bit_depth != 24 {
+            return Err(Error::UnsupportedBitDepth(bit_depth));
+        }
+
+        if !(1..=2).contains(&channels) {
+            return Err(Error::UnsupportedChannels(channels));
+        }
+
+        if frame_length == 0 {
+            return Err(Error::InvalidSampleCount(0));
+        }
+
+        Ok(Config {
+            frame_length,
+            sample_rate,
+            bit_depth,
+            channels,
+        })
+    }
+
+    /// Upper bound of an encoded frame, which is an escaped (uncompressed) frame plus its headers.
+    pub fn max_frame_bytes(&self) -> usize {
+        self.frame_length as usize * usize::from(self.channels) * usize::from(self.bit_depth / 8) + 8
+    }
+
+    /// The 24 byte `ALACSpecificConfig` describing this stream.
+    pub fn magic_cookie(&self) -> Vec<u8> {
+        let mut cookie = Vec::with_capacity(24);
+        cookie.extend(self.frame_length.to_be_bytes());
+        cookie.push(0);
+        cookie.push(self.bit_depth);
+        cookie.push(rice::PB0 as u8);
+        cookie.push(rice::MB0 as u8);
+        cookie.push(rice::KB0 as u8);
+        cookie.push(self.channels);
+        cookie.extend(rice::MAX_RUN.to_be_bytes());
+        cookie.extend((self.max_frame_bytes() as u32).to_be_bytes());
+        cookie.extend(0_u32.to_be_bytes());
+        cookie.extend(self.sample_rate.to_be_bytes());
+        cookie
+    }
+
+    /// The same parameters as [`Config::magic_cookie`], in the space separated `fmtp` form used by SDP.
+    pub fn fmtp(&self) -> String {
+        format!(
+            "{} 0 {} {} {} {} {} {} 0 0 {}",
+            self.frame_length, self.bit_depth, rice::PB0, rice::MB0, rice::KB0, self.channels, rice::MAX_RUN, self.sample_rate,
+        )
+    }
+
+    fn bytes_shifted(&self) -> u32 {
+        if self.bit_depth == 24 { 1 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> i32 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) as i32
+        }
+    }
+
+    fn sine(config: &Config, samples: usize, amplitude: f64) -> Vec<i32> {
+        (0..samples * usize::from(config.channels)).map(|i| {
+            let t = (i / usize::from(config.channels)) as f64 / f64::from(config.sample_rate);
+            let phase = if i % 2 == 0 { 0.0 } else { 0.7 };
+            ((2.0 * std::f64::consts::PI * 440.0 * t + phase).sin() * amplitude) as i32
+        }).collect()
+    }
+
+    fn noise(config: &Config, samples: usize) -> Vec<i32> {
+        let mut rng = Lcg(42);
+        let shift = 32 - u32::from(config.bit_depth);
+        (0..samples * usize::from(config.channels)).map(|_| (rng.next() << shift) >> shift).collect()
+    }
+
+    fn round_trip(config: Config, frames: &[Vec<i32>]) {
+        let mut encoder = Encoder::new(config);
+        let mut decoder = Decoder::new(config);
+
+        for frame in frames {
+            let encoded = encoder.encode(frame).unwrap();
+            assert!(encoded.len() <= config.max_frame_bytes());
+            assert_eq!(&decoder.decode(&encoded).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn round_trips_all_formats() {
+        for frame_length in [FRAME_LENGTH_REALTIME, FRAME_LENGTH_BUFFERED] {
+            for bit_depth in [16, 24] {
+                for channels in [1, 2] {
+                    let config = Config::new(frame_length, 44100, bit_depth, channels).unwrap();
+                    let full = 2_f64.powi(i32::from(bit_depth) - 1) - 1.0;
+                    let n = frame_length as usize;
+
+                    round_trip(config, &[
+                        sine(&config, n, full * 0.5),
+                        sine(&config, n, full),
+                        vec![0; n * usize::from(channels)],
+                        noise(&config, n),
+                        sine(&config, n / 3, full * 0.25),
+                    ]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_tonal_input() {
+        let config = Config::new(FRAME_LENGTH_BUFFERED, 44100, 16, 2).unwrap();
+        let frame = sine(&config, 4096, 8000.0);
+        let encoded = Encoder::new(config).encode(&frame).unwrap();
+        assert!(encoded.len() < frame.len());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert_eq!(Config::new(352, 44100, 20, 2), Err(Error::UnsupportedBitDepth(20)));
+        assert_eq!(Config::new(352, 44100, 16, 6), Err(Error::UnsupportedChannels(6)));
+
+        let config = Config::new(352, 44100, 16, 2).unwrap();
+        assert_eq!(Encoder::new(config).encode(&[0; 353 * 2]), Err(Error::InvalidSampleCount(353 * 2)));
+        assert_eq!(Encoder::new(config).encode(&[0; 3]), Err(Error::InvalidSampleCount(3)));
+
+        let encoded = Encoder::new(config).encode(&sine(&config, 352, 1000.0)).unwrap();
+        assert_eq!(Decoder::new(config).decode(&encoded[..encoded.len() / 2]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn rejects_out_of_range_mix_bits() {
+        let config = Config::new(352, 44100, 16, 2).unwrap();
+        let mut encoded = Encoder::new(config).encode(&sine(&config, 352, 1000.0)).unwrap();
+
+        // Not escaped, so the 8 bits of `mixBits` follow the 23 bits of element and frame header.
+        assert_eq!(encoded[2] & 0x02, 0);
+        encoded[2] |= 0x01;
+        encoded[3] |= 0xfe;
+
+        assert_eq!(Decoder::new(config).decode(&encoded), Err(Error::InvalidFrame));
+    }
+
+    #[test]
+    fn magic_cookie_layout() {
+        let config = Config::new(352, 44100, 16, 2).unwrap();
+        let cookie = config.magic_cookie();
+        assert_eq!(cookie.len(), 24);
+        assert_eq!(&cookie[..12], &[0, 0, 1, 96, 0, 16, 40, 10, 14, 2, 0, 255]);
+        assert_eq!(config.fmtp(), "352 0 16 40 10 14 2 255 0 0 44100");
+    }
+}
@@ -0,0 +1,136 @@
+//! Adaptive FIR predictor with sign-sign coefficient updates, modelled on Apple's `dp_enc`/`dp_dec`.
+
+pub(crate) const DEN_SHIFT: u32 = 9;
+pub(crate) const MAX_COEFS: usize = 32;
+
+pub(crate) type Coefs = [i16; MAX_COEFS];
+
+pub(crate) fn init_coefs(order: usize) -> Coefs {
+    let den = 1_i32 << DEN_SHIFT;
+    let mut coefs = [0; MAX_COEFS];
+
+    for (coef, init) in coefs.iter_mut().zip([38, -29, -2]).take(order) {
+        *coef = ((init * den) >> 4) as i16;
+    }
+
+    coefs
+}
+
+fn wrap(x: i32, chan_shift: u32) -> i32 {
+    x.wrapping_shl(chan_shift).wrapping_shr(chan_shift)
+}
+
+/// Updates the coefficients after predicting sample `j`, given its residual.
+fn adapt(history: &[i32], j: usize, top: i32, residual: i32, coefs: &mut Coefs, order: usize, den_shift: u32) {
+    let mut del0 = residual;
+
+    match residual.signum() {
+        1 => {
+            for k in (0..order).rev() {
+                let dd = top.wrapping_sub(history[j - 1 - k]);
+                let sgn = dd.signum();
+                coefs[k] = coefs[k].wrapping_sub(sgn as i16);
+                del0 = del0.wrapping_sub((order - k) as i32 * (sgn.wrapping_mul(dd) >> den_shift));
+
+                if del0 <= 0 {
+                    break;
+                }
+            }
+        },
+        -1 => {
+            for k in (0..order).rev() {
+                let dd = top.wrapping_sub(history[j - 1 - k]);
+                let sgn = dd.signum();
+                coefs[k] = coefs[k].wrapping_add(sgn as i16);
+                del0 = del0.wrapping_sub((order - k) as i32 * ((-sgn).wrapping_mul(dd) >> den_shift));
+
+                if del0 >= 0 {
+                    break;
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+fn prediction(history: &[i32], j: usize, top: i32, coefs: &Coefs, order: usize, den_shift: u32) -> i32 {
+    let mut sum: i32 = 0;
+
+    for (k, coef) in coefs.iter().enumerate().take(order) {
+        sum = sum.wrapping_add(i32::from(*coef).wrapping_mul(history[j - 1 - k].wrapping_sub(top)));
+    }
+
+    sum.wrapping_add(1 << (den_shift - 1)) >> den_shift
+}
+
+/// Turns `input` into prediction residuals, adapting `coefs` along the way.
+pub(crate) fn encode(input: &[i32], output: &mut [i32], coefs: &mut Coefs, order: usize, chan_bits: u32, den_shift: u32) {
+    let chan_shift = 32 - chan_bits;
+
+    if input.is_empty() {
+        return;
+    }
+
+    output[0] = input[0];
+
+    if order == 0 {
+        output[1..input.len()].copy_from_slice(&input[1..]);
+        return;
+    }
+
+    if order == 31 {
+        for j in 1..input.len() {
+            output[j] = wrap(input[j].wrapping_sub(input[j - 1]), chan_shift);
+        }
+        return;
+    }
+
+    for j in 1..=order.min(input.len() - 1) {
+        output[j] = wrap(input[j].wrapping_sub(input[j - 1]), chan_shift);
+    }
+
+    for j in (order + 1)..input.len() {
+        let top = input[j - order - 1];
+        let predicted = prediction(input, j, top, coefs, order, den_shift);
+        let residual = wrap(input[j].wrapping_sub(top).wrapping_sub(predicted), chan_shift);
+        output[j] = residual;
+
+        adapt(input, j, top, residual, coefs, order, den_shift);
+    }
+}
+
+/// Reconstructs samples from prediction residuals, mirroring [`encode`].
+pub(crate) fn decode(input: &[i32], output: &mut [i32], coefs: &mut Coefs, order: usize, chan_bits: u32, den_shift: u32) {
+    let chan_shift = 32 - chan_bits;
+
+    if input.is_empty() {
+        return;
+    }
+
+    output[0] = input[0];
+
+    if order == 0 {
+        output[1..input.len()].copy_from_slice(&input[1..]);
+        return;
+    }
+
+    if order == 31 {
+        for j in 1..input.len() {
+            output[j] = wrap(input[j].wrapping_add(output[j - 1]), chan_shift);
+        }
+        return;
+    }
+
+    for j in 1..=order.min(input.len() - 1) {
+        output[j] = wrap(input[j].wrapping_add(output[j - 1]), chan_shift);
+    }
+
+    for j in (order + 1)..input.len() {
+        let top = output[j - order - 1];
+        let predicted = prediction(output, j, top, coefs, order, den_shift);
+        let residual = input[j];
+        output[j] = wrap(residual.wrapping_add(top).wrapping_add(predicted), chan_shift);
+
+        adapt(output, j, top, residual, coefs, order, den_shift);
+    }
+}
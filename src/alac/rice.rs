@@ -0,0 +1,207 @@
+//! Adaptive Golomb-Rice coding of prediction residuals, modelled on Apple's `ag_enc`/`ag_dec`.
+
+use super::{bits::{BitReader, BitWriter}, Error};
+
+pub(crate) const MB0: u32 = 10;
+pub(crate) const PB0: u32 = 40;
+pub(crate) const KB0: u32 = 14;
+pub(crate) const MAX_RUN: u16 = 255;
+
+const QB_SHIFT: u32 = 9;
+const QB: u32 = 1 << QB_SHIFT;
+const MMUL_SHIFT: u32 = 2;
+const MDEN_SHIFT: u32 = QB_SHIFT - MMUL_SHIFT - 1;
+const MOFF: u32 = 1 << (MDEN_SHIFT - 2);
+const BIT_OFF: u32 = 24;
+
+const MAX_PREFIX_16: u32 = 9;
+const MAX_PREFIX_32: u32 = 9;
+const MAX_DATATYPE_BITS_16: u32 = 16;
+const N_MAX_MEAN_CLAMP: u32 = 0xffff;
+const N_MEAN_CLAMP_VAL: u32 = 0xffff;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Params {
+    mb: u32,
+    pb: u32,
+    kb: u32,
+    wb: u32,
+}
+
+impl Params {
+    pub fn new(pb_factor: u32) -> Params {
+        Params {
+            mb: MB0,
+            pb: (PB0 * pb_factor) / 4,
+            kb: KB0,
+            wb: (1 << KB0) - 1,
+        }
+    }
+}
+
+fn lg3a(x: u32) -> u32 {
+    31 - (x + 3).leading_zeros()
+}
+
+fn rice_k(mb: u32, kb: u32) -> u32 {
+    lg3a(mb >> QB_SHIFT).min(kb)
+}
+
+fn zero_run_k(mb: u32) -> u32 {
+    (mb.leading_zeros() + ((mb + MOFF) >> MDEN_SHIFT)).wrapping_sub(BIT_OFF)
+}
+
+fn write_value(w: &mut BitWriter, m: u32, k: u32, n: u32, max_bits: u32) {
+    let division = n / m;
+
+    if division < MAX_PREFIX_32 {
+        let modulo = n - m * division;
+        let de = u32::from(modulo == 0);
+        let num_bits = division + k + 1 - de;
+
+        if num_bits <= 25 {
+            let value = (((1 << division) - 1) << (num_bits - division)) + modulo + 1 - de;
+            w.write(value, num_bits);
+            return;
+        }
+    }
+
+    w.write((1 << MAX_PREFIX_32) - 1, MAX_PREFIX_32);
+    w.write(n, max_bits);
+}
+
+fn write_run(w: &mut BitWriter, m: u32, k: u32, n: u32) {
+    let division = n / m;
+
+    if division < MAX_PREFIX_16 {
+        let modulo = n % m;
+        let de = u32::from(modulo == 0);
+        let num_bits = division + k + 1 - de;
+
+        if num_bits <= MAX_PREFIX_16 + MAX_DATATYPE_BITS_16 {
+            let value = (((1 << division) - 1) << (num_bits - division)) + modulo + 1 - de;
+            w.write(value, num_bits);
+            return;
+        }
+    }
+
+    w.write((((1 << MAX_PREFIX_16) - 1) << MAX_DATATYPE_BITS_16) + n, MAX_PREFIX_16 + MAX_DATATYPE_BITS_16);
+}
+
+fn read_value(r: &mut BitReader, m: u32, k: u32, max_prefix: u32, max_bits: u32) -> Result<u32, Error> {
+    let prefix = (!r.peek(32)).leading_zeros();
+
+    if prefix >= max_prefix {
+        r.skip(max_prefix)?;
+        return r.read(max_bits);
+    }
+
+    r.skip(prefix + 1)?;
+
+    if k == 1 {
+        return Ok(prefix);
+    }
+
+    let v = r.peek(k);
+    r.skip(k - 1)?;
+
+    if v >= 2 {
+        r.skip(1)?;
+        Ok(prefix * m + v - 1)
+    } else {
+        Ok(prefix * m)
+    }
+}
+
+pub(crate) fn encode(w: &mut BitWriter, residuals: &[i32], params: Params, max_bits: u32) {
+    let mut mb = params.mb;
+    let mut zmode = 0;
+    let mut c = 0;
+
+    while c < residuals.len() {
+        let k = rice_k(mb, params.kb);
+        let m = (1 << k) - 1;
+
+        let del = residuals[c];
+        let n = ((del.unsigned_abs() << 1) - u32::from(del < 0)).wrapping_sub(zmode);
+        write_value(w, m, k, n, max_bits);
+        c += 1;
+
+        mb = params.pb.wrapping_mul(n + zmode).wrapping_add(mb).wrapping_sub(params.pb.wrapping_mul(mb) >> QB_SHIFT);
+
+        if n > N_MAX_MEAN_CLAMP {
+            mb = N_MEAN_CLAMP_VAL;
+        }
+
+        zmode = 0;
+
+        if (mb << MMUL_SHIFT) < QB && c < residuals.len() {
+            zmode = 1;
+            let mut nz = 0;
+
+            while c < residuals.len() && residuals[c] == 0 {
+                c += 1;
+                nz += 1;
+
+                if nz >= 65535 {
+                    zmode = 0;
+                    break;
+                }
+            }
+
+            let k = zero_run_k(mb);
+            let mz = ((1 << k) - 1) & params.wb;
+            write_run(w, mz, k, nz);
+
+            mb = 0;
+        }
+    }
+}
+
+pub(crate) fn decode(r: &mut BitReader, out: &mut [i32], params: Params, max_bits: u32) -> Result<(), Error> {
+    let mut mb = params.mb;
+    let mut zmode = 0;
+    let mut c = 0;
+
+    while c < out.len() {
+        let k = rice_k(mb, params.kb);
+        let m = (1 << k) - 1;
+
+        let n = read_value(r, m, k, MAX_PREFIX_32, max_bits)?;
+        let decoded = n.wrapping_add(zmode);
+        let magnitude = (decoded.wrapping_add(1) >> 1) as i32;
+        out[c] = if decoded & 1 == 1 { -magnitude } else { magnitude };
+        c += 1;
+
+        mb = params.pb.wrapping_mul(n + zmode).wrapping_add(mb).wrapping_sub(params.pb.wrapping_mul(mb) >> QB_SHIFT);
+
+        if n > N_MAX_MEAN_CLAMP {
+            mb = N_MEAN_CLAMP_VAL;
+        }
+
+        zmode = 0;
+
+        if (mb << MMUL_SHIFT) < QB && c < out.len() {
+            zmode = 1;
+
+            let k = zero_run_k(mb);
+            let mz = ((1 << k) - 1) & params.wb;
+            let nz = read_value(r, mz, k, MAX_PREFIX_16, MAX_DATATYPE_BITS_16)? as usize;
+
+            if c + nz > out.len() {
+                return Err(Error::InvalidFrame);
+            }
+
+            out[c..c + nz].fill(0);
+            c += nz;
+
+            if nz >= 65535 {
+                zmode = 0;
+            }
+
+            mb = 0;
+        }
+    }
+
+    Ok(())
+}
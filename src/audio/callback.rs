@@ -0,0 +1,76 @@
+use std::io;
+
+use super::{Format, Sink, Timing};
+
+/// Hands every block of audio to a function, along with its format and when it is due.
+pub struct CallbackSink<F> {
+    callback: F,
+    format: Option<Format>,
+}
+
+impl<F: FnMut(&[i32], Format, Timing) + Send> CallbackSink<F> {
+    pub fn new(callback: F) -> CallbackSink<F> {
+        CallbackSink { callback, format: None }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.callback
+    }
+}
+
+impl<F: FnMut(&[i32], Format, Timing) + Send> Sink for CallbackSink<F> {
+    async fn start(&mut self, format: Format) -> io::Result<()> {
+        self.format = Some(format);
+        Ok(())
+    }
+
+    async fn write(&mut self, samples: &[i32], timing: Timing) -> io::Result<()> {
+        let format = self.format.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "write before start"))?;
+        (self.callback)(samples, format, timing);
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn hands_over_blocks_with_their_format_and_timing() {
+        let at = Instant::now() + Duration::from_millis(250);
+        let mut blocks = Vec::new();
+
+        {
+            let mut sink = CallbackSink::new(|samples: &[i32], format, timing| blocks.push((samples.to_vec(), format, timing)));
+            sink.start(Format::new(44100, 2, 16)).await.unwrap();
+            sink.write(&[1, -1], Timing { rtp: 352, at: None }).await.unwrap();
+            sink.start(Format::new(48000, 1, 24)).await.unwrap();
+            sink.write(&[7], Timing { rtp: 704, at: Some(at) }).await.unwrap();
+            sink.finish().await.unwrap();
+        }
+
+        assert_eq!(blocks, vec![
+            (vec![1, -1], Format::new(44100, 2, 16), Timing { rtp: 352, at: None }),
+            (vec![7], Format::new(48000, 1, 24), Timing { rtp: 704, at: Some(at) }),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn write_before_start_fails() {
+        let mut calls = 0;
+
+        {
+            let mut sink = CallbackSink::new(|_: &[i32], _, _| calls += 1);
+            let err = sink.write(&[0, 0], Timing { rtp: 0, at: None }).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        assert_eq!(calls, 0);
+    }
+}
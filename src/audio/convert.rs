@@ -0,0 +1,268 @@
+use std::io;
+
+use super::{frames_in, resample::{Quality, Resampler}, Format, Source};
+
+/// Adapts a source to the sample rate, channel count and bit depth negotiated with the receiver.
+///
+/// Mono is duplicated onto both channels, downmixing averages all channels, and extra channels beyond
+/// the second are dropped when converting to stereo. Sample rate changes go through a [`Resampler`], and
+/// requantizing to fewer bits is TPDF dithered unless the quality is [`Quality::Fast`].
+pub struct Converter<S> {
+    source: S,
+    target: Format,
+    quality: Quality,
+    resampler: Option<Resampler>,
+    scratch: Vec<i32>,
+    pending: Vec<f64>,
+    finished: bool,
+    rng: u32,
+}
+
+impl<S: Source + Send> Converter<S> {
+    pub fn new(source: S, target: Format) -> io::Result<Converter<S>> {
+        Converter::with_quality(source, target, Quality::default())
+    }
+
+    pub fn with_quality(source: S, target: Format, quality: Quality) -> io::Result<Converter<S>> {
+        let format = source.format();
+
+        if target.channels == 0 || target.channels > 2 || !(8..=32).contains(&target.bit_depth) || target.sample_rate == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported target format: {:?}", target)));
+        }
+
+        let resampler = (format.sample_rate != target.sample_rate).then(|| {
+            Resampler::new(format.sample_rate, target.sample_rate, usize::from(target.channels), quality)
+        });
+
+        Ok(Converter {
+            source,
+            target,
+            quality,
+            resampler,
+            scratch: Vec::new(),
+            pending: Vec::new(),
+            finished: false,
+            rng: 0x9e37_79b9,
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        f64::from(self.rng) / f64::from(u32::MAX) - 0.5
+    }
+
+    /// Converts a normalized sample to the target bit depth.
+    fn quantize(&mut self, x: f64) -> i32 {
+        let scale = (1_i64 << (self.target.bit_depth - 1)) as f64;
+        let dither = if self.quality.dither() { self.random() + self.random() } else { 0.0 };
+        (x * scale + dither).round().clamp(-scale, scale - 1.0) as i32
+    }
+
+    fn remix(&self, input: &[i32], output: &mut [i32]) {
+        let from = input.len();
+
+        if output.len() == 1 {
+            let sum: i64 = input.iter().map(|x| i64::from(*x)).sum();
+            output[0] = (sum / from as i64) as i32;
+        } else {
+            output[0] = input[0];
+            output[1] = input[if from > 1 { 1 } else { 0 }];
+        }
+    }
+
+    async fn read_direct(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        let format = self.source.format();
+        let from = usize::from(format.channels);
+        let to = usize::from(self.target.channels);
+        let frames = buf.len() / to;
+
+        self.scratch.resize(frames * from, 0);
+        let n = self.source.read(&mut self.scratch).await? / from;
+        let scratch = std::mem::take(&mut self.scratch);
+
+        for (input, output) in scratch[..n * from].chunks_exact(from).zip(buf.chunks_exact_mut(to)) {
+            self.remix(input, output);
+
+            for x in output.iter_mut() {
+                *x = if self.target.bit_depth >= format.bit_depth {
+                    *x << (self.target.bit_depth - format.bit_depth)
+                } else if self.quality.dither() {
+                    self.quantize(f64::from(*x) / (1_i64 << (format.bit_depth - 1)) as f64)
+                } else {
+                    *x >> (format.bit_depth - self.target.bit_depth)
+                };
+            }
+        }
+
+        self.scratch = scratch;
+        Ok(n * to)
+    }
+
+    async fn read_resampled(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        let format = self.source.format();
+        let from = usize::from(format.channels);
+        let to = usize::from(self.target.channels);
+        let wanted = buf.len() / to * to;
+        let scale = (1_i64 << (format.bit_depth - 1)) as f64;
+
+        while self.pending.len() < wanted && !self.finished {
+            let frames = (wanted / to * format.sample_rate as usize / self.target.sample_rate as usize).max(64);
+            self.scratch.resize(frames * from, 0);
+            let n = self.source.read(&mut self.scratch).await? / from;
+
+            let mut mixed = vec![0; to];
+            let mut input = Vec::with_capacity(n * to);
+
+            for frame in self.scratch[..n * from].chunks_exact(from) {
+                self.remix(frame, &mut mixed);
+                input.extend(mixed.iter().map(|x| f64::from(*x) / scale));
+            }
+
+            let resampler = self.resampler.as_mut().unwrap();
+
+            if n == 0 {
+                resampler.finish(&mut self.pending);
+                self.finished = true;
+            } else {
+                resampler.process(&input, &mut self.pending);
+            }
+        }
+
+        let n = self.pending.len().min(wanted);
+        let pending: Vec<f64> = self.pending.drain(..n).collect();
+
+        for (x, y) in buf.iter_mut().zip(pending) {
+            *x = self.quantize(y);
+        }
+
+        Ok(n)
+    }
+}
+
+impl<S: Source + Send> Source for Converter<S> {
+    fn format(&self) -> Format {
+        self.target
+    }
+
+    async fn read(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        frames_in(buf, self.target)?;
+
+        if self.resampler.is_some() {
+            self.read_resampled(buf).await
+        } else {
+            self.read_direct(buf).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::read_frames;
+
+    /// Plays back `samples` in `format`, a few frames per read.
+    struct Samples {
+        format: Format,
+        samples: Vec<i32>,
+    }
+
+    impl Source for Samples {
+        fn format(&self) -> Format {
+            self.format
+        }
+
+        async fn read(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+            let channels = usize::from(self.format.channels);
+            let n = buf.len().min(self.samples.len()).min(64 * channels) / channels * channels;
+            buf[..n].copy_from_slice(&self.samples[..n]);
+            self.samples.drain(..n);
+            Ok(n)
+        }
+    }
+
+    async fn convert(format: Format, samples: Vec<i32>, target: Format, quality: Quality) -> Vec<i32> {
+        let mut converter = Converter::with_quality(Samples { format, samples }, target, quality).unwrap();
+        assert_eq!(converter.format(), target);
+        read_frames(&mut converter, 1 << 20).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn remixes_channels() {
+        let stereo = Format::new(44100, 2, 16);
+        let mono = Format::new(44100, 1, 16);
+
+        assert_eq!(convert(mono, vec![1, -2, 3], stereo, Quality::Fast).await, vec![1, 1, -2, -2, 3, 3]);
+        assert_eq!(convert(stereo, vec![100, 300, -5, -7], mono, Quality::Fast).await, vec![200, -6]);
+        assert_eq!(convert(Format::new(44100, 4, 16), vec![1, 2, 3, 4], stereo, Quality::Fast).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn changes_bit_depth() {
+        let samples = vec![0x123456, -0x123456, 0x7fffff, -0x800000];
+
+        assert_eq!(
+            convert(Format::new(44100, 1, 16), vec![0x1234, -1], Format::new(44100, 1, 24), Quality::Music).await,
+            vec![0x123400, -0x100],
+        );
+        assert_eq!(
+            convert(Format::new(44100, 1, 24), samples.clone(), Format::new(44100, 1, 16), Quality::Fast).await,
+            vec![0x1234, -0x1235, 0x7fff, -0x8000],
+        );
+
+        let dithered = convert(Format::new(44100, 1, 24), samples, Format::new(44100, 1, 16), Quality::Music).await;
+
+        for (x, exact) in dithered.iter().zip([0x1234 as f64 + 0.337, -(0x1234 as f64 + 0.337), 32767.0, -32768.0]) {
+            assert!((f64::from(*x) - exact).abs() <= 1.5, "{} against {}", x, exact);
+        }
+    }
+
+    #[tokio::test]
+    async fn dither_stays_within_one_step() {
+        let silence = vec![0; 4096];
+        let dithered = convert(Format::new(44100, 1, 24), silence.clone(), Format::new(44100, 1, 16), Quality::Music).await;
+
+        assert_eq!(dithered.len(), 4096);
+        assert!(dithered.iter().all(|x| (-1..=1).contains(x)));
+        assert!(dithered.iter().any(|x| *x != 0));
+
+        // TPDF over two steps, centered.
+        let mean = dithered.iter().map(|x| f64::from(*x)).sum::<f64>() / dithered.len() as f64;
+        assert!(mean.abs() < 0.05, "mean {}", mean);
+
+        let plain = convert(Format::new(44100, 1, 24), silence, Format::new(44100, 1, 16), Quality::Fast).await;
+        assert!(plain.iter().all(|x| *x == 0));
+    }
+
+    #[tokio::test]
+    async fn resamples_to_the_target_rate() {
+        let samples = vec![1000; 44100 * 2];
+        let output = convert(Format::new(44100, 2, 16), samples, Format::new(48000, 2, 16), Quality::Music).await;
+
+        assert!(output.len().abs_diff(48000 * 2) <= 4, "{} samples", output.len());
+        assert!(output[9600..86400].iter().all(|x| (999..=1001).contains(x)));
+    }
+
+    #[tokio::test]
+    async fn needs_room_for_a_frame() {
+        let source = Samples { format: Format::new(44100, 1, 16), samples: vec![1, 2] };
+        let mut converter = Converter::new(source, Format::new(44100, 2, 16)).unwrap();
+
+        assert_eq!(converter.read(&mut [0; 1]).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(converter.read(&mut [0; 2]).await.unwrap(), 2);
+    }
+
+    #[test]
+    fn rejects_targets_it_cant_produce() {
+        let source = || Samples { format: Format::new(44100, 2, 16), samples: Vec::new() };
+
+        for target in [Format::new(44100, 0, 16), Format::new(44100, 3, 16), Format::new(44100, 2, 4), Format::new(0, 2, 16)] {
+            assert!(Converter::new(source(), target).is_err(), "{:?}", target);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{alac, mdns::Features, rtsp::ops::DeviceInfo};
+
+use super::Format;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Pcm,
+    Alac,
+    AacLc,
+    AacEld,
+    Opus,
+}
+
+impl Codec {
+    /// The `ct` value of a stream SETUP.
+    pub fn compression_type(&self) -> u8 {
+        match self {
+            Self::Pcm => 0x01,
+            Self::Alac => 0x02,
+            Self::AacLc => 0x04,
+            Self::AacEld => 0x08,
+            Self::Opus => 0x20,
+        }
+    }
+
+    /// Frames per packet of a realtime stream, its `spf`.
+    pub fn frames_per_packet(&self) -> u32 {
+        match self {
+            Self::Pcm | Self::Alac => alac::FRAME_LENGTH_REALTIME,
+            Self::AacLc => 1024,
+            Self::AacEld | Self::Opus => 480,
+        }
+    }
+
+    pub fn is_supported_by(&self, features: &Features) -> bool {
+        match self {
+            Self::Pcm => true,
+            Self::Alac => features.supports_alac,
+            Self::AacLc => features.supports_aac,
+            Self::AacEld => features.supports_aac_eld,
+            Self::Opus => features.supports_opus,
+        }
+    }
+}
+
+/// One entry of the `audioFormat` bitmask exchanged in stream SETUP and advertised in `supportedFormats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub bit: u8,
+    pub codec: Codec,
+    pub format: Format,
+}
+
+const STREAM_FORMATS: [(Codec, u32, u8, u8); 29] = [
+    (Codec::Pcm, 8000, 16, 1),
+    (Codec::Pcm, 8000, 16, 2),
+    (Codec::Pcm, 16000, 16, 1),
+    (Codec::Pcm, 16000, 16, 2),
+    (Codec::Pcm, 24000, 16, 1),
+    (Codec::Pcm, 24000, 16, 2),
+    (Codec::Pcm, 32000, 16, 1),
+    (Codec::Pcm, 32000, 16, 2),
+    (Codec::Pcm, 44100, 16, 1),
+    (Codec::Pcm, 44100, 16, 2),
+    (Codec::Pcm, 44100, 24, 1),
+    (Codec::Pcm, 44100, 24, 2),
+    (Codec::Pcm, 48000, 16, 1),
+    (Codec::Pcm, 48000, 16, 2),
+    (Codec::Pcm, 48000, 24, 1),
+    (Codec::Pcm, 48000, 24, 2),
+    (Codec::Alac, 44100, 16, 2),
+    (Codec::Alac, 44100, 24, 2),
+    (Codec::Alac, 48000, 16, 2),
+    (Codec::Alac, 48000, 24, 2),
+    (Codec::AacLc, 44100, 16, 2),
+    (Codec::AacLc, 48000, 16, 2),
+    (Codec::AacEld, 44100, 16, 2),
+    (Codec::AacEld, 48000, 16, 2),
+    (Codec::AacEld, 16000, 16, 1),
+    (Codec::AacEld, 24000, 16, 1),
+    (Codec::Opus, 16000, 16, 1),
+    (Codec::Opus, 24000, 16, 1),
+    (Codec::Opus, 48000, 16, 1),
+];
+
+const FIRST_BIT: u8 = 2;
+
+impl StreamFormat {
+    pub fn from_bit(bit: u8) -> Option<StreamFormat> {
+        let (codec, sample_rate, bit_depth, channels) = *STREAM_FORMATS.get(usize::from(bit.checked_sub(FIRST_BIT)?))?;
+
+        Some(StreamFormat {
+            bit,
+            codec,
+            format: Format::new(sample_rate, channels, bit_depth),
+        })
+    }
+
+    pub fn all() -> impl Iterator<Item = StreamFormat> {
+        (FIRST_BIT..FIRST_BIT + STREAM_FORMATS.len() as u8).filter_map(StreamFormat::from_bit)
+    }
+
+    /// The value of `audioFormat` selecting this format in stream SETUP.
+    pub fn audio_format(&self) -> u64 {
+        1 << self.bit
+    }
+
+    /// Picks the best format out of a `supportedFormats` mask, trying `codecs` in order of preference and
+    /// skipping the ones the receiver's feature bits rule out.
+    ///
+    /// Within a codec, stereo is preferred over mono, 44.1 kHz over 48 kHz over anything else, and 16-bit
+    /// over 24-bit, which matches what Apple senders pick for music.
+    pub fn select(supported: u64, features: &Features, codecs: &[Codec]) -> Option<StreamFormat> {
+        codecs.iter().filter(|x| x.is_supported_by(features)).find_map(|codec| {
+            StreamFormat::all()
+                .filter(|x| x.codec == *codec && supported & x.audio_format() != 0)
+                .min_by_key(|x| (
+                    x.format.channels != 2,
+                    x.format.sample_rate != 44100,
+                    x.format.sample_rate != 48000,
+                    u32::MAX - x.format.sample_rate,
+                    x.format.bit_depth,
+                ))
+        })
+    }
+
+    /// Like [`StreamFormat::select`], using the formats and features a receiver reported in `/info`.
+    pub fn negotiate(info: &DeviceInfo, buffered: bool, codecs: &[Codec]) -> Option<StreamFormat> {
+        let supported = info.supported_formats.as_ref()?.for_stream(buffered)?;
+        StreamFormat::select(supported, &info.features()?, codecs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtsp::ops::SupportedFormats;
+
+    fn mask(formats: &[(Codec, u32, u8, u8)]) -> u64 {
+        StreamFormat::all()
+            .filter(|x| formats.contains(&(x.codec, x.format.sample_rate, x.format.bit_depth, x.format.channels)))
+            .fold(0, |mask, x| mask | x.audio_format())
+    }
+
+    #[test]
+    fn bits_match_the_table() {
+        let alac = StreamFormat::from_bit(18).unwrap();
+        assert_eq!((alac.codec, alac.format), (Codec::Alac, Format::new(44100, 2, 16)));
+        assert_eq!(alac.audio_format(), 0x40000);
+
+        assert_eq!(StreamFormat::from_bit(1), None);
+        assert_eq!(StreamFormat::from_bit(31), None);
+        assert_eq!(StreamFormat::all().count(), STREAM_FORMATS.len());
+    }
+
+    #[test]
+    fn selects_in_order_of_preference() {
+        let features = Features::from(1_u64 << 9 | 1 << 18 | 1 << 19 | 1 << 20);
+        assert!(features.supports_alac);
+
+        let supported = mask(&[
+            (Codec::Pcm, 48000, 24, 2),
+            (Codec::Pcm, 44100, 16, 1),
+            (Codec::Alac, 48000, 16, 2),
+            (Codec::Alac, 44100, 24, 2),
+            (Codec::Alac, 44100, 16, 2),
+        ]);
+
+        let pick = |supported, codecs: &[Codec]| StreamFormat::select(supported, &features, codecs).map(|x| (x.codec, x.format));
+
+        // Codecs in the order given, then 44.1 kHz and 16 bits.
+        assert_eq!(pick(supported, &[Codec::Alac, Codec::Pcm]), Some((Codec::Alac, Format::new(44100, 2, 16))));
+        assert_eq!(pick(supported, &[Codec::Pcm, Codec::Alac]), Some((Codec::Pcm, Format::new(48000, 2, 24))));
+        assert_eq!(pick(supported & !mask(&[(Codec::Alac, 44100, 16, 2)]), &[Codec::Alac]), Some((Codec::Alac, Format::new(44100, 2, 24))));
+        assert_eq!(pick(mask(&[(Codec::Alac, 48000, 16, 2)]), &[Codec::Alac]), Some((Codec::Alac, Format::new(48000, 2, 16))));
+
+        // Stereo wins over the sample rate.
+        let pcm = mask(&[(Codec::Pcm, 44100, 16, 1), (Codec::Pcm, 32000, 16, 2), (Codec::Pcm, 16000, 16, 2)]);
+        assert_eq!(pick(pcm, &[Codec::Pcm]), Some((Codec::Pcm, Format::new(32000, 2, 16))));
+
+        assert_eq!(pick(supported, &[Codec::Opus]), None);
+    }
+
+    #[test]
+    fn skips_codecs_the_features_rule_out() {
+        let supported = mask(&[(Codec::Alac, 44100, 16, 2), (Codec::Pcm, 44100, 16, 2)]);
+        let format = StreamFormat::select(supported, &Features::from(0_u64), &[Codec::Alac, Codec::Pcm]).unwrap();
+        assert_eq!(format.codec, Codec::Pcm);
+    }
+
+    #[test]
+    fn negotiates_from_device_info() {
+        let info = DeviceInfo {
+            audio_latencies: None,
+            device_id: None,
+            features: Some(1 << 9 | 1 << 18),
+            model: None,
+            name: None,
+            source_version: None,
+            status_flags: None,
+            supported_formats: Some(SupportedFormats {
+                audio_stream: Some(mask(&[(Codec::Alac, 44100, 16, 2)])),
+                buffer_stream: Some(mask(&[(Codec::AacLc, 44100, 16, 2)])),
+                ..Default::default()
+            }),
+        };
+
+        assert_eq!(StreamFormat::negotiate(&info, false, &[Codec::Alac]).map(|x| x.bit), Some(18));
+        assert_eq!(StreamFormat::negotiate(&info, true, &[Codec::Alac]), None);
+        assert_eq!(StreamFormat::negotiate(&DeviceInfo { features: None, ..info }, false, &[Codec::Alac]), None);
+    }
+}
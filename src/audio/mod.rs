@@ -0,0 +1,101 @@
+use std::{future::Future, io, time::Instant};
+
+mod callback;
+mod convert;
+mod format;
+mod pcm;
+mod resample;
+mod sine;
+mod wav;
+
+pub use callback::CallbackSink;
+pub use convert::Converter;
+pub use format::{Codec, StreamFormat};
+pub use pcm::{PcmSink, PcmSource};
+pub use resample::{Quality, Resampler};
+pub use sine::SineSource;
+pub use wav::{WavSink, WavSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bit_depth: u8,
+}
+
+impl Format {
+    pub const fn new(sample_rate: u32, channels: u8, bit_depth: u8) -> Format {
+        Format { sample_rate, channels, bit_depth }
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        usize::from(self.bit_depth).div_ceil(8)
+    }
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.bytes_per_sample() * usize::from(self.channels)
+    }
+}
+
+/// A pull-based producer of interleaved PCM.
+///
+/// Samples are sign-extended to `i32` at the source's bit depth, which is also what [`crate::alac::Encoder`]
+/// expects.
+pub trait Source {
+    fn format(&self) -> Format;
+
+    /// Fills the start of `buf` with whole frames of interleaved samples and returns the number of samples
+    /// written. Returns `0` once the source is exhausted. Fails with [`io::ErrorKind::InvalidInput`] when
+    /// `buf` can't hold a single frame, rather than returning `0` as if exhausted.
+    fn read(&mut self, buf: &mut [i32]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+/// How many whole frames of `format` fit in `buf`, at least one.
+fn frames_in(buf: &[i32], format: Format) -> io::Result<usize> {
+    match buf.len() / usize::from(format.channels).max(1) {
+        0 => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("buffer of {} samples is shorter than a frame", buf.len()))),
+        x => Ok(x),
+    }
+}
+
+/// When the first frame of a block written to a [`Sink`] is due to be heard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// RTP timestamp of the first frame.
+    pub rtp: u32,
+    /// Local time the first frame is to be heard at, once the stream is synced.
+    pub at: Option<Instant>,
+}
+
+/// A push-based consumer of interleaved PCM, the counterpart of [`Source`].
+pub trait Sink {
+    /// Prepares for audio in `format`. Called before the first write, and again when a stream in another
+    /// format starts.
+    fn start(&mut self, format: Format) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Takes whole frames of interleaved samples, sign-extended to `i32` as a [`Source`] produces them.
+    fn write(&mut self, samples: &[i32], timing: Timing) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// The stream ended. Flushes whatever is buffered.
+    fn finish(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Reads exactly `frames` frames unless the source runs out first, in which case the returned buffer is
+/// shorter. An empty buffer means the source is exhausted.
+pub async fn read_frames<S: Source>(source: &mut S, frames: usize) -> io::Result<Vec<i32>> {
+    let mut buf = vec![0; frames * usize::from(source.format().channels)];
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let n = source.read(&mut buf[filled..]).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        filled += n;
+    }
+
+    buf.truncate(filled);
+    Ok(buf)
+}
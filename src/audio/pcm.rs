@@ -0,0 +1,247 @@
+use std::{io, path::Path};
+
+use tokio::{fs::{File, OpenOptions}, io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Stdin, Stdout}};
+
+use super::{frames_in, Format, Sink, Source, Timing};
+
+fn decode_sample(bytes: &[u8]) -> i32 {
+    let mut x = [0_u8; 4];
+    x[4 - bytes.len()..].copy_from_slice(bytes);
+    i32::from_le_bytes(x) >> (32 - 8 * bytes.len())
+}
+
+pub(super) fn encode_sample(sample: i32, bytes: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&sample.to_le_bytes()[..bytes]);
+}
+
+fn check_format(format: Format) -> io::Result<()> {
+    if !matches!(format.bit_depth, 8 | 16 | 24 | 32) || format.channels == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported PCM format: {:?}", format)));
+    }
+
+    Ok(())
+}
+
+/// Raw signed little-endian PCM from any reader, e.g. `stdin` fed by `ffmpeg -f s16le`.
+pub struct PcmSource<R> {
+    reader: R,
+    format: Format,
+    pending: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin + Send> PcmSource<R> {
+    pub fn new(reader: R, format: Format) -> io::Result<PcmSource<R>> {
+        check_format(format)?;
+
+        Ok(PcmSource {
+            reader,
+            format,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl PcmSource<Stdin> {
+    pub fn stdin(format: Format) -> io::Result<PcmSource<Stdin>> {
+        PcmSource::new(tokio::io::stdin(), format)
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> Source for PcmSource<R> {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    async fn read(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        let sample_bytes = self.format.bytes_per_sample();
+        let frame_bytes = self.format.bytes_per_frame();
+        let wanted = frames_in(buf, self.format)? * frame_bytes;
+
+        let mut bytes = std::mem::take(&mut self.pending);
+        let mut filled = bytes.len();
+        bytes.resize(wanted.max(filled), 0);
+
+        while filled < frame_bytes {
+            let n = self.reader.read(&mut bytes[filled..]).await?;
+
+            if n == 0 {
+                break;
+            }
+
+            filled += n;
+        }
+
+        let whole = filled.min(wanted) / frame_bytes * frame_bytes;
+
+        for (x, sample) in buf.iter_mut().zip(bytes[..whole].chunks_exact(sample_bytes)) {
+            *x = decode_sample(sample);
+        }
+
+        self.pending = bytes[whole..filled].to_vec();
+        Ok(whole / sample_bytes)
+    }
+}
+
+/// Raw signed little-endian PCM to any writer, e.g. `stdout` piped into `aplay`, or a named pipe read by
+/// another player as shairport-sync does. Timing is ignored, audio is written as soon as it arrives.
+pub struct PcmSink<W> {
+    writer: W,
+    format: Option<Format>,
+    buf: Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin + Send> PcmSink<W> {
+    pub fn new(writer: W) -> PcmSink<W> {
+        PcmSink {
+            writer,
+            format: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// The format of the audio being written, known once started.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl PcmSink<Stdout> {
+    pub fn stdout() -> PcmSink<Stdout> {
+        PcmSink::new(tokio::io::stdout())
+    }
+}
+
+impl PcmSink<File> {
+    /// Writes to `path`, which may be a named pipe made with `mkfifo`. Opening a pipe waits for a reader.
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<PcmSink<File>> {
+        Ok(PcmSink::new(OpenOptions::new().write(true).create(true).truncate(true).open(path).await?))
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> Sink for PcmSink<W> {
+    async fn start(&mut self, format: Format) -> io::Result<()> {
+        check_format(format)?;
+        self.format = Some(format);
+        Ok(())
+    }
+
+    async fn write(&mut self, samples: &[i32], _timing: Timing) -> io::Result<()> {
+        let format = self.format.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "write before start"))?;
+        let bytes = format.bytes_per_sample();
+
+        self.buf.clear();
+
+        for sample in samples {
+            encode_sample(*sample, bytes, &mut self.buf);
+        }
+
+        self.writer.write_all(&self.buf).await
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::read_frames;
+
+    #[test]
+    fn samples_are_sign_extended() {
+        assert_eq!(decode_sample(&[0x7f]), 127);
+        assert_eq!(decode_sample(&[0x80]), -128);
+        assert_eq!(decode_sample(&[0x34, 0x12]), 0x1234);
+        assert_eq!(decode_sample(&[0xff, 0xff]), -1);
+        assert_eq!(decode_sample(&[0x56, 0x34, 0x92]), -0x6dcbaa);
+        assert_eq!(decode_sample(&[0x00, 0x00, 0x00, 0x80]), i32::MIN);
+
+        for (sample, bytes) in [(-0x6dcbaa, 3), (0x1234, 2), (-1, 2), (-128, 1), (i32::MIN, 4)] {
+            let mut out = Vec::new();
+            encode_sample(sample, bytes, &mut out);
+            assert_eq!(out.len(), bytes);
+            assert_eq!(decode_sample(&out), sample);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads_come_out_whole() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut source = PcmSource::new(reader, Format::new(44100, 2, 24)).unwrap();
+
+        let writing = tokio::spawn(async move {
+            for chunk in [[1, 0, 0, 2, 0].as_slice(), &[0, 3, 0], &[0, 0xff, 0xff, 0xff]] {
+                writer.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        assert_eq!(read_frames(&mut source, 10).await.unwrap(), vec![1, 2, 3, -1]);
+        writing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn needs_room_for_a_frame() {
+        let mut source = PcmSource::new([1_u8, 0, 2, 0].as_slice(), Format::new(44100, 2, 16)).unwrap();
+
+        assert_eq!(source.read(&mut [0; 1]).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(source.read(&mut []).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut buf = [0; 3];
+        assert_eq!(source.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(buf[..2], [1, 2]);
+        assert_eq!(source.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn written_samples_read_back() {
+        let samples: Vec<i32> = (0..64).map(|x| (x - 32) * 100_003).collect();
+        let format = Format::new(44100, 2, 24);
+        let timing = Timing { rtp: 0, at: None };
+
+        let mut sink = PcmSink::new(std::io::Cursor::new(Vec::new()));
+        assert_eq!(sink.format(), None);
+        sink.start(format).await.unwrap();
+        assert_eq!(sink.format(), Some(format));
+        sink.write(&samples[..32], timing).await.unwrap();
+        sink.write(&samples[32..], timing).await.unwrap();
+        sink.finish().await.unwrap();
+
+        let bytes = sink.into_inner().into_inner();
+        assert_eq!(bytes.len(), 64 * 3);
+
+        let mut source = PcmSource::new(bytes.as_slice(), format).unwrap();
+        assert_eq!(read_frames(&mut source, 100).await.unwrap(), samples);
+    }
+
+    #[tokio::test]
+    async fn sinks_need_a_supported_format_first() {
+        let mut sink = PcmSink::new(std::io::Cursor::new(Vec::new()));
+
+        let err = sink.write(&[1, 2], Timing { rtp: 0, at: None }).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        for format in [Format::new(44100, 0, 16), Format::new(44100, 2, 12)] {
+            assert!(sink.start(format).await.is_err(), "{:?}", format);
+        }
+
+        assert_eq!(sink.format(), None);
+        assert!(sink.into_inner().into_inner().is_empty());
+    }
+
+    #[test]
+    fn rejects_formats_it_cant_read() {
+        for format in [Format::new(44100, 0, 16), Format::new(44100, 2, 12), Format::new(44100, 2, 0)] {
+            assert!(PcmSource::new(tokio::io::empty(), format).is_err(), "{:?}", format);
+        }
+    }
+}
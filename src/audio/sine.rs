@@ -0,0 +1,95 @@
+use std::{f64::consts::PI, io};
+
+use super::{frames_in, Format, Source};
+
+/// A test tone, identical on every channel.
+pub struct SineSource {
+    format: Format,
+    frequency: f64,
+    amplitude: f64,
+    position: u64,
+    length: Option<u64>,
+}
+
+impl SineSource {
+    /// `amplitude` is relative to full scale, `length` is in frames and `None` plays forever.
+    pub fn new(format: Format, frequency: f64, amplitude: f64, length: Option<u64>) -> io::Result<SineSource> {
+        if format.channels == 0 || !(1..=32).contains(&format.bit_depth) || format.sample_rate == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported tone format: {:?}", format)));
+        }
+
+        Ok(SineSource {
+            format,
+            frequency,
+            amplitude: amplitude.clamp(0.0, 1.0),
+            position: 0,
+            length,
+        })
+    }
+}
+
+impl Source for SineSource {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    async fn read(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        let channels = usize::from(self.format.channels);
+        let mut frames = frames_in(buf, self.format)? as u64;
+
+        if let Some(length) = self.length {
+            frames = frames.min(length - self.position);
+        }
+
+        let full_scale = ((1_i64 << (self.format.bit_depth - 1)) - 1) as f64;
+
+        for frame in buf.chunks_exact_mut(channels).take(frames as usize) {
+            let t = self.position as f64 / f64::from(self.format.sample_rate);
+            let x = ((2.0 * PI * self.frequency * t).sin() * self.amplitude * full_scale).round() as i32;
+            frame.fill(x);
+            self.position += 1;
+        }
+
+        Ok(frames as usize * channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::read_frames;
+
+    #[tokio::test]
+    async fn plays_the_frequency_for_the_length() {
+        let mut source = SineSource::new(Format::new(48000, 2, 16), 1000.0, 0.5, Some(4800)).unwrap();
+        let samples = read_frames(&mut source, 10000).await.unwrap();
+        assert_eq!(samples.len(), 4800 * 2);
+        assert_eq!(source.read(&mut [0; 2]).await.unwrap(), 0);
+
+        let (left, right): (Vec<i32>, Vec<i32>) = samples.chunks_exact(2).map(|x| (x[0], x[1])).unzip();
+        assert_eq!(left, right);
+
+        // A tenth of a second of 1 kHz, two zero crossings a cycle.
+        let crossings = left.windows(2).filter(|x| (x[0] < 0) != (x[1] < 0)).count();
+        assert!((199..=201).contains(&crossings), "{} crossings", crossings);
+
+        assert_eq!(left[0], 0);
+        assert_eq!(left[12], 16384);
+        assert_eq!(left.iter().max(), Some(&16384));
+        assert_eq!(left.iter().min(), Some(&-16384));
+    }
+
+    #[tokio::test]
+    async fn needs_room_for_a_frame() {
+        let mut source = SineSource::new(Format::new(44100, 2, 16), 440.0, 1.0, None).unwrap();
+        assert_eq!(source.read(&mut [0; 1]).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(source.read(&mut [0; 3]).await.unwrap(), 2);
+    }
+
+    #[test]
+    fn rejects_formats_it_cant_play() {
+        for format in [Format::new(44100, 0, 16), Format::new(44100, 2, 0), Format::new(44100, 2, 33), Format::new(0, 2, 16)] {
+            assert!(SineSource::new(format, 440.0, 1.0, None).is_err(), "{:?}", format);
+        }
+    }
+}
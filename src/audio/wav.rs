@@ -0,0 +1,316 @@
+use std::{io::{self, SeekFrom}, path::Path};
+
+use tokio::{fs::File, io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, Take}};
+
+use super::{pcm::encode_sample, Format, PcmSource, Sink, Source, Timing};
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Integer PCM from a RIFF/WAVE stream. Only the `fmt ` and `data` chunks are interpreted.
+pub struct WavSource<R> {
+    pcm: PcmSource<Take<R>>,
+}
+
+impl WavSource<BufReader<File>> {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        WavSource::new(BufReader::new(File::open(path).await?)).await
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> WavSource<R> {
+    pub async fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0_u8; 12];
+        reader.read_exact(&mut header).await?;
+
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(invalid("not a RIFF/WAVE stream"));
+        }
+
+        let mut format = None;
+
+        loop {
+            let mut chunk = [0_u8; 8];
+            reader.read_exact(&mut chunk).await?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+            match &chunk[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0_u8; size as usize + (size & 1) as usize];
+                    reader.read_exact(&mut fmt).await?;
+
+                    if fmt.len() < 16 {
+                        return Err(invalid("truncated fmt chunk"));
+                    }
+
+                    let mut tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+
+                    if tag == WAVE_FORMAT_EXTENSIBLE && fmt.len() >= 26 {
+                        tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+                    }
+
+                    let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+                    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let bit_depth = u16::from_le_bytes([fmt[14], fmt[15]]);
+
+                    if tag != WAVE_FORMAT_PCM || !matches!(bit_depth, 16 | 24 | 32) || channels == 0 || channels > 8 {
+                        return Err(invalid("unsupported WAVE format, expected 16/24/32-bit integer PCM"));
+                    }
+
+                    format = Some(Format::new(sample_rate, channels as u8, bit_depth as u8));
+                },
+                b"data" => {
+                    let format = format.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+
+                    // Streaming writers leave the size at 0 or u32::MAX, so read until EOF in that case.
+                    let limit = match size {
+                        0 | u32::MAX => u64::MAX,
+                        x => u64::from(x),
+                    };
+
+                    return Ok(WavSource {
+                        pcm: PcmSource::new(reader.take(limit), format)?,
+                    });
+                },
+                _ => {
+                    let skip = u64::from(size) + u64::from(size & 1);
+                    tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+                },
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> Source for WavSource<R> {
+    fn format(&self) -> Format {
+        self.pcm.format()
+    }
+
+    async fn read(&mut self, buf: &mut [i32]) -> io::Result<usize> {
+        self.pcm.read(buf).await
+    }
+}
+
+/// Writes a RIFF/WAVE file. The chunk sizes are filled in by [`Sink::finish`], until then they read as
+/// unknown, which [`WavSource`] copes with.
+pub struct WavSink<W> {
+    writer: W,
+    format: Option<Format>,
+    data_len: u64,
+    buf: Vec<u8>,
+}
+
+impl WavSink<BufWriter<File>> {
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(WavSink::new(BufWriter::new(File::create(path).await?)))
+    }
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin + Send> WavSink<W> {
+    pub fn new(writer: W) -> Self {
+        WavSink {
+            writer,
+            format: None,
+            data_len: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    async fn write_header(&mut self, format: Format) -> io::Result<()> {
+        let block_align = format.bytes_per_frame() as u16;
+
+        let mut header = Vec::with_capacity(44);
+        header.extend(b"RIFF");
+        header.extend(u32::MAX.to_le_bytes());
+        header.extend(b"WAVE");
+        header.extend(b"fmt ");
+        header.extend(16_u32.to_le_bytes());
+        header.extend(WAVE_FORMAT_PCM.to_le_bytes());
+        header.extend(u16::from(format.channels).to_le_bytes());
+        header.extend(format.sample_rate.to_le_bytes());
+        header.extend((format.sample_rate * u32::from(block_align)).to_le_bytes());
+        header.extend(block_align.to_le_bytes());
+        header.extend(u16::from(format.bytes_per_sample() as u8 * 8).to_le_bytes());
+        header.extend(b"data");
+        header.extend(u32::MAX.to_le_bytes());
+
+        self.writer.write_all(&header).await
+    }
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin + Send> Sink for WavSink<W> {
+    async fn start(&mut self, format: Format) -> io::Result<()> {
+        match self.format {
+            Some(x) if x == format => Ok(()),
+            Some(x) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("WAVE file is already {:?}", x))),
+            None => {
+                if !matches!(format.bit_depth, 16 | 24 | 32) || format.channels == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported WAVE format: {:?}", format)));
+                }
+
+                self.write_header(format).await?;
+                self.format = Some(format);
+                Ok(())
+            },
+        }
+    }
+
+    async fn write(&mut self, samples: &[i32], _timing: Timing) -> io::Result<()> {
+        let format = self.format.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "write before start"))?;
+        let bytes = format.bytes_per_sample();
+
+        self.buf.clear();
+
+        for sample in samples {
+            encode_sample(*sample, bytes, &mut self.buf);
+        }
+
+        self.writer.write_all(&self.buf).await?;
+        self.data_len += self.buf.len() as u64;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        if self.format.is_none() {
+            return self.writer.flush().await;
+        }
+
+        let data_len = u32::try_from(self.data_len).unwrap_or(u32::MAX);
+
+        if data_len & 1 == 1 {
+            self.writer.write_all(&[0]).await?;
+        }
+
+        self.writer.seek(SeekFrom::Start(4)).await?;
+        self.writer.write_all(&(36_u32.saturating_add(data_len).saturating_add(data_len & 1)).to_le_bytes()).await?;
+        self.writer.seek(SeekFrom::Start(40)).await?;
+        self.writer.write_all(&data_len.to_le_bytes()).await?;
+        self.writer.seek(SeekFrom::End(0)).await?;
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::audio::read_frames;
+
+    #[tokio::test]
+    async fn written_files_read_back() {
+        let format = Format::new(44100, 2, 24);
+        let samples: Vec<i32> = (0..64).map(|x| (x - 32) * 100_003).collect();
+        let timing = Timing { rtp: 0, at: None };
+
+        let mut sink = WavSink::new(Cursor::new(Vec::new()));
+        sink.start(format).await.unwrap();
+        sink.write(&samples[..32], timing).await.unwrap();
+        sink.write(&samples[32..], timing).await.unwrap();
+        sink.finish().await.unwrap();
+
+        let bytes = sink.into_inner().into_inner();
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 64 * 3);
+
+        let mut source = WavSource::new(Cursor::new(bytes)).await.unwrap();
+        assert_eq!(source.format(), format);
+        assert_eq!(read_frames(&mut source, 100).await.unwrap(), samples);
+    }
+
+    #[tokio::test]
+    async fn unfinished_files_read_to_the_end() {
+        let format = Format::new(48000, 1, 16);
+        let mut sink = WavSink::new(Cursor::new(Vec::new()));
+        sink.start(format).await.unwrap();
+        sink.write(&[1, -1, i16::MAX as i32, i16::MIN as i32], Timing { rtp: 0, at: None }).await.unwrap();
+
+        let mut source = WavSource::new(Cursor::new(sink.into_inner().into_inner())).await.unwrap();
+        assert_eq!(read_frames(&mut source, 10).await.unwrap(), vec![1, -1, i16::MAX as i32, i16::MIN as i32]);
+    }
+
+    /// A `fmt ` chunk body for `tag`, padded with `extra` bytes.
+    fn fmt(tag: u16, channels: u16, sample_rate: u32, bit_depth: u16, extra: &[u8]) -> Vec<u8> {
+        let block_align = channels * bit_depth / 8;
+        let mut fmt = Vec::new();
+        fmt.extend(tag.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(sample_rate.to_le_bytes());
+        fmt.extend((sample_rate * u32::from(block_align)).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bit_depth.to_le_bytes());
+        fmt.extend(extra);
+        fmt
+    }
+
+    fn riff(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+
+        for (id, body) in chunks {
+            bytes.extend(*id);
+            bytes.extend((body.len() as u32).to_le_bytes());
+            bytes.extend(*body);
+
+            if body.len() & 1 == 1 {
+                bytes.push(0);
+            }
+        }
+
+        bytes
+    }
+
+    #[tokio::test]
+    async fn parses_headers_other_writers_produce() {
+        let data = [0x01, 0x00, 0xff, 0xff];
+
+        // WAVE_FORMAT_EXTENSIBLE with the PCM subformat, after an odd sized chunk to skip.
+        let mut extension = vec![22, 0, 16, 0, 3, 0, 0, 0];
+        extension.extend(WAVE_FORMAT_PCM.to_le_bytes());
+        extension.extend([0; 14]);
+        let fmt = fmt(WAVE_FORMAT_EXTENSIBLE, 2, 48000, 16, &extension);
+        let bytes = riff(&[(b"LIST", b"odd"), (b"fmt ", &fmt), (b"data", &data), (b"id3 ", b"trailing")]);
+
+        let mut source = WavSource::new(Cursor::new(bytes)).await.unwrap();
+        assert_eq!(source.format(), Format::new(48000, 2, 16));
+        assert_eq!(read_frames(&mut source, 10).await.unwrap(), vec![1, -1]);
+    }
+
+    #[tokio::test]
+    async fn rejects_streams_it_cant_read() {
+        let data: &[u8] = &[0; 4];
+        let float = fmt(0x0003, 2, 44100, 32, &[]);
+        let eight_bit = fmt(WAVE_FORMAT_PCM, 2, 44100, 8, &[]);
+        let no_channels = fmt(WAVE_FORMAT_PCM, 0, 44100, 16, &[]);
+        let pcm = fmt(WAVE_FORMAT_PCM, 2, 44100, 16, &[]);
+
+        let mut not_wave = riff(&[(b"fmt ", &pcm), (b"data", data)]);
+        not_wave[8..12].copy_from_slice(b"AVI ");
+
+        for bytes in [
+            not_wave,
+            riff(&[(b"fmt ", &float), (b"data", data)]),
+            riff(&[(b"fmt ", &eight_bit), (b"data", data)]),
+            riff(&[(b"fmt ", &no_channels), (b"data", data)]),
+            riff(&[(b"fmt ", &pcm[..14]), (b"data", data)]),
+            riff(&[(b"data", data), (b"fmt ", &pcm)]),
+            riff(&[(b"fmt ", &pcm)]),
+        ] {
+            assert!(WavSource::new(Cursor::new(bytes)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn format_cannot_change() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new()));
+        sink.start(Format::new(44100, 2, 16)).await.unwrap();
+        sink.start(Format::new(44100, 2, 16)).await.unwrap();
+        assert!(sink.start(Format::new(48000, 2, 16)).await.is_err());
+    }
+}
@@ -1,15 +1,25 @@
+use std::sync::Arc;
+
 use airplay::{mdns, rtsp::{self, ops::SetupInfoRequest}};
 use plist::Data;
 
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
     let meta = mdns::look_for("Nappali (2)".to_string()).await.expect("Device not found");
 
-    let mut client = rtsp::Client::connect((meta.ip_addresses.iter().find_map(|x| match x {
+    let client = rtsp::Client::connect((meta.ip_addresses.iter().find_map(|x| match x {
         std::net::IpAddr::V4(x) => Some(x),
         std::net::IpAddr::V6(_) => None,
     }).expect("No IPv4 address found for device").to_owned(), meta.port)).await.expect("Failed to connect to RTSP");
 
+    if let Ok(path) = std::env::var("AIRPLAY_CAPTURE") {
+        client.set_capture(Some(Arc::new(rtsp::Capture::create(path).expect("Failed to create capture file"))));
+    }
+
     let info = client.fetch_info().await.expect("Failed to fetch info");
     println!("{:#?}", info);
 
@@ -0,0 +1,233 @@
+//! DACP, how receivers hand the buttons pressed on them back to the sender.
+//!
+//! The sender tells each receiver a `DACP-ID` and an `Active-Remote` token in the headers of its RTSP
+//! requests and advertises `iTunes_Ctrl_<DACP-ID>._dacp._tcp`. Receivers look that service up and send
+//! plain HTTP requests such as `GET /ctrl-int/1/playpause` carrying the token, answered with
+//! `204 No Content`.
+
+use std::{io, net::SocketAddr};
+
+use tokio::{net::ToSocketAddrs, sync::broadcast};
+
+use crate::{mdns::{Advertiser, Service}, rtsp::{self, server::{Answer, Handler}, Method, Request, Response}};
+
+/// What a sender identifies itself to receivers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Remote {
+    pub id: u64,
+    /// The token receivers must send back, so only those we talk to can control us.
+    pub active_remote: u32,
+}
+
+impl Remote {
+    pub fn generate() -> Remote {
+        Remote {
+            id: rand::random(),
+            active_remote: rand::random(),
+        }
+    }
+
+    /// The `DACP-ID` header, also naming the service.
+    pub fn dacp_id(&self) -> String {
+        format!("{:016X}", self.id)
+    }
+
+    /// The `_dacp._tcp` service to advertise for receivers to reach us on `port`.
+    pub fn service(&self, port: u16) -> Service {
+        Service {
+            service_type: "_dacp._tcp.local".to_string(),
+            instance: format!("iTunes_Ctrl_{}", self.dacp_id()),
+            port,
+            txt: vec![
+                "txtvers=1".to_string(),
+                "Ver=131075".to_string(),
+                format!("DbId={}", self.dacp_id()),
+                "OSsi=0x1F5".to_string(),
+            ],
+        }
+    }
+
+    /// Adds the `DACP-ID` and `Active-Remote` headers to a request.
+    pub fn apply(&self, request: &mut Request) {
+        request.set_header("DACP-ID", self.dacp_id());
+        request.set_header("Active-Remote", self.active_remote);
+    }
+}
+
+/// A button pressed on a receiver, or a property it set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    /// Resume after an interruption, such as a Siri request on a HomePod.
+    PlayResume,
+    NextItem,
+    PreviousItem,
+    BeginFastForward,
+    BeginRewind,
+    VolumeUp,
+    VolumeDown,
+    MuteToggle,
+    ShuffleSongs,
+    /// `dmcp.device-volume`, in dB from -30 to 0, or -144 for muted.
+    SetVolume(f32),
+    /// `dacp.shufflestate`
+    SetShuffle(bool),
+    /// `dacp.repeatstate`: 0 for off, 1 to repeat the item, 2 for everything.
+    SetRepeat(u8),
+}
+
+impl RemoteCommand {
+    /// Reads the path of a request, such as `/ctrl-int/1/nextitem` or
+    /// `/ctrl-int/1/setproperty?dmcp.device-volume=-15.0`.
+    pub fn parse(path: &str) -> Option<RemoteCommand> {
+        let path = path.strip_prefix("/ctrl-int/1/")?;
+
+        let (command, query) = match path.split_once('?') {
+            Some((x, y)) => (x, Some(y)),
+            None => (path, None),
+        };
+
+        let command = match command {
+            "play" => RemoteCommand::Play,
+            "pause" => RemoteCommand::Pause,
+            "playpause" => RemoteCommand::PlayPause,
+            "stop" => RemoteCommand::Stop,
+            "playresume" => RemoteCommand::PlayResume,
+            "nextitem" => RemoteCommand::NextItem,
+            "previtem" => RemoteCommand::PreviousItem,
+            "beginff" => RemoteCommand::BeginFastForward,
+            "beginrew" => RemoteCommand::BeginRewind,
+            "volumeup" => RemoteCommand::VolumeUp,
+            "volumedown" => RemoteCommand::VolumeDown,
+            "mutetoggle" => RemoteCommand::MuteToggle,
+            "shuffle_songs" => RemoteCommand::ShuffleSongs,
+            "setproperty" => {
+                let (name, value) = query?.split('&').next()?.split_once('=')?;
+
+                match name {
+                    "dmcp.device-volume" => RemoteCommand::SetVolume(value.parse().ok()?),
+                    "dacp.shufflestate" => RemoteCommand::SetShuffle(value.parse::<u8>().ok()? != 0),
+                    "dacp.repeatstate" => RemoteCommand::SetRepeat(value.parse().ok()?),
+                    _ => return None,
+                }
+            },
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+struct Connection {
+    peer: SocketAddr,
+    remote: Remote,
+    commands: broadcast::Sender<RemoteCommand>,
+}
+
+impl Handler for Connection {
+    async fn handle(&mut self, request: Request) -> Answer {
+        let active_remote = self.remote.active_remote.to_string();
+
+        if request.headers.get("Active-Remote") != Some(active_remote.as_str()) {
+            tracing::debug!(peer = %self.peer, path = %request.path, "Refusing DACP request without our Active-Remote");
+            return Response::new(403, "Forbidden").into();
+        }
+
+        if request.method != Method::GET {
+            return Response::new(405, "Method Not Allowed").into();
+        }
+
+        let Some(command) = RemoteCommand::parse(&request.path) else {
+            tracing::debug!(peer = %self.peer, path = %request.path, "Unknown DACP command");
+            return Response::new(501, "Not Implemented").into();
+        };
+
+        tracing::debug!(peer = %self.peer, ?command, "DACP command");
+        let _ = self.commands.send(command);
+
+        Response::new(204, "No Content").into()
+    }
+}
+
+/// Takes DACP requests from receivers and hands them out as [`RemoteCommand`]s.
+pub struct Server {
+    remote: Remote,
+    server: rtsp::server::Server,
+    commands: broadcast::Sender<RemoteCommand>,
+    _advertiser: Option<Advertiser>,
+}
+
+impl Server {
+    /// Listens on any address and advertises the `_dacp._tcp` service for `remote`.
+    pub async fn start(remote: Remote) -> io::Result<Server> {
+        let mut server = Server::bind("0.0.0.0:0", remote).await?;
+        let service = remote.service(server.local_addr().port());
+        server._advertiser = Some(Advertiser::start_services(&service.instance, std::slice::from_ref(&service), &[]).await?);
+        Ok(server)
+    }
+
+    /// Listens on `addr` without advertising, for receivers told the port some other way.
+    pub async fn bind<A: ToSocketAddrs>(addr: A, remote: Remote) -> io::Result<Server> {
+        let (commands, _) = broadcast::channel(32);
+
+        let server = rtsp::server::Server::bind(addr, {
+            let commands = commands.clone();
+            move |peer| Connection { peer, remote, commands: commands.clone() }
+        }).await?;
+
+        Ok(Server { remote, server, commands, _advertiser: None })
+    }
+
+    pub fn remote(&self) -> Remote {
+        self.remote
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RemoteCommand> {
+        self.commands.subscribe()
+    }
+
+    /// Sends our `DACP-ID` and `Active-Remote` on every request of `client` from now on, so the receiver
+    /// can reach us.
+    pub fn attach(&self, client: &rtsp::Client) {
+        client.set_remote(Some(self.remote));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/playpause"), Some(RemoteCommand::PlayPause));
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/previtem"), Some(RemoteCommand::PreviousItem));
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/setproperty?dmcp.device-volume=-15.250000"), Some(RemoteCommand::SetVolume(-15.25)));
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/setproperty?dacp.shufflestate=1"), Some(RemoteCommand::SetShuffle(true)));
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/setproperty?dacp.repeatstate=2"), Some(RemoteCommand::SetRepeat(2)));
+
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/setproperty?dacp.unknown=1"), None);
+        assert_eq!(RemoteCommand::parse("/ctrl-int/1/rewind"), None);
+        assert_eq!(RemoteCommand::parse("/playpause"), None);
+    }
+
+    #[test]
+    fn advertises_the_dacp_id() {
+        let remote = Remote { id: 0x1A2B3C4D5E6F7A8B, active_remote: 1234 };
+        let service = remote.service(3689);
+
+        assert_eq!(service.instance, "iTunes_Ctrl_1A2B3C4D5E6F7A8B");
+        assert!(service.txt.contains(&"DbId=1A2B3C4D5E6F7A8B".to_string()));
+
+        let mut request = Request::new(Method::SETUP, "rtsp://127.0.0.1/1");
+        remote.apply(&mut request);
+        assert_eq!(request.headers.get("DACP-ID"), Some("1A2B3C4D5E6F7A8B"));
+        assert_eq!(request.headers.get("Active-Remote"), Some("1234"));
+    }
+}
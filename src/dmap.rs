@@ -0,0 +1,200 @@
+//! DMAP, the tagged binary format of iTunes sharing, which AirPlay still uses to describe the track playing.
+//!
+//! Every item is a four character tag, a big-endian 32-bit length and the data. Containers such as `mlit`
+//! hold further items.
+
+use std::{fmt, time::Duration};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Truncated,
+    /// The data isn't an `mlit` listing item.
+    NotListingItem,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("truncated DMAP item"),
+            Self::NotListingItem => f.write_str("DMAP data isn't a listing item"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Item<'a> {
+    pub tag: [u8; 4],
+    pub data: &'a [u8],
+}
+
+impl<'a> Item<'a> {
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.data).ok()
+    }
+
+    /// Integers come in 1, 2, 4 or 8 bytes, big-endian.
+    pub fn as_uint(&self) -> Option<u64> {
+        match self.data.len() {
+            1 | 2 | 4 | 8 => Some(self.data.iter().fold(0, |x, &b| x << 8 | u64::from(b))),
+            _ => None,
+        }
+    }
+
+    pub fn children(&self) -> Result<Vec<Item<'a>>, Error> {
+        parse(self.data)
+    }
+}
+
+/// Splits `data` into the items at its top level.
+pub fn parse(mut data: &[u8]) -> Result<Vec<Item<'_>>, Error> {
+    let mut items = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(Error::Truncated);
+        }
+
+        let tag = data[..4].try_into().unwrap();
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let rest = &data[8..];
+
+        if rest.len() < len {
+            return Err(Error::Truncated);
+        }
+
+        items.push(Item { tag, data: &rest[..len] });
+        data = &rest[len..];
+    }
+
+    Ok(items)
+}
+
+pub fn write(tag: &[u8; 4], data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(tag);
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// What the sender says about the track playing, as sent in a `application/x-dmap-tagged` SET_PARAMETER.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub track_number: Option<u16>,
+    pub track_count: Option<u16>,
+    pub duration: Option<Duration>,
+}
+
+impl TrackMetadata {
+    /// Reads an `mlit` item. Tags we don't know are skipped.
+    pub fn from_dmap(data: &[u8]) -> Result<TrackMetadata, Error> {
+        let items = parse(data)?;
+        let listing = match items.as_slice() {
+            [x] if &x.tag == b"mlit" => x.children()?,
+            _ => return Err(Error::NotListingItem),
+        };
+
+        let mut metadata = TrackMetadata::default();
+
+        for item in listing {
+            let text = || item.as_str().map(str::to_string);
+
+            match &item.tag {
+                b"minm" => metadata.title = text(),
+                b"asar" => metadata.artist = text(),
+                b"asal" => metadata.album = text(),
+                b"asaa" => metadata.album_artist = text(),
+                b"asgn" => metadata.genre = text(),
+                b"ascp" => metadata.composer = text(),
+                b"astn" => metadata.track_number = item.as_uint().map(|x| x as u16),
+                b"astc" => metadata.track_count = item.as_uint().map(|x| x as u16),
+                b"astm" => metadata.duration = item.as_uint().map(Duration::from_millis),
+                _ => {},
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn to_dmap(&self) -> Vec<u8> {
+        let mut listing = Vec::new();
+        let texts = [
+            (b"minm", &self.title),
+            (b"asar", &self.artist),
+            (b"asal", &self.album),
+            (b"asaa", &self.album_artist),
+            (b"asgn", &self.genre),
+            (b"ascp", &self.composer),
+        ];
+
+        for (tag, value) in texts {
+            if let Some(x) = value {
+                write(tag, x.as_bytes(), &mut listing);
+            }
+        }
+
+        if let Some(x) = self.track_number {
+            write(b"astn", &x.to_be_bytes(), &mut listing);
+        }
+
+        if let Some(x) = self.track_count {
+            write(b"astc", &x.to_be_bytes(), &mut listing);
+        }
+
+        if let Some(x) = self.duration {
+            write(b"astm", &(x.as_millis() as u32).to_be_bytes(), &mut listing);
+        }
+
+        let mut out = Vec::with_capacity(listing.len() + 8);
+        write(b"mlit", &listing, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_track_metadata() {
+        let metadata = TrackMetadata {
+            title: Some("Hívogató".to_string()),
+            artist: Some("Kispál és a Borz".to_string()),
+            track_number: Some(3),
+            duration: Some(Duration::from_millis(215_000)),
+            ..Default::default()
+        };
+
+        assert_eq!(TrackMetadata::from_dmap(&metadata.to_dmap()), Ok(metadata));
+    }
+
+    #[test]
+    fn skips_unknown_tags() {
+        let mut listing = Vec::new();
+        write(b"mper", &42_u64.to_be_bytes(), &mut listing);
+        write(b"minm", b"Title", &mut listing);
+        write(b"asdk", &[0], &mut listing);
+
+        let mut data = Vec::new();
+        write(b"mlit", &listing, &mut data);
+
+        let metadata = TrackMetadata::from_dmap(&data).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist, None);
+    }
+
+    #[test]
+    fn rejects_truncated_items() {
+        let mut data = Vec::new();
+        write(b"minm", b"Title", &mut data);
+
+        assert_eq!(parse(&data[..data.len() - 1]), Err(Error::Truncated));
+        assert_eq!(TrackMetadata::from_dmap(&data), Err(Error::NotListingItem));
+    }
+}
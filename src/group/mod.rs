@@ -0,0 +1,345 @@
+use std::{io, net::SocketAddr, time::{Duration, Instant, SystemTime}};
+
+use futures_util::future::join_all;
+use tokio::net::UdpSocket;
+
+use crate::{rtp::{self, AudioCipher, NtpTime, Sync}, rtsp::{ops::StreamInfo, Client}};
+
+/// Default distance between the sync timestamp and the timestamp being sent, in frames.
+pub const DEFAULT_LATENCY: u32 = 11025;
+
+/// Maps RTP timestamps of one stream onto local time. Every member of a [`Group`] shares one.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    pub(crate) origin: Instant,
+    pub(crate) origin_time: SystemTime,
+    pub(crate) sample_rate: u32,
+    pub(crate) rtp_origin: u32,
+}
+
+impl Clock {
+    pub fn new(sample_rate: u32, rtp_origin: u32) -> Clock {
+        Clock {
+            origin: Instant::now(),
+            origin_time: SystemTime::now(),
+            sample_rate,
+            rtp_origin,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// The RTP timestamp due to be heard at `at`.
+    pub fn rtp_at(&self, at: Instant) -> u32 {
+        let elapsed = at.saturating_duration_since(self.origin);
+        let frames = elapsed.as_nanos() * u128::from(self.sample_rate) / 1_000_000_000;
+        self.rtp_origin.wrapping_add(frames as u32)
+    }
+
+    /// When `timestamp` is due to be heard. Timestamps up to half the RTP range behind the origin count as
+    /// being in the past.
+    pub fn instant_of(&self, timestamp: u32) -> Instant {
+        let frames = timestamp.wrapping_sub(self.rtp_origin) as i32;
+        let offset = Duration::from_nanos(u64::from(frames.unsigned_abs()) * 1_000_000_000 / u64::from(self.sample_rate));
+
+        if frames >= 0 {
+            self.origin + offset
+        } else {
+            self.origin.checked_sub(offset).unwrap_or(self.origin)
+        }
+    }
+
+    pub fn ntp_at(&self, at: Instant) -> NtpTime {
+        NtpTime::from(self.origin_time + at.saturating_duration_since(self.origin))
+    }
+}
+
+/// Output latency of one member, in frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// What the receiver answered in `Audio-Latency` to RECORD, see [`Client::audio_latency`], unless set
+    /// with [`Group::set_reported_latency`]. Zero until then.
+    pub reported: u32,
+    /// Manual correction for devices whose real output latency differs from what they report. Positive
+    /// values mean the device plays later than reported.
+    pub offset: i32,
+}
+
+impl Latency {
+    pub fn output(&self) -> i64 {
+        i64::from(self.reported) + i64::from(self.offset)
+    }
+}
+
+struct Member {
+    id: String,
+    client: Client,
+    data: SocketAddr,
+    control: Option<SocketAddr>,
+    synced: bool,
+    /// Set by [`Group::set_reported_latency`], otherwise the client's is used.
+    reported: Option<u32>,
+    offset: i32,
+}
+
+impl Member {
+    /// Read on every use, since RECORD may complete after the member joined.
+    fn latency(&self) -> Latency {
+        Latency {
+            reported: self.reported.or_else(|| self.client.audio_latency()).unwrap_or(0),
+            offset: self.offset,
+        }
+    }
+}
+
+/// A set of receivers playing one stream in lockstep.
+///
+/// Every member receives byte-identical RTP packets and sync packets derived from the same [`Clock`], so
+/// members can join or leave while audio is playing without the others being flushed.
+///
+/// The group only sends: it doesn't answer retransmit requests, so a lost packet stays lost, and it doesn't
+/// answer timing requests, so members don't follow its clock. The shared [`Clock`] is a common timestamp
+/// base for the packets, not a synchronized wall clock.
+pub struct Group {
+    clock: Clock,
+    socket: UdpSocket,
+    local_peers: Vec<String>,
+    members: Vec<Member>,
+    key: [u8; 32],
+    cipher: AudioCipher,
+    ssrc: u32,
+    sequence: u16,
+    /// Packets sent so far, the nonce of the next one.
+    sent: u64,
+    next_timestamp: Option<u32>,
+    latency: u32,
+}
+
+impl Group {
+    /// `local_peers` are our own addresses, announced alongside the members in SETPEERS.
+    pub async fn new(sample_rate: u32, local_peers: Vec<String>) -> io::Result<Group> {
+        let rtp_origin = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let key: [u8; 32] = rand::random();
+
+        Ok(Group {
+            clock: Clock::new(sample_rate, rtp_origin),
+            socket: UdpSocket::bind("0.0.0.0:0").await?,
+            local_peers,
+            members: Vec::new(),
+            key,
+            cipher: AudioCipher::new(&key),
+            ssrc: rtp_origin.rotate_left(16),
+            sequence: 0,
+            sent: 0,
+            next_timestamp: None,
+            latency: DEFAULT_LATENCY,
+        })
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// The key audio is encrypted with. Members must be SET UP with it as the stream's `shk`.
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+
+    /// Sets how far ahead of the shared clock audio is sent. Members should be SET UP with this value as
+    /// `latency_min` and `latency_max` so they buffer enough.
+    pub fn set_latency(&mut self, frames: u32) {
+        self.latency = frames;
+    }
+
+    fn find(&self, id: &str) -> Option<&Member> {
+        self.members.iter().find(|x| x.id == id)
+    }
+
+    fn find_mut(&mut self, id: &str) -> Option<&mut Member> {
+        self.members.iter_mut().find(|x| x.id == id)
+    }
+
+    /// The output latency every member is aligned to: the largest one in the group.
+    fn aligned_output(&self) -> i64 {
+        self.members.iter().map(|x| x.latency().output()).max().unwrap_or(0).max(0)
+    }
+
+    pub fn member_latency(&self, id: &str) -> Option<Latency> {
+        self.find(id).map(Member::latency)
+    }
+
+    /// Overrides the latency the member's RECORD reported.
+    pub fn set_reported_latency(&mut self, id: &str, frames: u32) -> bool {
+        self.find_mut(id).map(|x| x.reported = Some(frames)).is_some()
+    }
+
+    pub fn set_latency_offset(&mut self, id: &str, frames: i32) -> bool {
+        self.find_mut(id).map(|x| x.offset = frames).is_some()
+    }
+
+    /// Time from handing a packet to [`Group::send`] until it is heard on the member's output.
+    ///
+    /// Members with less output latency, offsets included, are delayed to match the slowest one, so this is
+    /// the same for every member. `None` if `id` isn't a member.
+    pub fn effective_latency(&self, id: &str) -> Option<Duration> {
+        self.find(id)?;
+        let frames = i64::from(self.latency) + self.aligned_output();
+        Some(Duration::from_nanos((frames.max(0) as u64) * 1_000_000_000 / u64::from(self.clock.sample_rate)))
+    }
+
+    /// The sequence number and RTP timestamp of the next packet, `None` before the first one is sent.
+    pub fn position(&self) -> Option<(u16, u32)> {
+        Some((self.sequence, self.next_timestamp?))
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|x| x.id.as_str())
+    }
+
+    /// The member's control connection. Clone it to issue requests while the group keeps streaming.
+    pub fn client(&self, id: &str) -> Option<&Client> {
+        self.find(id).map(|x| &x.client)
+    }
+
+    /// The address list sent in SETPEERS: our own addresses followed by every member.
+    pub fn peers(&self) -> Vec<String> {
+        self.local_peers.iter().cloned()
+            .chain(self.members.iter().map(|x| x.client.peer.ip().to_string()))
+            .collect()
+    }
+
+    async fn announce_peers(&self) {
+        let peers = self.peers();
+
+        join_all(self.members.iter().map(|member| {
+            let peers = peers.clone();
+
+            async move {
+                if let Err(err) = member.client.setpeers(peers).await {
+                    tracing::warn!(member = %member.id, "SETPEERS failed: {}", err);
+                }
+            }
+        })).await;
+    }
+
+    /// Adds a receiver whose audio stream has already been SET UP. It starts with the next packet sent.
+    pub async fn join(&mut self, id: impl ToString, client: Client, stream: &StreamInfo) -> io::Result<()> {
+        let id = id.to_string();
+        let data_port = stream.data_port.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "stream has no data port"))?;
+
+        let data = SocketAddr::new(client.peer.ip(), data_port);
+        let control = stream.control_port.map(|x| SocketAddr::new(client.peer.ip(), x));
+        self.members.retain(|x| x.id != id);
+        self.members.push(Member { id, client, data, control, synced: false, reported: None, offset: 0 });
+        self.announce_peers().await;
+        Ok(())
+    }
+
+    /// Removes a receiver, handing its client back so it can be torn down or reused.
+    pub async fn leave(&mut self, id: &str) -> Option<Client> {
+        let index = self.members.iter().position(|x| x.id == id)?;
+        let member = self.members.remove(index);
+        self.announce_peers().await;
+        Some(member.client)
+    }
+
+    /// Sends one packet carrying `frames` frames to every member, encrypted with [`Group::key`], and
+    /// returns its RTP timestamp. Members the packet can't be sent to are logged and skipped.
+    ///
+    /// The first packet is stamped to play `latency` frames from now on the shared clock, later ones follow
+    /// on contiguously, so callers should pace sending to the clock.
+    pub async fn send(&mut self, payload: &[u8], frames: u32) -> io::Result<u32> {
+        let timestamp = self.next_timestamp.unwrap_or_else(|| self.clock.rtp_at(Instant::now()).wrapping_add(self.latency));
+        self.next_timestamp = Some(timestamp.wrapping_add(frames));
+
+        let mut packet = Vec::with_capacity(rtp::HEADER_LEN + payload.len() + AudioCipher::OVERHEAD);
+        rtp::Header {
+            marker: false,
+            extension: false,
+            payload_type: rtp::PAYLOAD_TYPE_AUDIO,
+            sequence: self.sequence,
+            timestamp,
+            ssrc: self.ssrc,
+        }.write(&mut packet);
+        packet.extend_from_slice(payload);
+        self.cipher.seal(&mut packet, self.sent);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sent += 1;
+
+        for member in &self.members {
+            if let Err(err) = self.socket.send_to(&packet, member.data).await {
+                tracing::warn!(member = %member.id, "Sending audio failed: {}", err);
+            }
+        }
+
+        Ok(timestamp)
+    }
+
+    /// Sends every member the current mapping between the shared clock and RTP time. Call this about once
+    /// a second, and after members join. Members the packet can't be sent to are logged and skipped.
+    ///
+    /// Members with less output latency than the slowest one are told playback is further behind, which
+    /// delays their output by the difference.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let current = self.clock.rtp_at(now);
+        let aligned = self.aligned_output();
+
+        for member in self.members.iter_mut() {
+            let Some(control) = member.control else { continue };
+            let timestamp = current.wrapping_sub((aligned - member.latency().output()).max(0) as u32);
+
+            let sync = Sync {
+                first: !member.synced,
+                timestamp,
+                time: self.clock.ntp_at(now),
+                next_timestamp: timestamp.wrapping_add(self.latency),
+            };
+
+            match self.socket.send_to(&sync.to_bytes(), control).await {
+                Ok(_) => member.synced = true,
+                Err(err) => tracing::warn!(member = %member.id, "Sending sync failed: {}", err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(rtp_origin: u32, origin: Instant) -> Clock {
+        Clock { origin, origin_time: SystemTime::now(), sample_rate: 44100, rtp_origin }
+    }
+
+    #[test]
+    fn clock_wraps_around_the_rtp_range() {
+        let origin = Instant::now() + Duration::from_secs(10);
+        let clock = clock(u32::MAX - 100, origin);
+
+        assert_eq!(clock.rtp_at(origin), u32::MAX - 100);
+        assert_eq!(clock.rtp_at(origin + Duration::from_secs(1)), 44100 - 101);
+        assert_eq!(clock.rtp_at(origin - Duration::from_secs(1)), u32::MAX - 100);
+
+        assert_eq!(clock.instant_of(44100 - 101), origin + Duration::from_secs(1));
+        assert_eq!(clock.instant_of(u32::MAX - 100 - 44100), origin - Duration::from_secs(1));
+        assert_eq!(clock.instant_of(clock.rtp_at(origin + Duration::from_millis(500))), origin + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn clock_counts_half_the_range_as_the_past() {
+        let origin = Instant::now() + Duration::from_secs(10);
+        let clock = clock(0, origin);
+
+        assert!(clock.instant_of(i32::MAX as u32) > origin);
+        assert!(clock.instant_of(i32::MAX as u32 + 1) <= origin);
+    }
+}
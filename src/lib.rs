@@ -1,2 +1,13 @@
+pub mod alac;
+pub mod audio;
+pub mod dacp;
+pub mod dmap;
+pub mod group;
+pub mod rtp;
 pub mod rtsp;
 pub mod mdns;
+pub mod mrp;
+pub mod pairing;
+pub mod raop;
+pub mod receiver;
+pub mod session;
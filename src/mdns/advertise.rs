@@ -0,0 +1,626 @@
+use std::{io, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use dns_parser::{Packet, QueryType};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use super::Metadata;
+
+const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MULTICAST_PORT: u16 = 5353;
+
+/// TTLs recommended by RFC 6762: host records and SRV expire sooner than the rest.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// A domain name as its labels, since service instance names may contain dots.
+type Name = Vec<String>;
+
+fn name(instance: Option<&str>, rest: &str) -> Name {
+    instance.into_iter().map(str::to_string).chain(rest.split('.').map(str::to_string)).collect()
+}
+
+fn name_matches(name: &Name, other: &str) -> bool {
+    name.join(".").eq_ignore_ascii_case(other.trim_end_matches('.'))
+}
+
+#[derive(Debug, Clone)]
+enum Data {
+    Ptr(Name),
+    Srv { port: u16, target: Name },
+    Txt(Vec<String>),
+    Address(IpAddr),
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: Name,
+    ttl: u32,
+    data: Data,
+}
+
+impl Record {
+    fn kind(&self) -> u16 {
+        match &self.data {
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Txt(_) => TYPE_TXT,
+            Data::Address(IpAddr::V4(_)) => TYPE_A,
+            Data::Address(IpAddr::V6(_)) => TYPE_AAAA,
+        }
+    }
+
+    /// Everything but PTRs is ours alone, so caches should drop what others said about the name.
+    fn is_unique(&self) -> bool {
+        !matches!(self.data, Data::Ptr(_))
+    }
+
+    fn answers(&self, question: &dns_parser::Question) -> bool {
+        let kind = match question.qtype {
+            QueryType::All => true,
+            QueryType::PTR => self.kind() == TYPE_PTR,
+            QueryType::SRV => self.kind() == TYPE_SRV,
+            QueryType::TXT => self.kind() == TYPE_TXT,
+            QueryType::A => self.kind() == TYPE_A,
+            QueryType::AAAA => self.kind() == TYPE_AAAA,
+            _ => false,
+        };
+
+        kind && name_matches(&self.name, &question.qname.to_string())
+    }
+
+    fn write(&self, buf: &mut Vec<u8>, ttl: u32) {
+        write_name(buf, &self.name);
+        buf.extend(self.kind().to_be_bytes());
+        buf.extend((CLASS_IN | if self.is_unique() { CACHE_FLUSH } else { 0 }).to_be_bytes());
+        buf.extend(ttl.to_be_bytes());
+
+        let mut data = Vec::new();
+
+        match &self.data {
+            Data::Ptr(x) => write_name(&mut data, x),
+            Data::Srv { port, target } => {
+                data.extend(0_u16.to_be_bytes());
+                data.extend(0_u16.to_be_bytes());
+                data.extend(port.to_be_bytes());
+                write_name(&mut data, target);
+            },
+            Data::Txt(entries) => {
+                for entry in entries {
+                    let entry = &entry.as_bytes()[..entry.len().min(255)];
+                    data.push(entry.len() as u8);
+                    data.extend(entry);
+                }
+
+                if entries.is_empty() {
+                    data.push(0);
+                }
+            },
+            Data::Address(IpAddr::V4(x)) => data.extend(x.octets()),
+            Data::Address(IpAddr::V6(x)) => data.extend(x.octets()),
+        }
+
+        buf.extend((data.len() as u16).to_be_bytes());
+        buf.extend(data);
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &Name) {
+    for label in name {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend(label);
+    }
+
+    buf.push(0);
+}
+
+/// A response packet, `ttl` overriding the records' own when given, as in goodbyes.
+fn response(id: u16, answers: &[&Record], additional: &[&Record], ttl: Option<u32>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1024);
+    buf.extend(id.to_be_bytes());
+    buf.extend(0x8400_u16.to_be_bytes());
+    buf.extend(0_u16.to_be_bytes());
+    buf.extend((answers.len() as u16).to_be_bytes());
+    buf.extend(0_u16.to_be_bytes());
+    buf.extend((additional.len() as u16).to_be_bytes());
+
+    for record in answers.iter().chain(additional) {
+        record.write(&mut buf, ttl.unwrap_or(record.ttl));
+    }
+
+    buf
+}
+
+/// One service instance to publish with [`Advertiser::start_services`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Such as `_dacp._tcp.local`.
+    pub service_type: String,
+    pub instance: String,
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+/// The records of every advertised service.
+struct Zone {
+    /// PTRs from service types to instances.
+    pointers: Vec<Record>,
+    /// SRV and TXT of the instances.
+    services: Vec<Record>,
+    addresses: Vec<Record>,
+    /// PTRs for service type enumeration.
+    types: Vec<Record>,
+}
+
+impl Zone {
+    fn new(metadata: &Metadata, addresses: &[IpAddr]) -> Zone {
+        let raop = format!("{}@{}", metadata.device_id.as_deref().unwrap_or_default().replace(':', ""), metadata.name);
+
+        let services = [
+            ("_airplay._tcp.local", metadata.name.clone(), metadata.txt_record()),
+            ("_raop._tcp.local", raop, raop_txt_record(metadata)),
+        ].map(|(service_type, instance, txt)| Service { service_type: service_type.to_string(), instance, port: metadata.port, txt });
+
+        Zone::with_services(&metadata.name, &services, addresses)
+    }
+
+    /// The records of `services` on a host named after `name`.
+    fn with_services(name: &str, services: &[Service], addresses: &[IpAddr]) -> Zone {
+        let host = self::name(None, &format!("{}.local", hostname(name)));
+        let mut zone = Zone { pointers: Vec::new(), services: Vec::new(), addresses: Vec::new(), types: Vec::new() };
+
+        for service in services {
+            let service_type = self::name(None, &service.service_type);
+            let instance = self::name(Some(&service.instance), &service.service_type);
+
+            zone.types.push(Record { name: self::name(None, SERVICES), ttl: OTHER_TTL, data: Data::Ptr(service_type.clone()) });
+            zone.pointers.push(Record { name: service_type, ttl: OTHER_TTL, data: Data::Ptr(instance.clone()) });
+            zone.services.push(Record { name: instance.clone(), ttl: HOST_TTL, data: Data::Srv { port: service.port, target: host.clone() } });
+            zone.services.push(Record { name: instance, ttl: OTHER_TTL, data: Data::Txt(service.txt.clone()) });
+        }
+
+        zone.addresses = addresses.iter().map(|x| Record { name: host.clone(), ttl: HOST_TTL, data: Data::Address(*x) }).collect();
+        zone
+    }
+
+    /// Every record, laid out as the answer to a PTR query for the services.
+    fn announcement(&self, ttl: Option<u32>) -> Vec<u8> {
+        let answers: Vec<&Record> = self.pointers.iter().collect();
+        let additional: Vec<&Record> = self.services.iter().chain(&self.addresses).collect();
+        response(0, &answers, &additional, ttl)
+    }
+
+    /// The response to a query, if any of its questions are about us.
+    fn answer(&self, packet: &Packet) -> Option<Vec<u8>> {
+        let all = self.pointers.iter().chain(&self.services).chain(&self.addresses).chain(&self.types);
+        let answers: Vec<&Record> = all.filter(|x| packet.questions.iter().any(|q| x.answers(q))).collect();
+
+        if answers.is_empty() {
+            return None;
+        }
+
+        // Pointers come with what they point to, SRVs with the host's addresses.
+        let pointed: Vec<&Name> = answers.iter().filter_map(|x| match &x.data {
+            Data::Ptr(x) => Some(x),
+            _ => None,
+        }).collect();
+
+        let mut additional: Vec<&Record> = self.services.iter()
+            .filter(|x| pointed.contains(&&x.name) && !answers.iter().any(|y| std::ptr::eq(*x, *y)))
+            .collect();
+
+        if answers.iter().chain(&additional).any(|x| matches!(x.data, Data::Srv { .. })) {
+            additional.extend(self.addresses.iter().filter(|x| !answers.iter().any(|y| std::ptr::eq(*x, *y))));
+        }
+
+        Some(response(packet.header.id, &answers, &additional, None))
+    }
+}
+
+/// Turns a device name into a host name label.
+fn hostname(name: &str) -> String {
+    let host: String = name.chars().map(|x| if x.is_ascii_alphanumeric() { x } else { '-' }).collect();
+    let host = host.trim_matches('-');
+
+    if host.is_empty() {
+        "airplay".to_string()
+    } else {
+        host.to_string()
+    }
+}
+
+/// The `_raop._tcp` TXT record for an AirPlay 2 receiver, derived from its `_airplay._tcp` metadata.
+fn raop_txt_record(metadata: &Metadata) -> Vec<String> {
+    let mut txt = vec![
+        "txtvers=1".to_string(),
+        "ch=2".to_string(),
+        "cn=0,1".to_string(),
+        "da=true".to_string(),
+        "et=0,4".to_string(),
+        "md=0,1,2".to_string(),
+        "sr=44100".to_string(),
+        "ss=16".to_string(),
+        "tp=UDP".to_string(),
+        "vn=65537".to_string(),
+        "vv=2".to_string(),
+    ];
+
+    if let Some(x) = &metadata.features {
+        txt.push(format!("ft={}", x.to_txt()));
+    }
+
+    if let Some(x) = &metadata.model {
+        txt.push(format!("am={}", x));
+    }
+
+    if let Some(x) = metadata.flags {
+        txt.push(format!("sf=0x{:X}", x));
+    }
+
+    if let Some(x) = &metadata.airplay_version {
+        txt.push(format!("vs={}", x));
+    }
+
+    if let Some(x) = &metadata.public_key {
+        txt.push(format!("pk={}", x.iter().map(|x| format!("{:02x}", x)).collect::<String>()));
+    }
+
+    txt
+}
+
+fn multicast_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MULTICAST_PORT)).into())?;
+    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The address other hosts reach us on, found by asking the routing table for the way to the mDNS group.
+fn local_address() -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MULTICAST_ADDR, MULTICAST_PORT)).ok()?;
+    socket.local_addr().ok().map(|x| x.ip()).filter(|x| !x.is_unspecified())
+}
+
+/// Publishes a device as `_airplay._tcp` and `_raop._tcp` services over multicast DNS, or any other
+/// services with [`Advertiser::start_services`].
+///
+/// The records are announced on start and whenever someone asks. Stopping, or dropping, the advertiser
+/// sends goodbye packets so browsers forget the device right away.
+pub struct Advertiser {
+    socket: Arc<UdpSocket>,
+    zone: Arc<Zone>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = self.socket.try_send_to(&self.zone.announcement(Some(0)), (MULTICAST_ADDR, MULTICAST_PORT).into());
+    }
+}
+
+impl Advertiser {
+    /// Starts advertising `metadata`, which needs at least a name and port. Without IP addresses, the
+    /// address of the interface multicast goes out on is used.
+    pub async fn start(metadata: &Metadata) -> io::Result<Advertiser> {
+        let addresses = Advertiser::addresses(&metadata.ip_addresses);
+        Advertiser::publish(Zone::new(metadata, &addresses), &metadata.name, addresses)
+    }
+
+    /// Starts advertising other services, on a host named after `name`, such as the `_dacp._tcp` service
+    /// of a sender.
+    pub async fn start_services(name: &str, services: &[Service], ip_addresses: &[IpAddr]) -> io::Result<Advertiser> {
+        let addresses = Advertiser::addresses(ip_addresses);
+        Advertiser::publish(Zone::with_services(name, services, &addresses), name, addresses)
+    }
+
+    fn addresses(ip_addresses: &[IpAddr]) -> Vec<IpAddr> {
+        match ip_addresses.is_empty() {
+            true => local_address().into_iter().collect(),
+            false => ip_addresses.to_vec(),
+        }
+    }
+
+    fn publish(zone: Zone, name: &str, addresses: Vec<IpAddr>) -> io::Result<Advertiser> {
+        let zone = Arc::new(zone);
+        let socket = Arc::new(multicast_socket()?);
+
+        let task = tokio::spawn({
+            let socket = socket.clone();
+            let zone = zone.clone();
+
+            async move {
+                let group = SocketAddr::from((MULTICAST_ADDR, MULTICAST_PORT));
+                let announcement = zone.announcement(None);
+
+                // RFC 6762 asks for at least two announcements, a second apart.
+                for _ in 0..2 {
+                    if let Err(err) = socket.send_to(&announcement, group).await {
+                        tracing::warn!("Failed to announce over mDNS: {}", err);
+                    }
+
+                    let _ = tokio::time::timeout(Duration::from_secs(1), Advertiser::answer(&socket, &zone)).await;
+                }
+
+                Advertiser::answer(&socket, &zone).await;
+            }
+        });
+
+        tracing::debug!(%name, ?addresses, "Advertising over mDNS");
+        Ok(Advertiser { socket, zone, task })
+    }
+
+    async fn answer(socket: &UdpSocket, zone: &Zone) {
+        let mut buf = vec![0; 9000];
+
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let Ok(packet) = Packet::parse(&buf[..n]) else { continue };
+
+            if !packet.header.query {
+                continue;
+            }
+
+            let Some(response) = zone.answer(&packet) else { continue };
+
+            // Legacy resolvers query from other ports and expect a unicast answer, as do questions with
+            // the unicast-response bit.
+            let to = if from.port() != MULTICAST_PORT || packet.questions.iter().any(|x| x.prefer_unicast) {
+                from
+            } else {
+                SocketAddr::from((MULTICAST_ADDR, MULTICAST_PORT))
+            };
+
+            if let Err(err) = socket.send_to(&response, to).await {
+                tracing::debug!(%to, "Failed to answer mDNS query: {}", err);
+            }
+        }
+    }
+
+    /// Stops advertising, telling browsers the device is gone.
+    pub async fn stop(self) -> io::Result<()> {
+        self.task.abort();
+        self.socket.send_to(&self.zone.announcement(Some(0)), (MULTICAST_ADDR, MULTICAST_PORT)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio::Codec, mdns::{Devices, Features, MediaRemoteMetadata, RaopEncryption}};
+
+    fn metadata() -> Metadata {
+        Metadata {
+            name: "Living Room".to_string(),
+            ip_addresses: vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))],
+            port: 7000,
+            device_id: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            features: Some(Features::from(0x84340481FCA00_u64)),
+            flags: Some(0x644),
+            model: Some("Receiver1,1".to_string()),
+            public_key: Some(vec![0xab; 32]),
+            airplay_version: Some("366.0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut buf, &name.split('.').map(str::to_string).collect());
+        buf.extend(qtype.to_be_bytes());
+        buf.extend(CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn announcement_parses_back_into_the_same_metadata() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+        let announcement = zone.announcement(None);
+        let response = mdns::Response::from_packet(&Packet::parse(&announcement).unwrap());
+        let parsed = Metadata::from_response(response).unwrap();
+
+        assert_eq!(parsed.name, "Living Room");
+        assert_eq!(parsed.port, 7000);
+        assert_eq!(parsed.ip_addresses, metadata().ip_addresses);
+        assert_eq!(parsed.device_id.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(parsed.features.as_ref().map(u64::from), Some(0x84340481FCA00));
+        assert_eq!(parsed.flags, Some(0x644));
+        assert_eq!(parsed.public_key, Some(vec![0xab; 32]));
+        assert!(parsed.is_sane());
+    }
+
+    #[test]
+    fn announcement_carries_the_raop_service() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+        let response = mdns::Response::from_packet(&Packet::parse(&zone.announcement(None)).unwrap());
+        let raop = Metadata::from_response(response).unwrap().raop.unwrap();
+
+        assert_eq!(raop.device_id, "AA:BB:CC:DD:EE:FF");
+        assert_eq!(raop.name, "Living Room");
+        assert_eq!(raop.port, Some(7000));
+        assert_eq!(raop.codecs, vec![Codec::Pcm, Codec::Alac]);
+        assert_eq!(raop.encryption_types, vec![RaopEncryption::None, RaopEncryption::MfiSap]);
+    }
+
+    #[test]
+    fn raop_only_answers_merge_into_the_device() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+        let answer = zone.answer(&Packet::parse(&query("_raop._tcp.local", TYPE_PTR)).unwrap()).unwrap();
+        let raop_only = Metadata::from_response(mdns::Response::from_packet(&Packet::parse(&answer).unwrap())).unwrap();
+
+        assert_eq!(raop_only.name, "Living Room");
+        assert_eq!(raop_only.port, 7000);
+        assert_eq!(raop_only.ip_addresses, metadata().ip_addresses);
+        assert!(raop_only.features.is_none());
+        assert!(raop_only.raop.is_some());
+
+        let mut device = metadata();
+        assert!(device.is_same_device(&raop_only));
+        device.merge(raop_only.clone());
+        assert!(device.features.is_some());
+        assert_eq!(device.raop, raop_only.raop);
+
+        // A later `_airplay._tcp` answer keeps what `_raop._tcp` told us.
+        device.merge(metadata());
+        assert_eq!(device.raop, raop_only.raop);
+        assert_eq!(device.flags, Some(0x644));
+    }
+
+    /// What an Apple TV answers for `_mediaremotetv._tcp`.
+    fn media_remote_response(identity: &str) -> mdns::Response {
+        let instance = name(Some("Living Room"), "_mediaremotetv._tcp.local");
+        let host = name(None, "Living-Room.local");
+        let txt = [
+            "ModelName=Apple TV",
+            "AllowPairing=YES",
+            "BluetoothAddress=False",
+            "macAddress=aa:bb:cc:dd:ee:ff",
+            "Name=Living Room",
+            "UniqueIdentifier=5D797FD3-4F72-4C0B-9C1B-ECE8BA8D1A2B",
+            "SystemBuildVersion=21K69",
+            &format!("LocalAirPlayReceiverPairingIdentity={}", identity),
+        ].map(str::to_string).to_vec();
+
+        let packet = response(
+            0,
+            &[&Record { name: name(None, "_mediaremotetv._tcp.local"), ttl: OTHER_TTL, data: Data::Ptr(instance.clone()) }],
+            &[
+                &Record { name: instance.clone(), ttl: HOST_TTL, data: Data::Srv { port: 49152, target: host.clone() } },
+                &Record { name: instance, ttl: OTHER_TTL, data: Data::Txt(txt) },
+                &Record { name: host, ttl: HOST_TTL, data: Data::Address(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))) },
+            ],
+            None,
+        );
+
+        mdns::Response::from_packet(&Packet::parse(&packet).unwrap())
+    }
+
+    #[test]
+    fn parses_media_remote_services() {
+        let media_remote = MediaRemoteMetadata::from_response(&media_remote_response("6E4B7C1B-01A5-4F2E-A8D2-5A1F1C2F0C3D")).unwrap();
+
+        assert_eq!(media_remote.port, 49152);
+        assert_eq!(media_remote.model_name, "Apple TV");
+        assert!(media_remote.allow_pairing);
+        assert!(media_remote.bluetooth_address.is_empty());
+        assert_eq!(media_remote.mac_address, "aa:bb:cc:dd:ee:ff");
+        assert_eq!(media_remote.name, "Living Room");
+        assert_eq!(media_remote.uuid, "5D797FD3-4F72-4C0B-9C1B-ECE8BA8D1A2B");
+        assert_eq!(media_remote.system_build_version, "21K69");
+        assert_eq!(media_remote.local_airplay_receiver_pairing_identity, "6E4B7C1B-01A5-4F2E-A8D2-5A1F1C2F0C3D");
+
+        assert!(Metadata::from_response(media_remote_response("x")).is_none());
+    }
+
+    #[test]
+    fn media_remote_attaches_by_pairing_identity() {
+        let identity = "6E4B7C1B-01A5-4F2E-A8D2-5A1F1C2F0C3D";
+        let device = Metadata { public_system_pairing_identity: Some(identity.to_lowercase()), ..metadata() };
+        let airplay = || {
+            let announcement = Zone::new(&device, &device.ip_addresses).announcement(None);
+            mdns::Response::from_packet(&Packet::parse(&announcement).unwrap())
+        };
+
+        // Before and after the AirPlay service.
+        let mut devices = Devices::default();
+        assert!(devices.insert(media_remote_response(identity)).is_none());
+        assert_eq!(devices.insert(airplay()).unwrap().media_remote.as_ref().unwrap().port, 49152);
+
+        let mut devices = Devices::default();
+        assert!(devices.insert(airplay()).unwrap().media_remote.is_none());
+        assert!(devices.insert(media_remote_response(identity)).unwrap().media_remote.is_some());
+        assert_eq!(devices.devices.len(), 1);
+
+        // Someone else's.
+        devices.insert(media_remote_response("9F0E2D1C-0000-0000-0000-000000000000"));
+        assert_eq!(devices.devices.len(), 1);
+        assert_eq!(devices.media_remotes.len(), 1);
+    }
+
+    #[test]
+    fn features_round_trip_through_txt() {
+        // A speaker's bits without the ones we have no field for, and transient pairing on its own.
+        for bits in [0x84340481FCA00_u64, 0x1000000040200] {
+            let txt = Features::from(bits).to_txt();
+            let (lo, hi) = txt.split_once(',').unwrap();
+            let parsed = Features::from((
+                u32::from_str_radix(lo.trim_start_matches("0x"), 16).unwrap(),
+                u32::from_str_radix(hi.trim_start_matches("0x"), 16).unwrap(),
+            ));
+            assert_eq!(u64::from(&parsed), bits);
+        }
+    }
+
+    #[test]
+    fn answers_service_queries_with_everything_needed_to_connect() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+        let query = query("_airplay._tcp.local", TYPE_PTR);
+        let answer = zone.answer(&Packet::parse(&query).unwrap()).unwrap();
+        let response = mdns::Response::from_packet(&Packet::parse(&answer).unwrap());
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(Metadata::from_response(response).unwrap().name, "Living Room");
+    }
+
+    #[test]
+    fn answers_host_and_instance_queries() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+
+        let answer = zone.answer(&Packet::parse(&query("Living-Room.local", TYPE_A)).unwrap()).unwrap();
+        let response = mdns::Response::from_packet(&Packet::parse(&answer).unwrap());
+        assert_eq!(response.ip_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))));
+
+        let raop = query("AABBCCDDEEFF@Living Room._raop._tcp.local", TYPE_SRV);
+        let answer = zone.answer(&Packet::parse(&raop).unwrap()).unwrap();
+        let response = mdns::Response::from_packet(&Packet::parse(&answer).unwrap());
+        assert_eq!(response.port(), Some(7000));
+
+        assert!(zone.answer(&Packet::parse(&query("_googlecast._tcp.local", TYPE_PTR)).unwrap()).is_none());
+    }
+
+    #[test]
+    fn answers_for_other_services() {
+        let service = Service {
+            service_type: "_dacp._tcp.local".to_string(),
+            instance: "iTunes_Ctrl_1A2B3C4D5E6F7A8B".to_string(),
+            port: 3689,
+            txt: vec!["DbId=1A2B3C4D5E6F7A8B".to_string()],
+        };
+        let zone = Zone::with_services(&service.instance, std::slice::from_ref(&service), &metadata().ip_addresses);
+
+        let answer = zone.answer(&Packet::parse(&query("_dacp._tcp.local", TYPE_PTR)).unwrap()).unwrap();
+        let response = mdns::Response::from_packet(&Packet::parse(&answer).unwrap());
+        assert_eq!(response.port(), Some(3689));
+        assert_eq!(response.ip_addr(), Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))));
+        assert!(response.txt_records().any(|x| x == "DbId=1A2B3C4D5E6F7A8B"));
+
+        assert!(zone.answer(&Packet::parse(&query("_airplay._tcp.local", TYPE_PTR)).unwrap()).is_none());
+    }
+
+    #[test]
+    fn goodbye_has_zero_ttl() {
+        let zone = Zone::new(&metadata(), &metadata().ip_addresses);
+        let goodbye = zone.announcement(Some(0));
+        let response = mdns::Response::from_packet(&Packet::parse(&goodbye).unwrap());
+        assert!(response.records().all(|x| x.ttl == 0));
+    }
+}
@@ -1,9 +1,15 @@
-use std::{time::Duration, net::IpAddr, collections::HashMap, io::Write};
+use std::{time::Duration, net::IpAddr, collections::HashMap};
 
 use futures_util::{StreamExt, pin_mut};
 use mdns::{Response, RecordKind};
 
-#[derive(Debug)]
+mod advertise;
+mod raop;
+
+pub use advertise::{Advertiser, Service};
+pub use raop::{RaopEncryption, RaopMetadata};
+
+#[derive(Debug, Clone)]
 pub struct Features {
     pub supports_video_v1: bool,
     pub supports_video_v2: bool,
@@ -100,6 +106,72 @@ impl From<u64> for Features {
     }
 }
 
+impl From<&Features> for u64 {
+    /// The inverse of `Features::from(u64)`. Bits without a field of their own are left out, except that
+    /// `supports_coreutils` alone sets bit 48, transient pairing.
+    fn from(f: &Features) -> Self {
+        let bits = [
+            (f.supports_video_v1, 0),
+            (f.supports_video_v2, 49),
+            (f.supports_photo, 1),
+            (f.supports_slideshow, 5),
+            (f.supports_screen, 7),
+            (f.supports_audio, 9),
+            (f.audio_redundant, 11),
+            (f.fairplay_auth, 14),
+            (f.rsa_auth, 23),
+            (f.mfi_auth && !f.supports_unified_pair_mfi, 26),
+            (f.send_artwork, 15),
+            (f.send_track_progress, 16),
+            (f.send_daap_nowplaying, 17),
+            (f.send_bplist_nowplaying, 50),
+            (f.supports_alac, 18),
+            (f.supports_aac, 19),
+            (f.supports_aac_eld, 20),
+            (f.supports_opus, 21),
+            (f.supports_legacy_pairing, 27),
+            (f.has_unified_advertiser_info, 30),
+            (f.is_carplay || !f.supports_volume, 32),
+            (f.supports_airplay_video_queue, 33),
+            (f.supports_airplay_from_cloud, 34),
+            (f.supports_tls_psk, 35),
+            (f.supports_unified_media_control, 38),
+            (f.supports_buffered_audio, 40),
+            (f.supports_ptp, 41),
+            (f.supports_screen_multi_codec, 42),
+            (f.supports_system_pairing, 43),
+            (f.is_ap_valeria_screen_sender, 44),
+            (f.supports_homekit, 46),
+            (f.supports_coreutils && !(f.supports_unified_media_control || f.supports_system_pairing || f.supports_homekit), 48),
+            (f.supports_unified_pair_mfi, 51),
+            (f.supports_setpeers_extended_message, 52),
+            (f.supports_ap_sync, 54),
+            (f.supports_wol, 55),
+            (f.supports_hangdog, 58),
+            (f.supports_audio_stream_connection_setup, 59),
+            (f.supports_audio_media_data_control, 60),
+            (f.supports_rfc2198_redundancy, 61),
+        ];
+
+        bits.iter().filter(|(set, _)| *set).fold(0, |v, (_, b)| v | 1 << b)
+    }
+}
+
+impl Features {
+    /// Whether audio has to go over legacy RAOP: the device authenticates senders with an RSA
+    /// `Apple-Challenge` or legacy pairing, and with none of the pairing schemes AirPlay 2 builds on.
+    pub fn requires_raop(&self) -> bool {
+        (self.rsa_auth || self.supports_legacy_pairing)
+            && !(self.supports_homekit || self.supports_system_pairing || self.supports_coreutils || self.supports_unified_pair_mfi)
+    }
+
+    /// The `features` TXT value: the lower and upper 32 bits in hex.
+    pub fn to_txt(&self) -> String {
+        let v = u64::from(self);
+        format!("0x{:X},0x{:X}", v & 0xffff_ffff, v >> 32)
+    }
+}
+
 impl From<(u32, u32)> for Features {
     fn from(v: (u32, u32)) -> Self {
         let x = u64::from(v.1) << 32 | u64::from(v.0);
@@ -107,8 +179,11 @@ impl From<(u32, u32)> for Features {
     }
 }
 
-#[derive(Debug)]
+/// The `_mediaremotetv._tcp` service Apple TVs and HomePods take remote control connections on.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MediaRemoteMetadata {
+    /// From the SRV record.
+    pub port: u16,
     pub model_name: String,
     pub allow_pairing: bool,
     pub bluetooth_address: Vec<u8>,
@@ -121,7 +196,7 @@ pub struct MediaRemoteMetadata {
     pub local_airplay_receiver_pairing_identity: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Metadata {
     pub name: String,
     pub ip_addresses: Vec<IpAddr>,
@@ -154,28 +229,152 @@ pub struct Metadata {
     pub airplay_version: Option<String>,
     pub os_version: Option<String>,
 
+    /// The `_raop._tcp` service of the device, which is all AirPlay 1 devices advertise.
+    pub raop: Option<RaopMetadata>,
     pub media_remote: Option<MediaRemoteMetadata>,
 }
 
+/// Addresses of `host` among the records of a response.
+fn host_addresses(response: &Response, host: &str) -> Vec<IpAddr> {
+    response.records().filter(|x| x.name == host).filter_map(|x| match &x.kind {
+        RecordKind::A(x) => Some(IpAddr::V4(*x)),
+        RecordKind::AAAA(x) => Some(IpAddr::V6(*x)),
+        _ => None,
+    }).collect()
+}
+
+/// The `_raop._tcp` service a response describes, with the addresses of its host.
+fn raop_from_response(response: &Response) -> Option<(RaopMetadata, Vec<IpAddr>)> {
+    let instance = response.records().find_map(|x| match &x.kind {
+        RecordKind::PTR(x) if x.ends_with("._raop._tcp.local") => Some(x),
+        _ => None,
+    })?;
+
+    let txt = response.records().find_map(|x| match &x.kind {
+        RecordKind::TXT(txt) if x.name == *instance => Some(txt.as_slice()),
+        _ => None,
+    }).unwrap_or_default();
+
+    let (host, port) = response.records().find_map(|x| match &x.kind {
+        RecordKind::SRV { port, target, .. } if x.name == *instance => Some((target.as_str(), *port)),
+        _ => None,
+    })?;
+
+    let raop = RaopMetadata { port: Some(port), ..RaopMetadata::from_txt(instance, txt)? };
+    Some((raop, host_addresses(response, host)))
+}
+
+impl MediaRemoteMetadata {
+    /// Reads the `_mediaremotetv._tcp` service of a response, if it has one.
+    pub fn from_response(response: &Response) -> Option<Self> {
+        let instance = response.records().find_map(|x| match &x.kind {
+            RecordKind::PTR(x) if x.ends_with("._mediaremotetv._tcp.local") => Some(x),
+            _ => None,
+        })?;
+
+        let port = response.records().find_map(|x| match &x.kind {
+            RecordKind::SRV { port, .. } if x.name == *instance => Some(*port),
+            _ => None,
+        })?;
+
+        let entries: HashMap<&str, &str> = response.records().find_map(|x| match &x.kind {
+            RecordKind::TXT(txt) if x.name == *instance => Some(txt),
+            _ => None,
+        })?.iter().filter_map(|x| x.split_once('=')).collect();
+
+        let entry = |key: &str| entries.get(key).map(|x| x.to_string()).unwrap_or_default();
+
+        Some(MediaRemoteMetadata {
+            port,
+            model_name: entry("ModelName"),
+            allow_pairing: entries.get("AllowPairing").is_some_and(|x| x.eq_ignore_ascii_case("YES")),
+            // Devices without Bluetooth say `False` here.
+            bluetooth_address: entries.get("BluetoothAddress")
+                .and_then(|x| x.split(':').map(|x| u8::from_str_radix(x, 16).ok()).collect::<Option<Vec<_>>>())
+                .unwrap_or_default(),
+            mac_address: entry("macAddress"),
+            name: entries.get("Name").map(|x| x.to_string()).unwrap_or_else(|| instance.trim_end_matches("._mediaremotetv._tcp.local").to_string()),
+            uuid: entry("UniqueIdentifier"),
+            system_build_version: entry("SystemBuildVersion"),
+            local_airplay_receiver_pairing_identity: entry("LocalAirPlayReceiverPairingIdentity"),
+        })
+    }
+}
+
 impl Metadata {
+    /// Reads the `_airplay._tcp` service of a response and the `_raop._tcp` one of the same device. Devices
+    /// advertising only `_raop._tcp` come out with [`Metadata::raop`] and what it tells about them.
     pub fn from_response(response: Response) -> Option<Self> {
-        let airplay_record_name = match &response.answers.iter().find(|x| x.name == "_airplay._tcp.local" && match x.kind {
-            RecordKind::PTR(_) => true,
-            _ => false,
-        })?.kind {
+        let raop = raop_from_response(&response);
+
+        let Some(mut meta) = Self::from_airplay_response(&response) else {
+            let (raop, ip_addresses) = raop?;
+
+            return Some(Metadata {
+                name: raop.name.clone(),
+                ip_addresses,
+                port: raop.port.unwrap_or_default(),
+                device_id: Some(raop.device_id.clone()),
+                model: raop.model.clone(),
+                raop: Some(raop),
+                ..Default::default()
+            });
+        };
+
+        meta.raop = raop.map(|(x, _)| x).filter(|x| meta.is_same_device_id(&x.device_id));
+        meta.media_remote = MediaRemoteMetadata::from_response(&response).filter(|x| meta.is_paired_with(x));
+        Some(meta)
+    }
+
+    /// Whether a `_mediaremotetv._tcp` service belongs to this device, which it names by the pairing
+    /// identity the AirPlay receiver advertises as `psi`, or `pi` on older releases.
+    pub fn is_paired_with(&self, media_remote: &MediaRemoteMetadata) -> bool {
+        let identity = &media_remote.local_airplay_receiver_pairing_identity;
+
+        !identity.is_empty() && [&self.public_system_pairing_identity, &self.public_airplay_pairing_identity]
+            .into_iter()
+            .flatten()
+            .any(|x| x.eq_ignore_ascii_case(identity))
+    }
+
+    fn is_same_device_id(&self, device_id: &str) -> bool {
+        self.device_id.as_ref().is_some_and(|x| x.eq_ignore_ascii_case(device_id))
+    }
+
+    /// Whether `other` describes the same device, by device ID, or by name when either has none.
+    pub fn is_same_device(&self, other: &Metadata) -> bool {
+        match &other.device_id {
+            Some(x) if self.device_id.is_some() => self.is_same_device_id(x),
+            _ => self.name == other.name,
+        }
+    }
+
+    /// Folds in a newer description of the same device, which may cover only some of its services. What
+    /// `other` knows replaces what we knew, the rest is kept.
+    pub fn merge(&mut self, mut other: Metadata) {
+        other.raop = other.raop.or(self.raop.take());
+        other.media_remote = other.media_remote.or(self.media_remote.take());
+
+        if other.features.is_some() || self.features.is_none() {
+            *self = other;
+        } else {
+            self.raop = other.raop;
+            self.media_remote = other.media_remote;
+        }
+    }
+
+    fn from_airplay_response(response: &Response) -> Option<Self> {
+        let airplay_record_name = match &response.answers.iter().find(|x| x.name == "_airplay._tcp.local" && matches!(x.kind, RecordKind::PTR(_)))?.kind {
             RecordKind::PTR(x) => Some(x),
             _ => None,
         }?.clone();
     
-        let mut name = airplay_record_name.trim_end_matches("._airplay._tcp.local");
+        let name = airplay_record_name.trim_end_matches("._airplay._tcp.local");
     
-        let airplay_entries: HashMap<&str, &str> = match &response.additional.iter().find(|x| x.name == airplay_record_name && match x.kind {
-            RecordKind::TXT(_) => true,
-            _ => false,
-        })?.kind {
+        let airplay_entries: HashMap<&str, &str> = match &response.additional.iter().find(|x| x.name == airplay_record_name && matches!(x.kind, RecordKind::TXT(_)))?.kind {
             RecordKind::TXT(x) => Some(x),
             _ => None,
-        }?.into_iter().map(|x| x.split_once('=').unwrap()).collect();
+        }?.iter().map(|x| x.split_once('=').unwrap()).collect();
     
         let (hostname, airplay_port) = response.additional.iter().find_map(|x| {
             if x.name == airplay_record_name {
@@ -191,8 +390,8 @@ impl Metadata {
         let ip_addresses: Vec<IpAddr> = response.additional.iter().filter_map(|x| {
             if x.name == *hostname {
                 match &x.kind {
-                    RecordKind::A(x) => Some(IpAddr::V4(x.clone())),
-                    RecordKind::AAAA(x) => Some(IpAddr::V6(x.clone())),
+                    RecordKind::A(x) => Some(IpAddr::V4(*x)),
+                    RecordKind::AAAA(x) => Some(IpAddr::V6(*x)),
                     _ => None,
                 }
             } else {
@@ -206,7 +405,7 @@ impl Metadata {
             port: airplay_port,
     
             firmware_version: airplay_entries.get("fv").map(|x| x.to_string()),
-            access_control_level: airplay_entries.get("acl").map(|x| i64::from_str_radix(x, 10).unwrap()),
+            access_control_level: airplay_entries.get("acl").map(|x| x.parse::<i64>().unwrap()),
             bluetooth_address: airplay_entries.get("btaddr").map(|x| x.to_string()),
             device_id: airplay_entries.get("deviceid").map(|x| x.to_string()),
             features: airplay_entries.get("features").map(|x| {
@@ -218,7 +417,7 @@ impl Metadata {
                 ))
             }),
             required_sender_features: airplay_entries.get("rsf").map(|x| Features::from(u64::from_str_radix(x.trim_start_matches("0x"), 16).unwrap())),
-            flags: airplay_entries.get("flags").map(|x| u64::from_str_radix(x.trim_start_matches("0x"), 10).unwrap()),
+            flags: airplay_entries.get("flags").map(|x| u64::from_str_radix(x.trim_start_matches("0x"), 16).unwrap()),
             group_id: airplay_entries.get("gid").map(|x| x.to_string()),
             group_contains_discoverable_leader: airplay_entries.get("gcgl").map(|x| *x == "1"),
             group_public_name: airplay_entries.get("gpn").map(|x| x.to_string()),
@@ -245,42 +444,165 @@ impl Metadata {
             airplay_version: airplay_entries.get("srcvers").map(|x| x.to_string()),
             os_version: airplay_entries.get("osvers").map(|x| x.to_string()),
     
+            raop: None,
             media_remote: None,
         })
     }
 
+    /// The `_airplay._tcp` TXT record describing this device, the inverse of [`Metadata::from_response`].
+    pub fn txt_record(&self) -> Vec<String> {
+        let flag = |x: bool| if x { "1" } else { "0" }.to_string();
+        let hex = |x: &Vec<u8>| x.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+
+        [
+            ("acl", self.access_control_level.map(|x| x.to_string())),
+            ("btaddr", self.bluetooth_address.clone()),
+            ("deviceid", self.device_id.clone()),
+            ("features", self.features.as_ref().map(Features::to_txt)),
+            ("rsf", self.required_sender_features.as_ref().map(|x| format!("0x{:X}", u64::from(x)))),
+            ("flags", self.flags.map(|x| format!("0x{:X}", x))),
+            ("fv", self.firmware_version.clone()),
+            ("gid", self.group_id.clone()),
+            ("gcgl", self.group_contains_discoverable_leader.map(flag)),
+            ("gpn", self.group_public_name.clone()),
+            ("igl", self.is_group_leader.map(flag)),
+            ("hgid", self.home_group_id.clone()),
+            ("hmid", self.household_id.clone()),
+            ("pgid", self.parent_group_id.clone()),
+            ("pgcgl", self.parent_group_contains_discoverable_leader.map(flag)),
+            ("tsid", self.tight_sync_id.clone()),
+            ("hkid", self.homekit_home_id.clone()),
+            ("model", self.model.clone()),
+            ("manufacturer", self.manufacturer.clone()),
+            ("serialNumber", self.serial_number.clone()),
+            ("protovers", self.protocol_version.clone()),
+            ("pi", self.public_airplay_pairing_identity.clone()),
+            ("psi", self.public_system_pairing_identity.clone()),
+            ("pk", self.public_key.as_ref().map(hex)),
+            ("srcvers", self.airplay_version.clone()),
+            ("osvers", self.os_version.clone()),
+        ].into_iter().filter_map(|(key, value)| Some(format!("{}={}", key, value?))).collect()
+    }
+
     pub fn is_sane(&self) -> bool {
         self.model.is_some() &&
         self.features.is_some() &&
-        self.name.len() > 0 &&
+        !self.name.is_empty() &&
         self.device_id.is_some() &&
         !self.name.ends_with(".local")
     }
 }
 
 pub async fn look_for(name: String) -> Option<Metadata> {
-    let stream = mdns::discover::all("_airplay._tcp.local", Duration::from_secs(1))
-        .unwrap()
-        .listen();
+    find(|meta| meta.name == name).await
+}
 
-    pin_mut!(stream);
+/// Looks for the device with `device_id`, which unlike its name survives renames and address changes.
+pub async fn resolve(device_id: &str, timeout: Duration) -> Option<Metadata> {
+    tokio::time::timeout(timeout, find(|meta| {
+        meta.device_id.as_ref().is_some_and(|x| x.eq_ignore_ascii_case(device_id))
+    })).await.ok().flatten()
+}
+
+/// Devices found so far, one entry each however many services they advertise.
+#[derive(Default)]
+struct Devices {
+    devices: Vec<Metadata>,
+    /// `_mediaremotetv._tcp` services seen before the AirPlay device they belong to.
+    media_remotes: Vec<MediaRemoteMetadata>,
+}
 
-    println!("looking...");
+impl Devices {
+    /// Folds a response into what we know and returns the device it told us about, if any.
+    fn insert(&mut self, response: Response) -> Option<&Metadata> {
+        let media_remote = MediaRemoteMetadata::from_response(&response);
 
-    while let Some(Ok(response)) = stream.next().await {
-        let meta = Metadata::from_response(response.clone());
+        let index = match Metadata::from_response(response) {
+            Some(mut meta) => {
+                if let Some(i) = self.media_remotes.iter().position(|x| meta.is_paired_with(x)) {
+                    let media_remote = self.media_remotes.swap_remove(i);
+                    meta.media_remote = meta.media_remote.or(Some(media_remote));
+                }
 
-        if let Some(meta) = meta {
-            println!("{} ({})", meta.name, meta.device_id.as_ref().unwrap_or(&"?".to_string()));
+                match self.devices.iter().position(|x| x.is_same_device(&meta)) {
+                    Some(i) => {
+                        self.devices[i].merge(meta);
+                        i
+                    },
+                    None => {
+                        self.devices.push(meta);
+                        self.devices.len() - 1
+                    },
+                }
+            },
+            None => {
+                let media_remote = media_remote?;
 
-            if name == meta.name {
-                return Some(meta);
-            }
-        }
+                match self.devices.iter().position(|x| x.is_paired_with(&media_remote)) {
+                    Some(i) => {
+                        self.devices[i].media_remote = Some(media_remote);
+                        i
+                    },
+                    None => {
+                        let identity = &media_remote.local_airplay_receiver_pairing_identity;
+                        self.media_remotes.retain(|x| x.local_airplay_receiver_pairing_identity != *identity);
+                        self.media_remotes.push(media_remote);
+                        return None;
+                    },
+                }
+            },
+        };
+
+        Some(&self.devices[index])
+    }
+}
+
+/// Responses for `_airplay._tcp`, `_raop._tcp` and `_mediaremotetv._tcp` as they come.
+fn responses() -> impl futures_util::Stream<Item = Response> {
+    let services = ["_airplay._tcp.local", "_raop._tcp.local", "_mediaremotetv._tcp.local"].map(|x| Box::pin(mdns::discover::all(x, Duration::from_secs(1)).unwrap().listen()));
+    futures_util::stream::select_all(services).filter_map(|x| async move { x.ok() })
+}
+
+async fn find(predicate: impl Fn(&Metadata) -> bool) -> Option<Metadata> {
+    let stream = responses();
+    let mut devices = Devices::default();
+
+    pin_mut!(stream);
+
+    tracing::debug!("Browsing for AirPlay devices");
 
-        std::io::stdout().flush().unwrap()
+    while let Some(response) = stream.next().await {
+        let Some(meta) = devices.insert(response) else { continue };
 
+        tracing::debug!(
+            name = %meta.name,
+            device_id = meta.device_id.as_deref().unwrap_or("?"),
+            raop = meta.raop.is_some(),
+            media_remote = meta.media_remote.is_some(),
+            "Found AirPlay device",
+        );
+
+        if predicate(meta) {
+            return Some(meta.clone());
+        }
     }
 
     None
-}
\ No newline at end of file
+}
+
+/// Every AirPlay and AirPlay 1 device that answers within `duration`, with the services of each merged
+/// into one entry by device ID, and the MediaRemote service by pairing identity.
+pub async fn browse(duration: Duration) -> Vec<Metadata> {
+    let stream = responses();
+    let mut devices = Devices::default();
+
+    pin_mut!(stream);
+
+    let _ = tokio::time::timeout(duration, async {
+        while let Some(response) = stream.next().await {
+            devices.insert(response);
+        }
+    }).await;
+
+    devices.devices
+}
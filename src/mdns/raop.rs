@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use crate::audio::Codec;
+
+/// How a RAOP receiver wants the audio key protected, one of the `et` TXT values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaopEncryption {
+    None,
+    /// The AES key is sent RSA encrypted in ANNOUNCE, after an `Apple-Challenge`.
+    Rsa,
+    FairPlay,
+    MfiSap,
+    FairPlaySap25,
+    Other(u8),
+}
+
+impl From<u8> for RaopEncryption {
+    fn from(x: u8) -> Self {
+        match x {
+            0 => Self::None,
+            1 => Self::Rsa,
+            3 => Self::FairPlay,
+            4 => Self::MfiSap,
+            5 => Self::FairPlaySap25,
+            x => Self::Other(x),
+        }
+    }
+}
+
+/// A `<MAC>@<name>._raop._tcp` service, as AirPlay 1 receivers advertise themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaopMetadata {
+    /// The MAC address from the instance name, formatted like the `deviceid` of `_airplay._tcp`.
+    pub device_id: String,
+    pub name: String,
+    /// From the SRV record, `None` when only the TXT record is known.
+    pub port: Option<u16>,
+    /// `cn`, the codecs accepted in ANNOUNCE.
+    pub codecs: Vec<Codec>,
+    /// `et`
+    pub encryption_types: Vec<RaopEncryption>,
+    /// `ss`, bits per sample.
+    pub sample_size: Option<u8>,
+    /// `sr`
+    pub sample_rate: Option<u32>,
+    /// `ch`
+    pub channels: Option<u8>,
+    /// `tp`, e.g. `UDP` or `TCP,UDP`.
+    pub transports: Vec<String>,
+    /// `am`
+    pub model: Option<String>,
+    /// `vn`, the RAOP protocol version.
+    pub version: Option<String>,
+    /// `pw`
+    pub password_required: bool,
+}
+
+impl RaopMetadata {
+    /// Reads the service instance name, with or without the `._raop._tcp.local` suffix, and its TXT record.
+    pub fn from_txt(instance: &str, txt: &[String]) -> Option<RaopMetadata> {
+        let instance = instance.trim_end_matches("._raop._tcp.local");
+        let (mac, name) = instance.split_once('@')?;
+
+        if mac.len() != 12 || !mac.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let device_id = mac.as_bytes()
+            .chunks(2)
+            .map(|x| std::str::from_utf8(x).unwrap().to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let entries: HashMap<&str, &str> = txt.iter().filter_map(|x| x.split_once('=')).collect();
+        let list = |key: &str| entries.get(key).into_iter().flat_map(|x| x.split(',')).map(str::trim).filter(|x| !x.is_empty());
+
+        Some(RaopMetadata {
+            device_id,
+            name: name.to_string(),
+            port: None,
+            codecs: list("cn").filter_map(|x| match x {
+                "0" => Some(Codec::Pcm),
+                "1" => Some(Codec::Alac),
+                "2" => Some(Codec::AacLc),
+                "3" => Some(Codec::AacEld),
+                _ => None,
+            }).collect(),
+            encryption_types: list("et").filter_map(|x| x.parse::<u8>().ok()).map(RaopEncryption::from).collect(),
+            sample_size: entries.get("ss").and_then(|x| x.parse().ok()),
+            sample_rate: entries.get("sr").and_then(|x| x.parse().ok()),
+            channels: entries.get("ch").and_then(|x| x.parse().ok()),
+            transports: list("tp").map(str::to_string).collect(),
+            model: entries.get("am").map(|x| x.to_string()),
+            version: entries.get("vn").map(|x| x.to_string()),
+            password_required: entries.get("pw").is_some_and(|x| *x == "true" || *x == "1"),
+        })
+    }
+
+    /// The MAC address the instance name carries, which receivers mix into their `Apple-Response`.
+    pub fn mac_address(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+
+        for (x, part) in mac.iter_mut().zip(self.device_id.split(':')) {
+            *x = u8::from_str_radix(part, 16).unwrap_or(0);
+        }
+
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_an_airport_express() {
+        let txt: Vec<String> = ["txtvers=1", "ch=2", "cn=0,1", "et=0,1", "sv=false", "da=true", "sr=44100", "ss=16", "pw=false", "vn=3", "tp=TCP,UDP", "vs=130.14", "am=AirPort4,107"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+
+        let meta = RaopMetadata::from_txt("0016CB0A1B2C@Kitchen._raop._tcp.local", &txt).unwrap();
+        assert_eq!(meta.device_id, "00:16:CB:0A:1B:2C");
+        assert_eq!(meta.mac_address(), [0x00, 0x16, 0xcb, 0x0a, 0x1b, 0x2c]);
+        assert_eq!(meta.name, "Kitchen");
+        assert_eq!(meta.codecs, vec![Codec::Pcm, Codec::Alac]);
+        assert_eq!(meta.encryption_types, vec![RaopEncryption::None, RaopEncryption::Rsa]);
+        assert_eq!((meta.sample_size, meta.sample_rate, meta.channels), (Some(16), Some(44100), Some(2)));
+        assert_eq!(meta.transports, vec!["TCP", "UDP"]);
+        assert_eq!(meta.model.as_deref(), Some("AirPort4,107"));
+        assert_eq!(meta.version.as_deref(), Some("3"));
+        assert!(!meta.password_required);
+    }
+
+    #[test]
+    fn rejects_instances_without_a_mac() {
+        assert_eq!(RaopMetadata::from_txt("Kitchen", &[]), None);
+        assert_eq!(RaopMetadata::from_txt("nothex000000@Kitchen", &[]), None);
+    }
+}
@@ -0,0 +1,251 @@
+use std::net::SocketAddr;
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use prost::Message;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream, ToSocketAddrs}};
+
+use crate::pairing::SessionKeys;
+
+use super::{protocol::{MessageType, ProtocolMessage}, Error};
+
+/// Longest message we accept, well above the artwork devices push.
+const MAX_MESSAGE: usize = 4 << 20;
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+/// The varint at the start of `buf` and its length, `None` while more bytes are needed.
+pub(crate) fn read_varint(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut value = 0;
+
+    for (i, byte) in buf.iter().enumerate() {
+        value |= usize::from(byte & 0x7f) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+
+        if i == 3 {
+            return Err(Error::MessageTooLarge(value));
+        }
+    }
+
+    Ok(None)
+}
+
+/// ChaCha20-Poly1305 over whole messages, the nonce counting them from zero in its first eight bytes.
+struct MessageCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl MessageCipher {
+    fn new(key: &[u8; 32]) -> MessageCipher {
+        MessageCipher {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        self.cipher.encrypt(&nonce.into(), data).unwrap()
+    }
+
+    fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.nonce();
+        self.cipher.decrypt(&nonce.into(), data).map_err(|_| Error::Decryption)
+    }
+}
+
+pub(crate) struct Reader {
+    half: OwnedReadHalf,
+    buf: Vec<u8>,
+    cipher: Option<MessageCipher>,
+}
+
+impl Reader {
+    /// The next message, or `None` once the other side closes the connection between messages.
+    pub(crate) async fn recv(&mut self) -> Result<Option<ProtocolMessage>, Error> {
+        loop {
+            if let Some((len, header)) = read_varint(&self.buf)? {
+                if len > MAX_MESSAGE {
+                    return Err(Error::MessageTooLarge(len));
+                }
+
+                if self.buf.len() >= header + len {
+                    let frame: Vec<u8> = self.buf.drain(..header + len).skip(header).collect();
+
+                    let data = match &mut self.cipher {
+                        Some(x) => x.open(&frame)?,
+                        None => frame,
+                    };
+
+                    return Ok(Some(ProtocolMessage::decode(data.as_slice())?));
+                }
+            }
+
+            if self.half.read_buf(&mut self.buf).await? == 0 {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(Error::ConnectionClosed),
+                };
+            }
+        }
+    }
+}
+
+pub(crate) struct Writer {
+    half: OwnedWriteHalf,
+    cipher: Option<MessageCipher>,
+}
+
+impl Writer {
+    pub(crate) async fn send(&mut self, message: &ProtocolMessage) -> Result<(), Error> {
+        let mut data = message.encode_to_vec();
+
+        if let Some(x) = &mut self.cipher {
+            data = x.seal(&data);
+        }
+
+        let mut frame = Vec::with_capacity(data.len() + 4);
+        write_varint(&mut frame, data.len());
+        frame.extend(data);
+
+        self.half.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+/// A MediaRemote connection, either end of it: messages framed by their varint length and encrypted
+/// once pair-verify has set up keys.
+pub struct Connection {
+    pub peer: SocketAddr,
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Connection {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Connection, Error> {
+        Ok(Connection::new(TcpStream::connect(addr).await?)?)
+    }
+
+    pub fn new(stream: TcpStream) -> std::io::Result<Connection> {
+        let peer = stream.peer_addr()?;
+        let (read, write) = stream.into_split();
+
+        Ok(Connection {
+            peer,
+            reader: Reader { half: read, buf: Vec::new(), cipher: None },
+            writer: Writer { half: write, cipher: None },
+        })
+    }
+
+    /// Encrypts everything from here on, in both directions.
+    pub fn enable_encryption(&mut self, keys: &SessionKeys) {
+        self.reader.cipher = Some(MessageCipher::new(&keys.read));
+        self.writer.cipher = Some(MessageCipher::new(&keys.write));
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.writer.cipher.is_some()
+    }
+
+    pub async fn send(&mut self, message: &ProtocolMessage) -> Result<(), Error> {
+        self.writer.send(message).await
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<ProtocolMessage>, Error> {
+        self.reader.recv().await
+    }
+
+    /// Sends `message` and waits for the first message of type `answer`, passing over any others.
+    pub async fn exchange(&mut self, message: &ProtocolMessage, answer: MessageType) -> Result<ProtocolMessage, Error> {
+        self.send(message).await?;
+
+        loop {
+            let message = self.recv().await?.ok_or(Error::ConnectionClosed)?;
+
+            if message.r#type() == answer {
+                return Ok(message);
+            }
+
+            tracing::debug!(r#type = ?message.r#type(), "Ignoring MediaRemote message while waiting for {:?}", answer);
+        }
+    }
+
+    pub(crate) fn into_split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, 16383, 16384, MAX_MESSAGE] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(read_varint(&buf).unwrap(), Some((value, buf.len())));
+            assert_eq!(read_varint(&buf[..buf.len() - 1]).unwrap(), None);
+        }
+
+        assert!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[test]
+    fn messages_use_consecutive_nonces() {
+        let key = [7; 32];
+        let (mut sealer, mut opener) = (MessageCipher::new(&key), MessageCipher::new(&key));
+
+        let first = sealer.seal(b"first");
+        let second = sealer.seal(b"first");
+        assert_ne!(first, second);
+
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+        assert_eq!(opener.open(&second).unwrap(), b"first");
+        assert!(matches!(opener.open(&second), Err(Error::Decryption)));
+    }
+
+    #[tokio::test]
+    async fn reads_messages_split_across_packets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let message = ProtocolMessage { identifier: Some("x".repeat(300)), ..ProtocolMessage::new(MessageType::SendCommand) };
+        let mut frame = Vec::new();
+        write_varint(&mut frame, message.encoded_len());
+        frame.extend(message.encode_to_vec());
+
+        let sender = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+
+            for chunk in frame.chunks(100) {
+                stream.write_all(chunk).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut connection = Connection::new(listener.accept().await.unwrap().0).unwrap();
+        assert_eq!(connection.recv().await.unwrap(), Some(message));
+
+        sender.await.unwrap();
+        assert_eq!(connection.recv().await.unwrap(), None);
+    }
+}
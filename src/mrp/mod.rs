@@ -0,0 +1,377 @@
+//! The MediaRemote Protocol (MRP), which Apple TVs and HomePods are remote controlled with over the
+//! `_mediaremotetv._tcp` service.
+//!
+//! Both sides start by exchanging a `DeviceInfoMessage`. A controller pairs once with pair-setup, typing
+//! the PIN the device shows, and on every later connection runs pair-verify, both carried as TLV8 in
+//! `CryptoPairingMessage`s. From then on each message is encrypted with keys derived from the pair-verify
+//! secret, and the device pushes `SetStateMessage`s as what it plays changes.
+
+use std::{collections::HashMap, fmt, io, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use tokio::{sync::{oneshot, watch}, task::JoinHandle};
+
+use crate::pairing::{self, Identity, Peer, SessionKeys, SetupClient, Tlv8, VerifyClient};
+
+mod connection;
+pub mod protocol;
+
+pub use connection::Connection;
+use connection::{Reader, Writer};
+use protocol::{
+    ClientUpdatesConfigMessage, Command, CommandOptions, DeviceInfoMessage, MessageType, PlaybackState, ProtocolMessage, SendCommandMessage,
+    SetStateMessage, SetVolumeMessage,
+};
+
+/// How long a device gets to answer a command.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Decode(prost::DecodeError),
+    Pairing(pairing::Error),
+    /// A message didn't decrypt, the other side derived a different key.
+    Decryption,
+    MessageTooLarge(usize),
+    Timeout,
+    ConnectionClosed,
+    /// The device refused a command, with its `SendError` and `HandlerReturnStatus` codes.
+    Command { error: i32, status: i32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(x) => write!(f, "MRP I/O error: {}", x),
+            Self::Decode(x) => write!(f, "malformed MRP message: {}", x),
+            Self::Pairing(x) => write!(f, "Pairing failed: {}", x),
+            Self::Decryption => f.write_str("MRP message failed to decrypt"),
+            Self::MessageTooLarge(x) => write!(f, "MRP message of {} bytes", x),
+            Self::Timeout => f.write_str("MRP request timed out"),
+            Self::ConnectionClosed => f.write_str("MRP connection closed"),
+            Self::Command { error, status } => write!(f, "command failed with error {} and status {}", error, status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(x: io::Error) -> Self {
+        Error::Io(x)
+    }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(x: prost::DecodeError) -> Self {
+        Error::Decode(x)
+    }
+}
+
+impl From<pairing::Error> for Error {
+    fn from(x: pairing::Error) -> Self {
+        Error::Pairing(x)
+    }
+}
+
+/// A random UUID, which is what devices expect as message identifiers.
+fn identifier() -> String {
+    let hex: String = rand::random::<[u8; 16]>().iter().map(|x| format!("{:02X}", x)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// How we introduce ourselves, as a remote app named `name`.
+fn device_info(name: &str, identity: &Identity) -> ProtocolMessage {
+    ProtocolMessage {
+        identifier: Some(identifier()),
+        device_info_message: Some(DeviceInfoMessage {
+            unique_identifier: Some(identity.id.clone()),
+            name: Some(name.to_string()),
+            localized_model_name: Some("iPhone".to_string()),
+            system_build_version: Some("18A393".to_string()),
+            application_bundle_identifier: Some("com.apple.TVRemote".to_string()),
+            application_bundle_version: Some("344.28".to_string()),
+            protocol_version: Some(1),
+            last_supported_message_type: Some(108),
+            supports_system_pairing: Some(true),
+            allows_pairing: Some(true),
+        }),
+        ..ProtocolMessage::new(MessageType::DeviceInfo)
+    }
+}
+
+async fn pairing_step(connection: &mut Connection, message: ProtocolMessage) -> Result<Tlv8, Error> {
+    let answer = connection.exchange(&message, MessageType::CryptoPairing).await?;
+    Ok(Tlv8::parse(answer.pairing_data().unwrap_or_default())?)
+}
+
+/// Pair-setup in progress, with the device showing a PIN.
+pub struct PairSetup {
+    connection: Connection,
+    identity: Identity,
+    m2: Tlv8,
+}
+
+/// Starts pair-setup with the device at `addr`, introducing ourselves as `name`, and returns once the
+/// device shows the PIN to finish with.
+pub async fn pair<A: tokio::net::ToSocketAddrs>(addr: A, name: &str, identity: Identity) -> Result<PairSetup, Error> {
+    let mut connection = Connection::connect(addr).await?;
+    connection.exchange(&device_info(name, &identity), MessageType::DeviceInfo).await?;
+
+    // M1 is the same whatever the PIN, which only comes into play with M2.
+    let mut m1 = ProtocolMessage::crypto_pairing(SetupClient::new(identity.clone(), "", false).start().to_bytes());
+
+    if let Some(x) = &mut m1.crypto_pairing_message {
+        x.is_retrying = Some(true);
+        x.is_using_system_pairing = Some(true);
+        x.state = Some(2);
+    }
+
+    let m2 = pairing_step(&mut connection, m1).await?;
+    Ok(PairSetup { connection, identity, m2 })
+}
+
+impl PairSetup {
+    /// Finishes with the PIN the device shows and returns its identity for [`Client::connect`].
+    pub async fn finish(mut self, pin: &str) -> Result<Peer, Error> {
+        let mut setup = SetupClient::new(self.identity, pin, false);
+        setup.start();
+
+        let mut message = setup.handle(&self.m2)?;
+
+        while let Some(x) = message {
+            let answer = pairing_step(&mut self.connection, ProtocolMessage::crypto_pairing(x.to_bytes())).await?;
+            message = setup.handle(&answer)?;
+        }
+
+        Ok(setup.peer().cloned().ok_or(pairing::Error::Authentication)?)
+    }
+}
+
+/// What the device is playing, as it last told us.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NowPlaying {
+    pub state: PlaybackState,
+    /// The app playing.
+    pub app: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+    /// As of when the update arrived.
+    pub elapsed: Option<Duration>,
+}
+
+impl NowPlaying {
+    fn update(&mut self, message: SetStateMessage) {
+        if message.playback_state.is_some() {
+            self.state = message.playback_state();
+        }
+
+        if let Some(x) = message.display_name {
+            self.app = Some(x);
+        }
+
+        if let Some(info) = message.now_playing_info {
+            let seconds = |x: f64| Duration::try_from_secs_f64(x).ok();
+
+            self.title = info.title;
+            self.artist = info.artist;
+            self.album = info.album;
+            self.duration = info.duration.and_then(seconds);
+            self.elapsed = info.elapsed_time.and_then(seconds);
+        }
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<ProtocolMessage>>>>;
+
+/// A paired and verified connection to an Apple TV or HomePod.
+///
+/// Clones share the connection. Commands are matched to their results by identifier, so any number may
+/// be in flight at once.
+#[derive(Clone)]
+pub struct Client {
+    pub peer: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    writer: tokio::sync::Mutex<Writer>,
+    pending: Pending,
+    now_playing: watch::Receiver<NowPlaying>,
+    closed: watch::Receiver<bool>,
+    reader_handle: JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.reader_handle.abort();
+    }
+}
+
+async fn read(mut reader: Reader, pending: Pending, now_playing: watch::Sender<NowPlaying>, closed: watch::Sender<bool>) {
+    loop {
+        let message = match reader.recv().await {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(err) => {
+                tracing::debug!("MediaRemote connection failed: {}", err);
+                break;
+            },
+        };
+
+        let waiter = message.identifier.as_ref().and_then(|x| pending.lock().unwrap().remove(x));
+
+        if let Some(waiter) = waiter {
+            let _ = waiter.send(message);
+            continue;
+        }
+
+        match message.r#type() {
+            MessageType::SetState => {
+                if let Some(state) = message.set_state_message {
+                    now_playing.send_modify(|x| x.update(state));
+                }
+            },
+            x => tracing::trace!(r#type = ?x, "Unhandled MediaRemote message"),
+        }
+    }
+
+    closed.send_replace(true);
+    pending.lock().unwrap().clear();
+}
+
+impl Client {
+    /// Connects to a device paired with [`pair`], runs pair-verify and asks for now playing updates.
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(addr: A, name: &str, identity: Identity, peer: Peer) -> Result<Client, Error> {
+        let mut connection = Connection::connect(addr).await?;
+        connection.exchange(&device_info(name, &identity), MessageType::DeviceInfo).await?;
+
+        let mut verify = VerifyClient::new(identity, Some(peer));
+        let mut message = Some(verify.start());
+
+        while let Some(x) = message {
+            let answer = pairing_step(&mut connection, ProtocolMessage::crypto_pairing(x.to_bytes())).await?;
+            message = verify.handle(&answer)?;
+        }
+
+        let secret = verify.shared_secret().ok_or(pairing::Error::Authentication)?;
+        connection.enable_encryption(&SessionKeys::media_remote(&secret, true));
+
+        let client = Client::new(connection);
+
+        client.send(ProtocolMessage {
+            client_updates_config_message: Some(ClientUpdatesConfigMessage {
+                now_playing_updates: Some(true),
+                volume_updates: Some(true),
+                ..Default::default()
+            }),
+            ..ProtocolMessage::new(MessageType::ClientUpdatesConfig)
+        }).await?;
+
+        Ok(client)
+    }
+
+    /// Takes over a connection that is ready for commands.
+    pub fn new(connection: Connection) -> Client {
+        let peer = connection.peer;
+        let (reader, writer) = connection.into_split();
+        let pending: Pending = Default::default();
+        let (now_playing_tx, now_playing) = watch::channel(NowPlaying::default());
+        let (closed_tx, closed) = watch::channel(false);
+
+        Client {
+            peer,
+            shared: Arc::new(Shared {
+                writer: tokio::sync::Mutex::new(writer),
+                pending: pending.clone(),
+                now_playing,
+                closed,
+                reader_handle: tokio::spawn(read(reader, pending, now_playing_tx, closed_tx)),
+            }),
+        }
+    }
+
+    /// Follows what the device plays.
+    pub fn now_playing(&self) -> watch::Receiver<NowPlaying> {
+        self.shared.now_playing.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.shared.closed.borrow()
+    }
+
+    /// Resolves once the device closes the connection.
+    pub async fn closed(&self) {
+        let _ = self.shared.closed.clone().wait_for(|x| *x).await;
+    }
+
+    /// Sends a message that expects no answer.
+    pub async fn send(&self, message: ProtocolMessage) -> Result<(), Error> {
+        if self.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+
+        self.shared.writer.lock().await.send(&message).await
+    }
+
+    /// Sends a message and waits for the answer with the same identifier.
+    pub async fn request(&self, mut message: ProtocolMessage) -> Result<ProtocolMessage, Error> {
+        let id = message.identifier.get_or_insert_with(identifier).clone();
+        let (tx, rx) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(id.clone(), tx);
+
+        let result = match self.send(message).await {
+            Ok(()) => tokio::time::timeout(TIMEOUT, rx).await.map_err(|_| Error::Timeout).and_then(|x| x.map_err(|_| Error::ConnectionClosed)),
+            Err(err) => Err(err),
+        };
+
+        self.shared.pending.lock().unwrap().remove(&id);
+        result
+    }
+
+    pub async fn send_command(&self, command: Command, options: Option<CommandOptions>) -> Result<(), Error> {
+        let answer = self.request(ProtocolMessage {
+            send_command_message: Some(SendCommandMessage { command: Some(command as i32), options }),
+            ..ProtocolMessage::new(MessageType::SendCommand)
+        }).await?;
+
+        let result = answer.send_command_result_message.unwrap_or_default();
+
+        match (result.error_code.unwrap_or(0), result.handler_return_status.unwrap_or(0)) {
+            (0, 0) => Ok(()),
+            (error, status) => Err(Error::Command { error, status }),
+        }
+    }
+
+    pub async fn play(&self) -> Result<(), Error> {
+        self.send_command(Command::Play, None).await
+    }
+
+    pub async fn pause(&self) -> Result<(), Error> {
+        self.send_command(Command::Pause, None).await
+    }
+
+    pub async fn next(&self) -> Result<(), Error> {
+        self.send_command(Command::NextTrack, None).await
+    }
+
+    pub async fn previous(&self) -> Result<(), Error> {
+        self.send_command(Command::PreviousTrack, None).await
+    }
+
+    /// Jumps to `position` into the current item.
+    pub async fn seek(&self, position: Duration) -> Result<(), Error> {
+        let options = CommandOptions { playback_position: Some(position.as_secs_f64()) };
+        self.send_command(Command::SeekToPlaybackPosition, Some(options)).await
+    }
+
+    /// Sets the volume from 0 to 1. Devices don't answer, they push a volume update instead.
+    pub async fn set_volume(&self, volume: f32) -> Result<(), Error> {
+        self.send(ProtocolMessage {
+            set_volume_message: Some(SetVolumeMessage { volume: Some(volume.clamp(0.0, 1.0)), output_device_uid: None }),
+            ..ProtocolMessage::new(MessageType::SetVolume)
+        }).await
+    }
+}
@@ -0,0 +1,219 @@
+//! The subset of the MediaRemote protobuf schema we speak.
+//!
+//! Every message is a `ProtocolMessage` whose `type` says which of its extensions is set. The extensions
+//! are plain optional fields here, numbered as on the wire.
+
+/// `ProtocolMessage.type`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MessageType {
+    Unknown = 0,
+    SendCommand = 1,
+    SendCommandResult = 2,
+    GetState = 3,
+    SetState = 4,
+    DeviceInfo = 15,
+    ClientUpdatesConfig = 16,
+    CryptoPairing = 34,
+    SetVolume = 50,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtocolMessage {
+    #[prost(enumeration = "MessageType", optional, tag = "1")]
+    pub r#type: Option<i32>,
+    /// Set on requests that expect an answer, which carries the same identifier.
+    #[prost(string, optional, tag = "2")]
+    pub identifier: Option<String>,
+    #[prost(int32, optional, tag = "4")]
+    pub error_code: Option<i32>,
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+
+    #[prost(message, optional, tag = "6")]
+    pub send_command_message: Option<SendCommandMessage>,
+    #[prost(message, optional, tag = "7")]
+    pub send_command_result_message: Option<SendCommandResultMessage>,
+    #[prost(message, optional, tag = "9")]
+    pub set_state_message: Option<SetStateMessage>,
+    #[prost(message, optional, tag = "20")]
+    pub device_info_message: Option<DeviceInfoMessage>,
+    #[prost(message, optional, tag = "21")]
+    pub client_updates_config_message: Option<ClientUpdatesConfigMessage>,
+    #[prost(message, optional, tag = "39")]
+    pub crypto_pairing_message: Option<CryptoPairingMessage>,
+    #[prost(message, optional, tag = "55")]
+    pub set_volume_message: Option<SetVolumeMessage>,
+}
+
+impl ProtocolMessage {
+    pub fn new(message_type: MessageType) -> ProtocolMessage {
+        ProtocolMessage {
+            r#type: Some(message_type as i32),
+            ..Default::default()
+        }
+    }
+
+    /// A `CryptoPairingMessage` carrying one TLV8 encoded pairing message.
+    pub fn crypto_pairing(pairing_data: Vec<u8>) -> ProtocolMessage {
+        ProtocolMessage {
+            crypto_pairing_message: Some(CryptoPairingMessage {
+                pairing_data: Some(pairing_data),
+                status: Some(0),
+                ..Default::default()
+            }),
+            ..ProtocolMessage::new(MessageType::CryptoPairing)
+        }
+    }
+
+    pub fn pairing_data(&self) -> Option<&[u8]> {
+        self.crypto_pairing_message.as_ref()?.pairing_data.as_deref()
+    }
+}
+
+/// `CommandInfo.Command`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Command {
+    Unknown = 0,
+    Play = 1,
+    Pause = 2,
+    TogglePlayPause = 3,
+    Stop = 4,
+    NextTrack = 5,
+    PreviousTrack = 6,
+    SeekToPlaybackPosition = 45,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CommandOptions {
+    /// Seconds into the item, for [`Command::SeekToPlaybackPosition`].
+    #[prost(double, optional, tag = "9")]
+    pub playback_position: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SendCommandMessage {
+    #[prost(enumeration = "Command", optional, tag = "1")]
+    pub command: Option<i32>,
+    #[prost(message, optional, tag = "2")]
+    pub options: Option<CommandOptions>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SendCommandResultMessage {
+    /// `SendError`, zero when the command was delivered.
+    #[prost(int32, optional, tag = "1")]
+    pub error_code: Option<i32>,
+    /// `HandlerReturnStatus`, zero when the app carried it out.
+    #[prost(int32, optional, tag = "2")]
+    pub handler_return_status: Option<i32>,
+}
+
+/// `PlaybackState`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum PlaybackState {
+    Unknown = 0,
+    Playing = 1,
+    Paused = 2,
+    Stopped = 3,
+    Interrupted = 4,
+    Seeking = 5,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NowPlayingInfo {
+    #[prost(string, optional, tag = "1")]
+    pub album: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub artist: Option<String>,
+    /// Seconds.
+    #[prost(double, optional, tag = "3")]
+    pub duration: Option<f64>,
+    /// Seconds, as of `timestamp`.
+    #[prost(double, optional, tag = "4")]
+    pub elapsed_time: Option<f64>,
+    #[prost(float, optional, tag = "5")]
+    pub playback_rate: Option<f32>,
+    #[prost(double, optional, tag = "8")]
+    pub timestamp: Option<f64>,
+    #[prost(string, optional, tag = "9")]
+    pub title: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetStateMessage {
+    #[prost(message, optional, tag = "1")]
+    pub now_playing_info: Option<NowPlayingInfo>,
+    /// The app playing, such as `com.apple.TVMusic`.
+    #[prost(string, optional, tag = "4")]
+    pub display_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub display_name: Option<String>,
+    #[prost(enumeration = "PlaybackState", optional, tag = "6")]
+    pub playback_state: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SetVolumeMessage {
+    /// From 0 to 1.
+    #[prost(float, optional, tag = "1")]
+    pub volume: Option<f32>,
+    #[prost(string, optional, tag = "2")]
+    pub output_device_uid: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeviceInfoMessage {
+    #[prost(string, optional, tag = "1")]
+    pub unique_identifier: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub localized_model_name: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub system_build_version: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub application_bundle_identifier: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub application_bundle_version: Option<String>,
+    #[prost(int32, optional, tag = "7")]
+    pub protocol_version: Option<i32>,
+    #[prost(uint32, optional, tag = "8")]
+    pub last_supported_message_type: Option<u32>,
+    #[prost(bool, optional, tag = "9")]
+    pub supports_system_pairing: Option<bool>,
+    #[prost(bool, optional, tag = "10")]
+    pub allows_pairing: Option<bool>,
+}
+
+/// Which updates the device should push to us.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ClientUpdatesConfigMessage {
+    #[prost(bool, optional, tag = "1")]
+    pub artwork_updates: Option<bool>,
+    #[prost(bool, optional, tag = "2")]
+    pub now_playing_updates: Option<bool>,
+    #[prost(bool, optional, tag = "3")]
+    pub volume_updates: Option<bool>,
+    #[prost(bool, optional, tag = "4")]
+    pub keyboard_updates: Option<bool>,
+    #[prost(bool, optional, tag = "5")]
+    pub output_device_updates: Option<bool>,
+}
+
+/// One TLV8 message of pair-setup or pair-verify.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CryptoPairingMessage {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub pairing_data: Option<Vec<u8>>,
+    #[prost(int32, optional, tag = "2")]
+    pub status: Option<i32>,
+    #[prost(bool, optional, tag = "3")]
+    pub is_retrying: Option<bool>,
+    #[prost(bool, optional, tag = "4")]
+    pub is_using_system_pairing: Option<bool>,
+    #[prost(int32, optional, tag = "5")]
+    pub state: Option<i32>,
+}
@@ -0,0 +1,126 @@
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, KeyInit};
+
+use super::{derive_key, Error};
+
+/// Longest plaintext in one frame.
+const MAX_FRAME: usize = 1024;
+const TAG_LEN: usize = 16;
+
+/// Keys for the encrypted control connection, from the point of view of whoever holds them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub write: [u8; 32],
+    pub read: [u8; 32],
+}
+
+impl SessionKeys {
+    /// Derives the control connection keys from a pair-verify or transient pair-setup secret. The sender
+    /// writes with the `Control-Write` key, the receiver with the `Control-Read` one.
+    pub fn control(secret: &[u8], sender: bool) -> SessionKeys {
+        let write = derive_key(secret, "Control-Salt", "Control-Write-Encryption-Key");
+        let read = derive_key(secret, "Control-Salt", "Control-Read-Encryption-Key");
+
+        if sender {
+            SessionKeys { write, read }
+        } else {
+            SessionKeys { write: read, read: write }
+        }
+    }
+
+    /// Derives the keys of a MediaRemote connection from a pair-verify secret. The client writes with the
+    /// `MediaRemote-Write` key.
+    pub fn media_remote(secret: &[u8], client: bool) -> SessionKeys {
+        let write = derive_key(secret, "MediaRemote-Salt", "MediaRemote-Write-Encryption-Key");
+        let read = derive_key(secret, "MediaRemote-Salt", "MediaRemote-Read-Encryption-Key");
+
+        if client {
+            SessionKeys { write, read }
+        } else {
+            SessionKeys { write: read, read: write }
+        }
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Splits a byte stream into frames of a little endian `u16` length, used as associated data, followed
+/// by up to 1024 bytes of ciphertext and its tag. Every frame uses the next nonce.
+pub struct Encrypter {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Encrypter {
+    pub fn new(key: &[u8; 32]) -> Encrypter {
+        Encrypter {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+        }
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + data.len().div_ceil(MAX_FRAME) * (2 + TAG_LEN));
+
+        for chunk in data.chunks(MAX_FRAME) {
+            let len = (chunk.len() as u16).to_le_bytes();
+            let sealed = self.cipher.encrypt(&nonce(self.counter).into(), Payload { msg: chunk, aad: &len }).unwrap();
+            self.counter += 1;
+
+            out.extend(len);
+            out.extend(sealed);
+        }
+
+        out
+    }
+}
+
+/// The inverse of [`Encrypter`], accepting the stream in arbitrary pieces.
+pub struct Decrypter {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl Decrypter {
+    pub fn new(key: &[u8; 32]) -> Decrypter {
+        Decrypter {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Decrypts every complete frame received so far, keeping partial ones for later.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut pos = 0;
+
+        while let Some(len) = self.buf.get(pos..pos + 2) {
+            let aad = [len[0], len[1]];
+            let len = usize::from(u16::from_le_bytes(aad));
+
+            if len > MAX_FRAME {
+                return Err(Error::FrameTooLarge(len));
+            }
+
+            let Some(sealed) = self.buf.get(pos + 2..pos + 2 + len + TAG_LEN) else {
+                break;
+            };
+
+            let plain = self.cipher.decrypt(&nonce(self.counter).into(), Payload { msg: sealed, aad: &aad })
+                .map_err(|_| Error::Decryption)?;
+            self.counter += 1;
+
+            out.extend(plain);
+            pos += 2 + len + TAG_LEN;
+        }
+
+        self.buf.drain(..pos);
+        Ok(out)
+    }
+}
@@ -0,0 +1,282 @@
+//! HomeKit style pairing as used by AirPlay 2: pair-setup (SRP-6a with a PIN, or transient), pair-verify
+//! (X25519 with Ed25519 signatures) and the ChaCha20-Poly1305 framing the control connection switches to
+//! afterwards. Both the sender and the receiver side are implemented.
+
+use std::fmt;
+
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, KeyInit};
+use ed25519_dalek::SigningKey;
+use hkdf::Hkdf;
+use sha2::Sha512;
+
+mod cipher;
+mod setup;
+pub mod srp;
+pub mod tlv;
+mod verify;
+
+pub use cipher::{Decrypter, Encrypter, SessionKeys};
+pub use setup::{SetupClient, SetupServer};
+pub use tlv::Tlv8;
+pub use verify::{VerifyClient, VerifyServer};
+
+/// The PIN used for transient pairing, where no PIN is shown to the user.
+pub const TRANSIENT_PIN: &str = "3939";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    InvalidTlv,
+    MissingItem(u8),
+    /// A message arrived out of order.
+    UnexpectedState(u8),
+    InvalidPublicKey,
+    InvalidSignature,
+    /// Decryption failed, the other side derived a different key.
+    Decryption,
+    /// Wrong PIN, or an unknown or mismatching identity during pair-verify.
+    Authentication,
+    /// The other side answered with a TLV error code, see the `ERROR_` constants in [`tlv`].
+    Peer(u8),
+    /// An encrypted frame was longer than the protocol allows.
+    FrameTooLarge(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTlv => f.write_str("malformed TLV8"),
+            Self::MissingItem(x) => write!(f, "TLV8 item {:#04x} missing", x),
+            Self::UnexpectedState(x) => write!(f, "unexpected pairing state {}", x),
+            Self::InvalidPublicKey => f.write_str("invalid public key"),
+            Self::InvalidSignature => f.write_str("invalid signature"),
+            Self::Decryption => f.write_str("decryption failed"),
+            Self::Authentication => f.write_str("authentication failed"),
+            Self::Peer(tlv::ERROR_AUTHENTICATION) => f.write_str("authentication failed"),
+            Self::Peer(tlv::ERROR_BACKOFF) => f.write_str("too many attempts, try again later"),
+            Self::Peer(tlv::ERROR_BUSY) => f.write_str("device is busy pairing"),
+            Self::Peer(x) => write!(f, "device reported pairing error {}", x),
+            Self::FrameTooLarge(x) => write!(f, "encrypted frame of {} bytes", x),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The TLV error code to answer a failed step with.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Peer(x) => *x,
+            _ => tlv::ERROR_AUTHENTICATION,
+        }
+    }
+}
+
+/// A long-term Ed25519 key pair and the identifier it is known by.
+#[derive(Clone)]
+pub struct Identity {
+    pub id: String,
+    key: SigningKey,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity").field("id", &self.id).field("public_key", &self.public_key()).finish()
+    }
+}
+
+impl Identity {
+    pub fn generate(id: impl ToString) -> Identity {
+        Identity {
+            id: id.to_string(),
+            key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    pub fn from_secret(id: impl ToString, secret: [u8; 32]) -> Identity {
+        Identity {
+            id: id.to_string(),
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// The secret to persist and pass to [`Identity::from_secret`] later.
+    pub fn secret(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    fn sign(&self, message: &[u8]) -> [u8; 64] {
+        use ed25519_dalek::Signer;
+        self.key.sign(message).to_bytes()
+    }
+}
+
+/// The other side of a pairing, as learned during pair-setup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub id: String,
+    pub public_key: [u8; 32],
+}
+
+impl Peer {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        verify_signature(&self.public_key, message, signature)
+    }
+}
+
+fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let key = ed25519_dalek::VerifyingKey::try_from(public_key).map_err(|_| Error::InvalidPublicKey)?;
+    let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+    key.verify_strict(message, &signature).map_err(|_| Error::InvalidSignature)
+}
+
+pub(crate) fn derive_key(secret: &[u8], salt: &str, info: &str) -> [u8; 32] {
+    let mut out = [0; 32];
+    Hkdf::<Sha512>::new(Some(salt.as_bytes()), secret).expand(info.as_bytes(), &mut out).unwrap();
+    out
+}
+
+/// Nonces of the pairing messages are their 8 byte labels, such as `PS-Msg05`, left-padded to 12 bytes.
+fn nonce(label: &[u8; 8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(label);
+    nonce
+}
+
+fn seal(key: &[u8; 32], label: &[u8; 8], data: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into()).encrypt(&nonce(label).into(), Payload { msg: data, aad: &[] }).unwrap()
+}
+
+fn open(key: &[u8; 32], label: &[u8; 8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    ChaCha20Poly1305::new(key.into()).decrypt(&nonce(label).into(), Payload { msg: data, aad: &[] }).map_err(|_| Error::Decryption)
+}
+
+/// Fails with [`Error::Peer`] if the message carries an error code, and with [`Error::UnexpectedState`]
+/// unless it is in `state`.
+fn expect_state(message: &Tlv8, state: u8) -> Result<(), Error> {
+    if let Some(code) = message.byte(tlv::ERROR) {
+        return Err(Error::Peer(code));
+    }
+
+    match message.byte(tlv::STATE) {
+        Some(x) if x == state => Ok(()),
+        x => Err(Error::UnexpectedState(x.unwrap_or(0))),
+    }
+}
+
+fn error_message(state: u8, error: &Error) -> Tlv8 {
+    Tlv8::new().with(tlv::STATE, [state]).with(tlv::ERROR, [error.code()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tlv_round_trips_fragmented_values() {
+        let long: Vec<u8> = (0..600).map(|x| x as u8).collect();
+        let message = Tlv8::new().with(tlv::STATE, [1]).with(tlv::PUBLIC_KEY, long.clone()).with(tlv::SIGNATURE, []);
+        let bytes = message.to_bytes();
+
+        assert_eq!(bytes.len(), 3 + (2 + 255) * 2 + 2 + 90 + 2);
+        assert_eq!(Tlv8::parse(&bytes).unwrap(), message);
+        assert_eq!(Tlv8::parse(&bytes[..bytes.len() - 1]), Err(Error::InvalidTlv));
+    }
+
+    fn setup(client_pin: &str, server_pin: &str, transient: bool) -> Result<(SetupClient, SetupServer), Error> {
+        let mut client = SetupClient::new(Identity::generate("sender"), client_pin, transient);
+        let mut server = SetupServer::new(Identity::generate("receiver"), server_pin);
+
+        let m2 = server.handle(&client.start());
+        let m4 = server.handle(&client.handle(&m2)?.unwrap());
+
+        if let Some(m5) = client.handle(&m4)? {
+            let m6 = server.handle(&m5);
+            assert!(client.handle(&m6)?.is_none());
+        }
+
+        Ok((client, server))
+    }
+
+    #[test]
+    fn pair_setup_exchanges_identities() {
+        let (client, server) = setup("1234", "1234", false).unwrap();
+
+        assert_eq!(client.peer().unwrap().id, "receiver");
+        assert_eq!(server.peer().unwrap().id, "sender");
+        assert_eq!(client.peer().unwrap().public_key, server.identity().public_key());
+    }
+
+    #[test]
+    fn transient_pair_setup_derives_matching_keys() {
+        let (client, server) = setup(TRANSIENT_PIN, TRANSIENT_PIN, true).unwrap();
+        let (sender, receiver) = (client.session_keys().unwrap(), server.session_keys().unwrap());
+
+        assert!(client.peer().is_none());
+        assert_eq!(sender.write, receiver.read);
+        assert_eq!(sender.read, receiver.write);
+    }
+
+    #[test]
+    fn pair_setup_rejects_wrong_pin() {
+        assert_eq!(setup("1111", "1234", false).err(), Some(Error::Peer(tlv::ERROR_AUTHENTICATION)));
+    }
+
+    fn verify(client: &Identity, server: &Identity, known: Option<Peer>) -> Result<(SessionKeys, SessionKeys), Error> {
+        let server_peer = Peer { id: server.id.clone(), public_key: server.public_key() };
+        let mut verifier = VerifyClient::new(client.clone(), Some(server_peer));
+        let mut responder = VerifyServer::new(server.clone(), move |id| known.clone().filter(|x| x.id == id));
+
+        let m2 = responder.handle(&verifier.start());
+        let m4 = responder.handle(&verifier.handle(&m2)?.unwrap());
+        assert!(verifier.handle(&m4)?.is_none());
+
+        Ok((verifier.session_keys().unwrap(), responder.session_keys().unwrap()))
+    }
+
+    #[test]
+    fn pair_verify_derives_matching_keys() {
+        let (sender, receiver) = (Identity::generate("sender"), Identity::generate("receiver"));
+        let known = Peer { id: sender.id.clone(), public_key: sender.public_key() };
+        let (sender_keys, receiver_keys) = verify(&sender, &receiver, Some(known)).unwrap();
+
+        assert_eq!(sender_keys.write, receiver_keys.read);
+        assert_eq!(sender_keys.read, receiver_keys.write);
+    }
+
+    #[test]
+    fn pair_verify_rejects_unknown_identity() {
+        let (sender, receiver) = (Identity::generate("sender"), Identity::generate("receiver"));
+        let impostor = Identity::generate("sender");
+        let known = Peer { id: impostor.id.clone(), public_key: impostor.public_key() };
+
+        assert_eq!(verify(&sender, &receiver, Some(known)).err(), Some(Error::Peer(tlv::ERROR_AUTHENTICATION)));
+        assert_eq!(verify(&sender, &receiver, None).err(), Some(Error::Peer(tlv::ERROR_AUTHENTICATION)));
+    }
+
+    #[test]
+    fn encrypted_frames_round_trip() {
+        let keys = SessionKeys { write: [7; 32], read: [9; 32] };
+        let mut encrypter = Encrypter::new(&keys.write);
+        let mut decrypter = Decrypter::new(&keys.write);
+
+        let message: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+        let frames = [encrypter.encrypt(b"hello"), encrypter.encrypt(&message)].concat();
+
+        let mut out = Vec::new();
+
+        for chunk in frames.chunks(100) {
+            out.extend(decrypter.decrypt(chunk).unwrap());
+        }
+
+        assert_eq!(out, [b"hello".as_slice(), &message].concat());
+
+        let mut tampered = encrypter.encrypt(b"hello");
+        tampered[4] ^= 1;
+        assert_eq!(decrypter.decrypt(&tampered), Err(Error::Decryption));
+    }
+}
@@ -0,0 +1,225 @@
+use subtle::ConstantTimeEq;
+
+use super::{derive_key, error_message, expect_state, open, seal, srp, tlv, verify_signature, Error, Identity, Peer, SessionKeys, Tlv8};
+
+const ENCRYPT_SALT: &str = "Pair-Setup-Encrypt-Salt";
+const ENCRYPT_INFO: &str = "Pair-Setup-Encrypt-Info";
+
+fn encrypt_key(srp_key: &[u8]) -> [u8; 32] {
+    derive_key(srp_key, ENCRYPT_SALT, ENCRYPT_INFO)
+}
+
+/// The identifier, public key and signature exchanged in M5 and M6, sealed with the SRP session key.
+fn sealed_identity(identity: &Identity, srp_key: &[u8], sign_salt: &str, sign_info: &str, label: &[u8; 8]) -> Vec<u8> {
+    let x = derive_key(srp_key, sign_salt, sign_info);
+    let signature = identity.sign(&[x.as_slice(), identity.id.as_bytes(), &identity.public_key()].concat());
+
+    let inner = Tlv8::new()
+        .with(tlv::IDENTIFIER, identity.id.as_bytes())
+        .with(tlv::PUBLIC_KEY, identity.public_key())
+        .with(tlv::SIGNATURE, signature);
+
+    seal(&encrypt_key(srp_key), label, &inner.to_bytes())
+}
+
+fn open_identity(data: &[u8], srp_key: &[u8], sign_salt: &str, sign_info: &str, label: &[u8; 8]) -> Result<Peer, Error> {
+    let inner = Tlv8::parse(&open(&encrypt_key(srp_key), label, data)?)?;
+    let id = String::from_utf8(inner.require(tlv::IDENTIFIER)?.to_vec()).map_err(|_| Error::InvalidTlv)?;
+    let public_key: [u8; 32] = inner.require(tlv::PUBLIC_KEY)?.try_into().map_err(|_| Error::InvalidPublicKey)?;
+
+    let x = derive_key(srp_key, sign_salt, sign_info);
+    verify_signature(&public_key, &[x.as_slice(), id.as_bytes(), &public_key].concat(), inner.require(tlv::SIGNATURE)?)?;
+
+    Ok(Peer { id, public_key })
+}
+
+enum ClientState {
+    Start,
+    AwaitM2,
+    AwaitM4(srp::Verified),
+    AwaitM6(Vec<u8>),
+    Done,
+}
+
+/// The sender side of pair-setup.
+///
+/// Transient pair-setup stops after the SRP proofs, whose session key then protects the control
+/// connection. Otherwise long-term identities are exchanged for later pair-verify.
+pub struct SetupClient {
+    identity: Identity,
+    pin: String,
+    transient: bool,
+    srp: srp::Client,
+    state: ClientState,
+    peer: Option<Peer>,
+    srp_key: Option<Vec<u8>>,
+}
+
+impl SetupClient {
+    pub fn new(identity: Identity, pin: impl ToString, transient: bool) -> SetupClient {
+        SetupClient {
+            identity,
+            pin: pin.to_string(),
+            transient,
+            srp: srp::Client::new(),
+            state: ClientState::Start,
+            peer: None,
+            srp_key: None,
+        }
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// M1, the first message to send.
+    pub fn start(&mut self) -> Tlv8 {
+        self.state = ClientState::AwaitM2;
+        let m1 = Tlv8::new().with(tlv::STATE, [1]).with(tlv::METHOD, [0]);
+
+        if self.transient {
+            m1.with(tlv::FLAGS, [tlv::FLAG_TRANSIENT])
+        } else {
+            m1
+        }
+    }
+
+    /// Processes the receiver's answer and returns the next message to send, or `None` once paired.
+    pub fn handle(&mut self, message: &Tlv8) -> Result<Option<Tlv8>, Error> {
+        match std::mem::replace(&mut self.state, ClientState::Done) {
+            ClientState::AwaitM2 => {
+                expect_state(message, 2)?;
+                let verified = self.srp.process(&self.pin, message.require(tlv::SALT)?, message.require(tlv::PUBLIC_KEY)?)?;
+
+                let m3 = Tlv8::new()
+                    .with(tlv::STATE, [3])
+                    .with(tlv::PUBLIC_KEY, self.srp.public_key())
+                    .with(tlv::PROOF, verified.proof.clone());
+
+                self.state = ClientState::AwaitM4(verified);
+                Ok(Some(m3))
+            },
+            ClientState::AwaitM4(verified) => {
+                expect_state(message, 4)?;
+
+                if !bool::from(message.require(tlv::PROOF)?.ct_eq(&verified.server_proof)) {
+                    return Err(Error::Authentication);
+                }
+
+                self.srp_key = Some(verified.key.clone());
+
+                if self.transient {
+                    return Ok(None);
+                }
+
+                let data = sealed_identity(&self.identity, &verified.key, "Pair-Setup-Controller-Sign-Salt", "Pair-Setup-Controller-Sign-Info", b"PS-Msg05");
+                self.state = ClientState::AwaitM6(verified.key);
+                Ok(Some(Tlv8::new().with(tlv::STATE, [5]).with(tlv::ENCRYPTED_DATA, data)))
+            },
+            ClientState::AwaitM6(key) => {
+                expect_state(message, 6)?;
+                let data = message.require(tlv::ENCRYPTED_DATA)?;
+                self.peer = Some(open_identity(data, &key, "Pair-Setup-Accessory-Sign-Salt", "Pair-Setup-Accessory-Sign-Info", b"PS-Msg06")?);
+                Ok(None)
+            },
+            ClientState::Start | ClientState::Done => Err(Error::UnexpectedState(message.byte(tlv::STATE).unwrap_or(0))),
+        }
+    }
+
+    /// The receiver's identity, known after a full pair-setup.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// Control connection keys after a transient pair-setup.
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        self.srp_key.as_ref().filter(|_| self.transient).map(|x| SessionKeys::control(x, true))
+    }
+}
+
+/// The receiver side of pair-setup.
+pub struct SetupServer {
+    identity: Identity,
+    pin: String,
+    transient: bool,
+    srp: Option<srp::Server>,
+    srp_key: Option<Vec<u8>>,
+    peer: Option<Peer>,
+}
+
+impl SetupServer {
+    pub fn new(identity: Identity, pin: impl ToString) -> SetupServer {
+        SetupServer {
+            identity,
+            pin: pin.to_string(),
+            transient: false,
+            srp: None,
+            srp_key: None,
+            peer: None,
+        }
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+
+    /// Answers one message of the sender. Failures are answered with a TLV error, as receivers do.
+    pub fn handle(&mut self, message: &Tlv8) -> Tlv8 {
+        let state = message.byte(tlv::STATE).unwrap_or(0);
+
+        match self.step(state, message) {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::debug!("pair-setup M{} failed: {}", state, err);
+                error_message(state + 1, &err)
+            },
+        }
+    }
+
+    fn step(&mut self, state: u8, message: &Tlv8) -> Result<Tlv8, Error> {
+        match state {
+            1 => {
+                self.transient = message.byte(tlv::FLAGS).is_some_and(|x| x & tlv::FLAG_TRANSIENT != 0);
+                let srp = srp::Server::new(&self.pin);
+
+                let m2 = Tlv8::new()
+                    .with(tlv::STATE, [2])
+                    .with(tlv::SALT, srp.salt())
+                    .with(tlv::PUBLIC_KEY, srp.public_key());
+
+                self.srp = Some(srp);
+                Ok(m2)
+            },
+            3 => {
+                let srp = self.srp.take().ok_or(Error::UnexpectedState(state))?;
+                let verified = srp.verify(message.require(tlv::PUBLIC_KEY)?, message.require(tlv::PROOF)?)?;
+                self.srp_key = Some(verified.key);
+                Ok(Tlv8::new().with(tlv::STATE, [4]).with(tlv::PROOF, verified.server_proof))
+            },
+            5 if !self.transient => {
+                let key = self.srp_key.as_ref().ok_or(Error::UnexpectedState(state))?;
+                let data = message.require(tlv::ENCRYPTED_DATA)?;
+                let peer = open_identity(data, key, "Pair-Setup-Controller-Sign-Salt", "Pair-Setup-Controller-Sign-Info", b"PS-Msg05")?;
+
+                let data = sealed_identity(&self.identity, key, "Pair-Setup-Accessory-Sign-Salt", "Pair-Setup-Accessory-Sign-Info", b"PS-Msg06");
+                self.peer = Some(peer);
+                Ok(Tlv8::new().with(tlv::STATE, [6]).with(tlv::ENCRYPTED_DATA, data))
+            },
+            x => Err(Error::UnexpectedState(x)),
+        }
+    }
+
+    /// The sender's identity, known after a full pair-setup.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// Control connection keys after a transient pair-setup.
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        self.srp_key.as_ref().filter(|_| self.transient).map(|x| SessionKeys::control(x, false))
+    }
+}
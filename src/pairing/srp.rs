@@ -0,0 +1,191 @@
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+
+use super::Error;
+
+/// The 3072-bit group from RFC 5054.
+const N_HEX: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DD",
+    "EF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF0598DA48361C55D39A69163FA8FD24CF5F",
+    "83655D23DCA3AD961C62F356208552BB9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF6955817183995497CEA956AE515D2261898FA0510",
+    "15728E5A8AAAC42DAD33170D04507A33A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864D87602733EC86A64521F2B18177B200C",
+    "BBE117577A615D6C770988C0BAD946E208E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+);
+const G: u32 = 5;
+
+/// HomeKit pair-setup always uses this as the SRP user name.
+pub const USERNAME: &str = "Pair-Setup";
+
+struct Group {
+    n: BigUint,
+    g: BigUint,
+    len: usize,
+}
+
+impl Group {
+    fn get() -> Group {
+        let n = BigUint::parse_bytes(N_HEX.as_bytes(), 16).unwrap();
+        let len = n.to_bytes_be().len();
+        Group { n, g: BigUint::from(G), len }
+    }
+
+    fn pad(&self, x: &BigUint) -> Vec<u8> {
+        let bytes = x.to_bytes_be();
+        let mut out = vec![0; self.len.saturating_sub(bytes.len())];
+        out.extend(bytes);
+        out
+    }
+
+    fn k(&self) -> BigUint {
+        hash_int(&[&self.n.to_bytes_be(), &self.pad(&self.g)])
+    }
+
+    fn u(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        hash_int(&[&self.pad(a), &self.pad(b)])
+    }
+
+    fn x(&self, salt: &[u8], password: &str) -> BigUint {
+        let inner = hash(&[USERNAME.as_bytes(), b":", password.as_bytes()]);
+        hash_int(&[salt, &inner])
+    }
+
+    fn m1(&self, salt: &[u8], a: &BigUint, b: &BigUint, key: &[u8]) -> Vec<u8> {
+        let hn = hash(&[&self.n.to_bytes_be()]);
+        let hg = hash(&[&self.g.to_bytes_be()]);
+        let xor: Vec<u8> = hn.iter().zip(&hg).map(|(x, y)| x ^ y).collect();
+        hash(&[&xor, &hash(&[USERNAME.as_bytes()]), salt, &a.to_bytes_be(), &b.to_bytes_be(), key])
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut h = Sha512::new();
+
+    for part in parts {
+        h.update(part);
+    }
+
+    h.finalize().to_vec()
+}
+
+fn hash_int(parts: &[&[u8]]) -> BigUint {
+    BigUint::from_bytes_be(&hash(parts))
+}
+
+fn random_int() -> BigUint {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BigUint::from_bytes_be(&bytes)
+}
+
+/// The outcome of a successful SRP exchange.
+pub struct Verified {
+    /// The shared session key `K`.
+    pub key: Vec<u8>,
+    /// The client's proof `M1`.
+    pub proof: Vec<u8>,
+    /// The server's proof `M2`.
+    pub server_proof: Vec<u8>,
+}
+
+pub struct Client {
+    group: Group,
+    a: BigUint,
+    public: BigUint,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        let group = Group::get();
+        let a = random_int();
+        let public = group.g.modpow(&a, &group.n);
+        Client { group, a, public }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.to_bytes_be()
+    }
+
+    /// Derives the session key from the server's salt and public key, returning the proof to send.
+    pub fn process(&self, password: &str, salt: &[u8], server_public: &[u8]) -> Result<Verified, Error> {
+        let group = &self.group;
+        let b = BigUint::from_bytes_be(server_public);
+
+        if (&b % &group.n) == BigUint::ZERO {
+            return Err(Error::InvalidPublicKey);
+        }
+
+        let u = group.u(&self.public, &b);
+        let x = group.x(salt, password);
+        let kgx = (group.k() * group.g.modpow(&x, &group.n)) % &group.n;
+        let base = (&b + &group.n - kgx) % &group.n;
+        let s = base.modpow(&(&self.a + &u * &x), &group.n);
+
+        let key = hash(&[&s.to_bytes_be()]);
+        let proof = group.m1(salt, &self.public, &b, &key);
+        let server_proof = hash(&[&self.public.to_bytes_be(), &proof, &key]);
+        Ok(Verified { key, proof, server_proof })
+    }
+}
+
+pub struct Server {
+    group: Group,
+    salt: Vec<u8>,
+    verifier: BigUint,
+    b: BigUint,
+    public: BigUint,
+}
+
+impl Server {
+    pub fn new(password: &str) -> Server {
+        let group = Group::get();
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let verifier = group.g.modpow(&group.x(&salt, password), &group.n);
+        let b = random_int();
+        let public = (group.k() * &verifier + group.g.modpow(&b, &group.n)) % &group.n;
+        Server { group, salt, verifier, b, public }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.to_bytes_be()
+    }
+
+    /// Checks the client's proof against its public key. Fails with [`Error::Authentication`] when the
+    /// client used a different password.
+    pub fn verify(&self, client_public: &[u8], proof: &[u8]) -> Result<Verified, Error> {
+        let group = &self.group;
+        let a = BigUint::from_bytes_be(client_public);
+
+        if (&a % &group.n) == BigUint::ZERO {
+            return Err(Error::InvalidPublicKey);
+        }
+
+        let u = group.u(&a, &self.public);
+        let s = ((&a * self.verifier.modpow(&u, &group.n)) % &group.n).modpow(&self.b, &group.n);
+        let key = hash(&[&s.to_bytes_be()]);
+
+        // Compared in constant time so the answer's timing says nothing about how much of the proof matched.
+        if !bool::from(group.m1(&self.salt, &a, &self.public, &key).ct_eq(proof)) {
+            return Err(Error::Authentication);
+        }
+
+        let server_proof = hash(&[&a.to_bytes_be(), proof, &key]);
+        Ok(Verified { key, proof: proof.to_vec(), server_proof })
+    }
+}
@@ -0,0 +1,94 @@
+use super::Error;
+
+pub const METHOD: u8 = 0x00;
+pub const IDENTIFIER: u8 = 0x01;
+pub const SALT: u8 = 0x02;
+pub const PUBLIC_KEY: u8 = 0x03;
+pub const PROOF: u8 = 0x04;
+pub const ENCRYPTED_DATA: u8 = 0x05;
+pub const STATE: u8 = 0x06;
+pub const ERROR: u8 = 0x07;
+pub const RETRY_DELAY: u8 = 0x08;
+pub const SIGNATURE: u8 = 0x0a;
+pub const FLAGS: u8 = 0x13;
+
+pub const ERROR_AUTHENTICATION: u8 = 0x02;
+pub const ERROR_BACKOFF: u8 = 0x03;
+pub const ERROR_UNAVAILABLE: u8 = 0x06;
+pub const ERROR_BUSY: u8 = 0x07;
+
+pub const FLAG_TRANSIENT: u8 = 0x10;
+
+/// A TLV8 message as used by HomeKit pairing. Values longer than 255 bytes are split into consecutive
+/// items of the same type on the wire and joined again when parsing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tlv8 {
+    items: Vec<(u8, Vec<u8>)>,
+}
+
+impl Tlv8 {
+    pub fn new() -> Tlv8 {
+        Tlv8::default()
+    }
+
+    pub fn with(mut self, kind: u8, value: impl Into<Vec<u8>>) -> Tlv8 {
+        self.items.push((kind, value.into()));
+        self
+    }
+
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        self.items.iter().find(|(k, _)| *k == kind).map(|(_, v)| v.as_slice())
+    }
+
+    /// The single byte value of `kind`, such as [`STATE`] or [`ERROR`].
+    pub fn byte(&self, kind: u8) -> Option<u8> {
+        match self.get(kind)? {
+            [x] => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn require(&self, kind: u8) -> Result<&[u8], Error> {
+        self.get(kind).ok_or(Error::MissingItem(kind))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for (kind, value) in &self.items {
+            if value.is_empty() {
+                out.extend([*kind, 0]);
+            }
+
+            for chunk in value.chunks(255) {
+                out.push(*kind);
+                out.push(chunk.len() as u8);
+                out.extend_from_slice(chunk);
+            }
+        }
+
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Tlv8, Error> {
+        let mut items: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut pos = 0;
+        let mut continues = false;
+
+        while pos < data.len() {
+            let kind = data[pos];
+            let len = *data.get(pos + 1).ok_or(Error::InvalidTlv)? as usize;
+            let value = data.get(pos + 2..pos + 2 + len).ok_or(Error::InvalidTlv)?;
+
+            match items.last_mut() {
+                Some((last, x)) if continues && *last == kind => x.extend_from_slice(value),
+                _ => items.push((kind, value.to_vec())),
+            }
+
+            continues = len == 255;
+            pos += 2 + len;
+        }
+
+        Ok(Tlv8 { items })
+    }
+}
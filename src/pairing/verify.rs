@@ -0,0 +1,183 @@
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{derive_key, error_message, expect_state, open, seal, tlv, Error, Identity, Peer, SessionKeys, Tlv8};
+
+fn encrypt_key(shared: &[u8]) -> [u8; 32] {
+    derive_key(shared, "Pair-Verify-Encrypt-Salt", "Pair-Verify-Encrypt-Info")
+}
+
+fn public_key(data: &[u8]) -> Result<PublicKey, Error> {
+    let bytes: [u8; 32] = data.try_into().map_err(|_| Error::InvalidPublicKey)?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Signs `ours | id | theirs` and seals it together with the identifier.
+fn sealed_proof(identity: &Identity, ours: &PublicKey, theirs: &PublicKey, shared: &[u8], label: &[u8; 8]) -> Vec<u8> {
+    let signature = identity.sign(&[ours.as_bytes().as_slice(), identity.id.as_bytes(), theirs.as_bytes()].concat());
+    let inner = Tlv8::new().with(tlv::IDENTIFIER, identity.id.as_bytes()).with(tlv::SIGNATURE, signature);
+    seal(&encrypt_key(shared), label, &inner.to_bytes())
+}
+
+/// Opens the other side's proof, returning its identifier and signature over `theirs | id | ours`.
+fn open_proof(data: &[u8], shared: &[u8], label: &[u8; 8]) -> Result<(String, Vec<u8>), Error> {
+    let inner = Tlv8::parse(&open(&encrypt_key(shared), label, data)?)?;
+    let id = String::from_utf8(inner.require(tlv::IDENTIFIER)?.to_vec()).map_err(|_| Error::InvalidTlv)?;
+    Ok((id, inner.require(tlv::SIGNATURE)?.to_vec()))
+}
+
+enum ClientState {
+    Start,
+    AwaitM2(EphemeralSecret, PublicKey),
+    AwaitM4,
+    Done,
+}
+
+/// The sender side of pair-verify.
+///
+/// The receiver's signature is only checked when its identity is known from a previous pair-setup.
+pub struct VerifyClient {
+    identity: Identity,
+    peer: Option<Peer>,
+    state: ClientState,
+    shared: Option<[u8; 32]>,
+}
+
+impl VerifyClient {
+    pub fn new(identity: Identity, peer: Option<Peer>) -> VerifyClient {
+        VerifyClient {
+            identity,
+            peer,
+            state: ClientState::Start,
+            shared: None,
+        }
+    }
+
+    pub fn start(&mut self) -> Tlv8 {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        self.state = ClientState::AwaitM2(secret, public);
+        Tlv8::new().with(tlv::STATE, [1]).with(tlv::PUBLIC_KEY, public.as_bytes().as_slice())
+    }
+
+    pub fn handle(&mut self, message: &Tlv8) -> Result<Option<Tlv8>, Error> {
+        match std::mem::replace(&mut self.state, ClientState::Done) {
+            ClientState::AwaitM2(secret, ours) => {
+                expect_state(message, 2)?;
+                let theirs = public_key(message.require(tlv::PUBLIC_KEY)?)?;
+                let shared = secret.diffie_hellman(&theirs).to_bytes();
+
+                let (id, signature) = open_proof(message.require(tlv::ENCRYPTED_DATA)?, &shared, b"PV-Msg02")?;
+
+                if let Some(peer) = &self.peer {
+                    if peer.id != id {
+                        return Err(Error::Authentication);
+                    }
+
+                    peer.verify(&[theirs.as_bytes().as_slice(), id.as_bytes(), ours.as_bytes()].concat(), &signature)?;
+                }
+
+                let data = sealed_proof(&self.identity, &ours, &theirs, &shared, b"PV-Msg03");
+                self.shared = Some(shared);
+                self.state = ClientState::AwaitM4;
+                Ok(Some(Tlv8::new().with(tlv::STATE, [3]).with(tlv::ENCRYPTED_DATA, data)))
+            },
+            ClientState::AwaitM4 => {
+                expect_state(message, 4)?;
+                Ok(None)
+            },
+            ClientState::Start | ClientState::Done => Err(Error::UnexpectedState(message.byte(tlv::STATE).unwrap_or(0))),
+        }
+    }
+
+    /// Control connection keys once verified.
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        self.shared_secret().map(|x| SessionKeys::control(&x, true))
+    }
+
+    /// The X25519 secret once verified, for protocols deriving keys of their own from it.
+    pub fn shared_secret(&self) -> Option<[u8; 32]> {
+        match self.state {
+            ClientState::Done => self.shared,
+            _ => None,
+        }
+    }
+}
+
+type Lookup = Box<dyn Fn(&str) -> Option<Peer> + Send + Sync>;
+
+/// The receiver side of pair-verify. Senders are looked up by identifier among the pairings made earlier.
+pub struct VerifyServer {
+    identity: Identity,
+    lookup: Lookup,
+    pending: Option<(PublicKey, PublicKey, [u8; 32])>,
+    verified: Option<(Peer, [u8; 32])>,
+}
+
+impl VerifyServer {
+    pub fn new(identity: Identity, lookup: impl Fn(&str) -> Option<Peer> + Send + Sync + 'static) -> VerifyServer {
+        VerifyServer {
+            identity,
+            lookup: Box::new(lookup),
+            pending: None,
+            verified: None,
+        }
+    }
+
+    pub fn handle(&mut self, message: &Tlv8) -> Tlv8 {
+        let state = message.byte(tlv::STATE).unwrap_or(0);
+
+        match self.step(state, message) {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::debug!("pair-verify M{} failed: {}", state, err);
+                error_message(state + 1, &err)
+            },
+        }
+    }
+
+    fn step(&mut self, state: u8, message: &Tlv8) -> Result<Tlv8, Error> {
+        match state {
+            1 => {
+                let theirs = public_key(message.require(tlv::PUBLIC_KEY)?)?;
+                let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+                let ours = PublicKey::from(&secret);
+                let shared = secret.diffie_hellman(&theirs).to_bytes();
+
+                let data = sealed_proof(&self.identity, &ours, &theirs, &shared, b"PV-Msg02");
+                self.pending = Some((ours, theirs, shared));
+                self.verified = None;
+
+                Ok(Tlv8::new()
+                    .with(tlv::STATE, [2])
+                    .with(tlv::PUBLIC_KEY, ours.as_bytes().as_slice())
+                    .with(tlv::ENCRYPTED_DATA, data))
+            },
+            3 => {
+                let (ours, theirs, shared) = self.pending.take().ok_or(Error::UnexpectedState(state))?;
+                let (id, signature) = open_proof(message.require(tlv::ENCRYPTED_DATA)?, &shared, b"PV-Msg03")?;
+                let peer = (self.lookup)(&id).ok_or(Error::Authentication)?;
+
+                peer.verify(&[theirs.as_bytes().as_slice(), id.as_bytes(), ours.as_bytes()].concat(), &signature)
+                    .map_err(|_| Error::Authentication)?;
+
+                self.verified = Some((peer, shared));
+                Ok(Tlv8::new().with(tlv::STATE, [4]))
+            },
+            x => Err(Error::UnexpectedState(x)),
+        }
+    }
+
+    /// The sender that verified.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.verified.as_ref().map(|(x, _)| x)
+    }
+
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        self.shared_secret().map(|x| SessionKeys::control(&x, false))
+    }
+
+    /// The X25519 secret once verified, see [`VerifyClient::shared_secret`].
+    pub fn shared_secret(&self) -> Option<[u8; 32]> {
+        self.verified.as_ref().map(|(_, x)| *x)
+    }
+}
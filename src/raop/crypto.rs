@@ -0,0 +1,178 @@
+use std::{fmt, net::IpAddr};
+
+use aes::Aes128;
+use base64::{alphabet, engine::{general_purpose::GeneralPurpose, DecodePaddingMode, GeneralPurposeConfig}, Engine};
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use rand::RngCore;
+use rsa::{BigUint, Oaep, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+
+/// Base64 the way RAOP uses it: written without padding, read with or without.
+pub const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_encode_padding(false).with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const AIRPORT_MODULUS: &str = "\
+    59dE8qLieItsH1WgjrcFRKj6eUWqi+bGLOX1HL3U3GhC/j0Qg90u3sG/1CUtwC5vOYvfDmFI6oSFXi5ELabWJmT2dKHzBJKa3k9ok+8t9ucR\
+    qMd6DZHJ2YCCLlDRKSKv6kDqnw4UwPdpOMXziC/AMj3Z/lUVX1G7WSHCAWKf1zNS1eLvqr+boEjXuBOitnZ/bDzPHrTOZz0Dew0uowxf/+sG\
+    +NCK3eQJVxqcaJ/vEHKIVd2M+5qL71yJQ+87X6oV3eaYvt3zWZYD6z5vYTcrtij2VZ9Zmni/UAaHqn9JdsBWLUEpVviYnhimNVvYFZeCXg/I\
+    dTQ+x4IRdiXNv5hEew";
+
+/// The public half of the RSA key every AirPort Express, and the receivers modelled on it, holds.
+pub fn airport_key() -> RsaPublicKey {
+    let modulus = BASE64.decode(AIRPORT_MODULUS).unwrap();
+    RsaPublicKey::new(BigUint::from_bytes_be(&modulus), BigUint::from(65537_u32)).unwrap()
+}
+
+/// The AES-128-CBC key and IV audio payloads are encrypted with, announced RSA encrypted in the SDP.
+#[derive(Clone)]
+pub struct AudioCipher {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl AudioCipher {
+    pub fn new(key: [u8; 16], iv: [u8; 16]) -> AudioCipher {
+        AudioCipher { key, iv }
+    }
+
+    pub fn generate() -> AudioCipher {
+        let mut cipher = AudioCipher { key: [0; 16], iv: [0; 16] };
+        rand::thread_rng().fill_bytes(&mut cipher.key);
+        rand::thread_rng().fill_bytes(&mut cipher.iv);
+        cipher
+    }
+
+    pub fn iv(&self) -> [u8; 16] {
+        self.iv
+    }
+
+    /// The AES key RSA-OAEP encrypted to `key`, for `rsaaeskey`.
+    pub fn wrap_key(&self, key: &RsaPublicKey) -> Result<Vec<u8>, rsa::Error> {
+        key.encrypt(&mut rand::thread_rng(), Oaep::new::<Sha1>(), &self.key)
+    }
+
+    /// The receiving side of [`AudioCipher::wrap_key`].
+    pub fn unwrap_key(key: &RsaPrivateKey, wrapped: &[u8], iv: [u8; 16]) -> Result<AudioCipher, rsa::Error> {
+        let aes = key.decrypt(Oaep::new::<Sha1>(), wrapped)?;
+        let key = aes.try_into().map_err(|_| rsa::Error::Decryption)?;
+        Ok(AudioCipher { key, iv })
+    }
+
+    /// Encrypts one packet's payload in place. Every packet starts over from the IV, and a trailing partial
+    /// block is left as it is.
+    pub fn encrypt(&self, payload: &mut [u8]) {
+        let mut encryptor = cbc::Encryptor::<Aes128>::new(&self.key.into(), &self.iv.into());
+
+        for block in payload.chunks_exact_mut(16) {
+            encryptor.encrypt_block_mut(aes::Block::from_mut_slice(block));
+        }
+    }
+
+    pub fn decrypt(&self, payload: &mut [u8]) {
+        let mut decryptor = cbc::Decryptor::<Aes128>::new(&self.key.into(), &self.iv.into());
+
+        for block in payload.chunks_exact_mut(16) {
+            decryptor.decrypt_block_mut(aes::Block::from_mut_slice(block));
+        }
+    }
+}
+
+impl fmt::Debug for AudioCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioCipher").finish_non_exhaustive()
+    }
+}
+
+/// The random `Apple-Challenge` a receiver proves it holds the private key with.
+///
+/// The receiver signs the challenge followed by the sender's IP address and its own MAC address, zero
+/// padded to 32 bytes, with raw PKCS#1 v1.5 and answers it base64 encoded in `Apple-Response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge([u8; 16]);
+
+impl Challenge {
+    pub fn generate() -> Challenge {
+        let mut challenge = [0; 16];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        Challenge(challenge)
+    }
+
+    pub fn from_header(value: &str) -> Option<Challenge> {
+        BASE64.decode(value.trim()).ok()?.try_into().ok().map(Challenge)
+    }
+
+    pub fn to_header(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    fn message(&self, ip: IpAddr, mac: [u8; 6]) -> Vec<u8> {
+        let mut message = self.0.to_vec();
+
+        match ip.to_canonical() {
+            IpAddr::V4(x) => message.extend(x.octets()),
+            IpAddr::V6(x) => message.extend(x.octets()),
+        }
+
+        message.extend(mac);
+        message.resize(message.len().max(32), 0);
+        message
+    }
+
+    /// The `Apple-Response` to this challenge from a sender at `ip`.
+    pub fn respond(&self, key: &RsaPrivateKey, ip: IpAddr, mac: [u8; 6]) -> Result<String, rsa::Error> {
+        let signature = key.sign(Pkcs1v15Sign::new_unprefixed(), &self.message(ip, mac))?;
+        Ok(BASE64.encode(signature))
+    }
+
+    /// Checks an `Apple-Response` against the receiver's public key. `ip` is our address as the receiver
+    /// sees it.
+    pub fn verify(&self, response: &str, key: &RsaPublicKey, ip: IpAddr, mac: [u8; 6]) -> bool {
+        let Ok(signature) = BASE64.decode(response.trim()) else { return false };
+        key.verify(Pkcs1v15Sign::new_unprefixed(), &self.message(ip, mac), &signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn airport_key_is_2048_bits() {
+        use rsa::traits::PublicKeyParts;
+
+        assert_eq!(airport_key().size(), 256);
+    }
+
+    #[test]
+    fn encrypts_whole_blocks_only() {
+        let cipher = AudioCipher::generate();
+        let plain: Vec<u8> = (0..40).collect();
+
+        let mut payload = plain.clone();
+        cipher.encrypt(&mut payload);
+        assert_ne!(payload[..32], plain[..32]);
+        assert_eq!(payload[32..], plain[32..]);
+
+        cipher.decrypt(&mut payload);
+        assert_eq!(payload, plain);
+    }
+
+    #[test]
+    fn challenge_round_trips() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let mac = [0, 0x16, 0xcb, 1, 2, 3];
+
+        let challenge = Challenge::generate();
+        assert_eq!(Challenge::from_header(&challenge.to_header()), Some(challenge));
+
+        let response = challenge.respond(&key, ip, mac).unwrap();
+        assert!(challenge.verify(&response, &key.to_public_key(), ip, mac));
+        assert!(!challenge.verify(&response, &key.to_public_key(), ip, [0; 6]));
+        assert!(!Challenge::generate().verify(&response, &key.to_public_key(), ip, mac));
+    }
+}
@@ -0,0 +1,247 @@
+//! Legacy RAOP (AirPlay 1) sending, for AirPort Express units and AVRs that only advertise `_raop._tcp`.
+//!
+//! A session is OPTIONS with an `Apple-Challenge`, ANNOUNCE with an SDP describing the stream and the RSA
+//! encrypted AES key, SETUP with our control and timing ports, then RECORD. Audio goes out as RTP over UDP
+//! with AES-128-CBC encrypted payloads, and the receiver follows our clock through the timing port.
+
+use std::{fmt, io, net::SocketAddr, time::Instant};
+
+use rand::Rng;
+use rsa::RsaPublicKey;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{group::{Clock, DEFAULT_LATENCY}, mdns::{RaopEncryption, RaopMetadata}, rtp::{self, Sync}, rtsp::{self, Client}};
+
+mod crypto;
+mod sdp;
+
+pub use crypto::{airport_key, AudioCipher, Challenge};
+pub use sdp::{attribute, Sdp, SdpFormat};
+
+#[derive(Debug)]
+pub enum Error {
+    Rtsp(rtsp::Error),
+    Rsa(rsa::Error),
+    /// The receiver's `Apple-Response` is missing or doesn't check out against its key.
+    Authentication,
+    /// The receiver asks for something we can't do, such as FairPlay or a codec it doesn't list.
+    Unsupported(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rtsp(x) => x.fmt(f),
+            Self::Rsa(x) => write!(f, "RSA error: {}", x),
+            Self::Authentication => f.write_str("receiver failed the Apple-Challenge"),
+            Self::Unsupported(x) => write!(f, "unsupported by RAOP sender: {}", x),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rtsp::Error> for Error {
+    fn from(x: rtsp::Error) -> Self {
+        Error::Rtsp(x)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(x: io::Error) -> Self {
+        Error::Rtsp(rtsp::Error::Io(x))
+    }
+}
+
+impl From<rsa::Error> for Error {
+    fn from(x: rsa::Error) -> Self {
+        Error::Rsa(x)
+    }
+}
+
+/// Answers the receiver's timing requests from the sender's clock.
+async fn serve_timing(socket: UdpSocket, clock: Clock) {
+    let mut buf = [0; 128];
+
+    while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+        let received = clock.ntp_at(Instant::now());
+
+        let Some(request) = rtp::Timing::parse(&buf[..n]).filter(|x| !x.response) else { continue };
+        let response = request.respond(received, clock.ntp_at(Instant::now()));
+
+        if let Err(err) = socket.send_to(&response.to_bytes(), from).await {
+            tracing::debug!(%from, "Failed to answer timing request: {}", err);
+        }
+    }
+}
+
+/// One RAOP receiver playing a stream.
+///
+/// Mirrors [`crate::group::Group`] for a single device: packets are stamped on a [`Clock`] to play
+/// `latency` frames after they are sent, and [`Sender::sync`] ties the two together.
+pub struct Sender {
+    client: Client,
+    session: Option<String>,
+    format: SdpFormat,
+    cipher: Option<AudioCipher>,
+    clock: Clock,
+    socket: UdpSocket,
+    data: SocketAddr,
+    control: SocketAddr,
+    timing: JoinHandle<()>,
+    ssrc: u32,
+    sequence: u16,
+    next_timestamp: u32,
+    latency: u32,
+    marker: bool,
+    synced: bool,
+}
+
+impl Sender {
+    /// Sets up a session with a receiver holding the AirPort key, see [`Sender::start_with_key`].
+    pub async fn start(client: Client, metadata: &RaopMetadata, format: SdpFormat) -> Result<Sender, Error> {
+        Self::start_with_key(client, metadata, format, &airport_key()).await
+    }
+
+    /// Sets up a session over a connected `client` and tells the receiver to RECORD. Audio is encrypted
+    /// whenever the receiver takes RSA, after it has proven it holds the private half of `key`.
+    pub async fn start_with_key(client: Client, metadata: &RaopMetadata, format: SdpFormat, key: &RsaPublicKey) -> Result<Sender, Error> {
+        if !metadata.codecs.contains(&format.codec()) {
+            return Err(Error::Unsupported(format!("{:?} audio", format.codec())));
+        }
+
+        let cipher = if metadata.encryption_types.contains(&RaopEncryption::Rsa) {
+            let challenge = Challenge::generate();
+            let res = client.options(Some(&challenge.to_header())).await?;
+            let response = res.headers.get("Apple-Response").ok_or(Error::Authentication)?;
+
+            if !challenge.verify(response, key, client.local_addr().ip(), metadata.mac_address()) {
+                return Err(Error::Authentication);
+            }
+
+            Some(AudioCipher::generate())
+        } else if metadata.encryption_types.is_empty() || metadata.encryption_types.contains(&RaopEncryption::None) {
+            client.options(None).await?;
+            None
+        } else {
+            return Err(Error::Unsupported(format!("encryption {:?}", metadata.encryption_types)));
+        };
+
+        let sdp = Sdp {
+            session_id: rand::thread_rng().gen(),
+            local: client.local_addr().ip(),
+            remote: client.peer.ip(),
+            format,
+            rsa_aes_key: cipher.as_ref().map(|x| x.wrap_key(key)).transpose()?,
+            aes_iv: cipher.as_ref().map(AudioCipher::iv),
+        };
+        client.announce(&sdp.to_string()).await?;
+
+        let local = client.local_addr().ip();
+        let socket = UdpSocket::bind((local, 0)).await?;
+        let timing_socket = UdpSocket::bind((local, 0)).await?;
+        let transport = client.setup_raop(socket.local_addr()?.port(), timing_socket.local_addr()?.port()).await?;
+
+        let mut rng = rand::thread_rng();
+        let clock = Clock::new(format.format().sample_rate, rng.gen());
+        let sequence = rng.gen();
+        let latency = DEFAULT_LATENCY;
+        let next_timestamp = clock.rtp_at(Instant::now()).wrapping_add(latency);
+        let timing = tokio::spawn(serve_timing(timing_socket, clock));
+
+        let sender = Sender {
+            session: transport.session.clone(),
+            format,
+            cipher,
+            clock,
+            socket,
+            data: SocketAddr::new(client.peer.ip(), transport.server_port),
+            control: SocketAddr::new(client.peer.ip(), transport.control_port),
+            timing,
+            ssrc: rng.gen(),
+            sequence,
+            next_timestamp,
+            latency,
+            marker: true,
+            synced: false,
+            client,
+        };
+
+        sender.client.record_raop(sender.session.as_deref(), sequence, next_timestamp).await?;
+        Ok(sender)
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn format(&self) -> SdpFormat {
+        self.format
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Sends one packet carrying `frames` frames, encrypted if the session is, and returns its RTP
+    /// timestamp. Packets follow on contiguously from the `rtptime` given in RECORD, so callers should
+    /// start sending right away and pace sending to the clock.
+    pub async fn send(&mut self, payload: &[u8], frames: u32) -> io::Result<u32> {
+        let timestamp = self.next_timestamp;
+        self.next_timestamp = timestamp.wrapping_add(frames);
+
+        let mut packet = Vec::with_capacity(rtp::HEADER_LEN + payload.len());
+        rtp::Header {
+            marker: self.marker,
+            extension: false,
+            payload_type: rtp::PAYLOAD_TYPE_AUDIO,
+            sequence: self.sequence,
+            timestamp,
+            ssrc: self.ssrc,
+        }.write(&mut packet);
+        packet.extend_from_slice(payload);
+
+        if let Some(cipher) = &self.cipher {
+            cipher.encrypt(&mut packet[rtp::HEADER_LEN..]);
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.marker = false;
+        self.socket.send_to(&packet, self.data).await?;
+        Ok(timestamp)
+    }
+
+    /// Sends the receiver the current mapping between the clock and RTP time. Call this about once a
+    /// second.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let timestamp = self.clock.rtp_at(now);
+
+        let sync = Sync {
+            first: !self.synced,
+            timestamp,
+            time: self.clock.ntp_at(now),
+            next_timestamp: timestamp.wrapping_add(self.latency),
+        };
+
+        self.socket.send_to(&sync.to_bytes(), self.control).await?;
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Ends the session and hands the client back.
+    pub async fn teardown(self) -> Result<Client, Error> {
+        self.client.teardown().await?;
+        Ok(self.client.clone())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.timing.abort();
+    }
+}
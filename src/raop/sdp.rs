@@ -0,0 +1,146 @@
+use std::{fmt, net::IpAddr};
+
+use base64::Engine;
+
+use crate::{alac, audio::{Codec, Format}, mdns::RaopMetadata};
+
+use super::crypto::BASE64;
+
+/// What the RAOP stream carries, as payload type 96.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdpFormat {
+    /// Big-endian linear PCM, `L16`.
+    Pcm(Format),
+    Alac(alac::Config),
+}
+
+impl SdpFormat {
+    /// ALAC when the receiver takes it, PCM otherwise, at the sample rate, size and channels it advertises.
+    pub fn preferred(metadata: &RaopMetadata) -> SdpFormat {
+        let format = Format::new(
+            metadata.sample_rate.unwrap_or(44100),
+            metadata.channels.unwrap_or(2),
+            metadata.sample_size.unwrap_or(16),
+        );
+
+        let alac = alac::Config::new(alac::FRAME_LENGTH_REALTIME, format.sample_rate, format.bit_depth, format.channels);
+
+        match alac {
+            Ok(x) if metadata.codecs.contains(&Codec::Alac) => SdpFormat::Alac(x),
+            _ => SdpFormat::Pcm(format),
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self {
+            Self::Pcm(_) => Codec::Pcm,
+            Self::Alac(_) => Codec::Alac,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match self {
+            Self::Pcm(x) => *x,
+            Self::Alac(x) => Format::new(x.sample_rate, x.channels, x.bit_depth),
+        }
+    }
+
+    /// Frames per packet.
+    pub fn frames_per_packet(&self) -> u32 {
+        match self {
+            Self::Pcm(_) => alac::FRAME_LENGTH_REALTIME,
+            Self::Alac(x) => x.frame_length,
+        }
+    }
+}
+
+/// The session description a RAOP sender ANNOUNCEs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sdp {
+    pub session_id: u32,
+    /// Our address, for the origin line.
+    pub local: IpAddr,
+    /// The receiver's address, for the connection line.
+    pub remote: IpAddr,
+    pub format: SdpFormat,
+    /// `rsaaeskey`: the AES key, RSA encrypted to the receiver.
+    pub rsa_aes_key: Option<Vec<u8>>,
+    /// `aesiv`
+    pub aes_iv: Option<[u8; 16]>,
+}
+
+fn address(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(x) => format!("IP4 {}", x),
+        IpAddr::V6(x) => format!("IP6 {}", x),
+    }
+}
+
+impl fmt::Display for Sdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v=0\r\n")?;
+        write!(f, "o=iTunes {} 0 IN {}\r\n", self.session_id, address(self.local))?;
+        write!(f, "s=iTunes\r\n")?;
+        write!(f, "c=IN {}\r\n", address(self.remote))?;
+        write!(f, "t=0 0\r\n")?;
+        write!(f, "m=audio 0 RTP/AVP 96\r\n")?;
+
+        match self.format {
+            SdpFormat::Pcm(x) => write!(f, "a=rtpmap:96 L{}/{}/{}\r\n", x.bit_depth, x.sample_rate, x.channels)?,
+            SdpFormat::Alac(x) => {
+                write!(f, "a=rtpmap:96 AppleLossless\r\n")?;
+                write!(f, "a=fmtp:96 {}\r\n", x.fmtp())?;
+            },
+        }
+
+        if let Some(x) = &self.rsa_aes_key {
+            write!(f, "a=rsaaeskey:{}\r\n", BASE64.encode(x))?;
+        }
+
+        if let Some(x) = &self.aes_iv {
+            write!(f, "a=aesiv:{}\r\n", BASE64.encode(x))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The value of the first `a=<name>:` attribute of an SDP body.
+pub fn attribute<'a>(sdp: &'a str, name: &str) -> Option<&'a str> {
+    sdp.lines().find_map(|x| x.trim().strip_prefix("a=")?.strip_prefix(name)?.strip_prefix(':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn describes_an_encrypted_alac_stream() {
+        let sdp = Sdp {
+            session_id: 1234,
+            local: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+            remote: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)),
+            format: SdpFormat::Alac(alac::Config::new(352, 44100, 16, 2).unwrap()),
+            rsa_aes_key: Some(vec![1, 2, 3]),
+            aes_iv: Some([0; 16]),
+        }.to_string();
+
+        assert!(sdp.starts_with("v=0\r\no=iTunes 1234 0 IN IP4 192.168.1.2\r\ns=iTunes\r\nc=IN IP4 192.168.1.20\r\n"));
+        assert_eq!(attribute(&sdp, "rtpmap"), Some("96 AppleLossless"));
+        assert_eq!(attribute(&sdp, "fmtp"), Some("96 352 0 16 40 10 14 2 255 0 0 44100"));
+        assert_eq!(attribute(&sdp, "rsaaeskey"), Some("AQID"));
+        assert_eq!(attribute(&sdp, "aesiv"), Some("AAAAAAAAAAAAAAAAAAAAAA"));
+    }
+
+    #[test]
+    fn prefers_alac() {
+        let txt = vec!["cn=0,1".to_string(), "sr=44100".to_string(), "ss=16".to_string(), "ch=2".to_string()];
+        let metadata = RaopMetadata::from_txt("001122334455@Den", &txt).unwrap();
+        assert_eq!(SdpFormat::preferred(&metadata).codec(), Codec::Alac);
+
+        let metadata = RaopMetadata { codecs: vec![Codec::Pcm], ..metadata };
+        assert_eq!(SdpFormat::preferred(&metadata), SdpFormat::Pcm(Format::new(44100, 2, 16)));
+    }
+}
@@ -0,0 +1,364 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+/// Counters kept by a [`JitterBuffer`], and by the [`super::Player`] built on one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub received: u64,
+    /// Arrived after their turn to be played had passed.
+    pub late: u64,
+    pub duplicates: u64,
+    /// Never arrived, or not in time.
+    pub lost: u64,
+    /// Frames of concealment played in place of lost packets.
+    pub concealed: u64,
+    /// Missing packets that arrived after a retransmit request.
+    pub recovered: u64,
+    pub retransmit_requests: u64,
+    /// Dropped because the buffer was full.
+    pub overflow: u64,
+    /// Packets held right now.
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+/// What comes out of a [`JitterBuffer`], in sequence order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Released {
+    Packet(Packet),
+    /// The packet with this sequence number never arrived. `timestamp` is where it would have been.
+    Lost { sequence: u16, timestamp: u32 },
+}
+
+#[derive(Debug)]
+enum Slot {
+    Missing { requested: Option<Instant> },
+    Filled(Packet),
+}
+
+/// Puts RTP packets of one stream back into sequence order, keeping track of the gaps.
+///
+/// Holds packets from the next one due up to the newest one received. Anything before the next one due
+/// arrived too late and is dropped.
+#[derive(Debug)]
+pub struct JitterBuffer {
+    frames_per_packet: u32,
+    capacity: usize,
+    slots: VecDeque<Slot>,
+    /// Sequence number and expected timestamp of the first slot.
+    head: Option<(u16, u32)>,
+    /// Packets before this sequence number were flushed and are dropped silently.
+    flushed: Option<u16>,
+    stats: Stats,
+}
+
+/// Whether `a` comes before `b`, allowing for wrap-around.
+fn before(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a).wrapping_sub(1) < 0x8000
+}
+
+impl JitterBuffer {
+    pub fn new(frames_per_packet: u32, capacity: usize) -> JitterBuffer {
+        JitterBuffer {
+            frames_per_packet,
+            capacity: capacity.max(1),
+            slots: VecDeque::new(),
+            head: None,
+            flushed: None,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn frames_per_packet(&self) -> u32 {
+        self.frames_per_packet
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            depth: self.slots.iter().filter(|x| matches!(x, Slot::Filled(_))).count(),
+            ..self.stats
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Whether any packet before the newest one received is missing.
+    pub fn has_gaps(&self) -> bool {
+        self.slots.iter().any(|x| matches!(x, Slot::Missing { .. }))
+    }
+
+    /// Timestamp of the next packet to be released, or where it is expected to be if it is missing.
+    pub fn next_timestamp(&self) -> Option<u32> {
+        match self.slots.front()? {
+            Slot::Filled(x) => Some(x.timestamp),
+            Slot::Missing { .. } => self.head.map(|(_, timestamp)| timestamp),
+        }
+    }
+
+    /// Adds a packet, returning whether it was kept. When it is too far ahead to fit, the oldest packets are
+    /// dropped to make room.
+    pub fn insert(&mut self, packet: Packet) -> bool {
+        self.stats.received += 1;
+
+        if let Some(flushed) = self.flushed {
+            if before(packet.sequence, flushed) {
+                return false;
+            }
+
+            self.flushed = None;
+        }
+
+        let Some((head, _)) = self.head else {
+            self.head = Some((packet.sequence, packet.timestamp));
+            self.slots.push_back(Slot::Filled(packet));
+            return true;
+        };
+
+        if before(packet.sequence, head) {
+            self.stats.late += 1;
+            return false;
+        }
+
+        let mut index = usize::from(packet.sequence.wrapping_sub(head));
+
+        while index >= self.capacity {
+            match self.pop_slot() {
+                Some(Slot::Filled(_)) => self.stats.overflow += 1,
+                Some(Slot::Missing { .. }) => self.stats.lost += 1,
+                None => {
+                    self.head = Some((packet.sequence, packet.timestamp));
+                    index = 0;
+                    break;
+                },
+            }
+
+            index -= 1;
+        }
+
+        while self.slots.len() <= index {
+            self.slots.push_back(Slot::Missing { requested: None });
+        }
+
+        match &self.slots[index] {
+            Slot::Filled(_) => {
+                self.stats.duplicates += 1;
+                false
+            },
+            Slot::Missing { requested } => {
+                if requested.is_some() {
+                    self.stats.recovered += 1;
+                }
+
+                if index == 0 {
+                    self.head = Some((packet.sequence, packet.timestamp));
+                }
+
+                self.slots[index] = Slot::Filled(packet);
+                true
+            },
+        }
+    }
+
+    /// Releases the next packet in sequence, or reports it lost if it hasn't arrived.
+    pub fn pop(&mut self) -> Option<Released> {
+        let (sequence, timestamp) = self.head?;
+
+        match self.pop_slot()? {
+            Slot::Filled(x) => Some(Released::Packet(x)),
+            Slot::Missing { .. } => {
+                self.stats.lost += 1;
+                Some(Released::Lost { sequence, timestamp })
+            },
+        }
+    }
+
+    fn pop_slot(&mut self) -> Option<Slot> {
+        let slot = self.slots.pop_front()?;
+        let (sequence, timestamp) = self.head?;
+        let timestamp = match &slot {
+            Slot::Filled(x) => x.timestamp,
+            Slot::Missing { .. } => timestamp,
+        };
+
+        self.head = Some((sequence.wrapping_add(1), timestamp.wrapping_add(self.frames_per_packet)));
+
+        if let Some(Slot::Filled(x)) = self.slots.front() {
+            self.head = Some((x.sequence, x.timestamp));
+        }
+
+        Some(slot)
+    }
+
+    /// Runs of missing packets to ask the sender for, as first sequence number and count. A packet is asked
+    /// for again once `interval` has passed since the last time.
+    pub fn missing(&mut self, now: Instant, interval: Duration) -> Vec<(u16, u16)> {
+        let Some((head, _)) = self.head else { return Vec::new() };
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let Slot::Missing { requested } = slot else { continue };
+
+            if requested.is_some_and(|x| now.saturating_duration_since(x) < interval) {
+                continue;
+            }
+
+            *requested = Some(now);
+            let sequence = head.wrapping_add(index as u16);
+
+            match runs.last_mut() {
+                Some((first, count)) if first.wrapping_add(*count) == sequence => *count += 1,
+                _ => runs.push((sequence, 1)),
+            }
+        }
+
+        self.stats.retransmit_requests += runs.len() as u64;
+        runs
+    }
+
+    /// Drops packets before `sequence`, or everything without one. Packets before it that arrive later are
+    /// dropped too.
+    pub fn flush(&mut self, sequence: Option<u16>) {
+        let Some(sequence) = sequence else {
+            self.slots.clear();
+            self.head = None;
+            self.flushed = None;
+            return;
+        };
+
+        while self.head.is_some_and(|(head, _)| before(head, sequence)) && !self.slots.is_empty() {
+            self.pop_slot();
+        }
+
+        if self.slots.is_empty() {
+            self.head = None;
+            self.flushed = Some(sequence);
+        }
+    }
+
+    /// Counts frames of concealment played by whoever consumes the buffer.
+    pub fn conceal(&mut self, frames: u32) {
+        self.stats.concealed += u64::from(frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(sequence: u16) -> Packet {
+        Packet {
+            sequence,
+            timestamp: u32::from(sequence) * 352,
+            payload: vec![sequence as u8],
+        }
+    }
+
+    fn drain(buffer: &mut JitterBuffer) -> Vec<Released> {
+        std::iter::from_fn(|| buffer.pop()).collect()
+    }
+
+    #[test]
+    fn reorders_and_reports_gaps() {
+        let mut buffer = JitterBuffer::new(352, 64);
+
+        for sequence in [10, 12, 11, 15, 14] {
+            assert!(buffer.insert(packet(sequence)));
+        }
+
+        assert!(!buffer.insert(packet(12)));
+        assert!(buffer.has_gaps());
+
+        let now = Instant::now();
+        assert_eq!(buffer.missing(now, Duration::from_millis(100)), vec![(13, 1)]);
+        assert!(buffer.missing(now, Duration::from_millis(100)).is_empty());
+        assert_eq!(buffer.missing(now + Duration::from_millis(100), Duration::from_millis(100)), vec![(13, 1)]);
+
+        assert_eq!(buffer.next_timestamp(), Some(10 * 352));
+        assert_eq!(drain(&mut buffer), vec![
+            Released::Packet(packet(10)),
+            Released::Packet(packet(11)),
+            Released::Packet(packet(12)),
+            Released::Lost { sequence: 13, timestamp: 13 * 352 },
+            Released::Packet(packet(14)),
+            Released::Packet(packet(15)),
+        ]);
+
+        assert!(!buffer.insert(packet(13)));
+
+        let stats = buffer.stats();
+        assert_eq!((stats.received, stats.duplicates, stats.lost, stats.late), (7, 1, 1, 1));
+        assert_eq!((stats.retransmit_requests, stats.depth), (2, 0));
+    }
+
+    #[test]
+    fn counts_recovered_packets() {
+        let mut buffer = JitterBuffer::new(352, 64);
+        buffer.insert(packet(1));
+        buffer.insert(packet(4));
+
+        assert_eq!(buffer.missing(Instant::now(), Duration::from_millis(100)), vec![(2, 2)]);
+        buffer.insert(packet(3));
+        buffer.insert(packet(2));
+
+        assert!(!buffer.has_gaps());
+        assert_eq!(buffer.stats().recovered, 2);
+        assert_eq!(buffer.stats().depth, 4);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut buffer = JitterBuffer::new(352, 64);
+
+        for sequence in [65534, 0, 65535, 1] {
+            assert!(buffer.insert(packet(sequence)));
+        }
+
+        let order: Vec<_> = drain(&mut buffer).into_iter().map(|x| match x {
+            Released::Packet(x) => x.sequence,
+            Released::Lost { sequence, .. } => panic!("{} lost", sequence),
+        }).collect();
+
+        assert_eq!(order, vec![65534, 65535, 0, 1]);
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest() {
+        let mut buffer = JitterBuffer::new(352, 4);
+
+        for sequence in 0..6 {
+            buffer.insert(packet(sequence));
+        }
+
+        assert_eq!(buffer.stats().overflow, 2);
+        assert_eq!(buffer.pop(), Some(Released::Packet(packet(2))));
+    }
+
+    #[test]
+    fn flush_drops_stale_packets() {
+        let mut buffer = JitterBuffer::new(352, 64);
+
+        for sequence in 0..4 {
+            buffer.insert(packet(sequence));
+        }
+
+        buffer.flush(Some(2));
+        assert_eq!(buffer.pop(), Some(Released::Packet(packet(2))));
+
+        buffer.flush(Some(100));
+        assert!(buffer.is_empty());
+        assert!(!buffer.insert(packet(5)));
+        assert!(buffer.insert(packet(100)));
+        assert_eq!(buffer.stats().late, 0);
+
+        buffer.flush(None);
+        assert!(buffer.is_empty());
+        assert!(buffer.insert(packet(7)));
+    }
+}
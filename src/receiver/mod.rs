@@ -0,0 +1,865 @@
+//! The receiving side of AirPlay 2: an [`crate::rtsp::server::Server`] whose connections each run the receiver state
+//! machine, from `/info` and pairing through SETUP and RECORD to TEARDOWN.
+//!
+//! Audio arrives as realtime RTP streams over UDP. Packets are handed out as they arrive through
+//! [`Receiver::take_audio`], in arrival order, decrypted but still encoded. A [`Player`] puts them back in
+//! order, decodes them and writes them to an [`crate::audio::Sink`] when they are due.
+
+use std::{fmt, io::Cursor, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::{atomic::{AtomicU16, Ordering}, Arc, Mutex}, time::Duration};
+
+use tokio::{net::{TcpListener, ToSocketAddrs, UdpSocket}, sync::{broadcast, mpsc}, task::JoinHandle};
+
+mod jitter;
+mod player;
+
+pub use jitter::{JitterBuffer, Packet, Released, Stats};
+pub use player::{Player, PlayerConfig};
+
+use crate::{audio::{Codec, StreamFormat}, dmap::TrackMetadata, mdns::{Features, Metadata}, pairing::{self, Identity, Peer, SessionKeys, SetupServer, Tlv8, VerifyServer}, rtp, rtsp::{ops::{AudioLatency, DeviceInfo, Progress, SetupInfoRequest, SetupStreamsRequest, SetupStreamsResponse, StreamDescription, StreamInfo, SupportedFormats, STREAM_TYPE_REALTIME}, server::{Answer, Handler, Server}, Body, Method, Request, Response}};
+
+/// Audio, ALAC, unified media control, PTP, HomeKit and transient pairing.
+pub const DEFAULT_FEATURES: u64 = 1 << 9 | 1 << 18 | 1 << 38 | 1 << 41 | 1 << 46 | 1 << 48;
+
+/// How many [`Audio`] messages wait for [`Receiver::take_audio`] before new ones are dropped.
+const AUDIO_QUEUE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub name: String,
+    /// Also the identifier used in pairing.
+    pub device_id: String,
+    pub model: String,
+    pub features: u64,
+    pub source_version: String,
+    /// `audioFormat` bits accepted for realtime streams.
+    pub audio_formats: u64,
+    /// Answered in `Audio-Latency` on RECORD, in frames.
+    pub audio_latency: u32,
+    pub output_latency: Duration,
+    /// PIN accepted by a full pair-setup. Only transient pairing is possible without one.
+    pub pin: Option<String>,
+    /// Refuses SETUP and the requests of a session with `470 Connection Authorization Required` until
+    /// the connection is encrypted.
+    pub require_pairing: bool,
+}
+
+impl Config {
+    pub fn new(name: impl ToString, device_id: impl ToString) -> Config {
+        Config {
+            name: name.to_string(),
+            device_id: device_id.to_string(),
+            model: "Receiver1,1".to_string(),
+            features: DEFAULT_FEATURES,
+            source_version: "366.0".to_string(),
+            audio_formats: StreamFormat::all()
+                .filter(|x| matches!(x.codec, Codec::Pcm | Codec::Alac))
+                .fold(0, |mask, x| mask | x.audio_format()),
+            audio_latency: 11025,
+            output_latency: Duration::ZERO,
+            pin: None,
+            require_pairing: true,
+        }
+    }
+
+    fn info(&self, identity: &Identity) -> plist::Value {
+        let info = DeviceInfo {
+            audio_latencies: Some(vec![AudioLatency {
+                audio_type: Some("default".to_string()),
+                input_latency_micros: Some(0),
+                output_latency_micros: Some(self.output_latency.as_micros() as u64),
+                stream_type: Some(STREAM_TYPE_REALTIME),
+            }]),
+            device_id: Some(self.device_id.clone()),
+            features: Some(self.features as i64),
+            model: Some(self.model.clone()),
+            name: Some(self.name.clone()),
+            source_version: Some(self.source_version.clone()),
+            status_flags: Some(4),
+            supported_formats: Some(SupportedFormats {
+                audio_stream: Some(self.audio_formats),
+                ..Default::default()
+            }),
+        };
+
+        let mut info = plist::to_value(&info).unwrap();
+
+        if let Some(x) = info.as_dictionary_mut() {
+            x.insert("pk".to_string(), plist::Value::Data(identity.public_key().to_vec()));
+        }
+
+        info
+    }
+}
+
+/// Where a connection is in the receiver state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Connected,
+    /// Pair-verify or a transient pair-setup succeeded and the connection is encrypted.
+    Paired,
+    /// The session SETUP succeeded.
+    SessionSetup,
+    /// Audio streams are SET UP and their ports open.
+    StreamsSetup,
+    Recording,
+}
+
+/// An audio stream SET UP by the sender.
+#[derive(Debug, Clone)]
+pub struct Stream {
+    pub id: u64,
+    pub stream_type: u32,
+    /// The format selected by `audioFormat`.
+    pub format: StreamFormat,
+    /// Frames per packet.
+    pub frames_per_packet: u32,
+    pub data_port: u16,
+    pub control_port: u16,
+    pub retransmit: Retransmit,
+}
+
+impl Stream {
+    fn new(id: u64, description: &StreamDescription, format: StreamFormat, data_port: u16, control_port: u16, retransmit: Retransmit) -> Stream {
+        Stream {
+            id,
+            stream_type: description.stream_type,
+            format,
+            frames_per_packet: description.spf,
+            data_port,
+            control_port,
+            retransmit,
+        }
+    }
+}
+
+/// Asks the sender of a stream to send packets again, from the stream's control port.
+#[derive(Clone)]
+pub struct Retransmit {
+    socket: Arc<UdpSocket>,
+    /// Where sync packets last came from, or the control port given in SETUP until one has.
+    sender: Arc<Mutex<Option<SocketAddr>>>,
+    sequence: Arc<AtomicU16>,
+}
+
+impl Retransmit {
+    /// Requests `count` packets from sequence number `first` on. Does nothing while the sender's control
+    /// address is unknown.
+    pub async fn request(&self, first: u16, count: u16) -> std::io::Result<()> {
+        let Some(sender) = *self.sender.lock().unwrap() else { return Ok(()) };
+
+        let request = rtp::RetransmitRequest {
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            first,
+            count,
+        };
+
+        self.socket.send_to(&request.to_bytes(), sender).await?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Retransmit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Retransmit").field("sender", &*self.sender.lock().unwrap()).finish()
+    }
+}
+
+/// What arrives from the sender, for whatever plays the audio.
+#[derive(Debug, Clone)]
+pub enum Audio {
+    /// A stream was SET UP and its packets follow.
+    Started(Stream),
+    /// An RTP packet, in arrival order and decrypted with the stream's `shk`. Retransmitted packets show up
+    /// here too.
+    Packet { stream: u64, header: rtp::Header, payload: Vec<u8> },
+    /// A sync packet from the control port.
+    Sync { stream: u64, sync: rtp::Sync },
+    /// FLUSH: audio before `seq` and `rtptime` is to be dropped, or everything without them.
+    Flush { seq: Option<u16>, rtptime: Option<u32> },
+    /// TEARDOWN, or the sender went away.
+    Stopped { stream: u64 },
+}
+
+/// Cover art of the track playing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artwork {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// What the sender says about playback through SET_PARAMETER, for showing what the receiver plays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReceiverEvent {
+    Metadata(TrackMetadata),
+    Progress(Progress),
+    /// `None` once the sender clears it.
+    Artwork(Option<Artwork>),
+    /// In dB from -30 to 0, -144 when muted.
+    Volume(f32),
+}
+
+/// The sender's session, as SET UP.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub peer: SocketAddr,
+    pub info: SetupInfoRequest,
+    pub state: State,
+    pub streams: Vec<Stream>,
+}
+
+struct Shared {
+    config: Config,
+    identity: Identity,
+    pairings: Mutex<Vec<Peer>>,
+    session: Mutex<Option<(u64, Session)>>,
+    volume: Mutex<Option<f32>>,
+    audio: mpsc::Sender<Audio>,
+    audio_rx: Mutex<Option<mpsc::Receiver<Audio>>>,
+    events: broadcast::Sender<ReceiverEvent>,
+    connections: Mutex<u64>,
+}
+
+impl Shared {
+    fn send(&self, audio: Audio) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.audio.try_send(audio) {
+            tracing::debug!("Dropping audio, nobody is reading it");
+        }
+    }
+
+    fn emit(&self, event: ReceiverEvent) {
+        tracing::debug!(?event, "receiver");
+        let _ = self.events.send(event);
+    }
+}
+
+/// An AirPlay 2 receiver.
+///
+/// Plays one session at a time: while a sender's session is SET UP, others are answered with
+/// `453 Not Enough Bandwidth`.
+pub struct Receiver {
+    server: Server,
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// Listens for senders on `addr`. `identity` is what senders pair with, so it should be kept along
+    /// with [`Receiver::pairings`] for them to pair-verify later.
+    pub async fn bind<A: ToSocketAddrs>(addr: A, config: Config, identity: Identity) -> std::io::Result<Receiver> {
+        let (audio, audio_rx) = mpsc::channel(AUDIO_QUEUE);
+
+        let shared = Arc::new(Shared {
+            config,
+            identity,
+            pairings: Default::default(),
+            session: Default::default(),
+            volume: Default::default(),
+            audio,
+            audio_rx: Mutex::new(Some(audio_rx)),
+            events: broadcast::channel(32).0,
+            connections: Default::default(),
+        });
+
+        let server = Server::bind(addr, {
+            let shared = shared.clone();
+
+            move |peer| {
+                let mut connections = shared.connections.lock().unwrap();
+                *connections += 1;
+                Connection::new(*connections, peer, shared.clone())
+            }
+        }).await?;
+
+        Ok(Receiver { server, shared })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.shared.config
+    }
+
+    /// What to advertise with [`crate::mdns::Advertiser`] for senders to find us.
+    pub fn metadata(&self) -> Metadata {
+        let config = &self.shared.config;
+        let ip = self.local_addr().ip();
+
+        Metadata {
+            name: config.name.clone(),
+            ip_addresses: if ip.is_unspecified() { Vec::new() } else { vec![ip] },
+            port: self.local_addr().port(),
+            device_id: Some(config.device_id.clone()),
+            features: Some(Features::from(config.features)),
+            flags: Some(4),
+            model: Some(config.model.clone()),
+            protocol_version: Some("1.1".to_string()),
+            public_airplay_pairing_identity: Some(config.device_id.clone()),
+            public_key: Some(self.shared.identity.public_key().to_vec()),
+            airplay_version: Some(config.source_version.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Our identity, as senders learn it from pair-setup.
+    pub fn peer(&self) -> Peer {
+        Peer {
+            id: self.shared.identity.id.clone(),
+            public_key: self.shared.identity.public_key(),
+        }
+    }
+
+    /// Senders that completed a full pair-setup, or were added.
+    pub fn pairings(&self) -> Vec<Peer> {
+        self.shared.pairings.lock().unwrap().clone()
+    }
+
+    /// Allows `peer` to pair-verify, e.g. a pairing persisted earlier.
+    pub fn add_pairing(&self, peer: Peer) {
+        let mut pairings = self.shared.pairings.lock().unwrap();
+        pairings.retain(|x| x.id != peer.id);
+        pairings.push(peer);
+    }
+
+    pub fn remove_pairing(&self, id: &str) {
+        self.shared.pairings.lock().unwrap().retain(|x| x.id != id);
+    }
+
+    /// The current session, if any sender has one.
+    pub fn session(&self) -> Option<Session> {
+        self.shared.session.lock().unwrap().as_ref().map(|(_, x)| x.clone())
+    }
+
+    /// The last volume the sender set, in dB from -30 to 0, or -144 for muted.
+    pub fn volume(&self) -> Option<f32> {
+        *self.shared.volume.lock().unwrap()
+    }
+
+    /// The audio of every session. Can be taken once.
+    pub fn take_audio(&self) -> Option<mpsc::Receiver<Audio>> {
+        self.shared.audio_rx.lock().unwrap().take()
+    }
+
+    /// Metadata, progress, artwork and volume changes of every session.
+    pub fn subscribe(&self) -> broadcast::Receiver<ReceiverEvent> {
+        self.shared.events.subscribe()
+    }
+}
+
+/// The open ports of one stream. Dropping it closes them.
+struct StreamPorts {
+    stream: Stream,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Drop for StreamPorts {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Default)]
+enum Pairing {
+    #[default]
+    None,
+    Setup(SetupServer),
+    Verify(VerifyServer),
+}
+
+/// The state machine of one sender connection.
+struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    shared: Arc<Shared>,
+    state: State,
+    pairing: Pairing,
+    info: Option<SetupInfoRequest>,
+    event: Option<JoinHandle<()>>,
+    streams: Vec<StreamPorts>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.end_session();
+    }
+}
+
+/// Setup bodies come as binary plists, but senders leave out the content type on the first SETUP.
+fn plist_body(body: &Body) -> Option<plist::Value> {
+    match body {
+        Body::PList(x) => Some(x.clone()),
+        Body::Raw(x) => plist::Value::from_reader(Cursor::new(x)).ok(),
+        Body::None => None,
+    }
+}
+
+fn tlv_response(message: Tlv8) -> Response {
+    let mut res = Response::new_body(200, "OK", Body::Raw(message.to_bytes()));
+    res.headers.set("Content-Type", "application/octet-stream");
+    res
+}
+
+fn plist_response(value: plist::Value) -> Response {
+    Response::new_body(200, "OK", Body::PList(value))
+}
+
+impl Handler for Connection {
+    async fn handle(&mut self, request: Request) -> Answer {
+        let mut answer = self.answer(&request).await;
+        answer.response.headers.set("Server", format!("AirTunes/{}", self.shared.config.source_version));
+        answer
+    }
+
+    fn closed(&mut self) {
+        self.end_session();
+    }
+}
+
+impl Connection {
+    fn new(id: u64, peer: SocketAddr, shared: Arc<Shared>) -> Connection {
+        Connection {
+            id,
+            peer,
+            shared,
+            state: State::Connected,
+            pairing: Pairing::None,
+            info: None,
+            event: None,
+            streams: Vec::new(),
+        }
+    }
+
+    async fn answer(&mut self, request: &Request) -> Answer {
+        let path = match request.path.strip_prefix("rtsp://") {
+            Some(x) => x.find('/').map_or("/", |i| &x[i..]),
+            None => &request.path,
+        };
+
+        match (&request.method, path) {
+            (Method::GET, "/info") => plist_response(self.shared.config.info(&self.shared.identity)).into(),
+            (Method::POST, "/pair-setup") => self.pair_setup(request),
+            (Method::POST, "/pair-verify") => self.pair_verify(request),
+            (Method::POST, "/feedback") => Response::new(200, "OK").into(),
+            (Method::SETUP, _) => self.setup(request).await.into(),
+            (Method::SETPEERS, _) => self.require_session(|_| Response::new(200, "OK")).into(),
+            (Method::RECORD, _) => self.record().into(),
+            (Method::SET_PARAMETER, _) => self.require_session(|this| this.set_parameter(request)).into(),
+            (Method::FLUSH, _) => self.flush(request).into(),
+            (Method::TEARDOWN, _) => self.teardown(request).into(),
+            _ => Response::new(501, "Not Implemented").into(),
+        }
+    }
+
+    /// Ports for local senders stay local.
+    fn bind_ip(&self) -> IpAddr {
+        if self.peer.ip().to_canonical().is_loopback() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        }
+    }
+
+    fn is_paired(&self) -> bool {
+        self.state != State::Connected || !self.shared.config.require_pairing
+    }
+
+    fn pair_setup(&mut self, request: &Request) -> Answer {
+        let Some(message) = Connection::tlv(request) else {
+            return Response::new(400, "Bad Request").into();
+        };
+
+        if message.byte(pairing::tlv::STATE) == Some(1) {
+            let transient = message.byte(pairing::tlv::FLAGS).is_some_and(|x| x & pairing::tlv::FLAG_TRANSIENT != 0);
+
+            let pin = match (&self.shared.config.pin, transient) {
+                (_, true) => pairing::TRANSIENT_PIN,
+                (Some(x), false) => x.as_str(),
+                (None, false) => {
+                    let answer = Tlv8::new().with(pairing::tlv::STATE, [2]).with(pairing::tlv::ERROR, [pairing::tlv::ERROR_UNAVAILABLE]);
+                    return tlv_response(answer).into();
+                },
+            };
+
+            self.pairing = Pairing::Setup(SetupServer::new(self.shared.identity.clone(), pin));
+        }
+
+        let Pairing::Setup(setup) = &mut self.pairing else {
+            return Response::new(470, "Connection Authorization Required").into();
+        };
+
+        let answer = setup.handle(&message);
+        let succeeded = answer.get(pairing::tlv::ERROR).is_none();
+        let mut keys = None;
+
+        match answer.byte(pairing::tlv::STATE) {
+            Some(4) if succeeded && setup.is_transient() => keys = setup.session_keys(),
+            Some(6) if succeeded => {
+                if let Some(peer) = setup.peer() {
+                    tracing::info!(peer = %self.peer, id = %peer.id, "Paired with sender");
+                    let mut pairings = self.shared.pairings.lock().unwrap();
+                    pairings.retain(|x| x.id != peer.id);
+                    pairings.push(peer.clone());
+                }
+            },
+            _ => {},
+        }
+
+        self.encrypt(tlv_response(answer), keys)
+    }
+
+    fn pair_verify(&mut self, request: &Request) -> Answer {
+        let Some(message) = Connection::tlv(request) else {
+            return Response::new(400, "Bad Request").into();
+        };
+
+        if message.byte(pairing::tlv::STATE) == Some(1) {
+            let shared = self.shared.clone();
+
+            self.pairing = Pairing::Verify(VerifyServer::new(self.shared.identity.clone(), move |id| {
+                shared.pairings.lock().unwrap().iter().find(|x| x.id == id).cloned()
+            }));
+        }
+
+        let Pairing::Verify(verify) = &mut self.pairing else {
+            return Response::new(470, "Connection Authorization Required").into();
+        };
+
+        let answer = verify.handle(&message);
+        let keys = verify.session_keys();
+        self.encrypt(tlv_response(answer), keys)
+    }
+
+    fn encrypt(&mut self, response: Response, keys: Option<SessionKeys>) -> Answer {
+        if keys.is_some() {
+            self.pairing = Pairing::None;
+            self.state = self.state.max(State::Paired);
+        }
+
+        Answer { response, keys }
+    }
+
+    fn tlv(request: &Request) -> Option<Tlv8> {
+        match &request.body {
+            Body::Raw(x) => Tlv8::parse(x).ok(),
+            _ => None,
+        }
+    }
+
+    fn require_session(&mut self, f: impl FnOnce(&mut Connection) -> Response) -> Response {
+        if !self.is_paired() {
+            return Response::new(470, "Connection Authorization Required");
+        }
+
+        match self.state {
+            State::Connected | State::Paired => Response::new(455, "Method Not Valid in This State"),
+            _ => f(self),
+        }
+    }
+
+    async fn setup(&mut self, request: &Request) -> Response {
+        if !self.is_paired() {
+            return Response::new(470, "Connection Authorization Required");
+        }
+
+        let Some(body) = plist_body(&request.body) else {
+            return Response::new(400, "Bad Request");
+        };
+
+        if body.as_dictionary().is_some_and(|x| x.contains_key("streams")) {
+            match plist::from_value::<SetupStreamsRequest>(&body) {
+                Ok(x) => self.setup_streams(x).await,
+                Err(_) => Response::new(400, "Bad Request"),
+            }
+        } else {
+            match plist::from_value::<SetupInfoRequest>(&body) {
+                Ok(x) => self.setup_session(x).await,
+                Err(_) => Response::new(400, "Bad Request"),
+            }
+        }
+    }
+
+    async fn setup_session(&mut self, info: SetupInfoRequest) -> Response {
+        {
+            let mut session = self.shared.session.lock().unwrap();
+
+            if session.as_ref().is_some_and(|(id, _)| *id != self.id) {
+                return Response::new(453, "Not Enough Bandwidth");
+            }
+
+            *session = Some((self.id, Session {
+                peer: self.peer,
+                info: info.clone(),
+                state: State::SessionSetup,
+                streams: Vec::new(),
+            }));
+        }
+
+        // Senders connect to the event port and expect it to stay open, nothing is sent on it.
+        let listener = match TcpListener::bind((self.bind_ip(), 0)).await {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::warn!("Failed to open event port: {}", err);
+                self.end_session();
+                return Response::new(500, "Internal Server Error");
+            },
+        };
+
+        let event_port = listener.local_addr().map(|x| x.port()).unwrap_or(0);
+
+        if let Some(x) = self.event.replace(tokio::spawn(async move {
+            let mut connections = Vec::new();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        })) {
+            x.abort();
+        }
+
+        tracing::info!(peer = %self.peer, sender = %info.name, "Session SET UP");
+        self.info = Some(info);
+        self.set_state(State::SessionSetup);
+
+        plist_response(plist::Value::Dictionary(plist::Dictionary::from_iter([
+            ("eventPort", plist::Value::from(u64::from(event_port))),
+            // Timing is PTP, or taken from sync packets, we don't serve NTP.
+            ("timingPort", plist::Value::from(0_u64)),
+        ])))
+    }
+
+    async fn setup_streams(&mut self, request: SetupStreamsRequest) -> Response {
+        if self.state == State::Connected || self.state == State::Paired {
+            return Response::new(455, "Method Not Valid in This State");
+        }
+
+        let mut answers = Vec::new();
+
+        for description in &request.streams {
+            if description.stream_type != STREAM_TYPE_REALTIME {
+                return Response::new(415, "Unsupported Media Type");
+            }
+
+            // `audioFormat` names exactly one format, which has to be one we offered.
+            let format = (description.audio_format.count_ones() == 1 && description.audio_format & self.shared.config.audio_formats != 0)
+                .then(|| StreamFormat::from_bit(description.audio_format.trailing_zeros() as u8))
+                .flatten();
+
+            let Some(format) = format else {
+                return Response::new(415, "Unsupported Media Type");
+            };
+
+            let Some(cipher) = rtp::AudioCipher::from_key(description.shk.as_ref()) else {
+                return Response::new(400, "Bad Request");
+            };
+
+            let id = self.streams.iter().map(|x| x.stream.id).max().unwrap_or(0) + 1;
+
+            let ports = match self.open(id, description, format, cipher).await {
+                Ok(x) => x,
+                Err(err) => {
+                    tracing::warn!("Failed to open stream ports: {}", err);
+                    return Response::new(500, "Internal Server Error");
+                },
+            };
+
+            answers.push(StreamInfo {
+                data_port: Some(ports.stream.data_port),
+                control_port: Some(ports.stream.control_port),
+                audio_buffer_size: None,
+                stream_id: Some(id),
+                stream_type: description.stream_type,
+            });
+
+            tracing::info!(peer = %self.peer, stream = id, format = ?ports.stream.format, "Stream SET UP");
+            self.shared.send(Audio::Started(ports.stream.clone()));
+            self.streams.push(ports);
+        }
+
+        if self.state == State::SessionSetup {
+            self.set_state(State::StreamsSetup);
+        } else {
+            self.sync_session();
+        }
+
+        plist_response(plist::to_value(&SetupStreamsResponse { streams: answers }).unwrap())
+    }
+
+    /// Opens the data and control ports of a stream, decrypting its packets with `cipher`.
+    async fn open(&self, id: u64, description: &StreamDescription, format: StreamFormat, cipher: rtp::AudioCipher) -> std::io::Result<StreamPorts> {
+        let data = UdpSocket::bind((self.bind_ip(), 0)).await?;
+        let control = Arc::new(UdpSocket::bind((self.bind_ip(), 0)).await?);
+        let sender = Arc::new(Mutex::new((description.control_port != 0).then(|| SocketAddr::new(self.peer.ip(), description.control_port))));
+        let retransmit = Retransmit { socket: control.clone(), sender: sender.clone(), sequence: Default::default() };
+        let stream = Stream::new(id, description, format, data.local_addr()?.port(), control.local_addr()?.port(), retransmit);
+
+        let data_task = tokio::spawn({
+            let shared = self.shared.clone();
+            let cipher = cipher.clone();
+
+            async move {
+                let mut buf = vec![0; 2048];
+
+                while let Ok(n) = data.recv(&mut buf).await {
+                    match cipher.open(&buf[..n]) {
+                        Some((header, payload)) => shared.send(Audio::Packet { stream: id, header, payload }),
+                        None => tracing::debug!(stream = id, "Dropping audio packet that doesn't decrypt"),
+                    }
+                }
+            }
+        });
+
+        let control_task = tokio::spawn({
+            let shared = self.shared.clone();
+
+            async move {
+                let mut buf = vec![0; 2048];
+
+                while let Ok((n, from)) = control.recv_from(&mut buf).await {
+                    let packet = &buf[..n];
+
+                    if let Some(sync) = rtp::Sync::parse(packet) {
+                        *sender.lock().unwrap() = Some(from);
+                        shared.send(Audio::Sync { stream: id, sync });
+                    } else if packet.len() > 4 && packet[1] & 0x7f == rtp::PAYLOAD_TYPE_RETRANSMIT_RESPONSE {
+                        if let Some((header, payload)) = cipher.open(&packet[4..]) {
+                            shared.send(Audio::Packet { stream: id, header, payload });
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(StreamPorts { stream, tasks: [data_task, control_task] })
+    }
+
+    fn record(&mut self) -> Response {
+        match self.state {
+            State::StreamsSetup | State::Recording => {
+                self.set_state(State::Recording);
+                let mut res = Response::new(200, "OK");
+                res.headers.set("Audio-Latency", self.shared.config.audio_latency);
+                res
+            },
+            _ => Response::new(455, "Method Not Valid in This State"),
+        }
+    }
+
+    fn set_parameter(&mut self, request: &Request) -> Response {
+        let body = match &request.body {
+            Body::Raw(x) => x.as_slice(),
+            Body::None => &[],
+            Body::PList(_) => return Response::new(200, "OK"),
+        };
+
+        match request.headers.content_type().unwrap_or("text/parameters").to_ascii_lowercase().as_str() {
+            "application/x-dmap-tagged" => match TrackMetadata::from_dmap(body) {
+                Ok(x) => self.shared.emit(ReceiverEvent::Metadata(x)),
+                Err(err) => {
+                    tracing::warn!(peer = %self.peer, "Bad track metadata: {}", err);
+                    return Response::new(400, "Bad Request");
+                },
+            },
+            "image/none" => self.shared.emit(ReceiverEvent::Artwork(None)),
+            x if x.starts_with("image/") => {
+                let artwork = (!body.is_empty()).then(|| Artwork { content_type: x.to_string(), data: body.to_vec() });
+                self.shared.emit(ReceiverEvent::Artwork(artwork));
+            },
+            _ => {
+                for line in String::from_utf8_lossy(body).lines() {
+                    match line.split_once(':') {
+                        Some(("volume", x)) => if let Ok(volume) = x.trim().parse::<f32>() {
+                            *self.shared.volume.lock().unwrap() = Some(volume);
+                            self.shared.emit(ReceiverEvent::Volume(volume));
+                        },
+                        Some(("progress", x)) => if let Some(progress) = Progress::parse(x) {
+                            self.shared.emit(ReceiverEvent::Progress(progress));
+                        },
+                        _ => {},
+                    }
+                }
+            },
+        }
+
+        Response::new(200, "OK")
+    }
+
+    fn flush(&mut self, request: &Request) -> Response {
+        self.require_session(|this| {
+            let mut seq = None;
+            let mut rtptime = None;
+
+            for part in request.headers.get("RTP-Info").unwrap_or_default().split(';') {
+                match part.trim().split_once('=') {
+                    Some(("seq", x)) => seq = x.parse().ok(),
+                    Some(("rtptime", x)) => rtptime = x.parse().ok(),
+                    _ => {},
+                }
+            }
+
+            this.shared.send(Audio::Flush { seq, rtptime });
+            Response::new(200, "OK")
+        })
+    }
+
+    /// With a `streams` body only the streams are torn down and the session stays, otherwise both go.
+    fn teardown(&mut self, request: &Request) -> Response {
+        self.require_session(|this| {
+            let streams_only = plist_body(&request.body)
+                .is_some_and(|x| x.as_dictionary().is_some_and(|x| x.contains_key("streams")));
+
+            this.close_streams();
+
+            if streams_only {
+                this.set_state(State::SessionSetup);
+            } else {
+                this.end_session();
+            }
+
+            Response::new(200, "OK")
+        })
+    }
+
+    fn close_streams(&mut self) {
+        for ports in self.streams.drain(..) {
+            self.shared.send(Audio::Stopped { stream: ports.stream.id });
+        }
+    }
+
+    fn end_session(&mut self) {
+        self.close_streams();
+
+        if let Some(x) = self.event.take() {
+            x.abort();
+        }
+
+        if self.info.take().is_some() {
+            tracing::info!(peer = %self.peer, "Session ended");
+        }
+
+        let mut session = self.shared.session.lock().unwrap();
+
+        if session.as_ref().is_some_and(|(id, _)| *id == self.id) {
+            *session = None;
+        }
+
+        drop(session);
+        self.state = self.state.min(State::Paired);
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.sync_session();
+    }
+
+    /// Mirrors our state into [`Receiver::session`].
+    fn sync_session(&self) {
+        if let Some((id, session)) = self.shared.session.lock().unwrap().as_mut() {
+            if *id == self.id {
+                session.state = self.state;
+                session.streams = self.streams.iter().map(|x| x.stream.clone()).collect();
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{io, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use tokio::{sync::{mpsc, watch}, time};
+
+use crate::{alac, audio::{Codec, Format, Sink, Timing}, group::Clock, rtp::NtpTime};
+
+use super::{jitter::{JitterBuffer, Packet, Released, Stats}, Audio, Stream};
+
+#[derive(Debug, Clone)]
+pub struct PlayerConfig {
+    /// How long after it arrives the first packet plays, until a sync packet anchors the stream.
+    pub latency: Duration,
+    /// How far ahead of being heard audio is written to the sink, to cover the sink's own buffering.
+    pub lead: Duration,
+    /// Most packets held at once.
+    pub capacity: usize,
+    /// How long before a missing packet is asked for again.
+    pub retransmit_interval: Duration,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> PlayerConfig {
+        PlayerConfig {
+            latency: Duration::from_millis(250),
+            lead: Duration::ZERO,
+            capacity: 1024,
+            retransmit_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+enum Decoder {
+    /// Big-endian samples, as RTP carries linear PCM.
+    Pcm { bytes: usize },
+    Alac(alac::Decoder),
+}
+
+impl Decoder {
+    fn new(stream: &Stream) -> Option<Decoder> {
+        let format = stream.format;
+
+        match format.codec {
+            Codec::Pcm => Some(Decoder::Pcm { bytes: format.format.bytes_per_sample() }),
+            Codec::Alac => {
+                let Format { sample_rate, channels, bit_depth } = format.format;
+                let config = alac::Config::new(stream.frames_per_packet, sample_rate, bit_depth, channels).ok()?;
+                Some(Decoder::Alac(alac::Decoder::new(config)))
+            },
+            _ => None,
+        }
+    }
+
+    fn decode(&mut self, payload: &[u8]) -> Option<Vec<i32>> {
+        match self {
+            Self::Pcm { bytes } => Some(payload.chunks_exact(*bytes).map(|x| {
+                let mut sample = [0; 4];
+                sample[..x.len()].copy_from_slice(x);
+                i32::from_be_bytes(sample) >> (32 - 8 * x.len())
+            }).collect()),
+            Self::Alac(decoder) => decoder.decode(payload).ok(),
+        }
+    }
+}
+
+struct Playing {
+    stream: Stream,
+    format: Format,
+    decoder: Decoder,
+    buffer: JitterBuffer,
+    clock: Option<Clock>,
+    /// Whether `clock` comes from a sync packet rather than the arrival of the first packet.
+    synced: bool,
+    /// The last frames played, faded out in place of a lost packet.
+    last: Vec<i32>,
+}
+
+impl Playing {
+    fn conceal(&mut self) -> Vec<i32> {
+        let frames = self.buffer.frames_per_packet();
+        let channels = usize::from(self.format.channels);
+        self.buffer.conceal(frames);
+
+        let mut samples = std::mem::take(&mut self.last);
+        samples.resize(frames as usize * channels, 0);
+
+        for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+            let gain = 1.0 - i as f32 / frames as f32;
+            frame.iter_mut().for_each(|x| *x = (*x as f32 * gain) as i32);
+        }
+
+        self.last = vec![0; samples.len()];
+        samples
+    }
+}
+
+/// Local time of `time` on the sender's clock, taking the two clocks to agree as NTP or PTP keeps them.
+fn local_instant(time: NtpTime) -> Instant {
+    let now = Instant::now();
+    let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let time = time.as_duration_since_unix();
+
+    if time >= since_unix {
+        now + (time - since_unix)
+    } else {
+        now.checked_sub(since_unix - time).unwrap_or(now)
+    }
+}
+
+/// A clock on which `rtp_origin` is heard at `origin`, which may be in the past or the future.
+fn anchored(sample_rate: u32, rtp_origin: u32, origin: Instant) -> Clock {
+    let now = Instant::now();
+    let origin_time = if origin >= now {
+        SystemTime::now() + (origin - now)
+    } else {
+        SystemTime::now() - (now - origin)
+    };
+
+    Clock { origin, origin_time, sample_rate, rtp_origin }
+}
+
+/// Plays what a [`super::Receiver`] receives into a [`Sink`].
+///
+/// Packets go through a [`JitterBuffer`] and are decoded and written once they are due, according to the
+/// latest sync packet. Missing packets are asked for again over the control channel while there is time,
+/// and faded out from the previous packet when they don't make it.
+pub struct Player<S> {
+    audio: mpsc::Receiver<Audio>,
+    sink: S,
+    config: PlayerConfig,
+    stats: watch::Sender<Stats>,
+    playing: Option<Playing>,
+}
+
+impl<S: Sink + Send> Player<S> {
+    pub fn new(audio: mpsc::Receiver<Audio>, sink: S, config: PlayerConfig) -> Player<S> {
+        Player {
+            audio,
+            sink,
+            config,
+            stats: watch::channel(Stats::default()).0,
+            playing: None,
+        }
+    }
+
+    /// Stats of the stream playing, updated as it plays.
+    pub fn stats(&self) -> watch::Receiver<Stats> {
+        self.stats.subscribe()
+    }
+
+    /// Plays until the receiver goes away, then hands the sink back.
+    pub async fn run(mut self) -> io::Result<S> {
+        loop {
+            let wake = self.wake();
+
+            tokio::select! {
+                audio = self.audio.recv() => match audio {
+                    Some(x) => self.handle(x).await?,
+                    None => break,
+                },
+                _ = time::sleep_until(wake.unwrap_or_else(Instant::now).into()), if wake.is_some() => {},
+            }
+
+            self.play_due().await?;
+            self.request_missing().await;
+
+            if let Some(playing) = &self.playing {
+                self.stats.send_replace(playing.buffer.stats());
+            }
+        }
+
+        self.stop().await?;
+        Ok(self.sink)
+    }
+
+    /// When the next packet is due, or missing ones should be asked for again.
+    fn wake(&self) -> Option<Instant> {
+        let playing = self.playing.as_ref()?;
+        let due = playing.clock.zip(playing.buffer.next_timestamp())
+            .map(|(clock, timestamp)| clock.instant_of(timestamp).checked_sub(self.config.lead).unwrap_or_else(Instant::now));
+        let retransmit = playing.buffer.has_gaps().then(|| Instant::now() + self.config.retransmit_interval);
+
+        due.into_iter().chain(retransmit).min()
+    }
+
+    fn playing_mut(&mut self, stream: u64) -> Option<&mut Playing> {
+        self.playing.as_mut().filter(|x| x.stream.id == stream)
+    }
+
+    async fn handle(&mut self, audio: Audio) -> io::Result<()> {
+        match audio {
+            Audio::Started(stream) => self.start(stream).await?,
+            Audio::Packet { stream, header, payload } => {
+                let latency = self.config.latency;
+                let Some(playing) = self.playing_mut(stream) else { return Ok(()) };
+
+                if playing.clock.is_none() {
+                    let at = Instant::now() + latency;
+                    playing.clock = Some(anchored(playing.format.sample_rate, header.timestamp, at));
+                }
+
+                playing.buffer.insert(Packet { sequence: header.sequence, timestamp: header.timestamp, payload });
+            },
+            Audio::Sync { stream, sync } => {
+                let Some(playing) = self.playing_mut(stream) else { return Ok(()) };
+                playing.clock = Some(anchored(playing.format.sample_rate, sync.timestamp, local_instant(sync.time)));
+                playing.synced = true;
+            },
+            Audio::Flush { seq, .. } => {
+                let Some(playing) = self.playing.as_mut() else { return Ok(()) };
+                playing.buffer.flush(seq);
+
+                if !playing.synced {
+                    playing.clock = None;
+                }
+            },
+            Audio::Stopped { stream } => {
+                if self.playing_mut(stream).is_some() {
+                    self.stop().await?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    async fn start(&mut self, stream: Stream) -> io::Result<()> {
+        self.stop().await?;
+
+        let format = stream.format;
+
+        let Some(decoder) = Decoder::new(&stream) else {
+            tracing::warn!(stream = stream.id, format = ?stream.format, "Can't play stream format");
+            return Ok(());
+        };
+
+        self.sink.start(format.format).await?;
+        self.stats.send_replace(Stats::default());
+        self.playing = Some(Playing {
+            buffer: JitterBuffer::new(stream.frames_per_packet, self.config.capacity),
+            stream,
+            format: format.format,
+            decoder,
+            clock: None,
+            synced: false,
+            last: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> io::Result<()> {
+        if self.playing.take().is_some() {
+            self.sink.finish().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn play_due(&mut self) -> io::Result<()> {
+        let Some(playing) = self.playing.as_mut() else { return Ok(()) };
+        let Some(clock) = playing.clock else { return Ok(()) };
+        let horizon = Instant::now() + self.config.lead;
+
+        while let Some(timestamp) = playing.buffer.next_timestamp() {
+            let at = clock.instant_of(timestamp);
+
+            if at > horizon {
+                break;
+            }
+
+            let samples = match playing.buffer.pop() {
+                Some(Released::Packet(packet)) => match playing.decoder.decode(&packet.payload) {
+                    Some(x) => {
+                        playing.last.clone_from(&x);
+                        x
+                    },
+                    None => {
+                        tracing::debug!(stream = playing.stream.id, sequence = packet.sequence, "Failed to decode packet");
+                        playing.conceal()
+                    },
+                },
+                Some(Released::Lost { .. }) => playing.conceal(),
+                None => break,
+            };
+
+            let timing = Timing { rtp: timestamp, at: playing.synced.then_some(at) };
+            self.sink.write(&samples, timing).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn request_missing(&mut self) {
+        let Some(playing) = self.playing.as_mut() else { return };
+
+        for (first, count) in playing.buffer.missing(Instant::now(), self.config.retransmit_interval) {
+            if let Err(err) = playing.stream.retransmit.request(first, count).await {
+                tracing::debug!(stream = playing.stream.id, "Failed to request retransmit: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchored_clocks_hear_their_origin_at_the_anchor() {
+        let now = Instant::now();
+
+        for at in [now - Duration::from_secs(3), now + Duration::from_secs(3)] {
+            let clock = anchored(48000, 0xfffff000, at);
+            assert_eq!(clock.instant_of(0xfffff000), at);
+            assert_eq!(clock.instant_of(0xfffff000_u32.wrapping_add(48000)), at + Duration::from_secs(1));
+            assert_eq!(clock.rtp_at(at), 0xfffff000);
+        }
+    }
+}
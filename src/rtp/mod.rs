@@ -0,0 +1,359 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, KeyInit};
+
+pub const PAYLOAD_TYPE_AUDIO: u8 = 0x60;
+pub const PAYLOAD_TYPE_TIMING_REQUEST: u8 = 0x52;
+pub const PAYLOAD_TYPE_TIMING_RESPONSE: u8 = 0x53;
+pub const PAYLOAD_TYPE_SYNC: u8 = 0x54;
+pub const PAYLOAD_TYPE_RETRANSMIT_REQUEST: u8 = 0x55;
+pub const PAYLOAD_TYPE_RETRANSMIT_RESPONSE: u8 = 0x56;
+
+pub const HEADER_LEN: usize = 12;
+
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub marker: bool,
+    pub extension: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl Header {
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(0x80 | if self.extension { 0x10 } else { 0 });
+        buf.push(self.payload_type & 0x7f | if self.marker { 0x80 } else { 0 });
+        buf.extend(self.sequence.to_be_bytes());
+        buf.extend(self.timestamp.to_be_bytes());
+        buf.extend(self.ssrc.to_be_bytes());
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] >> 6 != 2 {
+            return None;
+        }
+
+        let header = Header {
+            marker: packet[1] & 0x80 != 0,
+            extension: packet[0] & 0x10 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+
+        Some((header, &packet[HEADER_LEN..]))
+    }
+}
+
+/// ChaCha20-Poly1305 over the payload of realtime AirPlay 2 audio packets, keyed with the `shk` the
+/// stream was SET UP with.
+///
+/// The ciphertext is followed by the tag and the last eight bytes of the nonce, and the timestamp and SSRC
+/// of the header are authenticated along with it.
+#[derive(Clone)]
+pub struct AudioCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl AudioCipher {
+    /// Bytes added to the payload: the tag and the nonce.
+    pub const OVERHEAD: usize = 16 + 8;
+
+    pub fn new(key: &[u8; 32]) -> AudioCipher {
+        AudioCipher { cipher: ChaCha20Poly1305::new(key.into()) }
+    }
+
+    /// `None` unless `key` is 32 bytes long.
+    pub fn from_key(key: &[u8]) -> Option<AudioCipher> {
+        Some(AudioCipher::new(key.try_into().ok()?))
+    }
+
+    fn nonce(nonce: &[u8]) -> [u8; 12] {
+        let mut full = [0; 12];
+        full[4..].copy_from_slice(nonce);
+        full
+    }
+
+    /// Encrypts the payload of `packet`, a whole RTP packet, in place. Every packet sent with a key needs a
+    /// different `nonce`.
+    pub fn seal(&self, packet: &mut Vec<u8>, nonce: u64) {
+        let nonce = nonce.to_le_bytes();
+        let sealed = self.cipher.encrypt(&AudioCipher::nonce(&nonce).into(), Payload {
+            msg: &packet[HEADER_LEN..],
+            aad: &packet[4..HEADER_LEN],
+        }).unwrap();
+
+        packet.truncate(HEADER_LEN);
+        packet.extend(sealed);
+        packet.extend(nonce);
+    }
+
+    /// The header and decrypted payload of a whole RTP packet, `None` if it doesn't authenticate.
+    pub fn open(&self, packet: &[u8]) -> Option<(Header, Vec<u8>)> {
+        let (header, payload) = Header::parse(packet)?;
+        let (sealed, nonce) = payload.split_at(payload.len().checked_sub(8)?);
+
+        let payload = self.cipher.decrypt(&AudioCipher::nonce(nonce).into(), Payload {
+            msg: sealed,
+            aad: &packet[4..HEADER_LEN],
+        }).ok()?;
+
+        Some((header, payload))
+    }
+}
+
+impl std::fmt::Debug for AudioCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AudioCipher").finish_non_exhaustive()
+    }
+}
+
+/// A 64-bit NTP timestamp, seconds since 1900 in the upper half and the binary fraction in the lower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NtpTime(pub u64);
+
+impl NtpTime {
+    pub fn now() -> NtpTime {
+        NtpTime::from(SystemTime::now())
+    }
+
+    pub fn as_duration_since_unix(&self) -> Duration {
+        let seconds = (self.0 >> 32).saturating_sub(NTP_UNIX_OFFSET);
+        let nanos = ((self.0 & 0xffff_ffff) * 1_000_000_000) >> 32;
+        Duration::new(seconds, nanos as u32)
+    }
+}
+
+impl From<SystemTime> for NtpTime {
+    fn from(time: SystemTime) -> Self {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let fraction = (u64::from(since.subsec_nanos()) << 32) / 1_000_000_000;
+        NtpTime(((since.as_secs() + NTP_UNIX_OFFSET) << 32) | fraction)
+    }
+}
+
+/// The control channel packet tying an RTP timestamp to wall clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sync {
+    pub first: bool,
+    /// RTP timestamp that should be playing right now, i.e. `next_timestamp` minus the latency.
+    pub timestamp: u32,
+    pub time: NtpTime,
+    pub next_timestamp: u32,
+}
+
+impl Sync {
+    pub const LEN: usize = 20;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.push(if self.first { 0x90 } else { 0x80 });
+        buf.push(0x80 | PAYLOAD_TYPE_SYNC);
+        buf.extend(7_u16.to_be_bytes());
+        buf.extend(self.timestamp.to_be_bytes());
+        buf.extend(self.time.0.to_be_bytes());
+        buf.extend(self.next_timestamp.to_be_bytes());
+        buf
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<Sync> {
+        if packet.len() < Self::LEN || packet[1] & 0x7f != PAYLOAD_TYPE_SYNC {
+            return None;
+        }
+
+        Some(Sync {
+            first: packet[0] & 0x10 != 0,
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            time: NtpTime(u64::from_be_bytes(packet[8..16].try_into().unwrap())),
+            next_timestamp: u32::from_be_bytes([packet[16], packet[17], packet[18], packet[19]]),
+        })
+    }
+}
+
+/// An NTP style exchange on the timing port of RAOP, which receivers use to follow the sender's clock.
+/// A response carries the request's `sent` time as its `reference`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub response: bool,
+    pub reference: NtpTime,
+    pub received: NtpTime,
+    pub sent: NtpTime,
+}
+
+impl Timing {
+    pub const LEN: usize = 32;
+
+    /// The answer to this request, received at `received` and sent at `sent`.
+    pub fn respond(&self, received: NtpTime, sent: NtpTime) -> Timing {
+        Timing { response: true, reference: self.sent, received, sent }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload_type = if self.response { PAYLOAD_TYPE_TIMING_RESPONSE } else { PAYLOAD_TYPE_TIMING_REQUEST };
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.push(0x80);
+        buf.push(0x80 | payload_type);
+        buf.extend(7_u16.to_be_bytes());
+        buf.extend([0; 4]);
+        buf.extend(self.reference.0.to_be_bytes());
+        buf.extend(self.received.0.to_be_bytes());
+        buf.extend(self.sent.0.to_be_bytes());
+        buf
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<Timing> {
+        let response = match packet.get(1)? & 0x7f {
+            PAYLOAD_TYPE_TIMING_REQUEST => false,
+            PAYLOAD_TYPE_TIMING_RESPONSE => true,
+            _ => return None,
+        };
+
+        if packet.len() < Self::LEN {
+            return None;
+        }
+
+        let time = |at: usize| NtpTime(u64::from_be_bytes(packet[at..at + 8].try_into().unwrap()));
+
+        Some(Timing {
+            response,
+            reference: time(8),
+            received: time(16),
+            sent: time(24),
+        })
+    }
+}
+
+/// Asks the sender for `count` packets from sequence number `first` on, over the control channel. The
+/// answers come back as [`PAYLOAD_TYPE_RETRANSMIT_RESPONSE`] packets wrapping the original RTP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetransmitRequest {
+    pub sequence: u16,
+    pub first: u16,
+    pub count: u16,
+}
+
+impl RetransmitRequest {
+    pub const LEN: usize = 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.push(0x80);
+        buf.push(0x80 | PAYLOAD_TYPE_RETRANSMIT_REQUEST);
+        buf.extend(self.sequence.to_be_bytes());
+        buf.extend(self.first.to_be_bytes());
+        buf.extend(self.count.to_be_bytes());
+        buf
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<RetransmitRequest> {
+        if packet.len() < Self::LEN || packet[1] & 0x7f != PAYLOAD_TYPE_RETRANSMIT_REQUEST {
+            return None;
+        }
+
+        Some(RetransmitRequest {
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            first: u16::from_be_bytes([packet[4], packet[5]]),
+            count: u16::from_be_bytes([packet[6], packet[7]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(sequence: u16) -> Header {
+        Header {
+            marker: false,
+            extension: false,
+            payload_type: PAYLOAD_TYPE_AUDIO,
+            sequence,
+            timestamp: 0xfffffe00,
+            ssrc: 0x12345678,
+        }
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let header = Header { marker: true, extension: true, ..header(0xabcd) };
+        let mut packet = Vec::new();
+        header.write(&mut packet);
+        packet.push(0x42);
+
+        assert_eq!(packet[..HEADER_LEN], [0x90, 0xe0, 0xab, 0xcd, 0xff, 0xff, 0xfe, 0x00, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(Header::parse(&packet), Some((header, &[0x42][..])));
+        assert_eq!(Header::parse(&packet[..HEADER_LEN - 1]), None);
+        assert_eq!(Header::parse(&[0x40; HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn sync_packets_round_trip() {
+        let sync = Sync {
+            first: true,
+            timestamp: 0x01020304,
+            time: NtpTime(0x1112131415161718),
+            next_timestamp: 0x01022b14,
+        };
+        let bytes = sync.to_bytes();
+
+        assert_eq!(bytes, [
+            0x90, 0xd4, 0x00, 0x07,
+            0x01, 0x02, 0x03, 0x04,
+            0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18,
+            0x01, 0x02, 0x2b, 0x14,
+        ]);
+        assert_eq!(Sync::parse(&bytes), Some(sync));
+        assert_eq!(Sync::parse(&Sync { first: false, ..sync }.to_bytes()).map(|x| x.first), Some(false));
+        assert_eq!(Sync::parse(&bytes[..Sync::LEN - 1]), None);
+    }
+
+    #[test]
+    fn ntp_time_converts_from_unix_time() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_500);
+        let ntp = NtpTime::from(time);
+
+        assert_eq!(ntp.0 >> 32, NTP_UNIX_OFFSET + 1);
+        assert_eq!(ntp.0 & 0xffff_ffff, 1 << 31);
+        assert_eq!(ntp.as_duration_since_unix(), Duration::from_millis(1_500));
+    }
+
+    #[test]
+    fn audio_packets_round_trip() {
+        let cipher = AudioCipher::new(&[3; 32]);
+        let mut packet = Vec::new();
+        header(7).write(&mut packet);
+        packet.extend([1, 2, 3, 4]);
+
+        cipher.seal(&mut packet, 1);
+        assert_eq!(packet.len(), HEADER_LEN + 4 + AudioCipher::OVERHEAD);
+        assert_ne!(&packet[HEADER_LEN..HEADER_LEN + 4], &[1, 2, 3, 4]);
+        assert_eq!(&packet[packet.len() - 8..], &1_u64.to_le_bytes());
+
+        assert_eq!(cipher.open(&packet), Some((header(7), vec![1, 2, 3, 4])));
+        assert_eq!(AudioCipher::new(&[4; 32]).open(&packet), None);
+        assert_eq!(cipher.open(&packet[..HEADER_LEN + 4]), None);
+    }
+
+    #[test]
+    fn audio_packets_authenticate_the_timestamp() {
+        let cipher = AudioCipher::new(&[3; 32]);
+        let mut packet = Vec::new();
+        header(7).write(&mut packet);
+        packet.extend([1, 2, 3, 4]);
+        cipher.seal(&mut packet, 1);
+
+        // The sequence number isn't covered, retransmissions may carry another one.
+        let mut resequenced = packet.clone();
+        resequenced[3] = 8;
+        assert_eq!(cipher.open(&resequenced), Some((header(8), vec![1, 2, 3, 4])));
+
+        let mut retimed = packet.clone();
+        retimed[7] ^= 1;
+        assert_eq!(cipher.open(&retimed), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{fs::File, io::{self, BufRead, BufReader, BufWriter, Write}, net::SocketAddr, path::Path, sync::Mutex, time::SystemTime};
+
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use super::{Body, Headers, Request, Response};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Request,
+    Response,
+}
+
+/// One line of a capture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: f64,
+    pub peer: String,
+    pub direction: Direction,
+    pub kind: Kind,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: Value,
+}
+
+/// Writes every RTSP message passing through a [`super::Client`] as one JSON object per line, see
+/// [`Entry`].
+///
+/// Messages are recorded as they are parsed or before they are serialized, so they are in the clear
+/// even on encrypted connections. Property list bodies are converted to JSON, other bodies are kept as
+/// text when they are UTF-8 and as hex otherwise.
+pub struct Capture {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..data.len()).step_by(2).map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn plist_to_json(value: &plist::Value) -> Value {
+    match value {
+        plist::Value::Array(x) => x.iter().map(plist_to_json).collect(),
+        plist::Value::Dictionary(x) => Value::Object(x.iter().map(|(k, v)| (k.clone(), plist_to_json(v))).collect()),
+        plist::Value::Boolean(x) => json!(x),
+        plist::Value::Data(x) => json!({ "data": hex(x) }),
+        plist::Value::Date(x) => json!({ "date": x.to_xml_format() }),
+        plist::Value::Real(x) => json!(x),
+        plist::Value::Integer(x) => x.as_signed().map(|x| json!(x)).or(x.as_unsigned().map(|x| json!(x))).unwrap_or(Value::Null),
+        plist::Value::String(x) => json!(x),
+        plist::Value::Uid(x) => json!({ "uid": x.get() }),
+        _ => Value::Null,
+    }
+}
+
+/// Inverse of [`plist_to_json`]. Objects whose only key is `data`, `date` or `uid` are read back as those
+/// types, so dictionaries shaped like that don't survive a round trip.
+fn json_to_plist(value: &Value) -> Option<plist::Value> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(x) => plist::Value::Boolean(*x),
+        Value::Number(x) => match (x.as_i64(), x.as_u64()) {
+            (Some(x), _) => plist::Value::Integer(x.into()),
+            (None, Some(x)) => plist::Value::Integer(x.into()),
+            (None, None) => plist::Value::Real(x.as_f64()?),
+        },
+        Value::String(x) => plist::Value::String(x.clone()),
+        Value::Array(x) => plist::Value::Array(x.iter().map(json_to_plist).collect::<Option<_>>()?),
+        Value::Object(x) => match x.iter().next() {
+            Some((key, Value::String(data))) if x.len() == 1 && key == "data" => plist::Value::Data(unhex(data)?),
+            Some((key, Value::String(date))) if x.len() == 1 && key == "date" => plist::Value::Date(plist::Date::from_xml_format(date).ok()?),
+            Some((key, Value::Number(uid))) if x.len() == 1 && key == "uid" => plist::Value::Uid(plist::Uid::new(uid.as_u64()?)),
+            _ => plist::Value::Dictionary(x.iter().map(|(k, v)| Some((k.clone(), json_to_plist(v)?))).collect::<Option<_>>()?),
+        },
+    })
+}
+
+fn body_to_json(body: &Body) -> Value {
+    match body {
+        Body::None => Value::Null,
+        Body::PList(x) => json!({ "plist": plist_to_json(x) }),
+        Body::Raw(x) => match std::str::from_utf8(x) {
+            Ok(text) => json!({ "text": text }),
+            Err(_) => json!({ "hex": hex(x) }),
+        },
+    }
+}
+
+fn json_to_body(value: &Value) -> Option<Body> {
+    if value.is_null() {
+        return Some(Body::None);
+    }
+
+    let (key, value) = value.as_object()?.iter().next()?;
+
+    match (key.as_str(), value) {
+        ("plist", x) => json_to_plist(x).map(Body::PList),
+        ("text", Value::String(x)) => Some(Body::Raw(x.clone().into_bytes())),
+        ("hex", Value::String(x)) => unhex(x).map(Body::Raw),
+        _ => None,
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+impl Entry {
+    pub fn from_request(peer: SocketAddr, direction: Direction, request: &Request) -> Entry {
+        Entry {
+            time: now(),
+            peer: peer.to_string(),
+            direction,
+            kind: Kind::Request,
+            method: Some(request.method.to_string()),
+            path: Some(request.path.clone()),
+            version: Some(request.version.clone()),
+            status: None,
+            reason: None,
+            headers: request.headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: body_to_json(&request.body),
+        }
+    }
+
+    pub fn from_response(peer: SocketAddr, direction: Direction, response: &Response) -> Entry {
+        Entry {
+            time: now(),
+            peer: peer.to_string(),
+            direction,
+            kind: Kind::Response,
+            method: None,
+            path: None,
+            version: None,
+            status: Some(response.status),
+            reason: Some(response.status_string.clone()),
+            headers: response.headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: body_to_json(&response.body),
+        }
+    }
+
+    /// The captured request, `None` for responses or malformed entries.
+    pub fn to_request(&self) -> Option<Request> {
+        if self.kind != Kind::Request {
+            return None;
+        }
+
+        Some(Request {
+            method: self.method.as_deref()?.into(),
+            path: self.path.clone()?,
+            version: self.version.clone().unwrap_or_else(|| "RTSP/1.0".to_string()),
+            headers: self.headers.iter().cloned().collect::<Headers>(),
+            body: json_to_body(&self.body)?,
+        })
+    }
+
+    /// The captured response, `None` for requests or malformed entries.
+    pub fn to_response(&self) -> Option<Response> {
+        if self.kind != Kind::Response {
+            return None;
+        }
+
+        Some(Response {
+            status: self.status?,
+            status_string: self.reason.clone().unwrap_or_default(),
+            headers: self.headers.iter().cloned().collect::<Headers>(),
+            body: json_to_body(&self.body)?,
+        })
+    }
+}
+
+/// Reads a capture back, skipping blank lines.
+pub fn read(reader: impl BufRead) -> io::Result<Vec<Entry>> {
+    reader.lines()
+        .filter(|x| x.as_ref().map_or(true, |x| !x.trim().is_empty()))
+        .map(|x| serde_json::from_str(&x?).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x)))
+        .collect()
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    read(BufReader::new(File::open(path)?))
+}
+
+impl Capture {
+    pub fn new(writer: impl Write + Send + 'static) -> Capture {
+        Capture {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Captures into `path`, truncating it.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Capture> {
+        Ok(Capture::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn request(&self, peer: SocketAddr, direction: Direction, request: &Request) {
+        self.write(&Entry::from_request(peer, direction, request));
+    }
+
+    pub fn response(&self, peer: SocketAddr, direction: Direction, response: &Response) {
+        self.write(&Entry::from_response(peer, direction, response));
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut writer = self.writer.lock().unwrap();
+        let line = serde_json::to_string(entry).map_err(io::Error::from);
+
+        if let Err(err) = line.and_then(|x| writeln!(writer, "{}", x)).and_then(|_| writer.flush()) {
+            tracing::warn!("Failed to write RTSP capture: {}", err);
+        }
+    }
+}
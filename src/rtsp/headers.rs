@@ -0,0 +1,195 @@
+use std::fmt;
+
+/// RTSP header fields with case-insensitive names, kept in the order they were added.
+///
+/// A name may occur several times; [`Headers::get`] returns the first occurrence and
+/// [`Headers::get_all`] every one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replaces every value of `name` with a single one, keeping the position of the first occurrence.
+    /// Returns the previous first value.
+    pub fn set(&mut self, name: impl ToString, value: impl ToString) -> Option<String> {
+        let name = name.to_string();
+        let value = value.to_string();
+
+        match self.entries.iter().position(|(k, _)| k.eq_ignore_ascii_case(&name)) {
+            Some(index) => {
+                let previous = std::mem::replace(&mut self.entries[index].1, value);
+                let mut i = index + 1;
+
+                while i < self.entries.len() {
+                    if self.entries[i].0.eq_ignore_ascii_case(&name) {
+                        self.entries.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                Some(previous)
+            },
+            None => {
+                self.entries.push((name, value));
+                None
+            },
+        }
+    }
+
+    /// Adds another value for `name` after the existing ones.
+    pub fn append(&mut self, name: impl ToString, value: impl ToString) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Removes every value of `name`, returning the first one.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let first = self.get(name).map(|x| x.to_string());
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        first
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub(crate) fn last_mut(&mut self) -> Option<&mut String> {
+        self.entries.last_mut().map(|(_, v)| v)
+    }
+
+    pub fn cseq(&self) -> Option<usize> {
+        self.get("CSeq")?.trim().parse().ok()
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.get("Content-Length")?.trim().parse().ok()
+    }
+
+    /// The media type without parameters such as `charset`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")?.split(';').next().map(str::trim)
+    }
+
+    /// The session identifier without parameters such as `timeout`.
+    pub fn session(&self) -> Option<&str> {
+        self.get("Session")?.split(';').next().map(str::trim)
+    }
+}
+
+impl<K: ToString, V: ToString> FromIterator<(K, V)> for Headers {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        Headers {
+            entries: iter.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+}
+
+impl<K: ToString, V: ToString, const N: usize> From<[(K, V); N]> for Headers {
+    fn from(entries: [(K, V); N]) -> Self {
+        Headers::from_iter(entries)
+    }
+}
+
+impl fmt::Display for Headers {
+    /// Formats the headers as they appear on the wire, each line terminated by CRLF.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.entries {
+            write!(f, "{}: {}\r\n", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = Headers::new();
+        assert_eq!(headers.set("Content-Type", "application/sdp"), None);
+
+        assert_eq!(headers.get("content-type"), Some("application/sdp"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("application/sdp"));
+        assert!(headers.contains("Content-type"));
+
+        assert_eq!(headers.set("CONTENT-TYPE", "text/parameters"), Some("application/sdp".to_string()));
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.iter().next(), Some(("Content-Type", "text/parameters")));
+
+        assert_eq!(headers.remove("content-TYPE"), Some("text/parameters".to_string()));
+        assert_eq!(headers.remove("Content-Type"), None);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn appended_values_keep_their_order() {
+        let mut headers = Headers::from([("CSeq", "1"), ("Transport", "a")]);
+        headers.append("transport", "b");
+        headers.append("Server", "x");
+        headers.append("TRANSPORT", "c");
+
+        assert_eq!(headers.get("Transport"), Some("a"));
+        assert_eq!(headers.get_all("Transport").collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(headers.get_all("Missing").count(), 0);
+        assert_eq!(headers.to_string(), "CSeq: 1\r\nTransport: a\r\ntransport: b\r\nServer: x\r\nTRANSPORT: c\r\n");
+
+        // Setting collapses them into the first occurrence.
+        headers.set("Transport", "d");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("CSeq", "1"), ("Transport", "d"), ("Server", "x")]);
+
+        headers.append("Transport", "e");
+        assert_eq!(headers.remove("transport"), Some("d".to_string()));
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("CSeq", "1"), ("Server", "x")]);
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let headers = Headers::from([
+            ("cseq", " 42 "),
+            ("Content-Length", "1024"),
+            ("Content-Type", "text/parameters; charset=utf-8"),
+            ("Session", "DEADBEEF;timeout=60"),
+        ]);
+
+        assert_eq!(headers.cseq(), Some(42));
+        assert_eq!(headers.content_length(), Some(1024));
+        assert_eq!(headers.content_type(), Some("text/parameters"));
+        assert_eq!(headers.session(), Some("DEADBEEF"));
+
+        for value in ["", "abc", "-1", "1.5", "99999999999999999999999"] {
+            let headers = Headers::from([("CSeq", value), ("Content-Length", value)]);
+            assert_eq!(headers.cseq(), None, "{:?}", value);
+            assert_eq!(headers.content_length(), None, "{:?}", value);
+        }
+
+        assert_eq!(Headers::new().cseq(), None);
+        assert_eq!(Headers::new().content_type(), None);
+    }
+}
@@ -1,12 +1,33 @@
-use std::{collections::HashMap, sync::Arc, net::SocketAddr};
+use std::{borrow::Cow, fmt, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, net::SocketAddr, future::Future, pin::Pin, time::{Duration, Instant}};
 
-use tokio::{net::{tcp::OwnedWriteHalf, ToSocketAddrs, TcpStream}, sync::{RwLock, oneshot, Mutex}, io::{AsyncWriteExt, AsyncReadExt, self}, task::JoinHandle};
+use tokio::{net::{tcp::OwnedWriteHalf, ToSocketAddrs, TcpStream}, sync::{oneshot, watch, Mutex}, io::{AsyncWriteExt, AsyncReadExt, self}, task::JoinHandle};
+use tracing::Instrument;
 
+pub mod capture;
+mod headers;
 pub mod ops;
+pub mod parser;
+mod pending;
+pub mod server;
 
-#[derive(Clone, Copy)]
+pub use capture::{Capture, Direction};
+pub use headers::Headers;
+pub use parser::{Message, ParseError, Parser};
+pub use pending::PendingResponse;
+pub use server::Server;
+
+use pending::PendingSeqs;
+
+use crate::{dacp::Remote, pairing::{self, Decrypter, Encrypter, SessionKeys}};
+
+/// How long [`Client::request`] waits for an answer unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum Method {
+    OPTIONS,
+    ANNOUNCE,
     GET,
     POST,
     SETUP,
@@ -16,11 +37,33 @@ pub enum Method {
     RECORD,
     FLUSH,
     TEARDOWN,
+    Other(String),
 }
 
-impl ToString for Method {
-    fn to_string(&self) -> String {
-        match &self {
+impl From<&str> for Method {
+    fn from(x: &str) -> Self {
+        match x {
+            "OPTIONS" => Self::OPTIONS,
+            "ANNOUNCE" => Self::ANNOUNCE,
+            "GET" => Self::GET,
+            "POST" => Self::POST,
+            "SETUP" => Self::SETUP,
+            "SET_PARAMETER" => Self::SET_PARAMETER,
+            "GET_PARAMETER" => Self::GET_PARAMETER,
+            "SETPEERS" => Self::SETPEERS,
+            "RECORD" => Self::RECORD,
+            "FLUSH" => Self::FLUSH,
+            "TEARDOWN" => Self::TEARDOWN,
+            x => Self::Other(x.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match &self {
+            Self::OPTIONS => "OPTIONS",
+            Self::ANNOUNCE => "ANNOUNCE",
             Self::GET => "GET",
             Self::POST => "POST",
             Self::SETUP => "SETUP",
@@ -30,7 +73,8 @@ impl ToString for Method {
             Self::RECORD => "RECORD",
             Self::FLUSH => "FLUSH",
             Self::TEARDOWN => "TEARDOWN",
-        }.to_string()
+            Self::Other(x) => x,
+        })
     }
 }
 
@@ -41,11 +85,27 @@ pub enum Body {
     Raw(Vec<u8>),
 }
 
-#[derive(Clone)]
+impl Body {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Body::PList(x) => {
+                let mut body: Vec<u8> = Vec::new();
+                x.to_writer_binary(&mut body).unwrap();
+                body
+            },
+            Body::Raw(x) => x.clone(),
+            Body::None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Request {
     pub method: Method,
     pub path: String,
-    pub headers: HashMap<String, String>,
+    /// `RTSP/1.0`, or `HTTP/1.1` on reverse HTTP connections.
+    pub version: String,
+    pub headers: Headers,
     pub body: Body,
 }
 
@@ -54,9 +114,10 @@ impl Request {
         Request {
             method,
             path: path.to_string(),
-            headers: HashMap::from([
-                ("X-Apple-ProtocolVersion".to_string(), "1".to_string()),
-                ("User-Agent".to_string(), "AirPlay/409.16".to_string()),
+            version: "RTSP/1.0".to_string(),
+            headers: Headers::from([
+                ("X-Apple-ProtocolVersion", "1"),
+                ("User-Agent", "AirPlay/409.16"),
             ]),
             body: Body::None,
         }
@@ -69,19 +130,11 @@ impl Request {
     }
 
     pub fn set_header(&mut self, name: impl ToString, value: impl ToString) -> Option<String> {
-        self.headers.insert(name.to_string(), value.to_string())
+        self.headers.set(name, value)
     }
 
     pub(crate) fn normalize(&mut self, seq: usize) {
-        let cl: usize = match &self.body {
-            Body::PList(x) => {
-                let mut tmp: Vec<u8> = Vec::new();
-                x.to_writer_binary(&mut tmp).unwrap();
-                tmp.len()
-            },
-            Body::Raw(x) => x.len(),
-            Body::None => 0,
-        };
+        let cl = self.body.to_bytes().len();
 
         if cl > 0 {
             self.set_header("Content-Length", cl);
@@ -89,175 +142,522 @@ impl Request {
 
         self.set_header("CSeq", seq);
     }
+
+    /// The request as sent on the wire. Headers are written as they are, the client sets `CSeq` and
+    /// `Content-Length` before sending.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut req = format!("{} {} {}\r\n{}\r\n", self.method, self.path, self.version, self.headers).into_bytes();
+        req.extend(self.body.to_bytes());
+        req
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Response {
     pub status: i32,
     pub status_string: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Body,
 }
 
-pub struct Client {
-    pub peer: SocketAddr,
-    tx: OwnedWriteHalf,
-    seq: RwLock<usize>,
-    pending_seqs: Arc<Mutex<HashMap<usize, oneshot::Sender<Response>>>>,
-    listener_handle: JoinHandle<()>,
+impl Response {
+    pub fn new(status: i32, status_string: impl ToString) -> Response {
+        Response {
+            status,
+            status_string: status_string.to_string(),
+            headers: Headers::new(),
+            body: Body::None,
+        }
+    }
+
+    pub fn new_body(status: i32, status_string: impl ToString, body: Body) -> Response {
+        let mut res = Response::new(status, status_string);
+        res.body = body;
+        res
+    }
+
+    /// The response as sent on the wire, answering a request made with `version`. `Content-Length` is set
+    /// from the body, and `Content-Type` too for property lists.
+    pub fn to_bytes(&self, version: &str) -> Vec<u8> {
+        let body = self.body.to_bytes();
+        let mut headers = self.headers.clone();
+
+        if !body.is_empty() {
+            headers.set("Content-Length", body.len());
+
+            if matches!(self.body, Body::PList(_)) && !headers.contains("Content-Type") {
+                headers.set("Content-Type", "application/x-apple-binary-plist");
+            }
+        }
+
+        let mut res = format!("{} {} {}\r\n{}\r\n", version, self.status, self.status_string, headers).into_bytes();
+        res.extend(body);
+        res
+    }
 }
 
-impl Client {
-    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let peer = stream.peer_addr().unwrap();
-        let (mut rx, tx) = stream.into_split();
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// No answer arrived in time.
+    Timeout,
+    /// The connection was closed before an answer arrived.
+    ConnectionClosed,
+    /// The receiver answered with something other than `200 OK`.
+    Status(Response),
+    Pairing(pairing::Error),
+}
 
-        let pending_seqs: Arc<Mutex<HashMap<usize, oneshot::Sender<Response>>>> = Default::default();
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(x) => write!(f, "RTSP I/O error: {}", x),
+            Self::Timeout => f.write_str("RTSP request timed out"),
+            Self::ConnectionClosed => f.write_str("RTSP connection closed"),
+            Self::Status(x) => write!(f, "RTSP request failed: {} {}", x.status, x.status_string),
+            Self::Pairing(x) => write!(f, "Pairing failed: {}", x),
+        }
+    }
+}
 
-        Ok(Client {
-            peer,
-            tx,
-            seq: RwLock::new(0),
-            pending_seqs: pending_seqs.clone(),
-            listener_handle: tokio::spawn(async move {
-                'request: loop {
-                    let mut response_code = -1;
-                    let mut response_status = String::new();
-                    let mut response_headers: HashMap<String, String> = HashMap::new();
-                    let mut hanging: String = String::new();
-                    let mut leftover: Vec<u8> = Vec::new();
-                    let mut response_body: Body = Body::None;
-
-                    let mut buf = vec![0_u8; 128];
-                    while let Ok(n) = rx.read(&mut buf).await {
-                        if n == 0 {
-                            break 'request;
-                        }
+impl std::error::Error for Error {}
 
-                        let mut parsed = String::from_utf8(buf[0..n].to_vec());
+impl From<io::Error> for Error {
+    fn from(x: io::Error) -> Self {
+        Error::Io(x)
+    }
+}
 
-                        if let Err(err) = parsed {
-                            let valid_up_to = err.utf8_error().valid_up_to();
-                            parsed = String::from_utf8(buf[0..valid_up_to].to_vec());
-                            leftover.extend(&buf[valid_up_to..n]);
-                        }
+impl From<pairing::Error> for Error {
+    fn from(x: pairing::Error) -> Self {
+        Error::Pairing(x)
+    }
+}
 
-                        let parsed = parsed.unwrap();
+impl From<Error> for io::Error {
+    fn from(x: Error) -> Self {
+        match x {
+            Error::Io(x) => x,
+            Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, x),
+            Error::ConnectionClosed => io::Error::new(io::ErrorKind::ConnectionAborted, x),
+            x => io::Error::other(x),
+        }
+    }
+}
 
-                        let parsed = hanging.clone() + &parsed;
-                        let mut parsed = parsed.as_str();
+/// Answers requests a receiver sends on the control connection, see [`Client::set_request_handler`].
+pub type RequestHandler = Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
 
-                        let mut over = false;
+/// A control connection to a receiver.
+///
+/// Clones share the connection, so any number of tasks can issue requests at once. Writes are serialized
+/// and answers are matched to their requests by `CSeq`, so requests may be pipelined.
+///
+/// Dropping the last clone aborts the background tasks and closes the socket without a TEARDOWN, use
+/// [`Client::close`] to end the session politely.
+#[derive(Clone)]
+pub struct Client {
+    pub peer: SocketAddr,
+    shared: Arc<Shared>,
+}
 
-                        while parsed.contains("\r\n") {
-                            let (x, _parsed) = parsed.split_once("\r\n").unwrap();
-                            parsed = _parsed;
+struct Shared {
+    local: SocketAddr,
+    tx: Arc<Mutex<Writer>>,
+    decrypter: Arc<std::sync::Mutex<Option<Decrypter>>>,
+    seq: AtomicUsize,
+    pending_seqs: PendingSeqs,
+    closed: Arc<watch::Sender<bool>>,
+    session_active: AtomicBool,
+    closing: AtomicBool,
+    timeout: std::sync::Mutex<Option<Duration>>,
+    audio_latency: std::sync::Mutex<Option<u32>>,
+    remote: std::sync::Mutex<Option<Remote>>,
+    handler: Arc<std::sync::Mutex<Option<RequestHandler>>>,
+    capture: Arc<std::sync::Mutex<Option<Arc<Capture>>>>,
+    listener_handle: std::sync::Mutex<Option<JoinHandle<()>>>,
+    keepalive_handle: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
 
-                            if response_code == -1 {
-                                let (code, s) = x.split_once(' ').unwrap().1.split_once(' ').unwrap();
-                                response_code = str::parse(code).unwrap();
-                                response_status = s.to_string();
-                            } else if x.len() == 0 {
-                                over = true;
-                                break;
-                            } else {
-                                let (key, value) = x.split_once(": ").unwrap();
-                                response_headers.insert(key.to_string(), value.to_string());
-                            }
-                        }
+/// The write half of the connection, encrypting once pairing has set up keys.
+struct Writer {
+    half: OwnedWriteHalf,
+    encrypter: Option<Encrypter>,
+}
+
+impl Writer {
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = match &mut self.encrypter {
+            Some(x) => Cow::Owned(x.encrypt(data)),
+            None => Cow::Borrowed(data),
+        };
+
+        self.half.write_all(&data).await
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        for handle in [&mut self.listener_handle, &mut self.keepalive_handle] {
+            if let Some(handle) = handle.get_mut().unwrap().take() {
+                handle.abort();
+            }
+        }
+
+        mark_closed(&self.closed, &self.pending_seqs);
+    }
+}
+
+fn mark_closed(closed: &watch::Sender<bool>, pending_seqs: &PendingSeqs) {
+    closed.send_replace(true);
+
+    for (_, entry) in pending_seqs.lock().unwrap().drain() {
+        let _ = entry.send(Err(Error::ConnectionClosed));
+    }
+}
 
-                        hanging = parsed.to_string();
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let peer = stream.peer_addr()?;
+        let local = stream.local_addr()?;
+        let (mut rx, tx) = stream.into_split();
 
-                        if over {
+        let tx = Arc::new(Mutex::new(Writer { half: tx, encrypter: None }));
+        let decrypter: Arc<std::sync::Mutex<Option<Decrypter>>> = Default::default();
+        let pending_seqs: PendingSeqs = Default::default();
+        let closed = Arc::new(watch::Sender::new(false));
+        let handler: Arc<std::sync::Mutex<Option<RequestHandler>>> = Default::default();
+        let capture: Arc<std::sync::Mutex<Option<Arc<Capture>>>> = Default::default();
+
+        let shared = Shared {
+            local,
+            tx: tx.clone(),
+            decrypter: decrypter.clone(),
+            seq: AtomicUsize::new(0),
+            pending_seqs: pending_seqs.clone(),
+            closed: closed.clone(),
+            session_active: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            timeout: std::sync::Mutex::new(Some(DEFAULT_TIMEOUT)),
+            audio_latency: Default::default(),
+            remote: Default::default(),
+            handler: handler.clone(),
+            capture: capture.clone(),
+            keepalive_handle: Default::default(),
+            listener_handle: std::sync::Mutex::new(Some(tokio::spawn(async move {
+                let mut parser = Parser::new();
+                let mut buf = vec![0_u8; 4096];
+
+                loop {
+                    let message = match parser.next() {
+                        Ok(Some(x)) => x,
+                        Ok(None) => match rx.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => {
+                                let data = match decrypter.lock().unwrap().as_mut() {
+                                    Some(x) => x.decrypt(&buf[..n]).map(Cow::Owned),
+                                    None => Ok(Cow::Borrowed(&buf[..n])),
+                                };
+
+                                match data {
+                                    Ok(x) => parser.feed(&x),
+                                    Err(err) => {
+                                        tracing::warn!(%peer, "Closing RTSP connection after undecryptable data: {}", err);
+                                        break;
+                                    },
+                                }
+
+                                continue;
+                            },
+                        },
+                        Err(err) => {
+                            tracing::warn!(%peer, "Closing RTSP connection after malformed message: {}", err);
                             break;
-                        }
-                    }
+                        },
+                    };
 
-                    if hanging.len() > 0 {
-                        let mut lo = hanging.as_bytes().to_vec();
-                        lo.append(&mut leftover);
-                        leftover = lo;
-                    }
+                    let capture = capture.lock().unwrap().clone();
 
-                    if let Some(len) = response_headers.get("Content-Length") {
-                        let len = str::parse::<usize>(len).unwrap();
-                
-                        let mut body = leftover;
-                
-                        if body.len() < len {
-                            let mut more_body = vec![0_u8; len - body.len()];
-                            rx.read_exact(&mut more_body).await.unwrap();
-                            body.extend(more_body);
-                        }
-                
-                        let ct = response_headers.get("Content-Type").cloned().unwrap_or("".to_string());
-                
-                        if ct == "application/x-apple-binary-plist" {
-                            response_body = if let Ok(body) = plist::from_bytes::<plist::Value>(&body) {
-                                Body::PList(body)
-                            } else {
-                                Body::Raw(body)
+                    let response = match message {
+                        Message::Response(x) => x,
+                        Message::Request(request) => {
+                            if let Some(capture) = &capture {
+                                capture.request(peer, Direction::Received, &request);
                             }
-                        } else {
-                            response_body = Body::Raw(body);
-                        }
-                    }
 
-                    let response = Response {
-                        status: response_code,
-                        status_string: response_status,
-                        headers: response_headers,
-                        body: response_body,
+                            let handler = handler.lock().unwrap().clone();
+                            tokio::spawn(Client::answer(peer, tx.clone(), handler, capture, request));
+                            continue;
+                        },
                     };
 
-                    if let Some(Ok(seq)) = response.headers.get("CSeq").map(|x| str::parse::<usize>(&x)) {
-                        if let Some(entry) = pending_seqs.lock().await.remove(&seq) {
-                            entry.send(response).unwrap();
+                    if let Some(capture) = &capture {
+                        capture.response(peer, Direction::Received, &response);
+                    }
+
+                    if let Some(seq) = response.headers.cseq() {
+                        let entry = pending_seqs.lock().unwrap().remove(&seq);
+
+                        if let Some(entry) = entry {
+                            let _ = entry.send(Ok(response));
                         } else {
-                            println!("Encountered RTSP response with unlistened CSeq: {:#?}", response);
+                            tracing::warn!(%peer, cseq = seq, status = response.status, "RTSP response for unknown or abandoned request");
                         }
                     } else {
-                        println!("Encountered RTSP response without CSeq: {:#?}", response);
+                        tracing::warn!(%peer, status = response.status, "RTSP response without CSeq");
                     }
                 }
-            }),
+
+                tracing::debug!(%peer, "RTSP connection closed");
+                mark_closed(&closed, &pending_seqs);
+            }))),
+        };
+
+        Ok(Client {
+            peer,
+            shared: Arc::new(shared),
         })
     }
 
-    pub async fn request(&mut self, mut request: Request) -> Result<oneshot::Receiver<Response>, io::Error> {
-        let seq = {
-            let mut sw = self.seq.write().await;
-            let x = *sw;
-            *sw += 1;
-            x
+    async fn answer(peer: SocketAddr, tx: Arc<Mutex<Writer>>, handler: Option<RequestHandler>, capture: Option<Arc<Capture>>, request: Request) {
+        let started = Instant::now();
+        let mut response = match handler {
+            Some(handler) => handler(request.clone()).await,
+            None => Response::new(501, "Not Implemented"),
         };
 
-        request.normalize(seq);
-
-        let mut req = format!("{} {} RTSP/1.0\r\n", request.method.to_string(), request.path);
+        if let Some(seq) = request.headers.get("CSeq") {
+            response.headers.set("CSeq", seq);
+        }
 
-        for (key, value) in request.headers.iter() {
-            req += &format!("{}: {}\r\n", key, value);
+        if let Some(capture) = &capture {
+            capture.response(peer, Direction::Sent, &response);
         }
 
-        req += "\r\n";
+        let span = tracing::debug_span!("rtsp_answer", %peer, method = %request.method, path = %request.path, cseq = request.headers.cseq());
 
-        let mut req = req.as_bytes().to_vec();
+        async {
+            if let Err(err) = tx.lock().await.send(&response.to_bytes(&request.version)).await {
+                tracing::warn!("Failed to answer: {}", err);
+            } else {
+                tracing::debug!(status = response.status, latency_ms = started.elapsed().as_secs_f64() * 1000.0, "answered");
+            }
+        }.instrument(span).await;
+    }
 
-        match &request.body {
-            Body::PList(x) => {
-                let mut body: Vec<u8> = Vec::new(); // TODO: preallocate capacity from Content-Length header
-                x.to_writer_binary(&mut body).unwrap();
-                req.extend(body);
-            },
-            Body::Raw(x) => req.extend(x),
-            Body::None => {},
+    /// Installs the handler for requests the receiver sends us, such as `POST /command` or requests on a
+    /// reverse HTTP (`PTTH/1.0`) connection. Without one, they are answered with `501 Not Implemented`.
+    ///
+    /// The handler's response gets the request's `CSeq` and is sent in the request's protocol version.
+    pub fn set_request_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let handler: RequestHandler = Arc::new(move |request| Box::pin(handler(request)));
+        *self.shared.handler.lock().unwrap() = Some(handler);
+    }
+
+    /// Our end of the connection, the address the receiver knows us by.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local
+    }
+
+    /// Frames of latency the receiver reported when playback started.
+    pub fn audio_latency(&self) -> Option<u32> {
+        *self.shared.audio_latency.lock().unwrap()
+    }
+
+    pub(crate) fn set_audio_latency(&self, frames: u32) {
+        *self.shared.audio_latency.lock().unwrap() = Some(frames);
+    }
+
+    /// Sends `DACP-ID` and `Active-Remote` on every request from now on, see [`crate::dacp::Server::attach`].
+    pub fn set_remote(&self, remote: Option<Remote>) {
+        *self.shared.remote.lock().unwrap() = remote;
+    }
+
+    /// Sets the timeout [`Client::request`] applies on every clone, `None` waiting forever.
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn default_timeout(&self) -> Option<Duration> {
+        *self.shared.timeout.lock().unwrap()
+    }
+
+    /// Whether the connection has gone away, after which every request fails with
+    /// [`Error::ConnectionClosed`].
+    pub fn is_closed(&self) -> bool {
+        *self.shared.closed.borrow()
+    }
+
+    /// Resolves once the connection has gone away.
+    pub async fn closed(&self) {
+        let mut closed = self.shared.closed.subscribe();
+        let _ = closed.wait_for(|x| *x).await;
+    }
+
+    /// How many requests are still waiting for their answer, on every clone.
+    pub fn pending_requests(&self) -> usize {
+        self.shared.pending_seqs.lock().unwrap().len()
+    }
+
+    /// Whether a SETUP succeeded that hasn't been torn down yet.
+    pub fn is_session_active(&self) -> bool {
+        self.shared.session_active.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_session_active(&self, active: bool) {
+        self.shared.session_active.store(active, Ordering::SeqCst);
+    }
+
+    /// Whether [`Client::close`] was called on any clone, as opposed to the connection dropping or the
+    /// receiver hanging up.
+    pub fn is_closed_on_purpose(&self) -> bool {
+        self.shared.closing.load(Ordering::SeqCst)
+    }
+
+    /// Sends `POST /feedback` every `interval` so the receiver keeps the session alive while no other
+    /// requests are made. Replaces a previously started keepalive.
+    pub fn start_keepalive(&self, interval: Duration) {
+        let shared = Arc::downgrade(&self.shared);
+        let peer = self.peer;
+
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                // Only hold on to the connection while writing, so dropping the last clone isn't held up
+                // by an unanswered keepalive.
+                let pending = match shared.upgrade() {
+                    Some(shared) => Client { peer, shared }.request(Request::new(Method::POST, "/feedback")).await,
+                    None => break,
+                };
+
+                let result = match pending {
+                    Ok(x) => x.await,
+                    Err(err) => Err(err),
+                };
+
+                match result {
+                    Ok(res) if res.status == 200 => {},
+                    Ok(res) => tracing::warn!(%peer, status = res.status, "Keepalive rejected"),
+                    Err(Error::ConnectionClosed) => break,
+                    Err(err) => tracing::warn!(%peer, "Keepalive failed: {}", err),
+                }
+            }
+        });
+
+        if let Some(previous) = self.shared.keepalive_handle.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+    }
+
+    pub fn stop_keepalive(&self) {
+        if let Some(handle) = self.shared.keepalive_handle.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    /// Ends the connection for every clone: sends TEARDOWN if a session is active, stops the keepalive,
+    /// closes the socket and waits for the listener to finish. Pending requests fail with
+    /// [`Error::ConnectionClosed`].
+    ///
+    /// The connection is closed even when TEARDOWN fails, whose error is returned afterwards. Either way the
+    /// session counts as ended, see [`Client::is_closed_on_purpose`].
+    pub async fn close(&self) -> Result<(), Error> {
+        self.shared.closing.store(true, Ordering::SeqCst);
+
+        let teardown = if self.is_session_active() && !self.is_closed() {
+            self.teardown().await.map(|_| ())
+        } else {
+            Ok(())
         };
 
-        let (tx, rx) = oneshot::channel::<Response>();
-        self.pending_seqs.lock().await.insert(seq, tx);
-        self.tx.write_all(&req).await?;
-        Ok(rx)
+        self.set_session_active(false);
+
+        self.stop_keepalive();
+        let _ = self.shared.tx.lock().await.half.shutdown().await;
+
+        let listener = self.shared.listener_handle.lock().unwrap().take();
+
+        if let Some(listener) = listener {
+            listener.abort();
+            let _ = listener.await;
+        }
+
+        mark_closed(&self.shared.closed, &self.shared.pending_seqs);
+        teardown
+    }
+
+    /// Switches the connection to the encrypted framing agreed on during pairing. Everything sent and
+    /// received afterwards is encrypted, see [`pairing::Encrypter`].
+    pub async fn enable_encryption(&self, keys: &SessionKeys) {
+        let mut writer = self.shared.tx.lock().await;
+        *self.shared.decrypter.lock().unwrap() = Some(Decrypter::new(&keys.read));
+        writer.encrypter = Some(Encrypter::new(&keys.write));
     }
-}
\ No newline at end of file
+
+    pub fn is_encrypted(&self) -> bool {
+        self.shared.decrypter.lock().unwrap().is_some()
+    }
+
+    /// Records every message on this connection into `capture`, or stops recording with `None`.
+    pub fn set_capture(&self, capture: Option<Arc<Capture>>) {
+        *self.shared.capture.lock().unwrap() = capture;
+    }
+
+    /// Sends a request and returns its answer as a future, which times out after the default timeout.
+    pub async fn request(&self, request: Request) -> Result<PendingResponse, Error> {
+        self.request_with_timeout(request, self.default_timeout()).await
+    }
+
+    pub async fn request_with_timeout(&self, mut request: Request, timeout: Option<Duration>) -> Result<PendingResponse, Error> {
+        if self.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+
+        // Holding the writer while numbering keeps CSeqs in wire order when tasks race.
+        let mut writer = self.shared.tx.lock().await;
+        let seq = self.shared.seq.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(remote) = *self.shared.remote.lock().unwrap() {
+            remote.apply(&mut request);
+        }
+
+        request.normalize(seq);
+        let req = request.to_bytes();
+
+        let (tx, rx) = oneshot::channel();
+        self.shared.pending_seqs.lock().unwrap().insert(seq, tx);
+        let span = tracing::debug_span!(
+            "rtsp_request",
+            peer = %self.peer,
+            method = %request.method,
+            path = %request.path,
+            cseq = seq,
+            status = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        let pending = PendingResponse::new(seq, rx, self.shared.pending_seqs.clone(), timeout, span);
+
+        // The listener may have drained the map between the check above and the insert.
+        if self.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+
+        let capture = self.shared.capture.lock().unwrap().clone();
+
+        if let Some(capture) = capture {
+            capture.request(self.peer, Direction::Sent, &request);
+        }
+
+        writer.send(&req).await?;
+        Ok(pending)
+    }
+}
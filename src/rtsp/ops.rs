@@ -1,9 +1,13 @@
+use std::{io, time::Duration};
+
 use plist::Data;
 use serde::{Serialize, Deserialize};
 
-use super::{Client, Response, Request, Body, Method};
+use crate::{audio::{Codec, StreamFormat}, dmap::TrackMetadata, mdns::Features, pairing::{self, Identity, Peer, SessionKeys, SetupClient, Tlv8, VerifyClient}};
+
+use super::{Client, Error, Response, Request, Body, Method};
 
-type Result<T> = std::result::Result<T, Response>;
+type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -49,34 +53,574 @@ pub struct SetupInfoRequest {
     pub timing_protocol: String,
 }
 
+pub const STREAM_TYPE_REALTIME: u32 = 96;
+pub const STREAM_TYPE_BUFFERED: u32 = 103;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamDescription {
+    pub audio_format: u64,
+    pub audio_mode: String,
+    pub ct: u8,
+    pub control_port: u16,
+    pub is_media: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_min: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_max: Option<u32>,
+
+    pub shk: Data,
+    pub spf: u32,
+    pub sr: u32,
+
+    #[serde(rename = "streamConnectionID")]
+    pub stream_connection_id: i64,
+
+    pub supports_dynamic_stream_id: bool,
+
+    #[serde(rename = "type")]
+    pub stream_type: u32,
+}
+
+impl StreamDescription {
+    /// A realtime stream of `format` buffering `latency` frames, its packets encrypted with `shk` and
+    /// retransmissions answered on our `control_port`.
+    pub fn realtime(format: StreamFormat, control_port: u16, shk: [u8; 32], latency: u32) -> StreamDescription {
+        StreamDescription {
+            audio_format: format.audio_format(),
+            audio_mode: "default".to_string(),
+            ct: format.codec.compression_type(),
+            control_port,
+            is_media: true,
+            latency_min: Some(latency),
+            latency_max: Some(latency),
+            shk: Data::new(shk.to_vec()),
+            spf: format.codec.frames_per_packet(),
+            sr: format.format.sample_rate,
+            stream_connection_id: i64::from(rand::random::<u32>()),
+            supports_dynamic_stream_id: false,
+            stream_type: STREAM_TYPE_REALTIME,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetupStreamsRequest {
+    pub streams: Vec<StreamDescription>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInfo {
+    pub data_port: Option<u16>,
+    pub control_port: Option<u16>,
+    pub audio_buffer_size: Option<u64>,
+
+    #[serde(rename = "streamID")]
+    pub stream_id: Option<u64>,
+
+    #[serde(rename = "type")]
+    pub stream_type: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetupStreamsResponse {
+    pub streams: Vec<StreamInfo>,
+}
+
+impl SetupStreamsResponse {
+    pub fn from_response(response: &Response) -> Option<Self> {
+        match &response.body {
+            Body::PList(x) => plist::from_value(x).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Audio format bitmasks per stream type, see [`crate::audio::StreamFormat`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedFormats {
+    pub audio_stream: Option<u64>,
+    pub buffer_stream: Option<u64>,
+    pub low_latency_audio_stream: Option<u64>,
+    pub screen_stream: Option<u64>,
+}
+
+impl SupportedFormats {
+    pub fn for_stream(&self, buffered: bool) -> Option<u64> {
+        if buffered {
+            self.buffer_stream
+        } else {
+            self.audio_stream
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioLatency {
+    pub audio_type: Option<String>,
+    pub input_latency_micros: Option<u64>,
+    pub output_latency_micros: Option<u64>,
+
+    #[serde(rename = "type")]
+    pub stream_type: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub audio_latencies: Option<Vec<AudioLatency>>,
+
+    #[serde(rename = "deviceID")]
+    pub device_id: Option<String>,
+
+    /// NOTE: Some receivers report this as a negative number.
+    pub features: Option<i64>,
+
+    pub model: Option<String>,
+    pub name: Option<String>,
+    pub source_version: Option<String>,
+    pub status_flags: Option<u64>,
+    pub supported_formats: Option<SupportedFormats>,
+}
+
+impl DeviceInfo {
+    pub fn from_response(response: &Response) -> Option<Self> {
+        match &response.body {
+            Body::PList(x) => plist::from_value(x).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn features(&self) -> Option<Features> {
+        self.features.map(|x| Features::from(x as u64))
+    }
+
+    /// Output latency the receiver reports for the default audio of a stream type.
+    pub fn output_latency(&self, stream_type: u32) -> Option<Duration> {
+        self.audio_latencies.as_ref()?.iter()
+            .find(|x| x.stream_type == Some(stream_type) && x.audio_type.as_deref().unwrap_or("default") == "default")
+            .and_then(|x| x.output_latency_micros)
+            .map(Duration::from_micros)
+    }
+}
+
+/// Position in the track playing, as RTP timestamps of the stream: `progress: start/current/end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub start: u32,
+    pub current: u32,
+    pub end: u32,
+}
+
+impl Progress {
+    /// Parses the value after `progress:`.
+    pub fn parse(value: &str) -> Option<Progress> {
+        let mut parts = value.trim().splitn(3, '/').map(|x| x.trim().parse().ok());
+
+        Some(Progress {
+            start: parts.next()??,
+            current: parts.next()??,
+            end: parts.next()??,
+        })
+    }
+
+    pub fn elapsed(&self, sample_rate: u32) -> Duration {
+        frames_to_duration(self.current.wrapping_sub(self.start), sample_rate)
+    }
+
+    pub fn duration(&self, sample_rate: u32) -> Duration {
+        frames_to_duration(self.end.wrapping_sub(self.start), sample_rate)
+    }
+}
+
+/// Ports from the `Transport` header of a RAOP SETUP answer, and the `Session` to use afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaopTransport {
+    pub session: Option<String>,
+    pub server_port: u16,
+    pub control_port: u16,
+    pub timing_port: u16,
+}
+
+impl RaopTransport {
+    pub fn from_response(response: &Response) -> Option<Self> {
+        let mut transport = RaopTransport {
+            session: response.headers.session().map(str::to_string),
+            server_port: 0,
+            control_port: 0,
+            timing_port: 0,
+        };
+
+        for part in response.headers.get("Transport")?.split(';') {
+            match part.trim().split_once('=') {
+                Some(("server_port", x)) => transport.server_port = x.parse().ok()?,
+                Some(("control_port", x)) => transport.control_port = x.parse().ok()?,
+                Some(("timing_port", x)) => transport.timing_port = x.parse().ok()?,
+                _ => {},
+            }
+        }
+
+        (transport.server_port != 0).then_some(transport)
+    }
+}
+
+fn frames_to_duration(frames: u32, sample_rate: u32) -> Duration {
+    Duration::from_nanos(u64::from(frames) * 1_000_000_000 / u64::from(sample_rate.max(1)))
+}
+
 impl Client {
-    pub async fn fetch_info(&mut self) -> Result<Response> {
+    pub async fn fetch_info(&self) -> Result<Response> {
         let req = self.request(
             Request::new(Method::GET, "/info")
-        ).await.unwrap();
-        let res = req.await.unwrap();
+        ).await?;
+        let res = req.await?;
 
         if res.status == 200 {
             Ok(res)
         } else {
-            Err(res)
+            Err(Error::Status(res))
         }
     }
 
-    pub async fn setup_info(&mut self, body: SetupInfoRequest) -> Result<Response> {
+    pub async fn setup_info(&self, body: SetupInfoRequest) -> Result<Response> {
         let req = self.request(
             Request::new_body(
                 Method::SETUP,
-                format!("rtsp://{}/666", self.peer.ip().to_string()),
+                format!("rtsp://{}/666", self.peer.ip()),
                 Body::PList(plist::to_value(&body).unwrap()),
             )
-        ).await.unwrap();
-        let res = req.await.unwrap();
+        ).await?;
+        let res = req.await?;
 
         if res.status == 200 {
+            self.set_session_active(true);
             Ok(res)
         } else {
-            Err(res)
+            Err(Error::Status(res))
         }
     }
-}
\ No newline at end of file
+
+    pub async fn setup_streams(&self, body: SetupStreamsRequest) -> Result<Response> {
+        let mut request = Request::new_body(
+            Method::SETUP,
+            format!("rtsp://{}/666", self.peer.ip()),
+            Body::PList(plist::to_value(&body).unwrap()),
+        );
+        request.set_header("Content-Type", "application/x-apple-binary-plist");
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            self.set_session_active(true);
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// SETs UP a realtime stream in the best format of those the receiver reported in `/info`, trying
+    /// `codecs` in order, see [`StreamFormat::negotiate`] and [`StreamDescription::realtime`].
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] when the receiver takes none of `codecs`.
+    pub async fn setup_audio(&self, info: &DeviceInfo, codecs: &[Codec], control_port: u16, shk: [u8; 32], latency: u32) -> Result<(StreamFormat, StreamInfo)> {
+        let format = StreamFormat::negotiate(info, false, codecs)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no audio format in common with the receiver"))?;
+
+        let res = self.setup_streams(SetupStreamsRequest {
+            streams: vec![StreamDescription::realtime(format, control_port, shk, latency)],
+        }).await?;
+
+        let stream = SetupStreamsResponse::from_response(&res).and_then(|x| x.streams.into_iter().next())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "SETUP answer has no stream"))?;
+
+        Ok((format, stream))
+    }
+
+    /// Tells the receiver which addresses take part in the timing group, including our own.
+    pub async fn setpeers(&self, peers: Vec<String>) -> Result<Response> {
+        let mut request = Request::new_body(
+            Method::SETPEERS,
+            format!("rtsp://{}/666", self.peer.ip()),
+            Body::PList(plist::Value::Array(peers.into_iter().map(plist::Value::String).collect())),
+        );
+        request.set_header("Content-Type", "/peer-list-changed");
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Starts playback. The receiver's `Audio-Latency` answer is kept in [`Client::audio_latency`].
+    pub async fn record(&self) -> Result<Response> {
+        self.send_record(None).await
+    }
+
+    /// RECORD resuming at packet `seq` stamped `rtptime`, as after reconnecting in the middle of a stream.
+    pub async fn record_from(&self, seq: u16, rtptime: u32) -> Result<Response> {
+        self.send_record(Some((seq, rtptime))).await
+    }
+
+    async fn send_record(&self, position: Option<(u16, u32)>) -> Result<Response> {
+        let mut request = Request::new(Method::RECORD, format!("rtsp://{}/666", self.peer.ip()));
+
+        if let Some((seq, rtptime)) = position {
+            request.set_header("RTP-Info", format!("seq={};rtptime={}", seq, rtptime));
+        }
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            if let Some(Ok(latency)) = res.headers.get("Audio-Latency").map(|x| x.trim().parse::<u32>()) {
+                self.set_audio_latency(latency);
+            }
+
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    pub async fn teardown(&self) -> Result<Response> {
+        let req = self.request(
+            Request::new(Method::TEARDOWN, format!("rtsp://{}/666", self.peer.ip()))
+        ).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            self.set_session_active(false);
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Keeps the session alive, see [`Client::start_keepalive`].
+    pub async fn feedback(&self) -> Result<Response> {
+        let req = self.request(
+            Request::new(Method::POST, "/feedback")
+        ).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Sends `volume: <db>` where `db` runs from -30 to 0, and -144 mutes.
+    pub async fn set_volume(&self, db: f32) -> Result<Response> {
+        self.set_parameter("text/parameters", format!("volume: {:.6}\r\n", db).into_bytes(), None).await
+    }
+
+    /// OPTIONS, the first request of a RAOP session. `challenge` goes in `Apple-Challenge` for RSA
+    /// authenticated receivers to answer in `Apple-Response`.
+    pub async fn options(&self, challenge: Option<&str>) -> Result<Response> {
+        let mut request = Request::new(Method::OPTIONS, "*");
+
+        if let Some(x) = challenge {
+            request.set_header("Apple-Challenge", x);
+        }
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Describes the RAOP stream to come with an SDP body, see [`crate::raop::Sdp`].
+    pub async fn announce(&self, sdp: &str) -> Result<Response> {
+        let mut request = Request::new_body(
+            Method::ANNOUNCE,
+            format!("rtsp://{}/666", self.local_addr().ip()),
+            Body::Raw(sdp.as_bytes().to_vec()),
+        );
+        request.set_header("Content-Type", "application/sdp");
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// RAOP SETUP over UDP, offering our control and timing ports.
+    pub async fn setup_raop(&self, control_port: u16, timing_port: u16) -> Result<RaopTransport> {
+        let mut request = Request::new(Method::SETUP, format!("rtsp://{}/666", self.local_addr().ip()));
+        request.set_header(
+            "Transport",
+            format!("RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={};timing_port={}", control_port, timing_port),
+        );
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status != 200 {
+            return Err(Error::Status(res));
+        }
+
+        match RaopTransport::from_response(&res) {
+            Some(x) => {
+                self.set_session_active(true);
+                Ok(x)
+            },
+            None => Err(Error::Status(res)),
+        }
+    }
+
+    /// RAOP RECORD, starting playback from the packet with sequence number `seq` and RTP timestamp
+    /// `rtptime`.
+    pub async fn record_raop(&self, session: Option<&str>, seq: u16, rtptime: u32) -> Result<Response> {
+        let mut request = Request::new(Method::RECORD, format!("rtsp://{}/666", self.local_addr().ip()));
+        request.set_header("Range", "npt=0-");
+        request.set_header("RTP-Info", format!("seq={};rtptime={}", seq, rtptime));
+
+        if let Some(x) = session {
+            request.set_header("Session", x);
+        }
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            if let Some(Ok(latency)) = res.headers.get("Audio-Latency").map(|x| x.trim().parse::<u32>()) {
+                self.set_audio_latency(latency);
+            }
+
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Sends what the track playing from `rtptime` on is.
+    pub async fn set_metadata(&self, metadata: &TrackMetadata, rtptime: u32) -> Result<Response> {
+        self.set_parameter("application/x-dmap-tagged", metadata.to_dmap(), Some(rtptime)).await
+    }
+
+    pub async fn set_progress(&self, progress: Progress) -> Result<Response> {
+        let body = format!("progress: {}/{}/{}\r\n", progress.start, progress.current, progress.end);
+        self.set_parameter("text/parameters", body.into_bytes(), None).await
+    }
+
+    /// Sends cover art as `image/jpeg` or `image/png`. An empty `image/none` clears it.
+    pub async fn set_artwork(&self, content_type: &str, image: Vec<u8>, rtptime: u32) -> Result<Response> {
+        self.set_parameter(content_type, image, Some(rtptime)).await
+    }
+
+    async fn set_parameter(&self, content_type: &str, body: Vec<u8>, rtptime: Option<u32>) -> Result<Response> {
+        let mut request = Request::new_body(
+            Method::SET_PARAMETER,
+            format!("rtsp://{}/666", self.peer.ip()),
+            Body::Raw(body),
+        );
+        request.set_header("Content-Type", content_type);
+
+        if let Some(x) = rtptime {
+            request.set_header("RTP-Info", format!("rtptime={}", x));
+        }
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    /// Drops buffered audio up to the packet with sequence number `seq` and RTP timestamp `rtptime`.
+    pub async fn flush(&self, seq: u16, rtptime: u32) -> Result<Response> {
+        let mut request = Request::new(Method::FLUSH, format!("rtsp://{}/666", self.peer.ip()));
+        request.set_header("RTP-Info", format!("seq={};rtptime={}", seq, rtptime));
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        if res.status == 200 {
+            Ok(res)
+        } else {
+            Err(Error::Status(res))
+        }
+    }
+
+    async fn pairing_step(&self, path: &str, transient: bool, message: Tlv8) -> Result<Tlv8> {
+        let mut request = Request::new_body(Method::POST, path, Body::Raw(message.to_bytes()));
+        request.set_header("Content-Type", "application/octet-stream");
+        request.set_header("X-Apple-HKP", if transient { 4 } else { 3 });
+
+        let req = self.request(request).await?;
+        let res = req.await?;
+
+        match (res.status, &res.body) {
+            (200, Body::Raw(x)) => Ok(Tlv8::parse(x)?),
+            (200, Body::None) => Ok(Tlv8::new()),
+            _ => Err(Error::Status(res)),
+        }
+    }
+
+    /// Pairs without a PIN, as HomePods and other AirPlay 2 speakers allow, and encrypts the connection
+    /// with the resulting keys.
+    pub async fn pair_setup_transient(&self) -> Result<SessionKeys> {
+        let mut setup = SetupClient::new(Identity::generate(""), pairing::TRANSIENT_PIN, true);
+        let mut message = Some(setup.start());
+
+        while let Some(x) = message {
+            let answer = self.pairing_step("/pair-setup", true, x).await?;
+            message = setup.handle(&answer)?;
+        }
+
+        let keys = setup.session_keys().ok_or(pairing::Error::Authentication)?;
+        self.enable_encryption(&keys).await;
+        Ok(keys)
+    }
+
+    /// Pairs with the PIN the receiver shows, returning its identity to keep for [`Client::pair_verify`].
+    pub async fn pair_setup(&self, identity: Identity, pin: &str) -> Result<Peer> {
+        let mut setup = SetupClient::new(identity, pin, false);
+        let mut message = Some(setup.start());
+
+        while let Some(x) = message {
+            let answer = self.pairing_step("/pair-setup", false, x).await?;
+            message = setup.handle(&answer)?;
+        }
+
+        Ok(setup.peer().cloned().ok_or(pairing::Error::Authentication)?)
+    }
+
+    /// Proves a previous pairing and encrypts the connection. The receiver's signature is checked when
+    /// `peer` is given.
+    pub async fn pair_verify(&self, identity: Identity, peer: Option<Peer>) -> Result<SessionKeys> {
+        let mut verify = VerifyClient::new(identity, peer);
+        let mut message = Some(verify.start());
+
+        while let Some(x) = message {
+            let answer = self.pairing_step("/pair-verify", false, x).await?;
+            message = verify.handle(&answer)?;
+        }
+
+        let keys = verify.session_keys().ok_or(pairing::Error::Authentication)?;
+        self.enable_encryption(&keys).await;
+        Ok(keys)
+    }
+}
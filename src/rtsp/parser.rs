@@ -0,0 +1,419 @@
+use std::fmt;
+
+use super::{Body, Headers, Method, Request, Response};
+
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    InvalidStatusLine(String),
+    InvalidRequestLine(String),
+    InvalidHeader(String),
+    InvalidContentLength(String),
+    HeadTooLarge,
+    BodyTooLarge(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidStatusLine(x) => write!(f, "invalid status line: {:?}", x),
+            Self::InvalidRequestLine(x) => write!(f, "invalid request line: {:?}", x),
+            Self::InvalidHeader(x) => write!(f, "invalid header: {:?}", x),
+            Self::InvalidContentLength(x) => write!(f, "invalid Content-Length: {:?}", x),
+            Self::HeadTooLarge => write!(f, "message head exceeds {} bytes", MAX_HEAD_LEN),
+            Self::BodyTooLarge(x) => write!(f, "message body of {} bytes exceeds {} bytes", x, MAX_BODY_LEN),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Returns the length of the head including the blank line that terminates it. Lines may end in CRLF or
+/// a bare LF.
+fn head_len(buf: &[u8]) -> Option<usize> {
+    let mut line_start = 0;
+
+    for (i, b) in buf.iter().enumerate() {
+        if *b == b'\n' {
+            let line = &buf[line_start..i];
+
+            if line.is_empty() || line == b"\r" {
+                return Some(i + 1);
+            }
+
+            line_start = i + 1;
+        }
+    }
+
+    None
+}
+
+fn is_version(x: &str) -> bool {
+    x.starts_with("RTSP/") || x.starts_with("HTTP/")
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, String), ParseError> {
+    let mut parts = line.split_whitespace();
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None)
+            if is_version(version) && method.bytes().all(|x| x.is_ascii_uppercase() || x == b'_' || x == b'-') => {
+            Ok((Method::from(method), path.to_string(), version.to_string()))
+        },
+        _ => Err(ParseError::InvalidRequestLine(line.to_string())),
+    }
+}
+
+fn parse_status_line(line: &str) -> Result<(i32, String), ParseError> {
+    let invalid = || ParseError::InvalidStatusLine(line.to_string());
+
+    let (version, rest) = line.split_once([' ', '\t']).ok_or_else(invalid)?;
+
+    if !is_version(version) {
+        return Err(invalid());
+    }
+
+    let rest = rest.trim_start();
+    let (code, reason) = rest.split_once([' ', '\t']).unwrap_or((rest, ""));
+
+    if code.len() != 3 || !code.bytes().all(|x| x.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    Ok((code.parse().map_err(|_| invalid())?, reason.trim().to_string()))
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+
+    for line in lines {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            let value = headers.last_mut().ok_or_else(|| ParseError::InvalidHeader(line.to_string()))?;
+
+            if !value.is_empty() {
+                value.push(' ');
+            }
+
+            value.push_str(line.trim());
+            continue;
+        }
+
+        let (key, value) = line.split_once(':').ok_or_else(|| ParseError::InvalidHeader(line.to_string()))?;
+        let key = key.trim();
+
+        if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(ParseError::InvalidHeader(line.to_string()));
+        }
+
+        headers.append(key, value.trim());
+    }
+
+    Ok(headers)
+}
+
+fn parse_body(headers: &Headers, body: Vec<u8>) -> Body {
+    if body.is_empty() {
+        return Body::None;
+    }
+
+    let content_type = headers.content_type().unwrap_or("");
+
+    if content_type.eq_ignore_ascii_case("application/x-apple-binary-plist") {
+        if let Ok(value) = plist::from_bytes::<plist::Value>(&body) {
+            return Body::PList(value);
+        }
+    }
+
+    Body::Raw(body)
+}
+
+/// A message read off a connection. Receivers mostly send responses, but may also send requests of their
+/// own on the same connection.
+#[derive(Clone, Debug)]
+pub enum Message {
+    Request(Request),
+    Response(Response),
+}
+
+enum StartLine {
+    Status((i32, String)),
+    Request((Method, String, String)),
+}
+
+/// Incremental parser for RTSP (and HTTP) messages arriving in arbitrary chunks.
+///
+/// Bytes are appended with [`Parser::feed`] and complete messages taken out with [`Parser::next`]. A
+/// message is a response when its first line starts with a protocol version, and a request otherwise. Any
+/// input is either accepted, left pending, or rejected with a [`ParseError`]; it never panics. After an
+/// error the stream cannot be resynchronised and should be closed.
+#[derive(Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes received but not yet consumed by a complete message.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Message>, ParseError> {
+        // Tolerate stray line breaks between messages.
+        let skip = self.buf.iter().take_while(|x| **x == b'\r' || **x == b'\n').count();
+        self.buf.drain(..skip);
+
+        let Some(head_len) = head_len(&self.buf) else {
+            if self.buf.len() > MAX_HEAD_LEN {
+                return Err(ParseError::HeadTooLarge);
+            }
+
+            return Ok(None);
+        };
+
+        if head_len > MAX_HEAD_LEN {
+            return Err(ParseError::HeadTooLarge);
+        }
+
+        let head = String::from_utf8_lossy(&self.buf[..head_len]).into_owned();
+        let mut lines = head.lines().take_while(|x| !x.is_empty());
+
+        let start_line = lines.next().unwrap_or("");
+        let start = if start_line.split_whitespace().next().is_some_and(is_version) {
+            StartLine::Status(parse_status_line(start_line)?)
+        } else {
+            StartLine::Request(parse_request_line(start_line)?)
+        };
+        let headers = parse_headers(lines)?;
+
+        let body_len = match headers.get("Content-Length") {
+            Some(x) => x.trim().parse::<usize>().map_err(|_| ParseError::InvalidContentLength(x.to_string()))?,
+            None => 0,
+        };
+
+        if body_len > MAX_BODY_LEN {
+            return Err(ParseError::BodyTooLarge(body_len));
+        }
+
+        if self.buf.len() < head_len + body_len {
+            return Ok(None);
+        }
+
+        let body: Vec<u8> = self.buf.drain(..head_len + body_len).skip(head_len).collect();
+        let body = parse_body(&headers, body);
+
+        Ok(Some(match start {
+            StartLine::Status((status, status_string)) => Message::Response(Response { status, status_string, headers, body }),
+            StartLine::Request((method, path, version)) => Message::Request(Request { method, path, version, headers, body }),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn parse_messages(chunks: &[&[u8]]) -> Result<Vec<Message>, ParseError> {
+        let mut parser = Parser::new();
+        let mut messages = Vec::new();
+
+        for chunk in chunks {
+            parser.feed(chunk);
+
+            while let Some(message) = parser.next()? {
+                messages.push(message);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn parse_all(chunks: &[&[u8]]) -> Result<Vec<Response>, ParseError> {
+        Ok(parse_messages(chunks)?.into_iter().map(|x| match x {
+            Message::Response(x) => x,
+            Message::Request(x) => panic!("unexpected request: {:?}", x),
+        }).collect())
+    }
+
+    fn plist_body() -> Vec<u8> {
+        let mut body = Vec::new();
+        plist::Value::Dictionary(plist::Dictionary::from_iter([("name", plist::Value::from("Nappali"))]))
+            .to_writer_binary(&mut body)
+            .unwrap();
+        body
+    }
+
+    fn sample_stream() -> Vec<u8> {
+        let body = plist_body();
+        let mut stream = format!(
+            "RTSP/1.0 200 OK\r\nCSeq: 0\r\nContent-Type: application/x-apple-binary-plist\r\nContent-Length: {}\r\n\r\n",
+            body.len(),
+        ).into_bytes();
+        stream.extend(&body);
+        stream.extend(b"RTSP/1.0 453 Not Enough Bandwidth\r\nCSeq: 1\r\n\r\n");
+        stream.extend(b"RTSP/1.0 200\r\ncseq:2\r\ncontent-length: 5\r\nServer: AirTunes/\r\n  366.0\r\n\r\nhello");
+        stream
+    }
+
+    #[test]
+    fn parses_plist_response() {
+        let responses = parse_all(&[&sample_stream()]).unwrap();
+        assert_eq!(responses.len(), 3);
+
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[0].status_string, "OK");
+        assert!(matches!(&responses[0].body, Body::PList(plist::Value::Dictionary(x)) if x.get("name").and_then(|x| x.as_string()) == Some("Nappali")));
+
+        assert_eq!(responses[1].status, 453);
+        assert_eq!(responses[1].status_string, "Not Enough Bandwidth");
+        assert!(matches!(responses[1].body, Body::None));
+    }
+
+    #[test]
+    fn handles_missing_reason_lowercase_and_folded_headers() {
+        let responses = parse_all(&[&sample_stream()]).unwrap();
+        let response = &responses[2];
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.status_string, "");
+        assert_eq!(response.headers.get("CSeq"), Some("2"));
+        assert_eq!(response.headers.get("server"), Some("AirTunes/ 366.0"));
+        assert_eq!(response.headers.content_length(), Some(5));
+        assert!(matches!(&response.body, Body::Raw(x) if x == b"hello"));
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds() {
+        let responses = parse_all(&[b"RTSP/1.0 200 OK\nCSeq: 7\n\n"]).unwrap();
+        assert_eq!(responses[0].headers.get("cseq"), Some("7"));
+    }
+
+    #[test]
+    fn detects_requests() {
+        let messages = parse_messages(&[
+            b"POST /command RTSP/1.0\r\nCSeq: 3\r\nContent-Length: 2\r\n\r\nhiRTSP/1.0 200 OK\r\nCSeq: 0\r\n\r\n",
+            b"POST /event HTTP/1.1\r\nX-Apple-Session-ID: 1\r\n\r\n",
+        ]).unwrap();
+
+        assert!(matches!(&messages[0], Message::Request(x)
+            if x.method == Method::POST && x.path == "/command" && x.version == "RTSP/1.0" && x.headers.cseq() == Some(3) && matches!(&x.body, Body::Raw(b) if b == b"hi")));
+        assert!(matches!(&messages[1], Message::Response(x) if x.status == 200));
+        assert!(matches!(&messages[2], Message::Request(x) if x.version == "HTTP/1.1" && x.path == "/event"));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(parse_all(&[b"garbage\r\n\r\n"]), Err(ParseError::InvalidRequestLine(_))));
+        assert!(matches!(parse_all(&[b"get / RTSP/1.0\r\n\r\n"]), Err(ParseError::InvalidRequestLine(_))));
+        assert!(matches!(parse_all(&[b"RTSP/1.0 20x OK\r\n\r\n"]), Err(ParseError::InvalidStatusLine(_))));
+        assert!(matches!(parse_all(&[b"RTSP/1.0 200 OK\r\nno colon\r\n\r\n"]), Err(ParseError::InvalidHeader(_))));
+        assert!(matches!(parse_all(&[b"RTSP/1.0 200 OK\r\n folded first\r\n\r\n"]), Err(ParseError::InvalidHeader(_))));
+        assert!(matches!(parse_all(&[b"RTSP/1.0 200 OK\r\nContent-Length: -1\r\n\r\n"]), Err(ParseError::InvalidContentLength(_))));
+        assert!(matches!(parse_all(&[b"RTSP/1.0 200 OK\r\nContent-Length: 99999999999\r\n\r\n"]), Err(ParseError::BodyTooLarge(_))));
+        assert!(matches!(parse_all(&[&vec![b'a'; MAX_HEAD_LEN + 1]]), Err(ParseError::HeadTooLarge)));
+    }
+
+    #[test]
+    fn waits_for_incomplete_messages() {
+        let mut parser = Parser::new();
+        parser.feed(b"RTSP/1.0 200 OK\r\nContent-Length: 4\r\n\r\nab");
+        assert!(parser.next().unwrap().is_none());
+        parser.feed(b"cd");
+        assert!(parser.next().unwrap().is_some());
+        assert_eq!(parser.pending(), 0);
+    }
+
+    #[test]
+    fn fuzz_random_chunking() {
+        let stream = sample_stream();
+        let expected = parse_all(&[&stream]).unwrap();
+        let mut rng = Lcg(1);
+
+        for _ in 0..2000 {
+            let mut chunks = Vec::new();
+            let mut rest = stream.as_slice();
+
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(1 + rng.below(rest.len().min(40)));
+                chunks.push(chunk);
+                rest = tail;
+            }
+
+            let responses = parse_all(&chunks).unwrap();
+            assert_eq!(responses.len(), expected.len());
+
+            for (a, b) in responses.iter().zip(&expected) {
+                assert_eq!(a.status, b.status);
+                assert_eq!(a.headers, b.headers);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_mutations_never_panic() {
+        let stream = sample_stream();
+        let mut rng = Lcg(2);
+
+        for _ in 0..5000 {
+            let mut input = stream.clone();
+
+            for _ in 0..1 + rng.below(8) {
+                let i = rng.below(input.len());
+
+                match rng.below(4) {
+                    0 => input[i] = rng.next() as u8,
+                    1 => { input.remove(i); },
+                    2 => input.insert(i, [b'\r', b'\n', b':', b' ', 0xff][rng.below(5)]),
+                    _ => input.truncate(i),
+                }
+
+                if input.is_empty() {
+                    break;
+                }
+            }
+
+            let split = rng.below(input.len() + 1);
+            let _ = parse_messages(&[&input[..split], &input[split..]]);
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes_never_panic() {
+        let mut rng = Lcg(3);
+
+        for _ in 0..5000 {
+            let input: Vec<u8> = (0..rng.below(256)).map(|_| match rng.below(6) {
+                0 => b'\n',
+                1 => b':',
+                _ => rng.next() as u8,
+            }).collect();
+
+            let _ = parse_messages(&[b"RTSP/1.0 200 OK\r\n", &input]);
+            let _ = parse_messages(&[b"GET /info RTSP/1.0\r\n", &input]);
+            let _ = parse_messages(&[&input]);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, time::{Duration, Instant}};
+
+use tokio::{sync::oneshot, time::Sleep};
+use tracing::Span;
+
+use super::{Error, Response};
+
+pub(crate) type PendingSeqs = Arc<Mutex<HashMap<usize, oneshot::Sender<Result<Response, Error>>>>>;
+
+/// The answer to a request sent with [`super::Client::request`].
+///
+/// Resolves to [`Error::Timeout`] once its timeout elapses and to [`Error::ConnectionClosed`] if the
+/// connection goes away first. Dropping it stops listening for the answer.
+pub struct PendingResponse {
+    seq: usize,
+    rx: oneshot::Receiver<Result<Response, Error>>,
+    pending_seqs: PendingSeqs,
+    deadline: Option<Pin<Box<Sleep>>>,
+    span: Span,
+    started: Instant,
+}
+
+impl PendingResponse {
+    pub(crate) fn new(seq: usize, rx: oneshot::Receiver<Result<Response, Error>>, pending_seqs: PendingSeqs, timeout: Option<Duration>, span: Span) -> Self {
+        PendingResponse {
+            seq,
+            rx,
+            pending_seqs,
+            deadline: timeout.map(|x| Box::pin(tokio::time::sleep(x))),
+            span,
+            started: Instant::now(),
+        }
+    }
+
+    fn trace(&self, result: &Result<Response, Error>) {
+        let latency = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.span.record("latency_ms", latency);
+
+        match result {
+            Ok(res) => {
+                self.span.record("status", res.status);
+                tracing::debug!(parent: &self.span, "{} {}", res.status, res.status_string);
+            },
+            Err(err) => tracing::debug!(parent: &self.span, "{}", err),
+        }
+    }
+
+    /// The `CSeq` the request was sent with.
+    pub fn seq(&self) -> usize {
+        self.seq
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Result<Response, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(x) = Pin::new(&mut self.rx).poll(cx) {
+            let result = x.unwrap_or(Err(Error::ConnectionClosed));
+            self.trace(&result);
+            return Poll::Ready(result);
+        }
+
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                self.pending_seqs.lock().unwrap().remove(&self.seq);
+                let result = Err(Error::Timeout);
+                self.trace(&result);
+                return Poll::Ready(result);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if let Ok(mut pending_seqs) = self.pending_seqs.lock() {
+            pending_seqs.remove(&self.seq);
+        }
+    }
+}
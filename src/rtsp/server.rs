@@ -0,0 +1,161 @@
+use std::{borrow::Cow, future::Future, net::SocketAddr, time::Instant};
+
+use tokio::{io::{self, AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream, ToSocketAddrs}, task::{JoinHandle, JoinSet}};
+use tracing::Instrument;
+
+use crate::pairing::{Decrypter, Encrypter, SessionKeys};
+
+use super::{Message, Parser, Request, Response};
+
+/// What a [`Handler`] answers to one request.
+#[derive(Debug)]
+pub struct Answer {
+    pub response: Response,
+    /// Encrypts the connection from the next message on, after pair-verify or a transient pair-setup.
+    pub keys: Option<SessionKeys>,
+}
+
+impl From<Response> for Answer {
+    fn from(response: Response) -> Self {
+        Answer { response, keys: None }
+    }
+}
+
+/// Answers the requests of one connection, in order.
+pub trait Handler: Send + 'static {
+    fn handle(&mut self, request: Request) -> impl Future<Output = Answer> + Send;
+
+    /// The connection is gone, either side closed it.
+    fn closed(&mut self) {}
+}
+
+/// Accepts RTSP connections and hands every one of them to its own [`Handler`].
+///
+/// Responses get the request's `CSeq` and are sent in the request's protocol version. Connections are
+/// closed when the server is dropped.
+pub struct Server {
+    local_addr: SocketAddr,
+    accept: JoinHandle<()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+impl Server {
+    /// Listens on `addr`, creating a handler with `new_handler` for every connection from a peer.
+    pub async fn bind<A, F, H>(addr: A, new_handler: F) -> io::Result<Server>
+    where
+        A: ToSocketAddrs,
+        F: Fn(SocketAddr) -> H + Send + Sync + 'static,
+        H: Handler,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let accept = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => {
+                            tracing::debug!(%peer, "RTSP connection accepted");
+                            connections.spawn(Server::serve(stream, peer, new_handler(peer)));
+                        },
+                        Err(err) => {
+                            tracing::warn!("Failed to accept RTSP connection: {}", err);
+                        },
+                    },
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {},
+                }
+            }
+        });
+
+        Ok(Server { local_addr, accept })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    async fn serve<H: Handler>(stream: TcpStream, peer: SocketAddr, mut handler: H) {
+        let (mut rx, mut tx) = stream.into_split();
+        let mut parser = Parser::new();
+        let mut buf = vec![0_u8; 4096];
+        let mut encrypter: Option<Encrypter> = None;
+        let mut decrypter: Option<Decrypter> = None;
+
+        loop {
+            let request = match parser.next() {
+                Ok(Some(Message::Request(x))) => x,
+                Ok(Some(Message::Response(x))) => {
+                    tracing::warn!(%peer, status = x.status, "Ignoring RTSP response on a server connection");
+                    continue;
+                },
+                Ok(None) => match rx.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        let data = match decrypter.as_mut() {
+                            Some(x) => x.decrypt(&buf[..n]).map(Cow::Owned),
+                            None => Ok(Cow::Borrowed(&buf[..n])),
+                        };
+
+                        match data {
+                            Ok(x) => parser.feed(&x),
+                            Err(err) => {
+                                tracing::warn!(%peer, "Closing RTSP connection after undecryptable data: {}", err);
+                                break;
+                            },
+                        }
+
+                        continue;
+                    },
+                },
+                Err(err) => {
+                    tracing::warn!(%peer, "Closing RTSP connection after malformed message: {}", err);
+                    break;
+                },
+            };
+
+            let started = Instant::now();
+            let span = tracing::debug_span!("rtsp_serve", %peer, method = %request.method, path = %request.path, cseq = request.headers.cseq());
+            let Answer { mut response, keys } = handler.handle(request.clone()).await;
+
+            if let Some(seq) = request.headers.get("CSeq") {
+                response.headers.set("CSeq", seq);
+            }
+
+            let bytes = response.to_bytes(&request.version);
+            let bytes = match encrypter.as_mut() {
+                Some(x) => x.encrypt(&bytes),
+                None => bytes,
+            };
+
+            let sent = async {
+                let sent = tx.write_all(&bytes).await;
+
+                match &sent {
+                    Ok(()) => tracing::debug!(status = response.status, latency_ms = started.elapsed().as_secs_f64() * 1000.0, "answered"),
+                    Err(err) => tracing::warn!("Failed to answer: {}", err),
+                }
+
+                sent
+            }.instrument(span).await;
+
+            if sent.is_err() {
+                break;
+            }
+
+            if let Some(keys) = keys {
+                encrypter = Some(Encrypter::new(&keys.write));
+                decrypter = Some(Decrypter::new(&keys.read));
+            }
+        }
+
+        tracing::debug!(%peer, "RTSP connection closed");
+        handler.closed();
+    }
+}
@@ -0,0 +1,295 @@
+use std::{future::Future, io, net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+
+use tokio::{sync::{broadcast, watch}, task::JoinHandle};
+
+use crate::{dacp::Remote, mdns, rtsp::{self, ops::StreamInfo, Client}};
+
+/// Brings a fresh connection to the point where audio can flow again.
+///
+/// Implementations run pair-verify and SETUP (both the session and the audio stream) on `client`. The
+/// [`Session`] sends RECORD itself once this succeeds.
+pub trait Setup: Send + Sync + 'static {
+    fn setup(&self, client: &Client) -> impl Future<Output = Result<StreamInfo, rtsp::Error>> + Send;
+
+    /// The sequence number and RTP timestamp of the next packet the sender will send, such as
+    /// [`crate::group::Group::position`]. RECORD after a reconnect carries it so the device picks up there.
+    fn position(&self) -> Option<(u16, u32)> {
+        None
+    }
+
+    /// Sent as `DACP-ID` and `Active-Remote` on every connection, such as [`crate::dacp::Server::remote`], so
+    /// the device can still reach our remote control after a reconnect.
+    fn remote(&self) -> Option<Remote> {
+        None
+    }
+
+    /// Where the device with `device_id` is now. Looks it up over mDNS unless overridden.
+    fn locate(&self, device_id: &str, timeout: Duration) -> impl Future<Output = Option<SocketAddr>> + Send {
+        async move { mdns::resolve(device_id, timeout).await.as_ref().and_then(pick_addr) }
+    }
+}
+
+/// How a [`Session`] retries after losing its connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// How long each mDNS lookup for the device may take.
+    pub resolve_timeout: Duration,
+    /// Gives up after this many failed attempts in a row, retrying forever when `None`.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            resolve_timeout: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(self.max)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The control connection went away unexpectedly.
+    Disconnected,
+    /// Looking the device up by its device ID.
+    Resolving { attempt: u32 },
+    /// Found the device, connecting and setting up again.
+    Reconnecting { attempt: u32, addr: SocketAddr },
+    /// An attempt failed and the next one starts after `retry_in`.
+    AttemptFailed { attempt: u32, reason: String, retry_in: Duration },
+    /// Streaming can continue on [`Session::connection`].
+    Resumed(Connection),
+    /// Out of attempts. The session stays down.
+    Failed { reason: String },
+    /// The session was ended on purpose, through [`Session::close`] or a TEARDOWN. Nothing reconnects.
+    Closed,
+}
+
+/// The client and audio stream of one connection to the device.
+#[derive(Clone)]
+pub struct Connection {
+    pub client: Client,
+    pub stream: StreamInfo,
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("peer", &self.client.peer)
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+/// Keeps a receiver connected, re-establishing the session whenever the control connection drops.
+///
+/// On disconnection the device is found again by its device ID, reconnected with [`Setup::remote`], set up and
+/// told to RECORD from [`Setup::position`], after which [`Event::Resumed`] carries the new connection.
+/// Senders built on a [`crate::group::Group`] keep streaming by joining the new client under the same ID,
+/// since the group keeps its timeline across members coming and going.
+///
+/// A connection closed on purpose, by [`Session::close`], [`Client::close`] or after a TEARDOWN, isn't
+/// reconnected, even when the TEARDOWN itself fails.
+pub struct Session {
+    device_id: String,
+    connection: watch::Receiver<Option<Connection>>,
+    events: broadcast::Sender<Event>,
+    stop: watch::Sender<bool>,
+    failed: Arc<AtomicBool>,
+    supervisor: JoinHandle<()>,
+}
+
+fn pick_addr(meta: &mdns::Metadata) -> Option<SocketAddr> {
+    meta.ip_addresses.iter()
+        .find(|x| x.is_ipv4())
+        .or(meta.ip_addresses.first())
+        .map(|x| SocketAddr::new(*x, meta.port))
+}
+
+fn emit(events: &broadcast::Sender<Event>, event: Event) {
+    match &event {
+        Event::AttemptFailed { .. } | Event::Failed { .. } => tracing::warn!(?event, "session"),
+        _ => tracing::info!(?event, "session"),
+    }
+
+    let _ = events.send(event);
+}
+
+async fn establish<S: Setup>(addr: SocketAddr, setup: &S) -> Result<Connection, rtsp::Error> {
+    let client = Client::connect(addr).await?;
+    client.set_remote(setup.remote());
+    let stream = setup.setup(&client).await?;
+
+    match setup.position() {
+        Some((seq, rtptime)) => client.record_from(seq, rtptime).await?,
+        None => client.record().await?,
+    };
+
+    Ok(Connection { client, stream })
+}
+
+async fn reconnect<S: Setup>(device_id: &str, setup: &S, backoff: &Backoff, events: &broadcast::Sender<Event>) -> Option<Connection> {
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        emit(events, Event::Resolving { attempt });
+
+        let result = match setup.locate(device_id, backoff.resolve_timeout).await {
+            Some(addr) => {
+                emit(events, Event::Reconnecting { attempt, addr });
+                establish(addr, setup).await.map_err(|x| x.to_string())
+            },
+            None => Err(format!("{} not found", device_id)),
+        };
+
+        let reason = match result {
+            Ok(connection) => return Some(connection),
+            Err(x) => x,
+        };
+
+        if backoff.max_attempts.is_some_and(|x| attempt >= x) {
+            emit(events, Event::Failed { reason });
+            return None;
+        }
+
+        let retry_in = backoff.delay(attempt);
+        emit(events, Event::AttemptFailed { attempt, reason, retry_in });
+        tokio::time::sleep(retry_in).await;
+    }
+}
+
+impl Session {
+    /// Finds the device by `device_id`, connects and sets it up, then watches the connection.
+    pub async fn start<S: Setup>(device_id: impl ToString, setup: S, backoff: Backoff) -> Result<Session, rtsp::Error> {
+        let device_id = device_id.to_string();
+        let addr = setup.locate(&device_id, backoff.resolve_timeout).await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", device_id)))?;
+
+        let connection = establish(addr, &setup).await?;
+        Ok(Session::supervise(device_id, connection, setup, backoff))
+    }
+
+    /// Watches an already established connection, reconnecting as [`Session::start`] would.
+    pub fn supervise<S: Setup>(device_id: impl ToString, connection: Connection, setup: S, backoff: Backoff) -> Session {
+        let device_id = device_id.to_string();
+
+        if let Some(remote) = setup.remote() {
+            connection.client.set_remote(Some(remote));
+        }
+
+        let (events, _) = broadcast::channel(32);
+        let (connection_tx, connection) = watch::channel(Some(connection));
+        let (stop, mut stopped) = watch::channel(false);
+        let failed = Arc::new(AtomicBool::new(false));
+        let setup = Arc::new(setup);
+
+        let supervisor = tokio::spawn({
+            let device_id = device_id.clone();
+            let events = events.clone();
+            let failed = failed.clone();
+
+            async move {
+                loop {
+                    let client = match connection_tx.borrow().as_ref() {
+                        Some(x) => x.client.clone(),
+                        None => break,
+                    };
+
+                    tokio::select! {
+                        _ = client.closed() => {},
+                        _ = stopped.wait_for(|x| *x) => break,
+                    }
+
+                    // Closing or a TEARDOWN ends the session, a dropped connection leaves it active.
+                    if client.is_closed_on_purpose() || !client.is_session_active() {
+                        connection_tx.send_replace(None);
+                        emit(&events, Event::Closed);
+                        break;
+                    }
+
+                    drop(client);
+                    connection_tx.send_replace(None);
+                    emit(&events, Event::Disconnected);
+
+                    let connection = tokio::select! {
+                        x = reconnect(&device_id, setup.as_ref(), &backoff, &events) => x,
+                        _ = stopped.wait_for(|x| *x) => break,
+                    };
+
+                    match connection {
+                        Some(x) => {
+                            connection_tx.send_replace(Some(x.clone()));
+                            emit(&events, Event::Resumed(x));
+                        },
+                        None => {
+                            failed.store(true, Ordering::SeqCst);
+                            break;
+                        },
+                    }
+                }
+            }
+        });
+
+        Session {
+            device_id,
+            connection,
+            events,
+            stop,
+            failed,
+            supervisor,
+        }
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// The current connection, `None` while reconnecting or after giving up.
+    pub fn connection(&self) -> Option<Connection> {
+        self.connection.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Whether the session gave up reconnecting.
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Ends the session on purpose: stops reconnecting, then tears down and closes the current connection.
+    pub async fn close(mut self) -> Result<(), rtsp::Error> {
+        let running = !self.supervisor.is_finished();
+        self.stop.send_replace(true);
+        let _ = (&mut self.supervisor).await;
+
+        let result = match self.connection() {
+            Some(x) => x.client.close().await,
+            None => Ok(()),
+        };
+
+        if running {
+            emit(&self.events, Event::Closed);
+        }
+
+        result
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.supervisor.abort();
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use airplay::rtsp::{Body, Client, Message, Method, Parser, Request, Response};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, time::timeout};
+
+/// The other end of a client's connection, driven by hand.
+struct Peer {
+    stream: TcpStream,
+    parser: Parser,
+}
+
+impl Peer {
+    async fn recv(&mut self) -> Message {
+        let mut buf = vec![0_u8; 4096];
+
+        loop {
+            if let Some(message) = self.parser.next().unwrap() {
+                return message;
+            }
+
+            let n = timeout(Duration::from_secs(5), self.stream.read(&mut buf)).await.unwrap().unwrap();
+            assert_ne!(n, 0, "client closed the connection");
+            self.parser.feed(&buf[..n]);
+        }
+    }
+
+    async fn request(&mut self) -> Request {
+        match self.recv().await {
+            Message::Request(x) => x,
+            Message::Response(x) => panic!("expected a request, got {:?}", x),
+        }
+    }
+}
+
+async fn connected() -> (Client, Peer) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, accepted) = tokio::join!(Client::connect(listener.local_addr().unwrap()), listener.accept());
+    (client.unwrap(), Peer { stream: accepted.unwrap().0, parser: Parser::new() })
+}
+
+#[tokio::test]
+async fn pipelined_requests_from_clones_get_their_own_answers() {
+    let (client, mut peer) = connected().await;
+    let other = client.clone();
+
+    let (first, second) = tokio::join!(client.request(Request::new(Method::GET, "/first")), other.request(Request::new(Method::GET, "/second")));
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_ne!(first.seq(), second.seq());
+    assert_eq!(client.pending_requests(), 2);
+
+    let requests = [peer.request().await, peer.request().await];
+
+    // Answered in the opposite order, only the CSeq ties them together.
+    for request in requests.iter().rev() {
+        let mut response = Response::new(200, "OK");
+        response.headers.set("CSeq", request.headers.get("CSeq").unwrap());
+        response.headers.set("X-Path", &request.path);
+        peer.stream.write_all(&response.to_bytes(&request.version)).await.unwrap();
+    }
+
+    let (first_seq, second_seq) = (first.seq(), second.seq());
+    let (first, second) = tokio::join!(first, second);
+    let (first, second) = (first.unwrap(), second.unwrap());
+
+    assert_eq!(first.headers.get("X-Path"), Some("/first"));
+    assert_eq!(first.headers.cseq(), Some(first_seq));
+    assert_eq!(second.headers.get("X-Path"), Some("/second"));
+    assert_eq!(second.headers.cseq(), Some(second_seq));
+    assert_eq!(client.pending_requests(), 0);
+}
+
+#[tokio::test]
+async fn answers_requests_from_the_peer_with_the_handler() {
+    let (client, mut peer) = connected().await;
+
+    peer.stream.write_all(b"POST /command RTSP/1.0\r\nCSeq: 3\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+    let Message::Response(response) = peer.recv().await else { panic!("expected a response") };
+    assert_eq!((response.status, response.headers.cseq()), (501, Some(3)));
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    client.set_request_handler(move |request: Request| {
+        let tx = tx.clone();
+
+        async move {
+            let mut response = Response::new_body(200, "OK", Body::Raw(b"done".to_vec()));
+            response.headers.set("X-Path", &request.path);
+            tx.send(request).unwrap();
+            response
+        }
+    });
+
+    peer.stream.write_all(b"POST /command RTSP/1.0\r\nCSeq: 7\r\nContent-Length: 5\r\n\r\nhello").await.unwrap();
+    let Message::Response(response) = peer.recv().await else { panic!("expected a response") };
+    assert_eq!(response.status, 200);
+    assert_eq!(response.headers.cseq(), Some(7));
+    assert_eq!(response.headers.get("X-Path"), Some("/command"));
+    assert_eq!(response.body.to_bytes(), b"done");
+
+    let request = timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(request.method, Method::POST);
+    assert_eq!(request.body.to_bytes(), b"hello");
+
+    // Reverse HTTP requests are answered in HTTP.
+    peer.stream.write_all(b"GET /event HTTP/1.1\r\nCSeq: 8\r\n\r\n").await.unwrap();
+    let mut buf = vec![0; 1024];
+    let n = timeout(Duration::from_secs(5), peer.stream.read(&mut buf)).await.unwrap().unwrap();
+    let response = String::from_utf8_lossy(&buf[..n]);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("CSeq: 8\r\n"), "{}", response);
+    assert!(!client.is_closed());
+}
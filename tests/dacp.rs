@@ -0,0 +1,68 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use airplay::{dacp::{Remote, RemoteCommand, Server}, rtsp::{self, server::{Answer, Handler}, Client, Headers, Request, Response}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::broadcast, time::timeout};
+
+/// Sends one request the way receivers do and returns the status line.
+async fn get(server: &Server, path: &str, active_remote: u32) -> String {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: sender.local.\r\nActive-Remote: {}\r\n\r\n", path, active_remote);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut buf = vec![0; 1024];
+    let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap().unwrap();
+    String::from_utf8_lossy(&buf[..n]).lines().next().unwrap().to_string()
+}
+
+async fn command(commands: &mut broadcast::Receiver<RemoteCommand>) -> RemoteCommand {
+    timeout(Duration::from_secs(5), commands.recv()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn turns_requests_into_commands() {
+    let remote = Remote::generate();
+    let server = Server::bind("127.0.0.1:0", remote).await.unwrap();
+    let mut commands = server.subscribe();
+
+    assert_eq!(get(&server, "/ctrl-int/1/playpause", remote.active_remote).await, "HTTP/1.1 204 No Content");
+    assert_eq!(command(&mut commands).await, RemoteCommand::PlayPause);
+
+    assert_eq!(get(&server, "/ctrl-int/1/setproperty?dmcp.device-volume=-20.000000", remote.active_remote).await, "HTTP/1.1 204 No Content");
+    assert_eq!(command(&mut commands).await, RemoteCommand::SetVolume(-20.0));
+
+    assert_eq!(get(&server, "/ctrl-int/1/nextitem", remote.active_remote.wrapping_add(1)).await, "HTTP/1.1 403 Forbidden");
+    assert_eq!(get(&server, "/ctrl-int/1/dance", remote.active_remote).await, "HTTP/1.1 501 Not Implemented");
+    assert!(commands.try_recv().is_err());
+}
+
+/// Remembers the headers of every request.
+struct Recorder(Arc<Mutex<Vec<Headers>>>);
+
+impl Handler for Recorder {
+    async fn handle(&mut self, request: Request) -> Answer {
+        self.0.lock().unwrap().push(request.headers);
+        Response::new(200, "OK").into()
+    }
+}
+
+#[tokio::test]
+async fn attached_clients_identify_the_remote() {
+    let seen: Arc<Mutex<Vec<Headers>>> = Default::default();
+    let receiver = rtsp::server::Server::bind("127.0.0.1:0", {
+        let seen = seen.clone();
+        move |_| Recorder(seen.clone())
+    }).await.unwrap();
+
+    let client = Client::connect(receiver.local_addr()).await.unwrap();
+    client.options(None).await.unwrap();
+
+    let remote = Remote { id: 0x1A2B3C4D5E6F7A8B, active_remote: 3735928559 };
+    let server = Server::bind("127.0.0.1:0", remote).await.unwrap();
+    server.attach(&client);
+    client.options(None).await.unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen[0].get("DACP-ID"), None);
+    assert_eq!(seen[1].get("DACP-ID"), Some("1A2B3C4D5E6F7A8B"));
+    assert_eq!(seen[1].get("Active-Remote"), Some("3735928559"));
+}
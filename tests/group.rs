@@ -0,0 +1,108 @@
+mod support;
+
+use std::time::Duration;
+
+use airplay::{group::{Group, Latency}, rtsp::{ops::{SetupStreamsResponse, StreamInfo}, Client}};
+use support::{mock::{MockConfig, MockReceiver}, setup_info, setup_streams};
+use tokio::time::timeout;
+
+/// A mock receiver with its session and audio stream SET UP, not recording yet.
+async fn set_up(audio_latency: u32) -> (MockReceiver, Client, StreamInfo) {
+    let mock = MockReceiver::start(MockConfig { audio_latency, ..Default::default() }).await;
+    let client = Client::connect(mock.addr()).await.unwrap();
+    client.set_default_timeout(Some(Duration::from_secs(5)));
+    client.pair_setup_transient().await.unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+
+    let streams = SetupStreamsResponse::from_response(&client.setup_streams(setup_streams()).await.unwrap()).unwrap();
+    (mock, client, streams.streams[0].clone())
+}
+
+async fn recording(audio_latency: u32) -> (MockReceiver, Client, StreamInfo) {
+    let (mock, client, stream) = set_up(audio_latency).await;
+    client.record().await.unwrap();
+    (mock, client, stream)
+}
+
+async fn wait_for_packets(mock: &MockReceiver, count: usize) {
+    timeout(Duration::from_secs(5), async {
+        while mock.rtp_packets() < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("packets didn't arrive");
+}
+
+#[tokio::test]
+async fn members_join_and_leave_while_playing() {
+    let (kitchen, kitchen_client, kitchen_stream) = recording(11025).await;
+    let (den, den_client, den_stream) = recording(11025).await;
+    let mut group = Group::new(44100, vec!["10.0.0.1".to_string()]).await.unwrap();
+
+    group.join("kitchen", kitchen_client, &kitchen_stream).await.unwrap();
+    assert_eq!(kitchen.peers(), vec![vec!["10.0.0.1".to_string(), "127.0.0.1".to_string()]]);
+
+    assert_eq!(group.position(), None);
+    let start = group.send(&[0; 16], 352).await.unwrap();
+    assert_eq!(group.position(), Some((1, start.wrapping_add(352))));
+    wait_for_packets(&kitchen, 1).await;
+
+    group.join("den", den_client, &den_stream).await.unwrap();
+    assert_eq!(group.members().collect::<Vec<_>>(), vec!["kitchen", "den"]);
+    assert_eq!(group.peers().len(), 3);
+    assert_eq!(kitchen.peers().len(), 2);
+    assert_eq!(den.peers().len(), 1);
+
+    let first = group.send(&[0; 16], 352).await.unwrap();
+    wait_for_packets(&kitchen, 2).await;
+    wait_for_packets(&den, 1).await;
+
+    let client = group.leave("kitchen").await.unwrap();
+    assert_eq!(client.peer, kitchen.addr());
+    assert!(group.leave("kitchen").await.is_none());
+    assert_eq!(group.members().collect::<Vec<_>>(), vec!["den"]);
+    assert_eq!(den.peers().last().map(|x| x.len()), Some(2));
+
+    // The timeline carries on across members coming and going.
+    assert_eq!(group.send(&[0; 16], 352).await.unwrap(), first.wrapping_add(352));
+    wait_for_packets(&den, 2).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(kitchen.rtp_packets(), 2);
+}
+
+#[tokio::test]
+async fn aligns_members_to_the_slowest_one() {
+    let (_kitchen, kitchen_client, kitchen_stream) = set_up(11025).await;
+    let (_den, den_client, den_stream) = set_up(22050).await;
+    let mut group = Group::new(44100, Vec::new()).await.unwrap();
+    group.set_latency(4410);
+
+    // Members join before RECORD, whose answer carries their latency.
+    group.join("kitchen", kitchen_client.clone(), &kitchen_stream).await.unwrap();
+    group.join("den", den_client.clone(), &den_stream).await.unwrap();
+    assert_eq!(group.member_latency("den"), Some(Latency { reported: 0, offset: 0 }));
+    assert_eq!(group.effective_latency("den"), Some(Duration::from_millis(100)));
+
+    kitchen_client.record().await.unwrap();
+    den_client.record().await.unwrap();
+    assert_eq!(group.member_latency("kitchen"), Some(Latency { reported: 11025, offset: 0 }));
+    assert_eq!(group.member_latency("den"), Some(Latency { reported: 22050, offset: 0 }));
+
+    // The kitchen is held back to play with the den.
+    assert_eq!(group.effective_latency("kitchen"), Some(Duration::from_millis(600)));
+    assert_eq!(group.effective_latency("den"), Some(Duration::from_millis(600)));
+
+    // Until an offset makes it the slowest.
+    group.set_latency_offset("kitchen", 22050);
+    assert_eq!(group.member_latency("kitchen").map(|x| x.output()), Some(33075));
+    assert_eq!(group.effective_latency("kitchen"), Some(Duration::from_millis(850)));
+    assert_eq!(group.effective_latency("den"), Some(Duration::from_millis(850)));
+
+    // Offsets below zero can't make a member play before its audio is sent.
+    group.set_latency_offset("kitchen", -44100);
+    group.set_reported_latency("den", 0);
+    assert_eq!(group.effective_latency("kitchen"), Some(Duration::from_millis(100)));
+    assert_eq!(group.effective_latency("den"), Some(Duration::from_millis(100)));
+
+    assert!(!group.set_latency_offset("attic", 1));
+    assert_eq!(group.effective_latency("attic"), None);
+}
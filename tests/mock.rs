@@ -0,0 +1,203 @@
+mod support;
+
+use std::time::Duration;
+
+use airplay::{pairing::{self, tlv, Identity}, rtsp::{self, ops::{DeviceInfo, SetupStreamsResponse, STREAM_TYPE_REALTIME}, Client, Method, Request}};
+use tokio::time::{sleep, timeout};
+use support::{mock::{Fault, MockConfig, MockReceiver}, setup_info, setup_streams};
+
+async fn connect(mock: &MockReceiver) -> Client {
+    let client = Client::connect(mock.addr()).await.unwrap();
+    client.set_default_timeout(Some(Duration::from_secs(5)));
+    client
+}
+
+#[tokio::test]
+async fn transient_pairing_encrypts_the_session() {
+    let mock = MockReceiver::start(MockConfig { audio_latency: 22050, ..Default::default() }).await;
+    let client = connect(&mock).await;
+
+    client.pair_setup_transient().await.unwrap();
+    assert!(client.is_encrypted());
+
+    let info = DeviceInfo::from_response(&client.fetch_info().await.unwrap()).unwrap();
+    assert_eq!(info.name.as_deref(), Some("Mock"));
+    assert_eq!(info.features.map(|x| x as u64), Some(mock.config().features));
+    assert_eq!(info.output_latency(STREAM_TYPE_REALTIME), Some(Duration::from_millis(400)));
+
+    client.setup_info(setup_info()).await.unwrap();
+    let streams = SetupStreamsResponse::from_response(&client.setup_streams(setup_streams()).await.unwrap()).unwrap();
+    assert!(streams.streams[0].data_port.is_some_and(|x| x != 0));
+
+    client.record().await.unwrap();
+    assert!(mock.is_recording());
+    assert_eq!(client.audio_latency(), Some(22050));
+
+    client.set_volume(-15.0).await.unwrap();
+    assert_eq!(mock.volume(), Some(-15.0));
+
+    client.flush(5, 1000).await.unwrap();
+    assert_eq!(mock.flushes(), vec!["seq=5;rtptime=1000".to_string()]);
+
+    client.teardown().await.unwrap();
+    assert!(!mock.is_recording());
+    assert_eq!(mock.teardowns(), 1);
+}
+
+#[tokio::test]
+async fn pin_pairing_is_verified_on_a_new_connection() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let identity = Identity::generate("6B7D2E1C-6E41-4E3F-8A5D-3A1B7C9E0F21");
+
+    let peer = connect(&mock).await.pair_setup(identity.clone(), "1234").await.unwrap();
+    assert_eq!(peer, mock.peer());
+    assert_eq!(mock.pairings().iter().map(|x| x.id.as_str()).collect::<Vec<_>>(), vec![identity.id.as_str()]);
+
+    let client = connect(&mock).await;
+    client.pair_verify(identity, Some(peer)).await.unwrap();
+    assert!(client.is_encrypted());
+    client.fetch_info().await.unwrap();
+    assert_eq!(mock.connections(), 2);
+}
+
+#[tokio::test]
+async fn wrong_pin_is_rejected() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+
+    match client.pair_setup(Identity::generate("sender"), "0000").await {
+        Err(rtsp::Error::Pairing(pairing::Error::Peer(tlv::ERROR_AUTHENTICATION))) => {},
+        x => panic!("expected an authentication error, got {:?}", x),
+    }
+
+    assert!(mock.pairings().is_empty());
+    assert!(!client.is_encrypted());
+}
+
+#[tokio::test]
+async fn unknown_sender_fails_verification() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+
+    match client.pair_verify(Identity::generate("stranger"), Some(mock.peer())).await {
+        Err(rtsp::Error::Pairing(pairing::Error::Peer(tlv::ERROR_AUTHENTICATION))) => {},
+        x => panic!("expected an authentication error, got {:?}", x),
+    }
+
+    assert!(!client.is_encrypted());
+}
+
+#[tokio::test]
+async fn forbidden_pairing_is_a_status_error() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    mock.fail(Method::POST, "/pair-setup", Fault::Status(403, "Forbidden"));
+    let client = connect(&mock).await;
+
+    match client.pair_setup_transient().await {
+        Err(rtsp::Error::Status(res)) => assert_eq!(res.status, 403),
+        x => panic!("expected 403, got {:?}", x),
+    }
+
+    // Only the next request fails.
+    client.pair_setup_transient().await.unwrap();
+}
+
+#[tokio::test]
+async fn not_enough_bandwidth_on_stream_setup() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+    client.pair_setup_transient().await.unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+
+    mock.fail(Method::SETUP, "*", Fault::Status(453, "Not Enough Bandwidth"));
+
+    match client.setup_streams(setup_streams()).await {
+        Err(rtsp::Error::Status(res)) => {
+            assert_eq!(res.status, 453);
+            assert_eq!(res.status_string, "Not Enough Bandwidth");
+        },
+        x => panic!("expected 453, got {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn dropped_connection_fails_the_pending_request() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+    client.pair_setup_transient().await.unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+
+    mock.fail(Method::RECORD, "*", Fault::Drop);
+
+    match client.record().await {
+        Err(rtsp::Error::ConnectionClosed) => {},
+        x => panic!("expected the connection to close, got {:?}", x),
+    }
+
+    client.closed().await;
+    assert!(client.is_closed());
+    assert_eq!(mock.requests().last(), Some(&(Method::RECORD, "/666".to_string())));
+}
+
+#[tokio::test]
+async fn unanswered_requests_time_out() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+
+    mock.fail(Method::GET, "/info", Fault::Ignore);
+    let pending = client.request_with_timeout(Request::new(Method::GET, "/info"), Some(Duration::from_millis(100))).await.unwrap();
+    assert_eq!(client.pending_requests(), 1);
+
+    match pending.await {
+        Err(rtsp::Error::Timeout) => {},
+        x => panic!("expected a timeout, got {:?}", x),
+    }
+
+    assert_eq!(client.pending_requests(), 0);
+
+    // Giving up on an answer forgets the request as well.
+    mock.fail(Method::GET, "/info", Fault::Ignore);
+    let pending = client.request(Request::new(Method::GET, "/info")).await.unwrap();
+    assert_eq!(client.pending_requests(), 1);
+    drop(pending);
+    assert_eq!(client.pending_requests(), 0);
+
+    client.fetch_info().await.unwrap();
+    assert!(!client.is_closed());
+}
+
+#[tokio::test]
+async fn closing_stops_the_connection_for_every_clone() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let client = connect(&mock).await;
+    let feedback = || mock.requests().iter().filter(|(method, path)| *method == Method::POST && path == "/feedback").count();
+
+    client.start_keepalive(Duration::from_millis(20));
+    timeout(Duration::from_secs(5), async { while feedback() == 0 { sleep(Duration::from_millis(5)).await } }).await.unwrap();
+
+    mock.fail(Method::GET, "/info", Fault::Ignore);
+    let pending = client.request(Request::new(Method::GET, "/info")).await.unwrap();
+    let waiting = tokio::spawn({
+        let client = client.clone();
+        async move { client.closed().await }
+    });
+
+    client.close().await.unwrap();
+    timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+    assert!(client.is_closed());
+
+    match pending.await {
+        Err(rtsp::Error::ConnectionClosed) => {},
+        x => panic!("expected the connection to close, got {:?}", x),
+    }
+
+    assert_eq!(client.pending_requests(), 0);
+    assert!(matches!(client.request(Request::new(Method::GET, "/info")).await, Err(rtsp::Error::ConnectionClosed)));
+
+    // The socket is gone and nothing sends keepalives on it any more.
+    timeout(Duration::from_secs(5), async { while mock.disconnects() == 0 { sleep(Duration::from_millis(5)).await } }).await.unwrap();
+    let sent = feedback();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(feedback(), sent);
+    assert_eq!(mock.connections(), 1);
+}
@@ -0,0 +1,199 @@
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use airplay::{
+    mrp::{self, protocol::{Command, DeviceInfoMessage, MessageType, NowPlayingInfo, PlaybackState, ProtocolMessage, SendCommandResultMessage, SetStateMessage}, Client, Connection, Error},
+    pairing::{self, tlv, Identity, Peer, SessionKeys, SetupServer, Tlv8, VerifyServer},
+};
+use tokio::{net::TcpListener, time::timeout};
+
+/// What a stand-in Apple TV was told.
+#[derive(Default)]
+struct Seen {
+    controllers: Vec<Peer>,
+    commands: Vec<(Command, Option<f64>)>,
+    volume: Option<f32>,
+}
+
+struct StandIn {
+    addr: SocketAddr,
+    seen: Arc<Mutex<Seen>>,
+}
+
+fn set_state(state: PlaybackState, title: &str) -> ProtocolMessage {
+    ProtocolMessage {
+        set_state_message: Some(SetStateMessage {
+            now_playing_info: Some(NowPlayingInfo {
+                title: Some(title.to_string()),
+                artist: Some("Artist".to_string()),
+                duration: Some(200.0),
+                elapsed_time: Some(12.5),
+                ..Default::default()
+            }),
+            display_name: Some("Music".to_string()),
+            playback_state: Some(state as i32),
+            ..Default::default()
+        }),
+        ..ProtocolMessage::new(MessageType::SetState)
+    }
+}
+
+async fn serve(mut connection: Connection, identity: Identity, pin: &'static str, seen: Arc<Mutex<Seen>>) {
+    let mut setup = SetupServer::new(identity.clone(), pin);
+    let mut verify = VerifyServer::new(identity, {
+        let seen = seen.clone();
+        move |id| seen.lock().unwrap().controllers.iter().find(|x| x.id == id).cloned()
+    });
+    let mut verifying = false;
+
+    while let Ok(Some(message)) = connection.recv().await {
+        let answer = match message.r#type() {
+            MessageType::DeviceInfo => ProtocolMessage {
+                identifier: message.identifier.clone(),
+                device_info_message: Some(DeviceInfoMessage { name: Some("Living Room".to_string()), ..Default::default() }),
+                ..ProtocolMessage::new(MessageType::DeviceInfo)
+            },
+            MessageType::CryptoPairing => {
+                let request = Tlv8::parse(message.pairing_data().unwrap()).unwrap();
+
+                if request.byte(tlv::STATE) == Some(1) {
+                    verifying = request.get(tlv::METHOD).is_none();
+                }
+
+                let answer = if verifying { verify.handle(&request) } else { setup.handle(&request) };
+                connection.send(&ProtocolMessage::crypto_pairing(answer.to_bytes())).await.unwrap();
+
+                if let Some(peer) = setup.peer().filter(|_| !verifying) {
+                    seen.lock().unwrap().controllers.push(peer.clone());
+                }
+
+                if let Some(secret) = verify.shared_secret().filter(|_| !connection.is_encrypted()) {
+                    connection.enable_encryption(&SessionKeys::media_remote(&secret, false));
+                }
+
+                continue;
+            },
+            MessageType::ClientUpdatesConfig => set_state(PlaybackState::Paused, "First"),
+            MessageType::SendCommand => {
+                let command = message.send_command_message.unwrap();
+                let position = command.options.and_then(|x| x.playback_position);
+                let command = Command::try_from(command.command.unwrap()).unwrap();
+                seen.lock().unwrap().commands.push((command, position));
+
+                let status = if command == Command::Stop { 2 } else { 0 };
+                connection.send(&ProtocolMessage {
+                    identifier: message.identifier.clone(),
+                    send_command_result_message: Some(SendCommandResultMessage { error_code: Some(0), handler_return_status: Some(status) }),
+                    ..ProtocolMessage::new(MessageType::SendCommandResult)
+                }).await.unwrap();
+
+                match command {
+                    Command::Play => set_state(PlaybackState::Playing, "First"),
+                    Command::NextTrack => set_state(PlaybackState::Playing, "Second"),
+                    _ => continue,
+                }
+            },
+            MessageType::SetVolume => {
+                seen.lock().unwrap().volume = message.set_volume_message.unwrap().volume;
+                continue;
+            },
+            _ => continue,
+        };
+
+        connection.send(&answer).await.unwrap();
+    }
+}
+
+/// Starts a stand-in device pairing with `pin`.
+async fn stand_in(pin: &'static str) -> StandIn {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let identity = Identity::generate("5D797FD3-4F72-4C0B-9C1B-ECE8BA8D1A2B");
+    let seen: Arc<Mutex<Seen>> = Default::default();
+
+    tokio::spawn({
+        let seen = seen.clone();
+
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(Connection::new(stream).unwrap(), identity.clone(), pin, seen.clone()));
+            }
+        }
+    });
+
+    StandIn { addr, seen }
+}
+
+async fn paired(device: &StandIn) -> (Identity, Peer) {
+    let identity = Identity::generate("controller");
+    let peer = mrp::pair(device.addr, "Tests", identity.clone()).await.unwrap().finish("1234").await.unwrap();
+    (identity, peer)
+}
+
+#[tokio::test]
+async fn pairs_and_controls_playback() {
+    let device = stand_in("1234").await;
+    let (identity, peer) = paired(&device).await;
+    assert_eq!(peer.id, "5D797FD3-4F72-4C0B-9C1B-ECE8BA8D1A2B");
+    assert_eq!(device.seen.lock().unwrap().controllers[0].public_key, identity.public_key());
+
+    let client = Client::connect(device.addr, "Tests", identity, peer).await.unwrap();
+    let mut now_playing = client.now_playing();
+
+    let first = timeout(Duration::from_secs(5), now_playing.wait_for(|x| x.title.is_some())).await.unwrap().unwrap().clone();
+    assert_eq!(first.state, PlaybackState::Paused);
+    assert_eq!(first.app.as_deref(), Some("Music"));
+    assert_eq!(first.artist.as_deref(), Some("Artist"));
+    assert_eq!(first.duration, Some(Duration::from_secs(200)));
+    assert_eq!(first.elapsed, Some(Duration::from_millis(12500)));
+
+    client.play().await.unwrap();
+    timeout(Duration::from_secs(5), now_playing.wait_for(|x| x.state == PlaybackState::Playing)).await.unwrap().unwrap();
+
+    client.next().await.unwrap();
+    timeout(Duration::from_secs(5), now_playing.wait_for(|x| x.title.as_deref() == Some("Second"))).await.unwrap().unwrap();
+
+    client.set_volume(0.25).await.unwrap();
+    client.seek(Duration::from_secs(30)).await.unwrap();
+    client.previous().await.unwrap();
+    client.pause().await.unwrap();
+
+    let seen = device.seen.lock().unwrap();
+    assert_eq!(seen.commands, vec![
+        (Command::Play, None),
+        (Command::NextTrack, None),
+        (Command::SeekToPlaybackPosition, Some(30.0)),
+        (Command::PreviousTrack, None),
+        (Command::Pause, None),
+    ]);
+    assert_eq!(seen.volume, Some(0.25));
+}
+
+#[tokio::test]
+async fn reports_refused_commands() {
+    let device = stand_in("1234").await;
+    let (identity, peer) = paired(&device).await;
+    let client = Client::connect(device.addr, "Tests", identity, peer).await.unwrap();
+
+    let result = client.send_command(Command::Stop, None).await;
+    assert!(matches!(result, Err(Error::Command { error: 0, status: 2 })));
+    assert!(!client.is_closed());
+}
+
+#[tokio::test]
+async fn rejects_the_wrong_pin() {
+    let device = stand_in("1234").await;
+    let setup = mrp::pair(device.addr, "Tests", Identity::generate("controller")).await.unwrap();
+
+    let result = setup.finish("0000").await;
+    assert!(matches!(result, Err(Error::Pairing(pairing::Error::Peer(tlv::ERROR_AUTHENTICATION)))));
+    assert!(device.seen.lock().unwrap().controllers.is_empty());
+}
+
+#[tokio::test]
+async fn unpaired_controllers_fail_verification() {
+    let device = stand_in("1234").await;
+    let (_, peer) = paired(&device).await;
+
+    let result = Client::connect(device.addr, "Tests", Identity::generate("stranger"), peer).await;
+    assert!(matches!(result, Err(Error::Pairing(_))));
+}
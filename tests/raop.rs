@@ -0,0 +1,195 @@
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use airplay::{mdns::RaopMetadata, raop::{attribute, AudioCipher, Challenge, Error, SdpFormat, Sender}, rtp, rtsp::{server::{Answer, Handler, Server}, Body, Client, Method, Request, Response}};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use rsa::RsaPrivateKey;
+use tokio::{net::UdpSocket, time::timeout};
+
+/// What a stand-in RAOP receiver was told.
+#[derive(Default)]
+struct Seen {
+    sdp: Option<String>,
+    cipher: Option<AudioCipher>,
+    timing_port: Option<u16>,
+    rtp_info: Option<String>,
+}
+
+struct StandIn {
+    peer: SocketAddr,
+    key: Arc<RsaPrivateKey>,
+    mac: [u8; 6],
+    data_port: u16,
+    control_port: u16,
+    seen: Arc<Mutex<Seen>>,
+}
+
+impl Handler for StandIn {
+    async fn handle(&mut self, request: Request) -> Answer {
+        let mut res = Response::new(200, "OK");
+        let mut seen = self.seen.lock().unwrap();
+
+        match request.method {
+            Method::OPTIONS => {
+                if let Some(challenge) = request.headers.get("Apple-Challenge").and_then(Challenge::from_header) {
+                    res.headers.set("Apple-Response", challenge.respond(&self.key, self.peer.ip(), self.mac).unwrap());
+                }
+            },
+            Method::ANNOUNCE => {
+                let Body::Raw(body) = &request.body else { return Response::new(400, "Bad Request").into() };
+                let sdp = String::from_utf8(body.clone()).unwrap();
+
+                if let (Some(key), Some(iv)) = (attribute(&sdp, "rsaaeskey"), attribute(&sdp, "aesiv")) {
+                    let key = STANDARD_NO_PAD.decode(key).unwrap();
+                    let iv = STANDARD_NO_PAD.decode(iv).unwrap().try_into().unwrap();
+                    seen.cipher = Some(AudioCipher::unwrap_key(&self.key, &key, iv).unwrap());
+                }
+
+                seen.sdp = Some(sdp);
+            },
+            Method::SETUP => {
+                let transport = request.headers.get("Transport").unwrap_or_default();
+                seen.timing_port = transport.split(';').find_map(|x| x.strip_prefix("timing_port=")?.parse().ok());
+                res.headers.set("Session", "1");
+                res.headers.set(
+                    "Transport",
+                    format!("RTP/AVP/UDP;unicast;mode=record;server_port={};control_port={};timing_port=6002", self.data_port, self.control_port),
+                );
+            },
+            Method::RECORD => {
+                seen.rtp_info = request.headers.get("RTP-Info").map(str::to_string);
+                res.headers.set("Audio-Latency", 11025);
+            },
+            _ => {},
+        }
+
+        res.into()
+    }
+}
+
+struct Receiver {
+    server: Server,
+    data: UdpSocket,
+    control: UdpSocket,
+    seen: Arc<Mutex<Seen>>,
+}
+
+/// Starts a stand-in receiver answering challenges with `key`.
+async fn receiver(key: RsaPrivateKey) -> Receiver {
+    let data = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let control = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let seen: Arc<Mutex<Seen>> = Default::default();
+    let key = Arc::new(key);
+    let (data_port, control_port) = (data.local_addr().unwrap().port(), control.local_addr().unwrap().port());
+
+    let server = Server::bind("127.0.0.1:0", {
+        let seen = seen.clone();
+        move |peer| StandIn { peer, key: key.clone(), mac: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], data_port, control_port, seen: seen.clone() }
+    }).await.unwrap();
+
+    Receiver { server, data, control, seen }
+}
+
+fn metadata(txt: &[&str]) -> RaopMetadata {
+    let txt: Vec<String> = txt.iter().map(|x| x.to_string()).collect();
+    RaopMetadata::from_txt("AABBCCDDEEFF@Stand-in._raop._tcp.local", &txt).unwrap()
+}
+
+fn key() -> RsaPrivateKey {
+    RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap()
+}
+
+async fn recv(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = vec![0; 2048];
+    let (n, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await.expect("nothing arrived").unwrap();
+    buf.truncate(n);
+    buf
+}
+
+#[tokio::test]
+async fn streams_encrypted_audio() {
+    let key = key();
+    let public = key.to_public_key();
+    let receiver = receiver(key).await;
+    let client = Client::connect(receiver.server.local_addr()).await.unwrap();
+    let metadata = metadata(&["cn=0,1", "et=0,1", "sr=44100", "ss=16", "ch=2", "tp=UDP", "vn=3"]);
+
+    let mut sender = Sender::start_with_key(client, &metadata, SdpFormat::preferred(&metadata), &public).await.unwrap();
+    assert!(sender.is_encrypted());
+    assert_eq!(sender.client().audio_latency(), Some(11025));
+
+    let (sdp, cipher, timing_port, rtp_info) = {
+        let mut seen = receiver.seen.lock().unwrap();
+        (seen.sdp.take().unwrap(), seen.cipher.take().unwrap(), seen.timing_port.unwrap(), seen.rtp_info.take().unwrap())
+    };
+    assert_eq!(attribute(&sdp, "rtpmap"), Some("96 AppleLossless"));
+
+    let payload: Vec<u8> = (0..40).collect();
+    let timestamp = sender.send(&payload, 352).await.unwrap();
+    let packet = recv(&receiver.data).await;
+    let (header, encrypted) = rtp::Header::parse(&packet).unwrap();
+
+    assert!(header.marker);
+    assert_eq!(rtp_info, format!("seq={};rtptime={}", header.sequence, timestamp));
+    assert_ne!(encrypted[..32], payload[..32]);
+
+    let mut decrypted = encrypted.to_vec();
+    cipher.decrypt(&mut decrypted);
+    assert_eq!(decrypted, payload);
+
+    sender.sync().await.unwrap();
+    let sync = rtp::Sync::parse(&recv(&receiver.control).await).unwrap();
+    assert!(sync.first);
+
+    let request = rtp::Timing {
+        response: false,
+        reference: rtp::NtpTime(0),
+        received: rtp::NtpTime(0),
+        sent: rtp::NtpTime::now(),
+    };
+    receiver.data.send_to(&request.to_bytes(), ("127.0.0.1", timing_port)).await.unwrap();
+    let response = rtp::Timing::parse(&recv(&receiver.data).await).unwrap();
+    assert!(response.response);
+    assert_eq!(response.reference, request.sent);
+
+    sender.teardown().await.unwrap();
+}
+
+#[tokio::test]
+async fn plain_pcm_without_rsa() {
+    let receiver = receiver(key()).await;
+    let client = Client::connect(receiver.server.local_addr()).await.unwrap();
+    let metadata = metadata(&["cn=0", "et=0", "sr=44100", "ss=16", "ch=2"]);
+
+    let mut sender = Sender::start(client, &metadata, SdpFormat::preferred(&metadata)).await.unwrap();
+    assert!(!sender.is_encrypted());
+
+    let sdp = receiver.seen.lock().unwrap().sdp.take().unwrap();
+    assert_eq!(attribute(&sdp, "rtpmap"), Some("96 L16/44100/2"));
+    assert_eq!(attribute(&sdp, "rsaaeskey"), None);
+
+    let payload: Vec<u8> = (0..40).collect();
+    sender.send(&payload, 10).await.unwrap();
+    let packet = recv(&receiver.data).await;
+    assert_eq!(&packet[rtp::HEADER_LEN..], payload);
+}
+
+#[tokio::test]
+async fn rejects_a_receiver_without_the_key() {
+    let receiver = receiver(key()).await;
+    let client = Client::connect(receiver.server.local_addr()).await.unwrap();
+    let metadata = metadata(&["cn=1", "et=1"]);
+
+    let result = Sender::start_with_key(client, &metadata, SdpFormat::preferred(&metadata), &key().to_public_key()).await;
+    assert!(matches!(result, Err(Error::Authentication)));
+    assert!(receiver.seen.lock().unwrap().sdp.is_none());
+}
+
+#[tokio::test]
+async fn refuses_fairplay_only_receivers() {
+    let receiver = receiver(key()).await;
+    let client = Client::connect(receiver.server.local_addr()).await.unwrap();
+    let metadata = metadata(&["cn=1", "et=3"]);
+
+    let result = Sender::start(client, &metadata, SdpFormat::preferred(&metadata)).await;
+    assert!(matches!(result, Err(Error::Unsupported(_))));
+}
@@ -0,0 +1,372 @@
+mod support;
+
+use std::{sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+use airplay::{alac, audio::{CallbackSink, Codec, Format, StreamFormat, Timing}, dmap::TrackMetadata, group::Group, pairing::Identity, receiver::{Artwork, Audio, Config, Player, PlayerConfig, Receiver, ReceiverEvent, State}, rtp, rtsp::{self, ops::{DeviceInfo, Progress, SetupStreamsResponse}, Client}};
+use plist::Data;
+use support::{setup_info, setup_streams};
+use tokio::{net::UdpSocket, sync::{broadcast, mpsc}, time::timeout};
+
+async fn start(config: Config) -> Receiver {
+    Receiver::bind("127.0.0.1:0", config, Identity::generate("AA:BB:CC:DD:EE:FF")).await.unwrap()
+}
+
+async fn connect(receiver: &Receiver) -> Client {
+    let client = Client::connect(receiver.local_addr()).await.unwrap();
+    client.set_default_timeout(Some(Duration::from_secs(5)));
+    client
+}
+
+async fn next(audio: &mut mpsc::Receiver<Audio>) -> Audio {
+    timeout(Duration::from_secs(5), audio.recv()).await.expect("no audio arrived").unwrap()
+}
+
+async fn event(events: &mut broadcast::Receiver<ReceiverEvent>) -> ReceiverEvent {
+    timeout(Duration::from_secs(5), events.recv()).await.expect("no event arrived").unwrap()
+}
+
+fn status(result: Result<rtsp::Response, rtsp::Error>) -> i32 {
+    match result {
+        Err(rtsp::Error::Status(res)) => res.status,
+        x => panic!("expected an error status, got {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn plays_a_session_from_our_own_sender() {
+    let receiver = start(Config::new("Virtual", "AA:BB:CC:DD:EE:FF")).await;
+    let mut audio = receiver.take_audio().unwrap();
+    let client = connect(&receiver).await;
+
+    let info = DeviceInfo::from_response(&client.fetch_info().await.unwrap()).unwrap();
+    assert_eq!(info.name.as_deref(), Some("Virtual"));
+    assert!(info.features().unwrap().supports_alac);
+
+    client.pair_setup_transient().await.unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+    assert_eq!(receiver.session().unwrap().info.name, "crystal");
+
+    let mut group = Group::new(44100, vec!["127.0.0.1".to_string()]).await.unwrap();
+    let mut request = setup_streams();
+    request.streams[0].shk = Data::new(group.key().to_vec());
+
+    let streams = SetupStreamsResponse::from_response(&client.setup_streams(request).await.unwrap()).unwrap();
+    let stream = streams.streams[0].clone();
+
+    let Audio::Started(started) = next(&mut audio).await else { panic!("expected the stream to start") };
+    assert_eq!(Some(started.data_port), stream.data_port);
+    assert_eq!(started.format.format.sample_rate, 44100);
+
+    client.record().await.unwrap();
+    assert_eq!(client.audio_latency(), Some(11025));
+    assert_eq!(receiver.session().unwrap().state, State::Recording);
+
+    group.join("virtual", client.clone(), &stream).await.unwrap();
+    let timestamp = group.send(&[1, 2, 3, 4], 352).await.unwrap();
+
+    match next(&mut audio).await {
+        Audio::Packet { header, payload, .. } => {
+            assert_eq!(header.timestamp, timestamp);
+            assert_eq!(payload, vec![1, 2, 3, 4]);
+        },
+        x => panic!("expected a packet, got {:?}", x),
+    }
+
+    client.set_volume(-12.5).await.unwrap();
+    assert_eq!(receiver.volume(), Some(-12.5));
+
+    client.flush(7, 1234).await.unwrap();
+    assert!(matches!(next(&mut audio).await, Audio::Flush { seq: Some(7), rtptime: Some(1234) }));
+
+    client.teardown().await.unwrap();
+    assert!(matches!(next(&mut audio).await, Audio::Stopped { stream } if stream == started.id));
+    assert!(receiver.session().is_none());
+}
+
+#[tokio::test]
+async fn negotiates_the_stream_format() {
+    let config = Config {
+        require_pairing: false,
+        audio_formats: StreamFormat::all().filter(|x| x.codec == Codec::Pcm).fold(0, |mask, x| mask | x.audio_format()),
+        ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF")
+    };
+    let receiver = start(config).await;
+    let mut audio = receiver.take_audio().unwrap();
+    let client = connect(&receiver).await;
+    let info = DeviceInfo::from_response(&client.fetch_info().await.unwrap()).unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+
+    let result = client.setup_audio(&info, &[Codec::Opus], 6001, [1; 32], 11025).await;
+    assert!(matches!(result, Err(rtsp::Error::Io(x)) if x.kind() == std::io::ErrorKind::Unsupported));
+
+    let (format, stream) = client.setup_audio(&info, &[Codec::Alac, Codec::Pcm], 6001, [1; 32], 11025).await.unwrap();
+    assert_eq!((format.codec, format.format), (Codec::Pcm, Format::new(44100, 2, 16)));
+
+    let Audio::Started(started) = next(&mut audio).await else { panic!("expected the stream to start") };
+    assert_eq!(Some(started.id), stream.stream_id);
+    assert_eq!(started.format, format);
+    assert_eq!(started.frames_per_packet, 352);
+}
+
+#[tokio::test]
+async fn setup_requires_pairing() {
+    let receiver = start(Config::new("Virtual", "AA:BB:CC:DD:EE:FF")).await;
+    let client = connect(&receiver).await;
+
+    assert_eq!(status(client.setup_info(setup_info()).await), 470);
+    assert_eq!(status(client.record().await), 455);
+}
+
+#[tokio::test]
+async fn parameters_require_pairing() {
+    let receiver = start(Config::new("Virtual", "AA:BB:CC:DD:EE:FF")).await;
+    let mut events = receiver.subscribe();
+    let client = connect(&receiver).await;
+
+    assert_eq!(status(client.set_volume(-10.0).await), 470);
+    assert_eq!(status(client.set_metadata(&TrackMetadata::default(), 1000).await), 470);
+    assert_eq!(receiver.volume(), None);
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn streams_need_a_session() {
+    let config = Config { require_pairing: false, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let client = connect(&receiver).await;
+
+    assert_eq!(status(client.setup_streams(setup_streams()).await), 455);
+    client.setup_info(setup_info()).await.unwrap();
+    client.setup_streams(setup_streams()).await.unwrap();
+}
+
+#[tokio::test]
+async fn streams_name_exactly_one_offered_format() {
+    let alac = StreamFormat::all().find(|x| x.codec == Codec::Alac && x.format == Format::new(44100, 2, 16)).unwrap();
+    let pcm = StreamFormat::all().find(|x| x.codec == Codec::Pcm && x.format == Format::new(44100, 2, 16)).unwrap();
+    let config = Config { require_pairing: false, audio_formats: alac.audio_format(), ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let mut audio = receiver.take_audio().unwrap();
+    let client = connect(&receiver).await;
+    client.setup_info(setup_info()).await.unwrap();
+
+    let with_format = |audio_format| {
+        let mut request = setup_streams();
+        request.streams[0].audio_format = audio_format;
+        request
+    };
+
+    // One offered and one unsupported bit, the lower one being the unsupported one.
+    assert!(pcm.bit < alac.bit);
+    assert_eq!(status(client.setup_streams(with_format(pcm.audio_format() | alac.audio_format())).await), 415);
+    assert_eq!(status(client.setup_streams(with_format(pcm.audio_format())).await), 415);
+    assert_eq!(status(client.setup_streams(with_format(0)).await), 415);
+
+    client.setup_streams(with_format(alac.audio_format())).await.unwrap();
+    let Audio::Started(started) = next(&mut audio).await else { panic!("expected the stream to start") };
+    assert_eq!(started.format, alac);
+}
+
+#[tokio::test]
+async fn streams_need_a_format_we_know() {
+    let config = Config { require_pairing: false, audio_formats: u64::MAX, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let client = connect(&receiver).await;
+    client.setup_info(setup_info()).await.unwrap();
+
+    let mut request = setup_streams();
+    request.streams[0].audio_format = 1 << 60;
+    assert_eq!(status(client.setup_streams(request).await), 415);
+}
+
+#[tokio::test]
+async fn one_session_at_a_time() {
+    let config = Config { require_pairing: false, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+
+    let first = connect(&receiver).await;
+    first.setup_info(setup_info()).await.unwrap();
+
+    let second = connect(&receiver).await;
+    assert_eq!(status(second.setup_info(setup_info()).await), 453);
+
+    first.close().await.unwrap();
+    assert!(receiver.session().is_none());
+    second.setup_info(setup_info()).await.unwrap();
+}
+
+#[tokio::test]
+async fn pairs_with_pin_and_verifies() {
+    let config = Config { pin: Some("4321".to_string()), ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let identity = Identity::generate("sender");
+
+    let peer = connect(&receiver).await.pair_setup(identity.clone(), "4321").await.unwrap();
+    assert_eq!(peer, receiver.peer());
+    assert_eq!(receiver.pairings().len(), 1);
+
+    let client = connect(&receiver).await;
+    client.pair_verify(identity, Some(peer)).await.unwrap();
+    client.setup_info(setup_info()).await.unwrap();
+}
+
+#[tokio::test]
+async fn decrypts_audio_with_the_stream_key() {
+    let config = Config { require_pairing: false, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let mut audio = receiver.take_audio().unwrap();
+    let client = connect(&receiver).await;
+    client.setup_info(setup_info()).await.unwrap();
+
+    let mut request = setup_streams();
+    request.streams[0].shk = Data::new(vec![0; 16]);
+    assert_eq!(status(client.setup_streams(request).await), 400);
+
+    let mut request = setup_streams();
+    request.streams[0].shk = Data::new(vec![9; 32]);
+    let streams = SetupStreamsResponse::from_response(&client.setup_streams(request).await.unwrap()).unwrap();
+    assert!(matches!(next(&mut audio).await, Audio::Started(_)));
+
+    let packet = |sequence: u16, key: [u8; 32]| {
+        let mut packet = Vec::new();
+        rtp::Header {
+            marker: false,
+            extension: false,
+            payload_type: rtp::PAYLOAD_TYPE_AUDIO,
+            sequence,
+            timestamp: 352 * u32::from(sequence),
+            ssrc: 1,
+        }.write(&mut packet);
+        packet.extend([1, 2, 3, 4]);
+        rtp::AudioCipher::new(&key).seal(&mut packet, u64::from(sequence));
+        packet
+    };
+
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let data = ("127.0.0.1", streams.streams[0].data_port.unwrap());
+    sender.send_to(&packet(1, [0; 32]), data).await.unwrap();
+    sender.send_to(&packet(2, [9; 32]), data).await.unwrap();
+
+    match next(&mut audio).await {
+        Audio::Packet { header, payload, .. } => {
+            assert_eq!(header.sequence, 2);
+            assert_eq!(payload, vec![1, 2, 3, 4]);
+        },
+        x => panic!("expected a packet, got {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn player_reorders_recovers_and_conceals() {
+    let config = Config { require_pairing: false, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let client = connect(&receiver).await;
+    client.setup_info(setup_info()).await.unwrap();
+    let streams = SetupStreamsResponse::from_response(&client.setup_streams(setup_streams()).await.unwrap()).unwrap();
+    let stream = &streams.streams[0];
+
+    let played = Arc::new(Mutex::new(Vec::<(Vec<i32>, Timing)>::new()));
+    let sink = CallbackSink::new({
+        let played = played.clone();
+        move |samples: &[i32], _, timing| played.lock().unwrap().push((samples.to_vec(), timing))
+    });
+    let player = Player::new(receiver.take_audio().unwrap(), sink, PlayerConfig::default());
+    let mut player_stats = player.stats();
+    tokio::spawn(player.run());
+
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let data = ("127.0.0.1", stream.data_port.unwrap());
+    let control = ("127.0.0.1", stream.control_port.unwrap());
+
+    let sync = rtp::Sync {
+        first: true,
+        timestamp: 0,
+        time: (SystemTime::now() + Duration::from_millis(300)).into(),
+        next_timestamp: 11025,
+    };
+    sender.send_to(&sync.to_bytes(), control).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut encoder = alac::Encoder::new(alac::Config::new(352, 44100, 16, 2).unwrap());
+    let cipher = rtp::AudioCipher::new(&[0; 32]);
+    let packets: Vec<Vec<u8>> = (0..5_u16).map(|sequence| {
+        let mut packet = Vec::new();
+        rtp::Header {
+            marker: false,
+            extension: false,
+            payload_type: rtp::PAYLOAD_TYPE_AUDIO,
+            sequence,
+            timestamp: u32::from(sequence) * 352,
+            ssrc: 1,
+        }.write(&mut packet);
+        packet.extend(encoder.encode(&vec![i32::from(sequence + 1) * 100; 704]).unwrap());
+        cipher.seal(&mut packet, u64::from(sequence));
+        packet
+    }).collect();
+
+    for sequence in [0, 2, 4] {
+        sender.send_to(&packets[sequence], data).await.unwrap();
+    }
+
+    let mut buf = [0; 64];
+    let (n, _) = timeout(Duration::from_secs(5), sender.recv_from(&mut buf)).await.unwrap().unwrap();
+    let request = rtp::RetransmitRequest::parse(&buf[..n]).unwrap();
+    assert_eq!((request.first, request.count), (1, 1));
+
+    let mut response = vec![0x80, 0x80 | rtp::PAYLOAD_TYPE_RETRANSMIT_RESPONSE, 0, 1];
+    response.extend(&packets[1]);
+    sender.send_to(&response, control).await.unwrap();
+
+    timeout(Duration::from_secs(5), async {
+        while played.lock().unwrap().len() < 5 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("audio wasn't played");
+
+    let stats = *timeout(Duration::from_secs(5), player_stats.wait_for(|x| x.lost == 1)).await.unwrap().unwrap();
+    assert_eq!((stats.concealed, stats.recovered), (352, 1));
+    assert!(stats.retransmit_requests >= 2);
+
+    let played = played.lock().unwrap();
+    let timestamps: Vec<_> = played.iter().map(|(_, timing)| timing.rtp).collect();
+    assert_eq!(timestamps, vec![0, 352, 704, 1056, 1408]);
+    assert!(played.iter().all(|(_, timing)| timing.at.is_some()));
+
+    let first: Vec<_> = played.iter().map(|(samples, _)| samples[0]).collect();
+    assert_eq!(first, vec![100, 200, 300, 300, 500]);
+    assert_eq!(played[3].0.last(), Some(&0));
+}
+
+#[tokio::test]
+async fn emits_playback_events() {
+    let config = Config { require_pairing: false, ..Config::new("Virtual", "AA:BB:CC:DD:EE:FF") };
+    let receiver = start(config).await;
+    let mut events = receiver.subscribe();
+    let client = connect(&receiver).await;
+    client.setup_info(setup_info()).await.unwrap();
+
+    let metadata = TrackMetadata {
+        title: Some("Gyöngyhajú lány".to_string()),
+        artist: Some("Omega".to_string()),
+        ..Default::default()
+    };
+    let progress = Progress { start: 1000, current: 45100, end: 1000 + 44100 * 60 };
+
+    client.set_volume(-20.0).await.unwrap();
+    client.set_metadata(&metadata, 1000).await.unwrap();
+    client.set_progress(progress).await.unwrap();
+    client.set_artwork("image/png", vec![0x89, b'P', b'N', b'G'], 1000).await.unwrap();
+    client.set_artwork("image/none", Vec::new(), 1000).await.unwrap();
+
+    assert_eq!(event(&mut events).await, ReceiverEvent::Volume(-20.0));
+    assert_eq!(event(&mut events).await, ReceiverEvent::Metadata(metadata));
+    assert_eq!(event(&mut events).await, ReceiverEvent::Progress(progress));
+    assert_eq!(progress.elapsed(44100), Duration::from_secs(1));
+    assert_eq!(event(&mut events).await, ReceiverEvent::Artwork(Some(Artwork {
+        content_type: "image/png".to_string(),
+        data: vec![0x89, b'P', b'N', b'G'],
+    })));
+    assert_eq!(event(&mut events).await, ReceiverEvent::Artwork(None));
+
+    assert_eq!(status(client.set_artwork("application/x-dmap-tagged", vec![1, 2, 3], 1000).await), 400);
+}
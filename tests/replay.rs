@@ -0,0 +1,128 @@
+mod support;
+
+use std::{io::{self, Write}, sync::{Arc, Mutex}, time::Duration};
+
+use airplay::rtsp::{self, capture, ops::{DeviceInfo, SetupStreamsResponse, STREAM_TYPE_REALTIME}, Body, Client, Method, Request, Response};
+use support::{setup_info, setup_streams, Exchange, Replay};
+
+/// Runs every op of the session in `captures/session.jsonl` in order.
+///
+/// That capture is synthetic: it was written by hand in the capture format, with answers modelled on what
+/// a HomePod sends, rather than recorded from a device. It pins down the framing and the ops' handling of
+/// their answers, not the exact bytes a receiver produces.
+async fn run_session(client: &Client) {
+    let info = client.fetch_info().await.unwrap();
+    let info = DeviceInfo::from_response(&info).expect("/info body is a device info plist");
+    assert_eq!(info.name.as_deref(), Some("Kitchen"));
+    assert_eq!(info.output_latency(STREAM_TYPE_REALTIME), Some(Duration::from_millis(400)));
+    assert!(info.features().unwrap().supports_buffered_audio);
+
+    client.setup_info(setup_info()).await.unwrap();
+    assert!(client.is_session_active());
+
+    let streams = client.setup_streams(setup_streams()).await.unwrap();
+    let streams = SetupStreamsResponse::from_response(&streams).unwrap();
+    assert_eq!(streams.streams[0].data_port, Some(50123));
+    assert_eq!(streams.streams[0].control_port, Some(50124));
+
+    client.setpeers(vec!["192.168.1.10".to_string(), "192.168.1.20".to_string()]).await.unwrap();
+
+    client.record().await.unwrap();
+    assert_eq!(client.audio_latency(), Some(11025));
+
+    client.feedback().await.unwrap();
+    client.teardown().await.unwrap();
+    assert!(!client.is_session_active());
+}
+
+#[tokio::test]
+async fn replays_captured_session() {
+    let replay = Replay::load("session.jsonl").await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+
+    run_session(&client).await;
+
+    client.close().await.unwrap();
+    replay.finish().await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_requests_that_diverge_from_the_capture() {
+    let replay = Replay::load("session.jsonl").await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+
+    let err = client.record().await.unwrap_err();
+    assert!(matches!(err, rtsp::Error::Status(ref res) if res.status == 400), "{}", err);
+
+    client.close().await.unwrap();
+    assert!(replay.finish().await.unwrap_err().contains("expected GET /info, got RECORD"));
+}
+
+#[tokio::test]
+async fn reports_unplayed_exchanges() {
+    let replay = Replay::load("session.jsonl").await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+
+    client.fetch_info().await.unwrap();
+
+    client.close().await.unwrap();
+    assert!(replay.finish().await.unwrap_err().contains("6 exchanges left"));
+}
+
+#[tokio::test]
+async fn failed_status_is_an_error() {
+    let replay = Replay::serve(vec![Exchange {
+        request: Request::new(Method::SETUP, "rtsp://192.168.1.20/666"),
+        response: Response::new(453, "Not Enough Bandwidth"),
+    }]).await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+
+    match client.setup_streams(setup_streams()).await {
+        Err(rtsp::Error::Status(res)) => assert_eq!(res.status, 453),
+        x => panic!("expected 453, got {:?}", x.map(|x| x.status)),
+    }
+    assert!(!client.is_session_active());
+
+    client.close().await.unwrap();
+    replay.finish().await.unwrap();
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn captures_can_be_replayed() {
+    let buffer = SharedBuffer::default();
+
+    let replay = Replay::load("session.jsonl").await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+    client.set_capture(Some(Arc::new(rtsp::Capture::new(buffer.clone()))));
+    run_session(&client).await;
+    client.close().await.unwrap();
+    replay.finish().await.unwrap();
+
+    let entries = capture::read(buffer.0.lock().unwrap().as_slice()).unwrap();
+    let exchanges = support::exchanges(&entries);
+    assert_eq!(exchanges.len(), 7);
+
+    match &exchanges[3].request.body {
+        Body::PList(plist::Value::Array(x)) => assert_eq!(x.len(), 2),
+        x => panic!("SETPEERS body didn't survive the capture: {:?}", x),
+    }
+
+    let replay = Replay::serve(exchanges).await;
+    let client = Client::connect(replay.addr()).await.unwrap();
+    run_session(&client).await;
+    client.close().await.unwrap();
+    replay.finish().await.unwrap();
+}
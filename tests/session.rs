@@ -0,0 +1,145 @@
+mod support;
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use airplay::{dacp::Remote, rtsp::{self, ops::{SetupStreamsResponse, StreamInfo}, Client, Method}, session::{Backoff, Event, Session, Setup}};
+use support::{mock::{Fault, MockConfig, MockReceiver}, setup_info, setup_streams};
+use tokio::{sync::broadcast, time::timeout};
+
+/// Sets up the mock at `addr` the way a sender would.
+struct MockSetup {
+    addr: SocketAddr,
+    position: Arc<Mutex<Option<(u16, u32)>>>,
+    remote: Option<Remote>,
+}
+
+impl Setup for MockSetup {
+    async fn setup(&self, client: &Client) -> Result<StreamInfo, rtsp::Error> {
+        client.set_default_timeout(Some(Duration::from_secs(5)));
+        client.pair_setup_transient().await?;
+        client.setup_info(setup_info()).await?;
+        let response = client.setup_streams(setup_streams()).await?;
+        Ok(SetupStreamsResponse::from_response(&response).unwrap().streams.remove(0))
+    }
+
+    fn position(&self) -> Option<(u16, u32)> {
+        *self.position.lock().unwrap()
+    }
+
+    fn remote(&self) -> Option<Remote> {
+        self.remote
+    }
+
+    async fn locate(&self, _: &str, _: Duration) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+async fn start_with(mock: &MockReceiver, position: Arc<Mutex<Option<(u16, u32)>>>, remote: Option<Remote>) -> Session {
+    let backoff = Backoff { initial: Duration::from_millis(10), ..Default::default() };
+    Session::start(&mock.config().device_id, MockSetup { addr: mock.addr(), position, remote }, backoff).await.unwrap()
+}
+
+async fn start(mock: &MockReceiver, position: Arc<Mutex<Option<(u16, u32)>>>) -> Session {
+    start_with(mock, position, None).await
+}
+
+async fn event(events: &mut broadcast::Receiver<Event>) -> Event {
+    timeout(Duration::from_secs(5), events.recv()).await.expect("no event arrived").unwrap()
+}
+
+#[tokio::test]
+async fn reconnects_after_the_connection_drops() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let position: Arc<Mutex<Option<(u16, u32)>>> = Default::default();
+    let session = start(&mock, position.clone()).await;
+    let mut events = session.subscribe();
+    let first = session.connection().unwrap();
+    assert_eq!(mock.records(), vec![None]);
+
+    *position.lock().unwrap() = Some((7, 2464));
+
+    mock.fail(Method::SET_PARAMETER, "*", Fault::Drop);
+    assert!(first.client.set_volume(-10.0).await.is_err());
+
+    assert!(matches!(event(&mut events).await, Event::Disconnected));
+    assert!(matches!(event(&mut events).await, Event::Resolving { attempt: 1 }));
+    assert!(matches!(event(&mut events).await, Event::Reconnecting { attempt: 1, addr } if addr == mock.addr()));
+
+    let Event::Resumed(second) = event(&mut events).await else { panic!("expected the session to resume") };
+    assert!(!second.client.is_closed());
+    assert_eq!(session.connection().map(|x| x.client.peer), Some(mock.addr()));
+    assert_eq!(mock.connections(), 2);
+    assert_eq!(mock.records(), vec![None, Some("seq=7;rtptime=2464".to_string())]);
+
+    second.client.set_volume(-10.0).await.unwrap();
+    assert_eq!(mock.volume(), Some(-10.0));
+    assert!(!session.is_failed());
+}
+
+#[tokio::test]
+async fn reconnects_with_the_same_remote() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let remote = Remote { id: 0x1122334455667788, active_remote: 1234 };
+    let session = start_with(&mock, Default::default(), Some(remote)).await;
+    let mut events = session.subscribe();
+
+    mock.fail(Method::SET_PARAMETER, "*", Fault::Drop);
+    assert!(session.connection().unwrap().client.set_volume(-10.0).await.is_err());
+
+    while !matches!(event(&mut events).await, Event::Resumed(_)) {}
+    let headers = Some(("1122334455667788".to_string(), "1234".to_string()));
+    assert_eq!(mock.remotes(), vec![headers.clone(), headers]);
+}
+
+#[tokio::test]
+async fn closing_the_session_doesnt_reconnect() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let session = start(&mock, Default::default()).await;
+    let mut events = session.subscribe();
+    let client = session.connection().unwrap().client;
+
+    session.close().await.unwrap();
+    assert!(matches!(event(&mut events).await, Event::Closed));
+    assert!(client.is_closed());
+    assert_eq!(mock.teardowns(), 1);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(mock.connections(), 1);
+}
+
+#[tokio::test]
+async fn closing_the_client_doesnt_reconnect() {
+    let mock = MockReceiver::start(MockConfig::default()).await;
+    let session = start(&mock, Default::default()).await;
+    let mut events = session.subscribe();
+
+    session.connection().unwrap().client.close().await.unwrap();
+    assert!(matches!(event(&mut events).await, Event::Closed));
+    assert!(session.connection().is_none());
+    assert!(!session.is_failed());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(mock.connections(), 1);
+}
+
+#[tokio::test]
+async fn closing_the_client_doesnt_reconnect_when_teardown_fails() {
+    for fault in [Fault::Status(500, "Internal Server Error"), Fault::Drop] {
+        let mock = MockReceiver::start(MockConfig::default()).await;
+        let session = start(&mock, Default::default()).await;
+        let mut events = session.subscribe();
+
+        mock.fail(Method::TEARDOWN, "*", fault.clone());
+        let client = session.connection().unwrap().client;
+        assert!(client.close().await.is_err());
+        assert!(!client.is_session_active());
+
+        assert!(matches!(event(&mut events).await, Event::Closed), "{:?}", fault);
+        assert!(session.connection().is_none());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(events.try_recv().is_err());
+        assert_eq!(mock.connections(), 1);
+    }
+}
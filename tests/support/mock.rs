@@ -0,0 +1,492 @@
+use std::{borrow::Cow, net::SocketAddr, sync::{Arc, Mutex}};
+
+use airplay::{pairing::{self, Decrypter, Encrypter, Identity, Peer, SetupServer, Tlv8, VerifyServer}, rtsp::{Body, Message, Method, Parser, Request, Response}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream, UdpSocket}, task::JoinHandle};
+
+use super::resource;
+
+/// What the mock receiver reports about itself.
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub name: String,
+    pub device_id: String,
+    pub model: String,
+    pub features: u64,
+    /// The PIN pair-setup expects. Transient pairing always uses [`pairing::TRANSIENT_PIN`].
+    pub pin: String,
+    /// Answered in `Audio-Latency` on RECORD, in frames.
+    pub audio_latency: u32,
+    pub output_latency_micros: u64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            name: "Mock".to_string(),
+            device_id: "AA:BB:CC:DD:EE:FF".to_string(),
+            model: "AudioAccessory5,1".to_string(),
+            features: 0x3C155FDE4A7FDFD5,
+            pin: "1234".to_string(),
+            audio_latency: 11025,
+            output_latency_micros: 400_000,
+        }
+    }
+}
+
+/// How an injected failure answers a request.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    Status(i32, &'static str),
+    /// Closes the connection instead of answering.
+    Drop,
+    /// Never answers, leaving the connection open.
+    Ignore,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<(Method, String)>,
+    faults: Vec<(Method, String, Fault)>,
+    pairings: Vec<Peer>,
+    connections: usize,
+    disconnects: usize,
+    volume: Option<f32>,
+    recording: bool,
+    records: Vec<Option<String>>,
+    remotes: Vec<Option<(String, String)>>,
+    flushes: Vec<String>,
+    peers: Vec<Vec<String>>,
+    teardowns: usize,
+    rtp_packets: usize,
+    /// The tasks reading the ports of streams SET UP and not torn down yet.
+    streams: Vec<JoinHandle<()>>,
+}
+
+impl State {
+    fn close_streams(&mut self) {
+        for stream in self.streams.drain(..) {
+            stream.abort();
+        }
+    }
+}
+
+/// An in-process AirPlay 2 receiver on localhost that answers the control protocol, including pairing
+/// and the encrypted connection afterwards, and accepts RTP on the data ports it hands out.
+pub struct MockReceiver {
+    addr: SocketAddr,
+    config: MockConfig,
+    identity: Identity,
+    state: Arc<Mutex<State>>,
+    accept: JoinHandle<()>,
+}
+
+impl Drop for MockReceiver {
+    fn drop(&mut self) {
+        self.accept.abort();
+        self.state.lock().unwrap().close_streams();
+    }
+}
+
+impl MockReceiver {
+    pub async fn start(config: MockConfig) -> MockReceiver {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let identity = Identity::generate(&config.device_id);
+        let state: Arc<Mutex<State>> = Default::default();
+
+        let accept = tokio::spawn({
+            let connection = Connection { config: config.clone(), identity: identity.clone(), state: state.clone() };
+
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    connection.state.lock().unwrap().connections += 1;
+                    let connection = connection.clone();
+
+                    tokio::spawn(async move {
+                        let state = connection.state.clone();
+                        connection.serve(stream).await;
+                        state.lock().unwrap().disconnects += 1;
+                    });
+                }
+            }
+        });
+
+        MockReceiver { addr, config, identity, state, accept }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn config(&self) -> &MockConfig {
+        &self.config
+    }
+
+    /// The receiver's long-term identity, as a sender learns it from pair-setup.
+    pub fn peer(&self) -> Peer {
+        Peer { id: self.identity.id.clone(), public_key: self.identity.public_key() }
+    }
+
+    /// Makes the next request for `method` and `path` fail. `*` matches any path.
+    pub fn fail(&self, method: Method, path: &str, fault: Fault) {
+        self.state.lock().unwrap().faults.push((method, path.to_string(), fault));
+    }
+
+    /// Every request received so far, with `rtsp://` URLs reduced to their path.
+    pub fn requests(&self) -> Vec<(Method, String)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn pairings(&self) -> Vec<Peer> {
+        self.state.lock().unwrap().pairings.clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// How many connections have ended, by either side.
+    pub fn disconnects(&self) -> usize {
+        self.state.lock().unwrap().disconnects
+    }
+
+    pub fn volume(&self) -> Option<f32> {
+        self.state.lock().unwrap().volume
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.state.lock().unwrap().recording
+    }
+
+    /// The `RTP-Info` of every RECORD, if it had one.
+    pub fn records(&self) -> Vec<Option<String>> {
+        self.state.lock().unwrap().records.clone()
+    }
+
+    /// The `DACP-ID` and `Active-Remote` of every RECORD, if it had them.
+    pub fn remotes(&self) -> Vec<Option<(String, String)>> {
+        self.state.lock().unwrap().remotes.clone()
+    }
+
+    /// The `RTP-Info` of every FLUSH.
+    pub fn flushes(&self) -> Vec<String> {
+        self.state.lock().unwrap().flushes.clone()
+    }
+
+    /// The address list of every SETPEERS.
+    pub fn peers(&self) -> Vec<Vec<String>> {
+        self.state.lock().unwrap().peers.clone()
+    }
+
+    pub fn teardowns(&self) -> usize {
+        self.state.lock().unwrap().teardowns
+    }
+
+    pub fn rtp_packets(&self) -> usize {
+        self.state.lock().unwrap().rtp_packets
+    }
+}
+
+#[derive(Clone)]
+struct Connection {
+    config: MockConfig,
+    identity: Identity,
+    state: Arc<Mutex<State>>,
+}
+
+/// Per connection pairing progress.
+#[derive(Default)]
+struct Pairing {
+    setup: Option<SetupServer>,
+    verify: Option<VerifyServer>,
+}
+
+impl Connection {
+    async fn serve(self, stream: TcpStream) {
+        let (mut rx, mut tx) = stream.into_split();
+        let mut parser = Parser::new();
+        let mut buf = vec![0_u8; 4096];
+        let mut pairing = Pairing::default();
+        let mut encrypter: Option<Encrypter> = None;
+        let mut decrypter: Option<Decrypter> = None;
+
+        loop {
+            let request = match parser.next() {
+                Ok(Some(Message::Request(x))) => x,
+                Ok(Some(Message::Response(_))) | Err(_) => return,
+                Ok(None) => match rx.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => {
+                        match decrypter.as_mut().map(|x| x.decrypt(&buf[..n])) {
+                            Some(Ok(x)) => parser.feed(&x),
+                            Some(Err(_)) => return,
+                            None => parser.feed(&buf[..n]),
+                        }
+
+                        continue;
+                    },
+                },
+            };
+
+            let fault = {
+                let mut state = self.state.lock().unwrap();
+                let path = resource(&request.path).to_string();
+                state.requests.push((request.method.clone(), path.clone()));
+
+                let index = state.faults.iter().position(|(method, x, _)| *method == request.method && (x == "*" || *x == path));
+                index.map(|x| state.faults.remove(x).2)
+            };
+
+            let (mut response, keys) = match fault {
+                Some(Fault::Drop) => return,
+                Some(Fault::Ignore) => continue,
+                Some(Fault::Status(status, reason)) => (Response::new(status, reason), None),
+                None => self.answer(&request, &mut pairing).await,
+            };
+
+            if let Some(seq) = request.headers.get("CSeq") {
+                response.headers.set("CSeq", seq);
+            }
+
+            response.headers.set("Server", "AirTunes/770.8.1");
+            let bytes = response.to_bytes(&request.version);
+
+            let bytes = match encrypter.as_mut() {
+                Some(x) => Cow::Owned(x.encrypt(&bytes)),
+                None => Cow::Borrowed(&bytes),
+            };
+
+            if tx.write_all(&bytes).await.is_err() {
+                return;
+            }
+
+            // Keys apply from the message after the one that completed pairing.
+            if let Some(keys) = keys {
+                encrypter = Some(Encrypter::new(&keys.write));
+                decrypter = Some(Decrypter::new(&keys.read));
+            }
+        }
+    }
+
+    async fn answer(&self, request: &Request, pairing: &mut Pairing) -> (Response, Option<pairing::SessionKeys>) {
+        let ok = Response::new(200, "OK");
+
+        match (&request.method, resource(&request.path)) {
+            (Method::GET, "/info") => (Response::new_body(200, "OK", Body::PList(self.info())), None),
+            (Method::POST, "/pair-setup") => self.pair_setup(request, pairing),
+            (Method::POST, "/pair-verify") => self.pair_verify(request, pairing),
+            (Method::SETUP, _) => (self.setup(request).await, None),
+            (Method::SETPEERS, _) => {
+                let peers = match &request.body {
+                    Body::PList(x) => Some(x.clone()),
+                    Body::Raw(x) => plist::Value::from_reader(std::io::Cursor::new(x)).ok(),
+                    Body::None => None,
+                };
+                let peers = peers.as_ref().and_then(|x| x.as_array()).into_iter().flatten()
+                    .filter_map(|x| x.as_string())
+                    .map(str::to_string)
+                    .collect();
+
+                self.state.lock().unwrap().peers.push(peers);
+                (ok, None)
+            },
+            (Method::POST, "/feedback") => (ok, None),
+            (Method::RECORD, _) => {
+                let mut state = self.state.lock().unwrap();
+                state.recording = true;
+                state.records.push(request.headers.get("RTP-Info").map(str::to_string));
+                let remote = request.headers.get("DACP-ID").zip(request.headers.get("Active-Remote"));
+                state.remotes.push(remote.map(|(x, y)| (x.to_string(), y.to_string())));
+                drop(state);
+
+                let mut res = ok;
+                res.headers.set("Audio-Latency", self.config.audio_latency);
+                (res, None)
+            },
+            (Method::SET_PARAMETER, _) => {
+                let volume = match &request.body {
+                    Body::Raw(x) => std::str::from_utf8(x).ok()
+                        .and_then(|x| x.trim().strip_prefix("volume:"))
+                        .and_then(|x| x.trim().parse::<f32>().ok()),
+                    _ => None,
+                };
+
+                match volume {
+                    Some(x) => {
+                        self.state.lock().unwrap().volume = Some(x);
+                        (ok, None)
+                    },
+                    None => (Response::new(451, "Parameter Not Understood"), None),
+                }
+            },
+            (Method::FLUSH, _) => {
+                let info = request.headers.get("RTP-Info").unwrap_or_default().to_string();
+                self.state.lock().unwrap().flushes.push(info);
+                (ok, None)
+            },
+            (Method::TEARDOWN, _) => {
+                let mut state = self.state.lock().unwrap();
+                state.recording = false;
+                state.teardowns += 1;
+                state.close_streams();
+                (ok, None)
+            },
+            _ => (Response::new(501, "Not Implemented"), None),
+        }
+    }
+
+    fn info(&self) -> plist::Value {
+        let latency = |kind: u64, micros: u64| plist::Value::Dictionary(plist::Dictionary::from_iter([
+            ("audioType", plist::Value::from("default")),
+            ("inputLatencyMicros", plist::Value::from(0_u64)),
+            ("outputLatencyMicros", plist::Value::from(micros)),
+            ("type", plist::Value::from(kind)),
+        ]));
+
+        plist::Value::Dictionary(plist::Dictionary::from_iter([
+            ("audioLatencies", plist::Value::Array(vec![latency(96, self.config.output_latency_micros), latency(103, 2_000_000)])),
+            ("deviceID", plist::Value::from(self.config.device_id.as_str())),
+            ("features", plist::Value::from(self.config.features as i64)),
+            ("model", plist::Value::from(self.config.model.as_str())),
+            ("name", plist::Value::from(self.config.name.as_str())),
+            ("pk", plist::Value::Data(self.identity.public_key().to_vec())),
+            ("sourceVersion", plist::Value::from("770.8.1")),
+            ("statusFlags", plist::Value::from(4_u64)),
+            ("supportedFormats", plist::Value::Dictionary(plist::Dictionary::from_iter([
+                ("audioStream", plist::Value::from(21235712_u64)),
+                ("bufferStream", plist::Value::from(14680064_u64)),
+            ]))),
+        ]))
+    }
+
+    fn tlv(request: &Request) -> Option<Tlv8> {
+        match &request.body {
+            Body::Raw(x) => Tlv8::parse(x).ok(),
+            _ => None,
+        }
+    }
+
+    fn tlv_response(message: Tlv8) -> Response {
+        let mut res = Response::new_body(200, "OK", Body::Raw(message.to_bytes()));
+        res.headers.set("Content-Type", "application/octet-stream");
+        res
+    }
+
+    fn pair_setup(&self, request: &Request, pairing: &mut Pairing) -> (Response, Option<pairing::SessionKeys>) {
+        let Some(message) = Connection::tlv(request) else {
+            return (Response::new(400, "Bad Request"), None);
+        };
+
+        if message.byte(pairing::tlv::STATE) == Some(1) {
+            let transient = message.byte(pairing::tlv::FLAGS).is_some_and(|x| x & pairing::tlv::FLAG_TRANSIENT != 0);
+            let pin = if transient { pairing::TRANSIENT_PIN } else { &self.config.pin };
+            pairing.setup = Some(SetupServer::new(self.identity.clone(), pin));
+        }
+
+        let Some(setup) = pairing.setup.as_mut() else {
+            return (Response::new(470, "Connection Authorization Required"), None);
+        };
+
+        let answer = setup.handle(&message);
+        let succeeded = answer.get(pairing::tlv::ERROR).is_none();
+        let mut keys = None;
+
+        match answer.byte(pairing::tlv::STATE) {
+            Some(4) if succeeded && setup.is_transient() => keys = setup.session_keys(),
+            Some(6) if succeeded => {
+                if let Some(peer) = setup.peer() {
+                    let mut state = self.state.lock().unwrap();
+                    state.pairings.retain(|x| x.id != peer.id);
+                    state.pairings.push(peer.clone());
+                }
+            },
+            _ => {},
+        }
+
+        (Connection::tlv_response(answer), keys)
+    }
+
+    fn pair_verify(&self, request: &Request, pairing: &mut Pairing) -> (Response, Option<pairing::SessionKeys>) {
+        let Some(message) = Connection::tlv(request) else {
+            return (Response::new(400, "Bad Request"), None);
+        };
+
+        if message.byte(pairing::tlv::STATE) == Some(1) {
+            let state = self.state.clone();
+            pairing.verify = Some(VerifyServer::new(self.identity.clone(), move |id| {
+                state.lock().unwrap().pairings.iter().find(|x| x.id == id).cloned()
+            }));
+        }
+
+        let Some(verify) = pairing.verify.as_mut() else {
+            return (Response::new(470, "Connection Authorization Required"), None);
+        };
+
+        let answer = verify.handle(&message);
+        (Connection::tlv_response(answer), verify.session_keys())
+    }
+
+    async fn setup(&self, request: &Request) -> Response {
+        // Senders leave out the content type on the first SETUP, receivers sniff the plist anyway.
+        let body = match &request.body {
+            Body::PList(x) => Some(x.clone()),
+            Body::Raw(x) => plist::Value::from_reader(std::io::Cursor::new(x)).ok(),
+            Body::None => None,
+        };
+
+        let Some(plist::Value::Dictionary(body)) = body else {
+            return Response::new(400, "Bad Request");
+        };
+
+        let Some(plist::Value::Array(streams)) = body.get("streams") else {
+            return Response::new_body(200, "OK", Body::PList(plist::Value::Dictionary(plist::Dictionary::from_iter([
+                ("eventPort", plist::Value::from(0_u64)),
+                ("timingPort", plist::Value::from(0_u64)),
+            ]))));
+        };
+
+        let mut answers = Vec::new();
+
+        for stream in streams {
+            let kind = stream.as_dictionary().and_then(|x| x.get("type")).and_then(|x| x.as_unsigned_integer()).unwrap_or(96);
+            let data = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let control = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let data_port = data.local_addr().unwrap().port();
+            let control_port = control.local_addr().unwrap().port();
+
+            // Counts RTP, and takes sync packets on the control port without looking at them.
+            let task = tokio::spawn({
+                let state = self.state.clone();
+
+                async move {
+                    let (mut buf, mut control_buf) = (vec![0; 2048], vec![0; 2048]);
+
+                    loop {
+                        tokio::select! {
+                            x = data.recv(&mut buf) => match x {
+                                Ok(_) => state.lock().unwrap().rtp_packets += 1,
+                                Err(_) => break,
+                            },
+                            x = control.recv(&mut control_buf) => if x.is_err() {
+                                break;
+                            },
+                        }
+                    }
+                }
+            });
+
+            self.state.lock().unwrap().streams.push(task);
+
+            answers.push(plist::Value::Dictionary(plist::Dictionary::from_iter([
+                ("type", plist::Value::from(kind)),
+                ("dataPort", plist::Value::from(u64::from(data_port))),
+                ("controlPort", plist::Value::from(u64::from(control_port))),
+                ("streamID", plist::Value::from(answers.len() as u64 + 1)),
+            ])));
+        }
+
+        Response::new_body(200, "OK", Body::PList(plist::Value::Dictionary(plist::Dictionary::from_iter([
+            ("streams", plist::Value::Array(answers)),
+        ]))))
+    }
+}
@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+
+pub mod mock;
+
+use std::{net::SocketAddr, path::Path};
+
+use airplay::rtsp::{capture::{self, Direction, Entry}, ops::{SetupInfoRequest, SetupStreamsRequest, StreamDescription, STREAM_TYPE_REALTIME}, Message, Parser, Request, Response};
+use plist::Data;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, task::JoinHandle};
+
+/// A SETUP body as an iPhone sends it.
+pub fn setup_info() -> SetupInfoRequest {
+    SetupInfoRequest {
+        device_id: "00:00:00:00:00:00".to_string(),
+        eiv: Data::new(vec![]),
+        ekey: Data::new(vec![]),
+        et: 0,
+        group_contains_group_leader: false,
+        group_uuid: "67EAD1FA-7EAB-4810-82F7-A9132FD2D0BB".to_string(),
+        is_multi_select_airplay: true,
+        mac_address: "00:00:00:00:00:00".to_string(),
+        model: "iPhone10,6".to_string(),
+        name: "crystal".to_string(),
+        os_build_version: "17B111".to_string(),
+        os_name: "iPhone OS".to_string(),
+        os_version: "13.2.3".to_string(),
+        sender_supports_relay: false,
+        session_uuid: "3195C737-1E6E-4487-BECB-4D287B7C7626".to_string(),
+        source_version: "409.16".to_string(),
+        timing_peer_info: vec![],
+        timing_peer_list: vec![],
+        timing_protocol: "PTP".to_string(),
+    }
+}
+
+/// One realtime ALAC stream.
+pub fn setup_streams() -> SetupStreamsRequest {
+    SetupStreamsRequest {
+        streams: vec![StreamDescription {
+            audio_format: 1 << 18,
+            audio_mode: "default".to_string(),
+            ct: 2,
+            control_port: 6001,
+            is_media: true,
+            latency_min: Some(11025),
+            latency_max: Some(88200),
+            shk: Data::new(vec![0; 32]),
+            spf: 352,
+            sr: 44100,
+            stream_connection_id: 1,
+            supports_dynamic_stream_id: false,
+            stream_type: STREAM_TYPE_REALTIME,
+        }],
+    }
+}
+
+/// A sender request from a capture and the answer the receiver gave to it.
+pub struct Exchange {
+    pub request: Request,
+    pub response: Response,
+}
+
+/// `rtsp://192.168.1.20/666` and `/666` name the same resource, captures just have the device's address.
+fn resource(path: &str) -> &str {
+    match path.strip_prefix("rtsp://") {
+        Some(x) => x.find('/').map_or("/", |i| &x[i..]),
+        None => path,
+    }
+}
+
+/// Pairs every request we sent with the response carrying its `CSeq`, in the order they were sent.
+pub fn exchanges(entries: &[Entry]) -> Vec<Exchange> {
+    entries.iter()
+        .filter(|x| x.direction == Direction::Sent)
+        .filter_map(|x| x.to_request())
+        .filter_map(|request| {
+            let seq = request.headers.cseq()?;
+            let response = entries.iter()
+                .filter(|x| x.direction == Direction::Received)
+                .filter_map(|x| x.to_response())
+                .find(|x| x.headers.cseq() == Some(seq))?;
+
+            Some(Exchange { request, response })
+        })
+        .collect()
+}
+
+/// Plays the receiver's side of a captured session on a local port.
+///
+/// Every request the client makes has to match the next captured one by method and resource, and is
+/// answered with the captured response under the client's `CSeq`. Bodies aren't compared, they often
+/// carry random keys and identifiers.
+pub struct Replay {
+    addr: SocketAddr,
+    handle: JoinHandle<Result<(), String>>,
+}
+
+impl Replay {
+    pub async fn load(name: &str) -> Replay {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/captures").join(name);
+        let entries = capture::load(&path).unwrap_or_else(|err| panic!("Failed to load {}: {}", path.display(), err));
+        Replay::serve(exchanges(&entries)).await
+    }
+
+    pub async fn serve(exchanges: Vec<Exchange>) -> Replay {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.map_err(|x| x.to_string())?;
+            let mut parser = Parser::new();
+            let mut buf = vec![0_u8; 4096];
+            let mut exchanges = exchanges.into_iter();
+            let mut error = None;
+
+            loop {
+                let request = match parser.next().map_err(|x| x.to_string())? {
+                    Some(Message::Request(x)) => x,
+                    Some(Message::Response(x)) => return Err(format!("unexpected response from client: {:?}", x)),
+                    None => match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            parser.feed(&buf[..n]);
+                            continue;
+                        },
+                    },
+                };
+
+                // After the first mismatch the rest of the capture is meaningless, keep answering until the
+                // client hangs up so it sees the error rather than a reset connection.
+                let mut response = match (&error, exchanges.next()) {
+                    (None, Some(x)) if request.method == x.request.method && resource(&request.path) == resource(&x.request.path) => x.response,
+                    (None, expected) => {
+                        error = Some(match expected {
+                            Some(x) => format!("expected {} {}, got {} {}", x.request.method, x.request.path, request.method, request.path),
+                            None => format!("{} {} after the end of the capture", request.method, request.path),
+                        });
+                        Response::new(400, "Bad Request")
+                    },
+                    (Some(_), _) => Response::new(400, "Bad Request"),
+                };
+
+                if let Some(seq) = request.headers.get("CSeq") {
+                    response.headers.set("CSeq", seq);
+                }
+
+                stream.write_all(&response.to_bytes(&request.version)).await.map_err(|x| x.to_string())?;
+            }
+
+            match (error, exchanges.len()) {
+                (Some(x), _) => Err(x),
+                (None, 0) => Ok(()),
+                (None, left) => Err(format!("client disconnected with {} exchanges left", left)),
+            }
+        });
+
+        Replay { addr, handle }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the client to disconnect and fails unless the whole capture was played.
+    pub async fn finish(self) -> Result<(), String> {
+        self.handle.await.map_err(|x| x.to_string())?
+    }
+}